@@ -0,0 +1,40 @@
+//! Times a tight loop running from dram with the decode cache and without it, where
+//! every fetch goes through the bus. Run with `cargo bench --bench hot_loop`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rusty_riscv_ave::{builder::CpuBuilder, cpu::HaltReason};
+
+/// Count down from 1 000 000, 2 000 003 instructions.
+const LOOP: [u32; 5] = [
+    0x000f42b7, // lui t0, 0xf4
+    0x24028293, // addi t0, t0, 0x240
+    0xfff28293, // addi t0, t0, -1
+    0xfe029ee3, // bnez t0, -4
+    0x00100073, // ebreak
+];
+
+fn hot_loop(c: &mut Criterion) {
+    let code: Vec<u8> = LOOP.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let mut group = c.benchmark_group("hot_loop");
+    group.sample_size(10);
+    for (name, decode_cache) in [("decode cache", true), ("no decode cache", false)] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    CpuBuilder::new()
+                        .code(code.clone())
+                        .headless_uart(true)
+                        .exit_on_ebreak(true)
+                        .decode_cache(decode_cache)
+                        .build()
+                },
+                |mut cpu| assert_eq!(cpu.run(), HaltReason::Exit(0)),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hot_loop);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+//! Times a memcpy loop in S-mode under Sv39 with 4 KiB pages, with the TLB and with
+//! every access walking the page table. Run with `cargo bench --bench sv39_memcpy`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rusty_riscv_ave::{builder::CpuBuilder, cpu::{Cpu, HaltReason}, param::*};
+
+/// Turn on Sv39 with the satp value at offset 0x100, then copy 64 KiB from 0x10000 on
+/// to 0x30000 on, 20 times, about 1 000 000 instructions. Addresses are identity
+/// mapped.
+const MEMCPY: [u32; 20] = [
+    0x00000317, // auipc t1, 0
+    0x10033283, // ld t0, 0x100(t1)
+    0x18029073, // csrw satp, t0
+    0x12000073, // sfence.vma
+    0x01400413, // li s0, 20
+    0x00010537, // lui a0, 0x10
+    0x00650533, // add a0, a0, t1
+    0x000305b7, // lui a1, 0x30
+    0x006585b3, // add a1, a1, t1
+    0x00010637, // lui a2, 0x10
+    0x00053383, // ld t2, 0(a0)
+    0x0075b023, // sd t2, 0(a1)
+    0x00850513, // addi a0, a0, 8
+    0x00858593, // addi a1, a1, 8
+    0xff860613, // addi a2, a2, -8
+    0xfe0616e3, // bnez a2, -20
+    0xfff40413, // addi s0, s0, -1
+    0xfc0418e3, // bnez s0, -48
+    0x00000513, // li a0, 0
+    0x00100073, // ebreak
+];
+
+/// Where the page tables go, past the data.
+const PAGE_TABLES: u64 = DRAM_BASE + 0x10_0000;
+
+/// A machine about to run `MEMCPY`, with the first 2 MiB of dram mapped.
+fn machine(builder: CpuBuilder) -> Cpu {
+    let mut code: Vec<u8> = MEMCPY.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    let (root, l1, l0) = (PAGE_TABLES, PAGE_TABLES + 0x1000, PAGE_TABLES + 0x2000);
+    code.resize(0x100, 0);
+    code.extend_from_slice(&(8 << 60 | root >> 12).to_le_bytes());
+    let mut cpu = builder.code(code).headless_uart(true).exit_on_ebreak(true).initial_mode(0b01).build();
+
+    // The first 2 MiB of dram in 4 KiB pages.
+    let pte = |addr: u64, flags: u64| (addr >> 12) << 10 | flags | PTE_V;
+    cpu.write_mem(root + (DRAM_BASE >> 30) * 8, 64, pte(l1, 0)).unwrap();
+    cpu.write_mem(l1, 64, pte(l0, 0)).unwrap();
+    for i in 0..512 {
+        let flags = PTE_R | PTE_W | PTE_X | PTE_A | PTE_D;
+        cpu.write_mem(l0 + i * 8, 64, pte(DRAM_BASE + i * PAGE_SIZE, flags)).unwrap();
+    }
+    cpu
+}
+
+fn sv39_memcpy(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sv39_memcpy");
+    group.sample_size(10);
+    for (name, tlb) in [("tlb", true), ("no tlb", false)] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || machine(CpuBuilder::new().tlb(tlb)),
+                |mut cpu| assert_eq!(cpu.run(), HaltReason::Exit(0)),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sv39_memcpy);
+criterion_main!(benches);
@@ -0,0 +1,38 @@
+//! Boot a minimal bare-metal program end to end through the library API.
+//!
+//! The program is `tests/test_helloworld.bin`, built from `tests/test_helloworld.c`.
+//! It writes a greeting to the UART and returns from `main`, which jumps to address 0
+//! and halts the cpu with an instruction access fault.
+//!
+//! ```bash
+//! cargo run --example bare_metal
+//! ```
+
+use rusty_riscv_ave::{builder::CpuBuilder, cpu::HaltReason};
+
+const PROGRAM: &[u8] = include_bytes!("../tests/test_helloworld.bin");
+
+fn main() {
+    let mut cpu = CpuBuilder::new()
+        .code(PROGRAM.to_vec())
+        .headless_uart(true)
+        .build();
+
+    let reason = cpu.run();
+    let output = String::from_utf8_lossy(cpu.bus.uart.output()).into_owned();
+
+    print!("{}", output);
+    match reason {
+        HaltReason::Fault(e) => println!("halted: {}", e),
+        HaltReason::Exit(code) => println!("exited with code {}", code),
+        HaltReason::WatchdogReset => println!("reset by the watchdog"),
+        HaltReason::Reset => println!("reset by the guest"),
+        HaltReason::Breakpoint(pc) => println!("stopped at breakpoint {:#x}", pc),
+        HaltReason::NullDeref { addr, pc } => println!("null pointer access {:#x} at pc {:#x}", addr, pc),
+        HaltReason::SelfLoop(pc) => println!("stuck in a loop at {:#x}", pc),
+        HaltReason::Caught { exception, pc } => println!("caught {} at pc {:#x}", exception, pc),
+    }
+    cpu.dump_pc();
+
+    assert!(output.contains("Hello, world!"), "unexpected uart output {:?}", output);
+}
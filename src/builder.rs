@@ -0,0 +1,630 @@
+//! The builder module contains `CpuBuilder`, which configures a `Cpu` and the
+//! devices on its bus before the first instruction runs.
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use crate::{
+    bus::{Bus, MemAttr},
+    cpu::{Cpu, MisalignPolicy},
+    decode,
+    dram::Dram,
+    dtb,
+    elf::{self, ElfError, ProgramSegment},
+    finisher::TestFinisher,
+    hart::Scheduler,
+    param::*,
+    uart::Uart,
+    virtio_net::{NetBackend, VirtioNet},
+    watchdog::Watchdog,
+};
+
+/// Why `CpuBuilder::try_build` couldn't build the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildError {
+    /// The code given to `code` is larger than dram.
+    CodeTooLarge { len: u64, dram_size: u64 },
+    /// A segment of the ELF executable doesn't fit in dram.
+    OutsideDram { paddr: u64, mem_size: u64 },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::CodeTooLarge { len, dram_size } => {
+                write!(f, "code of {:#x} bytes doesn't fit in dram of {:#x} bytes", len, dram_size)
+            }
+            BuildError::OutsideDram { paddr, mem_size } => {
+                write!(f, "segment of {:#x} bytes at {:#x} is outside dram", mem_size, paddr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[derive(Default)]
+pub struct CpuBuilder {
+    code: Vec<u8>,
+    disk_image: Vec<u8>,
+    dram_size: Option<u64>,
+    headless_uart: bool,
+    uart_input: Option<Box<dyn Read + Send>>,
+    uart_output: Option<Box<dyn Write>>,
+    exit_on_ebreak: bool,
+    exit_ecall: Option<u64>,
+    semihosting: bool,
+    no_zicntr: bool,
+    strict: bool,
+    net: Option<Box<dyn NetBackend>>,
+    dram_latency: Option<u64>,
+    mmio_latency: Option<u64>,
+    hartid: u64,
+    entry_pc: Option<u64>,
+    initial_mode: Option<u64>,
+    harts: usize,
+    coverage: bool,
+    check_mem_attrs: bool,
+    mem_attrs: Vec<(u64, u64, MemAttr)>,
+    watchdog: Option<u64>,
+    mtime_divisor: Option<u64>,
+    test_finisher: Option<u64>,
+    null_guard: bool,
+    break_on_exception: Option<Vec<u64>>,
+    no_tlb: bool,
+    no_decode_cache: bool,
+    tohost: Option<u64>,
+    halt_on_self_loop: bool,
+    misaligned: MisalignPolicy,
+    trace: bool,
+    rom: Option<Vec<u8>>,
+    virt_machine: bool,
+    dtb: bool,
+    elf: Option<(Vec<ProgramSegment>, u64)>,
+}
+
+impl CpuBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The raw binary copied to the start of dram.
+    pub fn code(mut self, code: Vec<u8>) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// The size of dram in bytes, a multiple of the page size. Defaults to `DRAM_SIZE`.
+    /// Accesses past its end are access faults, and the stack starts at its end.
+    pub fn dram_size(mut self, size: u64) -> Self {
+        assert!(size > 0 && size.is_multiple_of(PAGE_SIZE), "dram size {:#x} is not a multiple of pages", size);
+        self.dram_size = Some(size);
+        self
+    }
+
+    /// Load the segments of the ELF64 executable `bytes` into dram and start at its
+    /// entry point, instead of running `code` from the start of dram. The segments have
+    /// to fit in dram, which `try_build` checks.
+    pub fn elf(mut self, bytes: &[u8]) -> Result<Self, ElfError> {
+        let (segments, entry) = elf::load_elf(bytes)?;
+        if self.tohost.is_none() {
+            self.tohost = elf::find_symbol(bytes, "tohost")?;
+        }
+        self.elf = Some((segments, entry));
+        Ok(self)
+    }
+
+    /// The disk image behind the virtio block device.
+    pub fn disk(mut self, disk_image: Vec<u8>) -> Self {
+        self.disk_image = disk_image;
+        self
+    }
+
+    /// Detach the UART from the terminal and capture its output instead.
+    pub fn headless_uart(mut self, headless: bool) -> Self {
+        self.headless_uart = headless;
+        self
+    }
+
+    /// Detach the UART from the terminal and receive the bytes of `input` instead.
+    pub fn uart_input(mut self, input: Box<dyn Read + Send>) -> Self {
+        self.uart_input = Some(input);
+        self
+    }
+
+    /// Detach the UART from the terminal and write what the guest transmits to `out`.
+    /// Nothing is received. `uart_input` takes precedence over it.
+    pub fn uart_output(mut self, out: Box<dyn Write>) -> Self {
+        self.uart_output = Some(out);
+        self
+    }
+
+    /// Halt with `HaltReason::Exit(a0)` on `ebreak`, the usual exit convention of test programs.
+    pub fn exit_on_ebreak(mut self, exit: bool) -> Self {
+        self.exit_on_ebreak = exit;
+        self
+    }
+
+    /// Serve the semihosting calls of newlib and picolibc, which print to the UART
+    /// output and exit, instead of raising a breakpoint exception. A plain `ebreak`
+    /// is still a breakpoint. See the `semihosting` module.
+    pub fn semihosting(mut self, semihosting: bool) -> Self {
+        self.semihosting = semihosting;
+        self
+    }
+
+    /// Halt with `HaltReason::Exit(a0)` on an `ecall` with `number` in a7, such as 93,
+    /// the `exit` system call of Linux.
+    pub fn exit_ecall(mut self, number: u64) -> Self {
+        self.exit_ecall = Some(number);
+        self
+    }
+
+    /// Whether the hart has the unprivileged counters `cycle`, `time` and `instret`
+    /// (Zicntr). Defaults to true, without them reading one is an illegal instruction.
+    pub fn zicntr(mut self, zicntr: bool) -> Self {
+        self.no_zicntr = !zicntr;
+        self
+    }
+
+    /// Reject reserved encodings, such as `slli` with imm[11:6] != 0, as illegal instructions.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Attach a virtio network device whose packets go through `backend`.
+    pub fn net(mut self, backend: Box<dyn NetBackend>) -> Self {
+        self.net = Some(backend);
+        self
+    }
+
+    /// Cycles each load or store to dram adds to `mcycle`.
+    pub fn dram_latency(mut self, cycles: u64) -> Self {
+        self.dram_latency = Some(cycles);
+        self
+    }
+
+    /// Cycles each load or store to a device register adds to `mcycle`.
+    pub fn mmio_latency(mut self, cycles: u64) -> Self {
+        self.mmio_latency = Some(cycles);
+        self
+    }
+
+    /// The value of `mhartid`, for code written for a particular hart. Defaults to 0.
+    pub fn hartid(mut self, hartid: u64) -> Self {
+        self.hartid = hartid;
+        self
+    }
+
+    /// Start at `pc` instead of the start of dram, the entry point of an ELF or the boot ROM.
+    pub fn entry_pc(mut self, pc: u64) -> Self {
+        self.entry_pc = Some(pc);
+        self
+    }
+
+    /// Start in privilege mode `mode`, encoded as in `mstatus.MPP`: 0 for user, 1 for
+    /// supervisor and 3 for machine. Defaults to machine mode.
+    pub fn initial_mode(mut self, mode: u64) -> Self {
+        assert!(matches!(mode, 0b00 | 0b01 | 0b11), "{} is not a privilege mode", mode);
+        self.initial_mode = Some(mode);
+        self
+    }
+
+    /// Give the machine `count` harts sharing the bus, numbered from `hartid`. They all
+    /// start at the beginning of dram and take turns as `Cpu::set_schedule` says.
+    pub fn harts(mut self, count: usize) -> Self {
+        self.harts = count;
+        self
+    }
+
+    /// Count how many times each instruction runs, for `Cpu::write_coverage`.
+    pub fn coverage(mut self, coverage: bool) -> Self {
+        self.coverage = coverage;
+        self
+    }
+
+    /// Raise access faults for accesses the memory attributes forbid, such as an AMO
+    /// to device memory.
+    pub fn check_mem_attrs(mut self, check: bool) -> Self {
+        self.check_mem_attrs = check;
+        self
+    }
+
+    /// Declare `base..=end` as memory of kind `attr`. Later declarations take precedence.
+    pub fn mem_attr(mut self, base: u64, end: u64, attr: MemAttr) -> Self {
+        self.mem_attrs.push((base, end, attr));
+        self
+    }
+
+    /// Attach a watchdog that resets the machine unless the guest kicks it at least
+    /// once every `timeout` instructions. It is counting from the first instruction.
+    pub fn watchdog(mut self, timeout: u64) -> Self {
+        self.watchdog = Some(timeout);
+        self
+    }
+
+    /// Advance `mtime`, and with it the `time` CSR, once every `divisor` instructions
+    /// instead of every instruction. A hart stalled in `wfi` counts as executing.
+    pub fn mtime_divisor(mut self, divisor: u64) -> Self {
+        self.mtime_divisor = Some(divisor);
+        self
+    }
+
+    /// Map a SiFive test finisher at `base`, usually `TEST_FINISHER_BASE`, for the guest
+    /// to exit with `HaltReason::Exit` or reset with `HaltReason::Reset`. `base` must be
+    /// free in the memory map.
+    pub fn test_finisher(mut self, base: u64) -> Self {
+        self.test_finisher = Some(base);
+        self
+    }
+
+    /// Start like QEMU's `virt` machine: from the reset vector in its boot ROM at
+    /// `MROM_BASE`, which passes the hartid in a0 and the device tree in a1 and jumps
+    /// to dram. The devices are already where `virt` has them, see `param`.
+    pub fn virt_machine(mut self) -> Self {
+        self.virt_machine = true;
+        self.dtb = true;
+        self
+    }
+
+    /// Generate a device tree describing the machine and place it at the end of dram,
+    /// with the hartid in a0 and its address in a1 as firmware passes them. The stack
+    /// starts below it.
+    pub fn dtb(mut self, dtb: bool) -> Self {
+        self.dtb = dtb;
+        self
+    }
+
+    /// Map a boot ROM holding `rom` at `MROM_BASE` and start from there, as firmware
+    /// does before it hands over to the code in dram.
+    pub fn rom(mut self, rom: Vec<u8>) -> Self {
+        assert!(rom.len() as u64 <= MROM_SIZE, "the boot ROM holds at most {:#x} bytes", MROM_SIZE);
+        self.rom = Some(rom);
+        self
+    }
+
+    /// Halt with `HaltReason::NullDeref` on an access to the first page of the address
+    /// space instead of raising an access fault, to catch null pointers in the guest.
+    pub fn null_guard(mut self, guard: bool) -> Self {
+        self.null_guard = guard;
+        self
+    }
+
+    /// Whether translations are cached in a TLB, which is the default. Without it every
+    /// access walks the page table, which is slower but sees changes to the page table
+    /// without `sfence.vma`.
+    pub fn tlb(mut self, tlb: bool) -> Self {
+        self.no_tlb = !tlb;
+        self
+    }
+
+    /// Whether fetched instructions are cached, which is the default. Without it every
+    /// fetch goes through the bus, which is slower but sees code a device writes
+    /// without `fence.i`.
+    pub fn decode_cache(mut self, decode_cache: bool) -> Self {
+        self.no_decode_cache = !decode_cache;
+        self
+    }
+
+    /// Halt with `HaltReason::Caught` before trapping an exception whose code, as in
+    /// mcause, is in `codes`, to find the first unexpected one.
+    pub fn break_on_exception(mut self, codes: Vec<u64>) -> Self {
+        self.break_on_exception = Some(codes);
+        self
+    }
+
+    /// Halt with `HaltReason::Exit` when the guest stores an odd value to `addr`, as
+    /// riscv-tests do to report the result. An ELF with a `tohost` symbol sets it already.
+    pub fn tohost(mut self, addr: u64) -> Self {
+        self.tohost = Some(addr);
+        self
+    }
+
+    /// Halt with `HaltReason::SelfLoop` on an instruction that jumps to itself, where a
+    /// test program without an exit convention ends. An interrupt could still end such
+    /// a loop, so this is off by default.
+    pub fn halt_on_self_loop(mut self, halt: bool) -> Self {
+        self.halt_on_self_loop = halt;
+        self
+    }
+
+    /// How misaligned loads and stores are handled, see `MisalignPolicy`. Defaults to
+    /// raising an exception.
+    pub fn misaligned(mut self, policy: MisalignPolicy) -> Self {
+        self.misaligned = policy;
+        self
+    }
+
+    /// Trace every executed instruction to the debug log, see `Cpu::set_trace`.
+    pub fn trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Build the machine. Panics where `try_build` returns an error.
+    pub fn build(self) -> Cpu {
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Build the machine, or return an error if what goes into dram doesn't fit.
+    pub fn try_build(self) -> Result<Cpu, BuildError> {
+        let dram_size = self.dram_size.unwrap_or(DRAM_SIZE);
+        if self.code.len() as u64 > dram_size {
+            return Err(BuildError::CodeTooLarge { len: self.code.len() as u64, dram_size });
+        }
+        let dram_end = DRAM_BASE + dram_size - 1;
+        for segment in self.elf.iter().flat_map(|(segments, _)| segments) {
+            let in_dram = segment.paddr >= DRAM_BASE
+                && segment.paddr.checked_add(segment.mem_size).is_some_and(|end| end - 1 <= dram_end);
+            if !in_dram {
+                return Err(BuildError::OutsideDram { paddr: segment.paddr, mem_size: segment.mem_size });
+            }
+        }
+
+        let uart = match (self.uart_input, self.uart_output) {
+            (Some(input), _) => Uart::with_reader(input),
+            (None, Some(out)) => Uart::with_writer(out),
+            (None, None) if self.headless_uart => Uart::headless(),
+            (None, None) => Uart::new(),
+        };
+        let fdt = self.dtb.then(|| {
+            let blob = dtb::generate(self.hartid, self.harts.max(1), dram_size);
+            // The blob must be 8-byte aligned.
+            ((DRAM_BASE + dram_size - blob.len() as u64) & !0x7, blob)
+        });
+        let fdt_addr = fdt.as_ref().map_or(0, |(addr, _)| *addr);
+        let rom = match self.rom {
+            None if self.virt_machine => Some(reset_vector(DRAM_BASE, fdt_addr)),
+            rom => rom,
+        };
+        let dram = Dram::with_size(self.code, dram_size as usize);
+        let mut bus = Bus::with_dram(dram, self.disk_image, uart);
+        bus.virtio_net = self.net.map(VirtioNet::new);
+        bus.check_attrs = self.check_mem_attrs;
+        bus.watchdog = self.watchdog.map(Watchdog::new);
+        if let Some(divisor) = self.mtime_divisor {
+            bus.clint.set_divisor(divisor);
+        }
+        if let Some(base) = self.test_finisher {
+            bus.attach_finisher(TestFinisher::new(base));
+        }
+        let has_rom = rom.is_some();
+        bus.boot_rom = rom;
+        for region in bus.regions.iter_mut() {
+            let latency = if region.name == "dram" { self.dram_latency } else { self.mmio_latency };
+            if let Some(cycles) = latency {
+                region.latency = cycles;
+            }
+        }
+        for (base, end, attr) in self.mem_attrs {
+            bus.declare(base, end, attr);
+        }
+        let mut cpu = Cpu::with_bus(bus, self.hartid);
+        if let Some((addr, blob)) = fdt {
+            let start = (addr - DRAM_BASE) as usize;
+            cpu.bus.dram_mut()[start..start + blob.len()].copy_from_slice(&blob);
+            cpu.regs[2] = addr & !0xf;
+            cpu.regs[10] = self.hartid;
+            cpu.regs[11] = addr;
+        }
+        if self.harts > 1 {
+            cpu.scheduler = Scheduler::new(self.hartid, self.harts, cpu.regs[2]);
+            if self.dtb {
+                cpu.scheduler.pass_fdt(fdt_addr);
+            }
+        }
+        cpu.exit_on_ebreak = self.exit_on_ebreak;
+        cpu.exit_ecall = self.exit_ecall;
+        cpu.semihosting = self.semihosting;
+        cpu.zicntr = !self.no_zicntr;
+        cpu.strict = self.strict;
+        cpu.null_guard = self.null_guard;
+        cpu.break_on_exception = self.break_on_exception;
+        cpu.use_tlb = !self.no_tlb;
+        cpu.use_decode_cache = !self.no_decode_cache;
+        cpu.tohost = self.tohost;
+        cpu.halt_on_self_loop = self.halt_on_self_loop;
+        cpu.set_misaligned_policy(self.misaligned);
+        cpu.set_trace(self.trace);
+        if let Some((segments, entry)) = self.elf {
+            // The segments have been checked to be in dram.
+            for segment in segments {
+                let bss = vec![0; segment.mem_size as usize - segment.data.len()];
+                cpu.bus.load_segment(segment.paddr, &segment.data).unwrap();
+                cpu.bus.load_segment(segment.paddr + segment.data.len() as u64, &bss).unwrap();
+            }
+            cpu.pc = entry;
+        }
+        if has_rom {
+            cpu.pc = MROM_BASE;
+        }
+        if let Some(pc) = self.entry_pc {
+            cpu.pc = pc;
+        }
+        if let Some(mode) = self.initial_mode {
+            cpu.mode = mode;
+        }
+        if self.coverage {
+            cpu.coverage = Some(vec![0; decode::INSTRUCTIONS.len()]);
+        }
+        Ok(cpu)
+    }
+}
+
+/// The reset vector of QEMU's `riscv_setup_rom_reset_vec`, jumping to `start` with the
+/// hartid in a0 and the device tree address in a1.
+fn reset_vector(start: u64, fdt: u64) -> Vec<u8> {
+    let code: [u32; 6] = [
+        0x00000297, // auipc t0, 0
+        0x02828613, // addi a2, t0, 40
+        0xf1402573, // csrr a0, mhartid
+        0x0202b583, // ld a1, 32(t0)
+        0x0182b283, // ld t0, 24(t0)
+        0x00028067, // jr t0
+    ];
+    let mut rom: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    rom.extend_from_slice(&start.to_le_bytes());
+    rom.extend_from_slice(&fdt.to_le_bytes());
+    rom
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cpu::HaltReason, exception::Exception};
+
+    #[test]
+    fn test_headless_helloworld() {
+        let code = include_bytes!("../tests/test_helloworld.bin").to_vec();
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+
+        // main returns to ra = 0, where there is nothing to fetch.
+        match cpu.run() {
+            HaltReason::Fault(Exception::InstructionAccessFault(0)) => (),
+            reason => panic!("unexpected halt {:?}", reason),
+        }
+        assert_eq!(cpu.bus.uart.output(), b"Hello, world!\n");
+    }
+
+    #[test]
+    fn test_uart_input() {
+        let code: Vec<u8> = [
+            0x100002b7u32, // lui t0, 0x10000
+            0x0052c303,    // lbu t1, 5(t0)
+            0x00137313,    // andi t1, t1, 1
+            0xfe030ce3,    // beqz t1, -8
+            0x0002c503,    // lbu a0, 0(t0)
+            0x00100073,    // ebreak
+        ]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+        // Each machine's receive thread ends with it, none is left waiting for its
+        // guest to read.
+        for _ in 0..50 {
+            let mut cpu = CpuBuilder::new()
+                .code(code.clone())
+                .uart_input(Box::new(std::io::repeat(b'x')))
+                .exit_on_ebreak(true)
+                .build();
+            assert_eq!(cpu.run(), HaltReason::Exit(b'x' as i32));
+        }
+    }
+
+    #[test]
+    fn test_virt_machine() {
+        let mut cpu = CpuBuilder::new().headless_uart(true).hartid(2).virt_machine().build();
+
+        // The bases of QEMU's virt machine, hw/riscv/virt.c.
+        let base = |name| cpu.bus.regions.iter().find(|r| r.name == name).unwrap().base;
+        assert_eq!(base("mrom"), 0x1000);
+        assert_eq!(base("clint"), 0x200_0000);
+        assert_eq!(base("plic"), 0xc00_0000);
+        assert_eq!(base("uart"), 0x1000_0000);
+        assert_eq!(base("virtio-blk"), 0x1000_1000);
+        assert_eq!(base("dram"), 0x8000_0000);
+
+        // The reset vector reaches dram with the hartid in a0 and the device tree in a1.
+        assert_eq!(cpu.pc, 0x1000);
+        for _ in 0..6 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.pc, DRAM_BASE);
+        assert_eq!(cpu.reg("a0"), 2);
+        assert_eq!(cpu.read_mem(cpu.reg("a1"), 32), Ok(0xedfe0dd0));
+    }
+
+    #[test]
+    fn test_dtb() {
+        let size = 16 * 1024 * 1024;
+        let mut cpu = CpuBuilder::new().headless_uart(true).hartid(1).dram_size(size).dtb(true).build();
+        // The blob is at the end of dram, 8-byte aligned, and describes the configured size.
+        let blob = dtb::generate(1, 1, size);
+        let fdt = (DRAM_BASE + size - blob.len() as u64) & !0x7;
+        assert_eq!((cpu.reg("a0"), cpu.reg("a1")), (1, fdt));
+        assert_eq!(cpu.read_bytes(fdt, blob.len() as u64), Ok(blob));
+        // The stack is below it.
+        assert!(cpu.reg("sp") <= fdt && cpu.reg("sp").is_multiple_of(16));
+
+        // Without it a0 and a1 are left alone.
+        let cpu = CpuBuilder::new().headless_uart(true).hartid(1).build();
+        assert_eq!((cpu.reg("a0"), cpu.reg("a1")), (0, 0));
+    }
+
+    #[test]
+    fn test_dram_size() {
+        let size = 0x10_0000;
+        let end = DRAM_BASE + size - 1;
+        let mut cpu = CpuBuilder::new().headless_uart(true).dram_size(size).harts(2).build();
+        assert_eq!(cpu.bus.dram_end(), end);
+        assert_eq!(cpu.bus.region(end).unwrap().name, "dram");
+        assert!(cpu.bus.load(end - 7, 64).is_ok());
+        assert_eq!(cpu.bus.load(end - 3, 64), Err(Exception::LoadAccessFault(end - 3)));
+        assert_eq!(cpu.bus.store(end + 1, 8, 0), Err(Exception::StoreAMOAccessFault(end + 1)));
+
+        // Every hart starts with its stack at the top.
+        assert_eq!(cpu.reg("sp"), end + 1);
+        cpu.switch_hart(1);
+        assert_eq!(cpu.reg("sp"), end + 1);
+
+        // Past the default size.
+        let mut cpu = CpuBuilder::new().headless_uart(true).dram_size(2 * DRAM_SIZE).build();
+        cpu.bus.store(DRAM_END + 1, 64, 42).unwrap();
+        assert_eq!(cpu.bus.load(DRAM_END + 1, 64), Ok(42));
+    }
+
+    #[test]
+    fn test_too_large_for_dram() {
+        // dram_size may come after the code, it is checked when the machine is built.
+        let result = CpuBuilder::new().code(vec![0; 0x2000]).dram_size(0x1000).try_build();
+        assert_eq!(result.err(), Some(BuildError::CodeTooLarge { len: 0x2000, dram_size: 0x1000 }));
+    }
+
+    #[test]
+    fn test_supervisor_entry() {
+        let code: Vec<u8> = [
+            0x00100513u32, // li a0, 1
+            0x00200513,    // li a0, 2
+            0x00000073,    // ecall
+        ]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+        let mut cpu = CpuBuilder::new()
+            .code(code)
+            .headless_uart(true)
+            .entry_pc(DRAM_BASE + 4)
+            .initial_mode(0b01)
+            .build();
+        assert_eq!((cpu.pc, cpu.mode), (DRAM_BASE + 4, 0b01));
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), 2);
+        cpu.step().unwrap();
+        assert_eq!(cpu.csr.load(crate::csr::MCAUSE), Exception::EnvironmentCallFromSMode(0).code());
+        assert_eq!(cpu.mode, 0b11);
+    }
+
+    #[test]
+    fn test_execute_from_rom() {
+        let rom: Vec<u8> = [
+            0x02a00513u32, // li a0, 42
+            0x000015b7,    // lui a1, 0x1 (the ROM)
+            0x00a5a023,    // sw a0, 0(a1)
+        ]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+        let mut cpu = CpuBuilder::new().headless_uart(true).rom(rom).build();
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), 42);
+        assert_eq!(cpu.reg("a1"), MROM_BASE);
+
+        // The ROM is read-only.
+        assert_eq!(cpu.step(), Err(HaltReason::Fault(Exception::StoreAMOAccessFault(MROM_BASE))));
+        assert_eq!(cpu.bus.load(MROM_BASE, 32), Ok(0x02a00513));
+    }
+}
@@ -6,59 +6,299 @@ use crate::{
     clint::Clint,
     dram::Dram,
     exception::Exception,
-    param::{DRAM_BASE, DRAM_END},
+    finisher::TestFinisher,
+    mailbox::Mailbox,
     plic::Plic,
     uart::Uart,
     param::*,
     virtio::*,
+    virtio_net::VirtioNet,
+    watchdog::Watchdog,
 };
+use tracing::trace;
+
+/// What kind of memory a region is, which decides the accesses it allows when
+/// `Bus::check_attrs` is on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemAttr {
+    /// Ordinary memory. Any access is allowed.
+    Normal,
+    /// Device registers. Atomics and instruction fetches raise access faults.
+    Device,
+}
+
+/// An address range on the bus and what one access to it costs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemRegion {
+    pub name: &'static str,
+    pub base: u64,
+    pub end: u64,
+    /// Cycles added to `mcycle` by each load or store to this region.
+    pub latency: u64,
+    pub attr: MemAttr,
+}
+
+impl MemRegion {
+    pub fn new(name: &'static str, base: u64, end: u64, latency: u64, attr: MemAttr) -> Self {
+        Self { name, base, end, latency, attr }
+    }
+
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.base..=self.end).contains(&addr)
+    }
+}
+
+/// Default access latency of dram, in cycles.
+pub const DRAM_LATENCY: u64 = 1;
+/// Default access latency of device registers, in cycles.
+pub const MMIO_LATENCY: u64 = 10;
 
 pub struct Bus {
     dram: Dram,
-    clint: Clint,
-    plic: Plic,
+    /// The last address of dram, which depends on its size.
+    dram_end: u64,
+    pub clint: Clint,
+    pub plic: Plic,
     pub uart: Uart,
     pub virtio_blk: VirtioBlock,
+    /// The network device, if the machine has one.
+    pub virtio_net: Option<VirtioNet>,
+    /// The watchdog timer, if the machine has one.
+    pub watchdog: Option<Watchdog>,
+    /// The mailbox, if a handler is set with `Cpu::set_mailbox_handler`.
+    pub mailbox: Option<Mailbox>,
+    /// The test finisher, if the machine has one. Its address isn't fixed, use
+    /// `attach_finisher` to map it.
+    pub finisher: Option<TestFinisher>,
+    /// The contents of the boot ROM, if the machine has one. It is read-only.
+    pub boot_rom: Option<Vec<u8>>,
+    /// The memory map, dram first since it is accessed the most. The first region
+    /// containing an address describes it.
+    pub regions: Vec<MemRegion>,
+    /// Enforce the attributes of the regions.
+    pub check_attrs: bool,
 }
 
 impl Bus {
     /// Create a bus from given code.
     pub fn new(code: Vec<u8>, disk_image: Vec<u8>) -> Bus {
+        Self::with_uart(code, disk_image, Uart::new())
+    }
+
+    /// Create a bus from given code, attaching the given UART instead of one on the terminal.
+    pub fn with_uart(code: Vec<u8>, disk_image: Vec<u8>, uart: Uart) -> Bus {
+        Self::with_dram(Dram::new(code), disk_image, uart)
+    }
+
+    /// Create a bus around `dram`, which may have any size.
+    pub fn with_dram(dram: Dram, disk_image: Vec<u8>, uart: Uart) -> Bus {
+        let dram_end = DRAM_BASE + dram.len() as u64 - 1;
         Self {
-            dram: Dram::new(code),
+            dram,
+            dram_end,
             clint: Clint::new(),
             plic: Plic::new(),
-            uart: Uart::new(),
+            uart,
             virtio_blk: VirtioBlock::new(disk_image),
+            virtio_net: None,
+            watchdog: None,
+            mailbox: None,
+            finisher: None,
+            boot_rom: None,
+            regions: vec![
+                MemRegion::new("dram", DRAM_BASE, dram_end, DRAM_LATENCY, MemAttr::Normal),
+                MemRegion::new("clint", CLINT_BASE, CLINT_END, MMIO_LATENCY, MemAttr::Device),
+                MemRegion::new("plic", PLIC_BASE, PLIC_END, MMIO_LATENCY, MemAttr::Device),
+                MemRegion::new("uart", UART_BASE, UART_END, MMIO_LATENCY, MemAttr::Device),
+                MemRegion::new("virtio-blk", VIRTIO_BASE, VIRTIO_END, MMIO_LATENCY, MemAttr::Device),
+                MemRegion::new("virtio-net", VIRTIO_NET_BASE, VIRTIO_NET_END, MMIO_LATENCY, MemAttr::Device),
+                MemRegion::new("watchdog", WATCHDOG_BASE, WATCHDOG_END, MMIO_LATENCY, MemAttr::Device),
+                MemRegion::new("mailbox", MAILBOX_BASE, MAILBOX_END, MMIO_LATENCY, MemAttr::Device),
+                MemRegion::new("mrom", MROM_BASE, MROM_END, DRAM_LATENCY, MemAttr::Normal),
+            ],
+            check_attrs: false,
         }
     }
 
-    /// Checks the address and call load on dram.
+    /// Map `finisher` at its base, which no other device may use.
+    pub fn attach_finisher(&mut self, finisher: TestFinisher) {
+        let (base, end) = (finisher.base(), finisher.end());
+        self.regions.push(MemRegion::new("test-finisher", base, end, MMIO_LATENCY, MemAttr::Device));
+        self.finisher = Some(finisher);
+    }
+
+    /// Declare `base..=end` as memory of kind `attr`, overriding the map. The range
+    /// keeps the latency of the region it starts in.
+    pub fn declare(&mut self, base: u64, end: u64, attr: MemAttr) {
+        let latency = self.latency(base);
+        self.regions.insert(0, MemRegion::new("declared", base, end, latency, attr));
+    }
+
+    /// The attribute of `addr`. Unmapped addresses fault anyway, they count as normal.
+    pub fn attr(&self, addr: u64) -> MemAttr {
+        self.region(addr).map_or(MemAttr::Normal, |r| r.attr)
+    }
+
+    /// The region containing `addr`, if any.
+    pub fn region(&self, addr: u64) -> Option<&MemRegion> {
+        self.regions.iter().find(|r| r.contains(addr))
+    }
+
+    /// Cycles one access to `addr` costs. An unmapped address costs nothing, it faults.
+    #[inline]
+    pub fn latency(&self, addr: u64) -> u64 {
+        self.region(addr).map_or(0, |r| r.latency)
+    }
+
+    /// Checks the address and call load on dram or the device it belongs to.
+    /// Device accesses are logged at trace level, see `trace_device`.
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if addr >= DRAM_BASE && fits_in(addr, size, self.dram_end) {
+            return self.dram.load(addr, size);
+        }
+        let result = self.load_device(addr, size);
+        if tracing::enabled!(tracing::Level::TRACE) {
+            self.trace_device("load", addr, size, result);
+        }
+        result
+    }
+
+    fn load_device(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let fits = |end| fits_in(addr, size, end);
         match addr {
-            CLINT_BASE..=CLINT_END => self.clint.load(addr, size),
-            PLIC_BASE..=PLIC_END => self.plic.load(addr, size),
-            DRAM_BASE..=DRAM_END => self.dram.load(addr, size),
-            UART_BASE..=UART_END => self.uart.load(addr, size),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio_blk.load(addr, size),
-            _ => Err(Exception::LoadAccessFault(addr)),
+            CLINT_BASE..=CLINT_END if fits(CLINT_END) => self.clint.load(addr, size),
+            PLIC_BASE..=PLIC_END if fits(PLIC_END) => {
+                let value = self.plic.load(addr, size)?;
+                // Claiming the UART acknowledges a THR empty interrupt, like reading IIR.
+                // xv6 relies on it, it never reads IIR.
+                let claim = addr >= PLIC_CLAIM && (addr - PLIC_CLAIM).is_multiple_of(PLIC_CONTEXT_STRIDE);
+                if claim && value == UART_IRQ {
+                    self.uart.acknowledge_thr_empty();
+                }
+                Ok(value)
+            }
+            UART_BASE..=UART_END if fits(UART_END) => self.uart.load(addr, size),
+            VIRTIO_BASE..=VIRTIO_END if fits(VIRTIO_END) => self.virtio_blk.load(addr, size),
+            VIRTIO_NET_BASE..=VIRTIO_NET_END if fits(VIRTIO_NET_END) => match &self.virtio_net {
+                Some(net) => net.load(addr, size),
+                None => Err(Exception::LoadAccessFault(addr)),
+            },
+            WATCHDOG_BASE..=WATCHDOG_END if fits(WATCHDOG_END) => match &self.watchdog {
+                Some(watchdog) => watchdog.load(addr, size),
+                None => Err(Exception::LoadAccessFault(addr)),
+            },
+            MAILBOX_BASE..=MAILBOX_END if fits(MAILBOX_END) => match &self.mailbox {
+                Some(mailbox) => mailbox.load(addr, size),
+                None => Err(Exception::LoadAccessFault(addr)),
+            },
+            MROM_BASE..=MROM_END if fits(MROM_END) => match &self.boot_rom {
+                Some(rom) => load_rom(rom, addr, size),
+                None => Err(Exception::LoadAccessFault(addr)),
+            },
+            _ => match &self.finisher {
+                Some(finisher) if addr >= finisher.base() && fits(finisher.end()) => finisher.load(addr, size),
+                _ => Err(Exception::LoadAccessFault(addr)),
+            },
         }
     }
 
-    /// Checks the address and call store on dram.
+    /// Checks the address and call store on dram or the device it belongs to.
+    /// Device accesses are logged at trace level, see `trace_device`.
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if addr >= DRAM_BASE && fits_in(addr, size, self.dram_end) {
+            return self.dram.store(addr, size, value);
+        }
+        let result = self.store_device(addr, size, value);
+        if tracing::enabled!(tracing::Level::TRACE) {
+            // Only the low `size` bits are stored.
+            let stored = if size < 64 { value & ((1 << size) - 1) } else { value };
+            self.trace_device("store", addr, size, result.map(|_| stored));
+        }
+        result
+    }
+
+    /// Log an access to a device region with the device, offset, size in bytes and
+    /// value, e.g. with `RUST_LOG=rusty_riscv_ave::bus=trace`. Dram and the boot ROM
+    /// are not devices and aren't logged, neither are addresses outside any device.
+    fn trace_device(&self, kind: &str, addr: u64, size: u64, result: Result<u64, Exception>) {
+        let Some(region) = self.regions.iter().find(|r| r.attr == MemAttr::Device && r.contains(addr)) else {
+            return;
+        };
+        let offset = addr - region.base;
+        match result {
+            Ok(value) => trace!("{} {}+{:#x} size {} = {:#x}", kind, region.name, offset, size / 8, value),
+            Err(e) => trace!("{} {}+{:#x} size {} faulted: {}", kind, region.name, offset, size / 8, e),
+        }
+    }
+
+    fn store_device(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let fits = |end| fits_in(addr, size, end);
         match addr {
-            CLINT_BASE..=CLINT_END => self.clint.store(addr, size, value),
-            PLIC_BASE..=PLIC_END => self.plic.store(addr, size, value),
-            DRAM_BASE..=DRAM_END => self.dram.store(addr, size, value),
-            UART_BASE..=UART_END => self.uart.store(addr, size, value),
-            VIRTIO_BASE..=VIRTIO_END => self.virtio_blk.store(addr, size, value),
-            _ => Err(Exception::StoreAMOAccessFault(addr)),
+            CLINT_BASE..=CLINT_END if fits(CLINT_END) => self.clint.store(addr, size, value),
+            PLIC_BASE..=PLIC_END if fits(PLIC_END) => self.plic.store(addr, size, value),
+            UART_BASE..=UART_END if fits(UART_END) => self.uart.store(addr, size, value),
+            VIRTIO_BASE..=VIRTIO_END if fits(VIRTIO_END) => self.virtio_blk.store(addr, size, value),
+            VIRTIO_NET_BASE..=VIRTIO_NET_END if fits(VIRTIO_NET_END) => match &mut self.virtio_net {
+                Some(net) => net.store(addr, size, value),
+                None => Err(Exception::StoreAMOAccessFault(addr)),
+            },
+            WATCHDOG_BASE..=WATCHDOG_END if fits(WATCHDOG_END) => match &mut self.watchdog {
+                Some(watchdog) => watchdog.store(addr, size, value),
+                None => Err(Exception::StoreAMOAccessFault(addr)),
+            },
+            MAILBOX_BASE..=MAILBOX_END if fits(MAILBOX_END) => match &mut self.mailbox {
+                Some(mailbox) => mailbox.store(addr, size, value),
+                None => Err(Exception::StoreAMOAccessFault(addr)),
+            },
+            _ => match &mut self.finisher {
+                Some(finisher) if addr >= finisher.base() && fits(finisher.end()) => finisher.store(addr, size, value),
+                _ => Err(Exception::StoreAMOAccessFault(addr)),
+            },
         }
     }
 
+    /// The contents of dram.
+    pub fn dram(&self) -> &[u8] {
+        &self.dram.dram
+    }
+
+    /// The contents of dram, for writing without going through `store`.
+    pub fn dram_mut(&mut self) -> &mut [u8] {
+        &mut self.dram.dram
+    }
+
+    /// Copy `bytes` to dram at physical address `paddr`, see `Dram::load_segment`.
+    pub fn load_segment(&mut self, paddr: u64, bytes: &[u8]) -> Result<(), Exception> {
+        self.dram.load_segment(paddr, bytes)
+    }
+
     /// Get the dram size.
     pub fn dram_size(&self) -> usize {
         self.dram.len()
     }
+
+    /// The last address of dram.
+    pub fn dram_end(&self) -> u64 {
+        self.dram_end
+    }
+}
+
+/// Whether the `size`-bit access at `addr` ends at or before `end`. An access that
+/// runs past the end of its device faults instead of reaching into the next one.
+#[inline]
+fn fits_in(addr: u64, size: u64, end: u64) -> bool {
+    addr.checked_add(size / 8 - 1).is_some_and(|last| last <= end)
+}
+
+/// Read from the boot ROM. The part of the window past its contents reads as zeros.
+fn load_rom(rom: &[u8], addr: u64, size: u64) -> Result<u64, Exception> {
+    if !matches!(size, 8 | 16 | 32 | 64) {
+        return Err(Exception::LoadAccessFault(addr));
+    }
+    let offset = (addr - MROM_BASE) as usize;
+    let value = (0..size as usize / 8)
+        .map(|i| rom.get(offset + i).copied().unwrap_or(0) as u64)
+        .rev()
+        .fold(0, |value, byte| value << 8 | byte);
+    Ok(value)
 }
@@ -1,44 +1,132 @@
-
 //! The clint module contains the core-local interruptor (CLINT). The CLINT
 //! block holds memory-mapped control and status registers associated with
 //! software and timer interrupts. It generates per-hart software interrupts and timer.
-
+//!
+//! `mtime` is the clock of the machine, the `time` CSR reads it too. It advances by
+//! one every `divisor` steps, every step by default. A hart's timer interrupt is pending while
+//! `mtime >= mtimecmp`, its software interrupt while bit 0 of its `msip` is set.
 
 use crate::exception::*;
 use crate::param::*;
 
 use Exception::*;
 
+/// Harts the msip and mtimecmp arrays have room for.
+const CLINT_HARTS: u64 = 4095;
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Clint {
     mtime: u64,
-    mtimecmp: u64,
+    /// Steps per tick of `mtime`.
+    divisor: u64,
+    /// Steps since the last tick of `mtime`.
+    steps: u64,
+    /// Per hartid, grown on the first write. A hart that is missing has msip 0.
+    msip: Vec<u32>,
+    /// Per hartid, grown on the first write. A hart that is missing has the largest
+    /// mtimecmp, so no timer interrupt fires before software sets one up.
+    mtimecmp: Vec<u64>,
+}
+
+/// A register of the CLINT, and the byte of it an access starts at.
+enum Reg {
+    Msip(usize),
+    Mtimecmp(usize),
+    Mtime,
 }
 
 impl Clint {
     pub fn new() -> Self {
-        Self { mtime: 0, mtimecmp: 0 }
+        Self { mtime: 0, divisor: 1, steps: 0, msip: Vec::new(), mtimecmp: Vec::new() }
     }
-    
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if size != 64 {
-            return Err(LoadAccessFault(addr));
-        }
-        match addr {
-            CLINT_MTIMECMP => Ok(self.mtimecmp),
-            CLINT_MTIME => Ok(self.mtime),
-            _ => Err(LoadAccessFault(addr)),
+
+    /// Make `mtime` advance once every `divisor` steps instead of every step.
+    pub fn set_divisor(&mut self, divisor: u64) {
+        assert!(divisor > 0, "the mtime divisor must not be 0");
+        self.divisor = divisor;
+        self.steps = 0;
+    }
+
+    /// The current value of `mtime`.
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Count a step, advancing `mtime` by one tick every `divisor` steps.
+    #[inline]
+    pub fn tick(&mut self) {
+        self.steps += 1;
+        if self.steps == self.divisor {
+            self.steps = 0;
+            self.mtime = self.mtime.wrapping_add(1);
         }
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if size != 64 {
-            return Err(LoadAccessFault(addr));
+    /// Whether the timer interrupt of `hartid` is pending.
+    pub fn is_timer_interrupting(&self, hartid: u64) -> bool {
+        self.mtime >= self.mtimecmp.get(hartid as usize).copied().unwrap_or(u64::MAX)
+    }
+
+    /// Whether the software interrupt of `hartid` is pending.
+    pub fn is_software_interrupting(&self, hartid: u64) -> bool {
+        self.msip.get(hartid as usize).is_some_and(|msip| msip & 1 != 0)
+    }
+
+    /// The register at `addr` and the hart it belongs to. Accesses are 32 or 64 bits
+    /// wide and naturally aligned; msip is only 32 bits wide.
+    fn reg(addr: u64, size: u64) -> Option<(Reg, u64)> {
+        if !(size == 32 || size == 64) || !addr.is_multiple_of(size / 8) {
+            return None;
         }
         match addr {
-            CLINT_MTIMECMP => Ok(self.mtimecmp = value),
-            CLINT_MTIME => Ok(self.mtime = value),
-            _ => Err(StoreAMOAccessFault(addr)),
+            CLINT_MSIP..CLINT_MTIMECMP if size == 32 && (addr - CLINT_MSIP) / 4 < CLINT_HARTS => {
+                Some((Reg::Msip(((addr - CLINT_MSIP) / 4) as usize), 0))
+            }
+            CLINT_MTIMECMP..CLINT_MTIME => Some((Reg::Mtimecmp(((addr - CLINT_MTIMECMP) / 8) as usize), addr % 8)),
+            CLINT_MTIME..=CLINT_END if addr < CLINT_MTIME + 8 => Some((Reg::Mtime, addr % 8)),
+            _ => None,
         }
     }
 
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let (reg, offset) = Self::reg(addr, size).ok_or(LoadAccessFault(addr))?;
+        let value = match reg {
+            Reg::Msip(hart) => self.msip.get(hart).copied().unwrap_or(0) as u64,
+            Reg::Mtimecmp(hart) => self.mtimecmp.get(hart).copied().unwrap_or(u64::MAX),
+            Reg::Mtime => self.mtime,
+        };
+        let value = value >> (offset * 8);
+        Ok(if size == 32 { value & 0xffff_ffff } else { value })
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let (reg, offset) = Self::reg(addr, size).ok_or(StoreAMOAccessFault(addr))?;
+        // A 32-bit store replaces one half of a 64-bit register.
+        let merge = |old: u64| {
+            if size == 64 {
+                value
+            } else {
+                let shift = offset * 8;
+                (old & !(0xffff_ffff << shift)) | ((value & 0xffff_ffff) << shift)
+            }
+        };
+        match reg {
+            Reg::Msip(hart) => {
+                if self.msip.len() <= hart {
+                    self.msip.resize(hart + 1, 0);
+                }
+                // Only bit 0 is writable.
+                self.msip[hart] = value as u32 & 1;
+            }
+            Reg::Mtimecmp(hart) => {
+                if self.mtimecmp.len() <= hart {
+                    self.mtimecmp.resize(hart + 1, u64::MAX);
+                }
+                self.mtimecmp[hart] = merge(self.mtimecmp[hart]);
+            }
+            Reg::Mtime => self.mtime = merge(self.mtime),
+        }
+        Ok(())
+    }
 }
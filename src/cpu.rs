@@ -1,33 +1,93 @@
 //! The cpu module contains `Cpu` and implementarion for it.
 
 #![allow(dead_code)]
-use std::mem::size_of;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
 
+use crate::builder::CpuBuilder;
 use crate::bus::*;
+use crate::decode::{self, DecodedInst, Instruction};
+use crate::disasm;
 use crate::exception::*;
+use crate::finisher::FinisherRequest;
+use crate::hart::Scheduler;
+use crate::icache::DecodeCache;
+use crate::mailbox::{Mailbox, MailboxHandler};
 use crate::interrupt::*;
 use crate::param::*;
 use crate::csr::*;
-use crate::virtqueue::*;
+use crate::tlb::{Tlb, TlbEntry};
+use crate::trace::{DebugLog, TraceFormatter, TraceRecord, Tracer};
+use tracing::debug;
 
 
 // Riscv Privilege Mode
-type Mode = u64;
-const User: Mode = 0b00;
-const Supervisor: Mode = 0b01;
-const Machine: Mode = 0b11;
+pub(crate) type Mode = u64;
+pub(crate) const User: Mode = 0b00;
+pub(crate) const Supervisor: Mode = 0b01;
+pub(crate) const Machine: Mode = 0b11;
 
+#[derive(Clone, Copy)]
 pub enum AccessType {
     Instruction,
     Load,
     Store,
 }
 
+/// Executes an instruction the cpu doesn't implement. Returns the next pc, or `None`
+/// to leave the instruction illegal.
+pub type CustomInsnHandler = Box<dyn FnMut(&mut Cpu, u64) -> Option<Result<u64, Exception>>>;
+
+/// The most instructions `Cpu::exec_bytes` runs before it returns control.
+pub const EXEC_BYTES_LIMIT: usize = 1 << 20;
+
+/// Why `Cpu::run` stopped.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HaltReason {
+    /// A fatal exception. It has already been taken as a trap, so the CSRs hold its cause.
+    Fault(Exception),
+    /// The guest asked to exit with the given code.
+    Exit(i32),
+    /// The watchdog expired and resets the machine.
+    WatchdogReset,
+    /// The guest asked the test finisher to reset the machine.
+    Reset,
+    /// The pc reached a breakpoint set with `Cpu::add_breakpoint`. The instruction
+    /// there has not run yet.
+    Breakpoint(u64),
+    /// An access to the guard page at address 0, at the instruction at `pc`. Only with
+    /// `CpuBuilder::null_guard`.
+    NullDeref { addr: u64, pc: u64 },
+    /// The instruction at this pc jumped or branched to itself, so only an interrupt
+    /// could end the loop. Only with `CpuBuilder::halt_on_self_loop`.
+    SelfLoop(u64),
+    /// The instruction at `pc` raised an exception listed in `Cpu::break_on_exception`.
+    /// It has not been trapped yet, so the pc and CSRs are as they were before; resuming
+    /// takes the trap.
+    Caught { exception: Exception, pc: u64 },
+}
+
+/// What a load or store to an address that isn't a multiple of its size does. AMOs and
+/// `lr`/`sc` always raise an exception.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum MisalignPolicy {
+    /// Raise an address-misaligned exception, for the trap handler to emulate the access.
+    #[default]
+    Fault,
+    /// Split the access into bytes, as hardware that supports misaligned accesses does.
+    Emulate,
+}
+
 /// The `Cpu` struct that contains registers, a program coutner, system bus that connects
 /// peripheral devices, and control and status registers.
 pub struct Cpu {
     /// 32 64-bit integer registers.
     pub regs: [u64; 32],
+    /// 32 64-bit floating-point registers. No F or D instruction is implemented yet,
+    /// they only hold state.
+    pub fregs: [u64; 32],
     /// Program counter to hold the the dram address of the next instruction that would be executed.
     pub pc: u64,
     /// The current privilege mode.
@@ -41,28 +101,126 @@ pub struct Cpu {
     pub enable_paging: bool,
     /// physical page number (PPN) × PAGE_SIZE (4096).
     pub page_table: u64,
+    /// Treat `ebreak` as a request to exit with the code in a0 instead of a breakpoint.
+    pub exit_on_ebreak: bool,
+    /// Halt with `HaltReason::Exit(a0)` on an `ecall` with this number in a7.
+    pub exit_ecall: Option<u64>,
+    /// Serve semihosting calls instead of raising a breakpoint exception, see the
+    /// `semihosting` module.
+    pub semihosting: bool,
+    /// Whether the unprivileged counters `cycle`, `time` and `instret` (Zicntr) exist.
+    pub zicntr: bool,
+    /// Raise IllegalInstruction for reserved encodings instead of ignoring the reserved bits.
+    pub strict: bool,
+    /// Halt with `HaltReason::NullDeref` on an access fault in the first page.
+    pub null_guard: bool,
+    /// Halt with `HaltReason::Caught` instead of trapping on an exception with one of
+    /// these codes, like `catch signal` in gdb.
+    pub break_on_exception: Option<Vec<u64>>,
+    /// Halt with `HaltReason::Exit` when the guest stores an odd value to this physical
+    /// address, the `tohost` convention of riscv-tests. The exit code is the value
+    /// shifted right by one, so 0 is a pass and otherwise the number of the failed test.
+    pub tohost: Option<u64>,
+    /// Halt with `HaltReason::SelfLoop` on an instruction that jumps to itself, such as `j .`.
+    pub halt_on_self_loop: bool,
+    /// How misaligned loads and stores are handled.
+    pub misaligned: MisalignPolicy,
+    /// The address reserved by the last `lr`, if `sc` may still succeed there.
+    pub reservation: Option<u64>,
+    /// Stalled in `wfi` until an interrupt is pending.
+    pub waiting: bool,
+    /// Recently fetched instructions. Stores drop the ones they overwrite, `fence.i`
+    /// flushes all of them.
+    pub icache: DecodeCache,
+    /// Cache fetched instructions in `icache`. Without it every fetch goes through the
+    /// bus.
+    pub use_decode_cache: bool,
+    /// Recent translations. `sfence.vma` and writes to satp flush them.
+    pub tlb: Tlb,
+    /// Cache translations in `tlb`. Without it every access walks the page table, and
+    /// changes to it are seen at once.
+    pub use_tlb: bool,
+    /// How many times each instruction of `decode::INSTRUCTIONS` ran, when coverage is on.
+    pub coverage: Option<Vec<u64>>,
+    /// Consulted before an instruction is raised as illegal.
+    custom_insn_handler: Option<CustomInsnHandler>,
+    /// Writes a record of every executed instruction, once it has an output.
+    tracer: Tracer,
+    /// The exit code stored to `tohost` by the running instruction.
+    tohost_exit: Option<i32>,
+    /// Addresses to stop at before fetching from them.
+    breakpoints: HashSet<u64>,
+    /// The breakpoint the cpu last stopped at, so that resuming runs its instruction
+    /// instead of stopping again.
+    stopped_at: Option<u64>,
+    /// The pc of the exception the cpu was last caught at, so that resuming traps it
+    /// instead of stopping again.
+    caught_at: Option<u64>,
+    /// The other harts of an SMP machine and whose turn it is.
+    pub(crate) scheduler: Scheduler,
 }
 
-const RVABI: [&str; 32] = [
+pub const RVABI: [&str; 32] = [
     "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", 
     "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", 
     "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", 
     "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
 ];
+
+pub const RVFABI: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7",
+    "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+    "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
  
 impl Cpu {
     /// Create a new `Cpu` object.
     pub fn new(code: Vec<u8>, disk_image: Vec<u8>) -> Self {
+        CpuBuilder::new().code(code).disk(disk_image).build()
+    }
+
+    /// Create a `Cpu` in its reset state on top of an already built bus.
+    pub(crate) fn with_bus(bus: Bus, hartid: u64) -> Self {
         let mut regs = [0; 32];
-        regs[2] = DRAM_END;
+        let fregs = [0; 32];
+        // The stack grows down from the end of dram, 16-byte aligned as the ABI requires.
+        regs[2] = DRAM_BASE + bus.dram_size() as u64;
         let pc = DRAM_BASE;
-        let bus = Bus::new(code, disk_image);
-        let csr = Csr::new();
+        let csr = Csr::new(hartid);
         let mode = Machine;
         let page_table = 0;
         let enable_paging = false;
+        let exit_on_ebreak = false;
+        let exit_ecall = None;
+        let semihosting = false;
+        let zicntr = true;
+        let strict = false;
+        let null_guard = false;
+        let break_on_exception = None;
+        let tohost = None;
+        let halt_on_self_loop = false;
+        let misaligned = MisalignPolicy::Fault;
+        let reservation = None;
+        let waiting = false;
+        let icache = DecodeCache::new();
+        let use_decode_cache = true;
+        let tlb = Tlb::new();
+        let use_tlb = true;
+        let coverage = None;
+        let custom_insn_handler = None;
+        let tracer = Tracer::new();
+        let tohost_exit = None;
+        let breakpoints = HashSet::new();
+        let stopped_at = None;
+        let caught_at = None;
+        let scheduler = Scheduler::default();
 
-        Self {regs, pc, bus, csr, mode, page_table, enable_paging}
+        Self {
+            regs, fregs, pc, bus, csr, mode, page_table, enable_paging, exit_on_ebreak, exit_ecall, semihosting, zicntr, strict, null_guard,
+            break_on_exception, tohost, halt_on_self_loop, misaligned, reservation, waiting, icache, use_decode_cache, tlb, use_tlb, coverage, custom_insn_handler, tracer,
+            tohost_exit, breakpoints, stopped_at, caught_at, scheduler,
+        }
     }
 
     pub fn set_pc(&mut self, pc: u64) {
@@ -70,18 +228,20 @@ impl Cpu {
     }
 
     pub fn reg(&self, r: &str) -> u64 {
-        match RVABI.iter().position(|&x| x == r) {
+        self.try_reg(r).unwrap_or_else(|| panic!("Invalid register {}", r))
+    }
+
+    /// The register or csr named `r` like `reg` does, or `None` for an unknown name.
+    pub fn try_reg(&self, r: &str) -> Option<u64> {
+        let value = match RVABI.iter().position(|&x| x == r) {
             Some(i) => self.regs[i],
             None => match r {
                 "pc" => self.pc,
-                "fp" => self.reg("s0"),
-                r if r.starts_with("x") => {
-                    if let Ok(i) = r[1..].parse::<usize>() {
-                        if i <= 31 { return self.regs[i]; }
-                        panic!("Invalid register {}", r);
-                    }
-                    panic!("Invalid register {}", r);
-                }
+                "fp" => self.regs[8],
+                r if r.starts_with("x") => match r[1..].parse::<usize>() {
+                    Ok(i) if i <= 31 => self.regs[i],
+                    _ => return None,
+                },
                 "mhartid" => self.csr.load(MHARTID),
                 "mstatus" => self.csr.load(MSTATUS),
                 "mtvec" => self.csr.load(MTVEC),
@@ -100,9 +260,10 @@ impl Cpu {
                 "sscratch" => self.csr.load(SSCRATCH),
                 "SIP" => self.csr.load(SIP),
                 "SATP" => self.csr.load(SATP),
-                _ => panic!("Invalid register {}", r),
-            }
-        }
+                _ => return None,
+            },
+        };
+        Some(value)
     }
 
     pub fn dump_pc(&self) {
@@ -133,11 +294,288 @@ impl Cpu {
         println!("{}", output);
     }
 
+    /// Print the floating-point registers, see `fregs_dump`.
+    pub fn dump_fregs(&self) {
+        println!("{:-^80}", "fregs");
+        println!("{}", self.fregs_dump());
+    }
+
+    /// The floating-point registers, one per line, in hex and as the values they hold.
+    /// A single-precision value is only valid when NaN-boxed, with the upper 32 bits set.
+    pub fn fregs_dump(&self) -> String {
+        let mut output = String::new();
+        for (i, &bits) in self.fregs.iter().enumerate() {
+            let single = if bits >> 32 == 0xffff_ffff {
+                format!("{} (NaN-boxed)", f32::from_bits(bits as u32))
+            } else {
+                String::from("NaN (not NaN-boxed)")
+            };
+            output += &format!(
+                "{:3}({:^4}) = {:#018x} single = {:<24} double = {}\n",
+                format!("f{}", i),
+                RVFABI[i],
+                bits,
+                single,
+                f64::from_bits(bits)
+            );
+        }
+        output
+    }
+
+    /// Print `count` instructions from `addr` on, see `memory_dump`.
+    pub fn dump_memory(&mut self, addr: u64, count: usize) {
+        println!("{:-^80}", "memory");
+        println!("{}", self.memory_dump(addr, count));
+    }
+
+    /// `count` instructions from virtual address `addr` on, like `x/10i` in gdb: one per
+    /// line with its address, the raw instruction and the disassembly. Device memory
+    /// isn't read and shows as `<device>`, an address that can't be fetched from shows
+    /// the exception. Both take 4 bytes.
+    pub fn memory_dump(&mut self, addr: u64, count: usize) -> String {
+        let mut output = String::new();
+        let mut addr = addr;
+        for _ in 0..count {
+            let device = self
+                .translate_inspect(addr, AccessType::Instruction)
+                .is_ok_and(|p_addr| self.bus.attr(p_addr) == MemAttr::Device);
+            let pc = std::mem::replace(&mut self.pc, addr);
+            let peeked = if device { None } else { Some(self.peek_instruction()) };
+            self.pc = pc;
+            let (line, len) = match peeked {
+                None => (String::from("<device>"), 4),
+                Some(Ok((raw, inst, text))) if inst.len == 2 => (format!("{:04x}      {}", raw, text), 2),
+                Some(Ok((raw, _, text))) => (format!("{:08x}  {}", raw, text), 4),
+                Some(Err(e)) => (format!("<{}>", e), 4),
+            };
+            output += &format!("{:#018x}: {}\n", addr, line);
+            addr = addr.wrapping_add(len);
+        }
+        output
+    }
+
     /// Print values in some csrs.
     pub fn dump_csrs(&self) {
         self.csr.dump_csrs();
     }
 
+    /// Run one fetch-execute cycle and then take a pending interrupt, if any.
+    /// Exceptions are trapped as usual; only a fatal one stops the cpu.
+    pub fn step(&mut self) -> Result<(), HaltReason> {
+        if self.waiting {
+            return self.wait_step();
+        }
+        if !self.breakpoints.is_empty()
+            && self.stopped_at.take() != Some(self.pc)
+            && self.breakpoints.contains(&self.pc)
+        {
+            self.stopped_at = Some(self.pc);
+            return Err(HaltReason::Breakpoint(self.pc));
+        }
+
+        let inst = match self.fetch() {
+            Ok(inst) => inst,
+            Err(e) => {
+                // A fetch fault takes a step like any instruction, so that a hart caught
+                // in a loop of them still lets time pass and the other harts run.
+                self.check_null_deref(e)?;
+                self.check_caught(e)?;
+                self.add_cycles(1);
+                self.trap(e)?;
+                return self.end_step();
+            }
+        };
+
+        self.add_cycles(1);
+        if let Some(counts) = self.coverage.as_mut() {
+            if let Some(i) = decode::lookup(inst as u32) {
+                counts[i] += 1;
+            }
+        }
+        // What the tracer needs from before the instruction ran.
+        #[cfg(feature = "invariants")]
+        let pc = self.pc;
+        let before = if self.tracer.is_enabled() { Some((self.pc, self.mode, self.regs)) } else { None };
+        match self.execute(inst) {
+            Ok(new_pc) if new_pc == self.pc && self.halt_on_self_loop => {
+                return Err(HaltReason::SelfLoop(self.pc));
+            }
+            Ok(new_pc) => {
+                self.set_pc(new_pc);
+                self.csr.store(MINSTRET, self.csr.load(MINSTRET).wrapping_add(1));
+            }
+            Err(Exception::Breakpoint(_)) if self.semihosting && self.is_semihosting_call(inst) => {
+                if let Some(code) = self.semihosting_call() {
+                    return Err(HaltReason::Exit(code));
+                }
+                self.set_pc(self.pc.wrapping_add(4));
+                self.csr.store(MINSTRET, self.csr.load(MINSTRET).wrapping_add(1));
+            }
+            Err(Exception::Breakpoint(_)) if self.exit_on_ebreak => {
+                return Err(HaltReason::Exit(self.regs[10] as i32));
+            }
+            Err(
+                Exception::EnvironmentCallFromUMode(_)
+                | Exception::EnvironmentCallFromSMode(_)
+                | Exception::EnvironmentCallFromMMode(_),
+            ) if self.exit_ecall == Some(self.regs[17]) => {
+                return Err(HaltReason::Exit(self.regs[10] as i32));
+            }
+            Err(e) => {
+                self.check_null_deref(e)?;
+                self.check_caught(e)?;
+                self.trap(e)?
+            }
+        }
+        // x0 may have been written as the destination, it reads as 0 again from here on.
+        self.regs[0] = 0;
+        if let Some(code) = self.tohost_exit.take() {
+            return Err(HaltReason::Exit(code));
+        }
+        match self.bus.finisher.as_mut().and_then(|finisher| finisher.take_request()) {
+            Some(FinisherRequest::Exit(code)) => return Err(HaltReason::Exit(code)),
+            Some(FinisherRequest::Reset) => return Err(HaltReason::Reset),
+            None => (),
+        }
+
+        if let Some((pc, mode, regs)) = before {
+            self.trace(pc, mode, inst, &regs);
+        }
+        #[cfg(feature = "invariants")]
+        self.assert_invariants(pc, inst);
+
+        self.end_step()
+    }
+
+    /// The end of a step: count it against the watchdog and the hart's turn, advance
+    /// mtime and take a pending interrupt.
+    fn end_step(&mut self) -> Result<(), HaltReason> {
+        if self.bus.watchdog.as_mut().is_some_and(|watchdog| watchdog.tick()) {
+            return Err(HaltReason::WatchdogReset);
+        }
+
+        self.bus.clint.tick();
+        if let Some(interrupt) = self.check_pending_interrupt() {
+            self.handle_interrupt(interrupt);
+        }
+        self.schedule_harts();
+        Ok(())
+    }
+
+    /// A step of a hart stalled in `wfi`: time passes and devices are served, but nothing
+    /// runs until an interrupt is pending, even one that is globally disabled.
+    fn wait_step(&mut self) -> Result<(), HaltReason> {
+        self.add_cycles(1);
+        if self.bus.watchdog.as_mut().is_some_and(|watchdog| watchdog.tick()) {
+            return Err(HaltReason::WatchdogReset);
+        }
+        self.bus.clint.tick();
+        if let Some(interrupt) = self.check_pending_interrupt() {
+            self.handle_interrupt(interrupt);
+        } else if self.csr.load(MIE) & self.csr.load(MIP) != 0 {
+            self.waiting = false;
+        }
+        self.schedule_harts();
+        Ok(())
+    }
+
+    /// With the null guard, an access fault in the first page halts instead of trapping.
+    fn check_null_deref(&self, e: Exception) -> Result<(), HaltReason> {
+        match e {
+            Exception::InstructionAccessFault(addr)
+            | Exception::LoadAccessFault(addr)
+            | Exception::StoreAMOAccessFault(addr)
+                if self.null_guard && addr < PAGE_SIZE =>
+            {
+                Err(HaltReason::NullDeref { addr, pc: self.pc })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Halt on an exception `break_on_exception` lists, unless the cpu is resuming from
+    /// the same one.
+    fn check_caught(&mut self, e: Exception) -> Result<(), HaltReason> {
+        let resumed = self.caught_at.take() == Some(self.pc);
+        match &self.break_on_exception {
+            Some(codes) if !resumed && codes.contains(&e.code()) => {
+                self.caught_at = Some(self.pc);
+                Err(HaltReason::Caught { exception: e, pc: self.pc })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Keep stepping until the cpu halts.
+    pub fn run(&mut self) -> HaltReason {
+        loop {
+            if let Err(reason) = self.step() {
+                return reason;
+            }
+        }
+    }
+
+    /// Step at most `max_steps` times. Returns why the cpu halted, or `None` if it
+    /// still runs.
+    pub fn run_steps(&mut self, max_steps: usize) -> Option<HaltReason> {
+        (0..max_steps).find_map(|_| self.step().err())
+    }
+
+    /// Copy `code` to the current pc and step until the pc leaves it.
+    ///
+    /// The fragment gets at most `EXEC_BYTES_LIMIT` instructions, so a loop that never
+    /// leaves it still returns control. A fatal exception stops it early.
+    pub fn exec_bytes(&mut self, code: &[u8]) -> Result<(), HaltReason> {
+        let start = self.pc;
+        let end = start.wrapping_add(code.len() as u64);
+        for (i, &byte) in code.iter().enumerate() {
+            if let Err(e) = self.store(start + i as u64, 8, byte as u64) {
+                return self.trap(e);
+            }
+        }
+        self.icache.flush();
+
+        for _ in 0..EXEC_BYTES_LIMIT {
+            if self.pc < start || self.pc >= end {
+                break;
+            }
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Write a JSON report of every instruction in `decode::INSTRUCTIONS`, whether it
+    /// ran and how many times. Without coverage every count is 0.
+    pub fn write_coverage<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let count = |i: usize| self.coverage.as_ref().map_or(0, |counts| counts[i]);
+        let executed = (0..decode::INSTRUCTIONS.len()).filter(|&i| count(i) > 0).count();
+
+        let mut json = String::from("{\n");
+        json += &format!("  \"total\": {},\n", decode::INSTRUCTIONS.len());
+        json += &format!("  \"executed\": {},\n", executed);
+        json += "  \"instructions\": [\n";
+        for (i, inst) in decode::INSTRUCTIONS.iter().enumerate() {
+            let sep = if i + 1 < decode::INSTRUCTIONS.len() { "," } else { "" };
+            json += &format!(
+                "    {{\"name\": \"{}\", \"executed\": {}, \"count\": {}}}{}\n",
+                inst.name,
+                count(i) > 0,
+                count(i),
+                sep
+            );
+        }
+        json += "  ]\n}\n";
+        fs::write(path, json)
+    }
+
+    fn trap(&mut self, e: Exception) -> Result<(), HaltReason> {
+        self.handle_exception(e);
+        if e.is_fatal() {
+            return Err(HaltReason::Fault(e));
+        }
+        Ok(())
+    }
+
     pub fn handle_exception(&mut self, e: Exception) {
         // the process to handle exception in S-mode and M-mode is similar,
         // includes following steps:
@@ -165,7 +603,8 @@ impl Cpu {
             };
         // 3.1.7 & 4.1.2
         // The BASE field in tvec is a WARL field that can hold any valid virtual or physical address,
-        // subject to the following alignment constraints: the address must be 4-byte aligned
+        // subject to the following alignment constraints: the address must be 4-byte aligned.
+        // `Csr::store` keeps the MODE field legal, exceptions go to BASE in either mode.
         self.pc = self.csr.load(TVEC) & !0b11;
         // 3.1.14 & 4.1.7
         // When a trap is taken into S-mode (or M-mode), sepc (or mepc) is written with the virtual address 
@@ -199,6 +638,8 @@ impl Cpu {
 
     pub fn handle_interrupt(&mut self, interrupt: Interrupt) {
         // similar to handle exception
+        // A hart stalled in wfi resumes, in the handler.
+        self.waiting = false;
         let pc = self.pc; 
         let mode = self.mode;
         let cause = interrupt.code();
@@ -222,7 +663,7 @@ impl Cpu {
         let tvec_base = tvec & !0b11;
         match tvec_mode { // DIrect
             0 => self.pc = tvec_base,
-            1 => self.pc = tvec_base + cause << 2,
+            1 => self.pc = tvec_base + ((cause & !MASK_INTERRUPT_BIT) << 2),
             _ => unreachable!(),
         };
         // 3.1.14 & 4.1.7
@@ -266,27 +707,60 @@ impl Cpu {
         // the following are true: (a) either the current privilege mode is M and the MIE bit in the mstatus
         // register is set, or the current privilege mode has less privilege than M-mode; (b) bit i is set in both
         // mip and mie; and (c) if register mideleg exists, bit i is not set in mideleg.
-        if (self.mode == Machine) && (self.csr.load(MSTATUS) & MASK_MIE) == 0 {
-            return None;
+
+        // Devices raise their interrupts through the PLIC whether or not the hart
+        // currently takes them. The UART is level-sensitive: its line stays up while
+        // an interrupt enabled in IER is outstanding.
+        self.bus.uart.flush_if_stale();
+        let uart_level = self.bus.uart.is_interrupting();
+        self.bus.plic.set_level(UART_IRQ, uart_level);
+        if self.bus.virtio_blk.take_notify() && self.disk_access() {
+            self.bus.plic.raise(VIRTIO_IRQ);
         }
-        if (self.mode == Supervisor) && (self.csr.load(SSTATUS) & MASK_SIE) == 0 {
-            return None;
+        if self.bus.virtio_net.as_mut().is_some_and(|net| net.needs_service()) && self.net_access() {
+            self.bus.plic.raise(VIRTIO_NET_IRQ);
+        }
+        // The PLIC drives MEIP and SEIP through the M-mode and S-mode contexts of this
+        // hart, the CLINT drives MTIP and MSIP.
+        let hartid = self.csr.load(MHARTID);
+        let mut levels = 0;
+        if self.bus.plic.is_interrupting(2 * hartid as usize) {
+            levels |= MASK_MEIP;
+        }
+        if self.bus.plic.is_interrupting(2 * hartid as usize + 1) {
+            levels |= MASK_SEIP;
         }
-        
-        // In fact, we should using priority to decide which interrupt should be handled first.
-        if self.bus.uart.is_interrupting() {
-            self.bus.store(PLIC_SCLAIM, 32, UART_IRQ).unwrap();
-            self.csr.store(MIP, self.csr.load(MIP) | MASK_SEIP); 
-        } else if self.bus.virtio_blk.is_interrupting() {
-            self.disk_access();
-            self.bus.store(PLIC_SCLAIM, 32, VIRTIO_IRQ).unwrap();  
-            self.csr.store(MIP, self.csr.load(MIP) | MASK_SEIP);
+        if self.bus.clint.is_timer_interrupting(hartid) {
+            levels |= MASK_MTIP;
+        }
+        if self.bus.clint.is_software_interrupting(hartid) {
+            levels |= MASK_MSIP;
+        }
+        let wires = MASK_MEIP | MASK_SEIP | MASK_MTIP | MASK_MSIP;
+        let mip = self.csr.load(MIP);
+        if (mip & wires) != levels {
+            self.csr.store(MIP, (mip & !wires) | levels);
         }
 
-        // 3.1.9 & 4.1.3
-        // Multiple simultaneous interrupts destined for M-mode are handled in the following decreasing
-        // priority order: MEI, MSI, MTI, SEI, SSI, STI.
         let pending = self.csr.load(MIE) & self.csr.load(MIP);
+        if pending == 0 {
+            return None;
+        }
+        // The interrupts that aren't delegated go to M-mode, which takes them below M-mode
+        // or with MIE set. The delegated ones go to S-mode, which never takes them in M-mode.
+        let mstatus = self.csr.load(MSTATUS);
+        let mideleg = self.csr.load(MIDELEG);
+        let m_enabled = self.mode != Machine || mstatus & MASK_MIE != 0;
+        let s_enabled = self.mode == User || (self.mode == Supervisor && mstatus & MASK_SIE != 0);
+        let m_pending = if m_enabled { pending & !mideleg } else { 0 };
+        let s_pending = if s_enabled { pending & mideleg } else { 0 };
+
+        // 3.1.9 & 4.1.3
+        // Multiple simultaneous interrupts destined for different privilege modes are handled in
+        // decreasing order of destination privilege mode. Multiple simultaneous interrupts destined
+        // for the same privilege mode are handled in the following decreasing priority order: MEI,
+        // MSI, MTI, SEI, SSI, STI.
+        let pending = if m_pending != 0 { m_pending } else { s_pending };
 
         if (pending & MASK_MEIP) != 0 {
             self.csr.store(MIP, self.csr.load(MIP) & !MASK_MEIP);
@@ -316,66 +790,200 @@ impl Cpu {
     }
 
 
-    pub fn disk_access(&mut self) {
-        const desc_size: u64 = size_of::<VirtqDesc>() as u64;
-        // 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
-        // ------------------------------------------------------------------
-        // Descriptor Table  | Available Ring | (...padding...) | Used Ring
-        // ------------------------------------------------------------------
-        let desc_addr = self.bus.virtio_blk.desc_addr();
-        let avail_addr = desc_addr + DESC_NUM as u64 * desc_size;
-        let used_addr = desc_addr + PAGE_SIZE;
-
-        // cast addr to a reference to ease field access.
-        let virtq_avail = unsafe { &(*(avail_addr as *const VirtqAvail)) };
-        let virtq_used  = unsafe { &(*(used_addr  as *const VirtqUsed)) };
-
-        // The idx field of virtq_avail should be indexed into available ring to get the
-        // index of descriptor we need to process.
-        let idx = self.bus.load(&virtq_avail.idx as *const _ as u64, 16).unwrap() as usize;
-        let index = self.bus.load(&virtq_avail.ring[idx % DESC_NUM] as *const _ as u64, 16).unwrap();
-
-        // The first descriptor:
-        // which contains the request information and a pointer to the data descriptor.
-        let desc_addr0 = desc_addr + desc_size * index;
-        let virtq_desc0 = unsafe { &(*(desc_addr0 as *const VirtqDesc)) };
-        // The addr field points to a virtio block request. We need the sector number stored 
-        // in the sector field. The iotype tells us whether to read or write.
-        let req_addr = self.bus.load(&virtq_desc0.addr as *const _ as u64, 64).unwrap();
-        let virtq_blk_req = unsafe { &(*(req_addr as *const VirtioBlkRequest)) };
-        let blk_sector = self.bus.load(&virtq_blk_req.sector as *const _ as u64, 64).unwrap();
-        let iotype = self.bus.load(&virtq_blk_req.iotype as *const _ as u64, 32).unwrap() as u32;
-        // The next field points to the second descriptor. (data descriptor)
-        let next0  = self.bus.load(&virtq_desc0.next  as *const _ as u64, 16).unwrap();
-
-        // the second descriptor. 
-        let desc_addr1 = desc_addr + desc_size * next0;
-        let virtq_desc1 = unsafe { &(*(desc_addr1 as *const VirtqDesc)) };
-        // The addr field points to the data to read or write
-        let addr1  = self.bus.load(&virtq_desc1.addr  as *const _ as u64, 64).unwrap();
-        // the len donates the size of the data
-        let len1   = self.bus.load(&virtq_desc1.len   as *const _ as u64, 32).unwrap();
-        // the flags mark this buffer as device write-only or read-only.
-        // We ignore it here
-        // let flags1 = self.bus.load(&virtq_desc1.flags as *const _ as u64, 16).unwrap();
-        match iotype {
+    /// Serve the requests on the queue of the block device. Each chain is a
+    /// virtio_blk_req header, the data buffers and a status byte the device writes.
+    /// Return whether the device used any chain, which is when it interrupts.
+    pub fn disk_access(&mut self) -> bool {
+        let page_size = self.bus.virtio_blk.page_size();
+        let mut queue = self.bus.virtio_blk.queue();
+        let mut used = false;
+        while let Some(head) = queue.next_avail(&mut self.bus, page_size) {
+            let chain = queue.chain(&mut self.bus, page_size, head);
+            let written = self.disk_request(&chain);
+            queue.push_used(&mut self.bus, page_size, head, written);
+            used = true;
+        }
+        *self.bus.virtio_blk.queue_mut() = queue;
+        if used {
+            self.bus.virtio_blk.notify_used();
+        }
+        used
+    }
+
+    /// Perform the request of the descriptor `chain` and return how many bytes the
+    /// device wrote to its buffers.
+    fn disk_request(&mut self, chain: &[(u64, u64, u16)]) -> u32 {
+        let (header, data, status) = match chain {
+            [header, data @ .., status] if header.1 >= 16 && status.1 >= 1 => (header.0, data, status.0),
+            // Without a header and a status byte there is nothing to do or to report.
+            _ => return 0,
+        };
+        let iotype = self.bus.load(header, 32).unwrap_or(u64::MAX) as u32;
+        let sector = self.bus.load(header + 8, 64).unwrap_or(u64::MAX);
+        let len: u64 = data.iter().map(|&(_, len, _)| len).sum();
+        let in_disk = sector
+            .checked_mul(SECTOR_SIZE)
+            .and_then(|start| start.checked_add(len))
+            .is_some_and(|end| end <= self.bus.virtio_blk.disk().len() as u64);
+
+        let mut written = 0;
+        let result = match iotype {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT if !in_disk => VIRTIO_BLK_S_IOERR,
+            VIRTIO_BLK_T_IN => {
+                let mut offset = sector * SECTOR_SIZE;
+                for &(addr, len, _) in data {
+                    for i in 0..len {
+                        let byte = self.bus.virtio_blk.read_disk(offset + i);
+                        let _ = self.bus.store(addr + i, 8, byte);
+                    }
+                    offset += len;
+                }
+                written = len;
+                VIRTIO_BLK_S_OK
+            }
             VIRTIO_BLK_T_OUT => {
-                for i in 0..len1 {
-                    let data = self.bus.load(addr1 + i, 8).unwrap();
-                    self.bus.virtio_blk.write_disk(blk_sector * SECTOR_SIZE + i, data);
+                let mut offset = sector * SECTOR_SIZE;
+                for &(addr, len, _) in data {
+                    for i in 0..len {
+                        let byte = self.bus.load(addr + i, 8).unwrap_or(0);
+                        self.bus.virtio_blk.write_disk(offset + i, byte);
+                    }
+                    offset += len;
                 }
+                VIRTIO_BLK_S_OK
             }
-            VIRTIO_BLK_T_IN => {
-                for i in 0..len1 {
-                    let data = self.bus.virtio_blk.read_disk(blk_sector * SECTOR_SIZE + i);
-                    self.bus.store(addr1 + i, 8, data as u64).unwrap();
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+        let _ = self.bus.store(status, 8, result);
+        written as u32 + 1
+    }
+
+    /// Move packets between the virtqueues of the network device and its backend.
+    /// Return whether the device used any buffer, which is when it interrupts.
+    pub fn net_access(&mut self) -> bool {
+        let tx_notified = match self.bus.virtio_net.as_mut() {
+            Some(net) => net.take_tx_notify(),
+            None => return false,
+        };
+        let mut used = false;
+
+        // Each transmit chain is a virtio_net_hdr followed by the frame.
+        if tx_notified {
+            while let Some(head) = self.net_next_avail(VIRTIO_NET_TX) {
+                let mut packet = Vec::new();
+                for (addr, len, _) in self.net_chain(VIRTIO_NET_TX, head) {
+                    packet.extend((0..len).map(|i| self.bus.load(addr + i, 8).unwrap_or(0) as u8));
                 }
-            } 
-            _ => unreachable!(),
-        }     
+                let frame = packet.get(VIRTIO_NET_HDR_SIZE as usize..).unwrap_or(&[]);
+                if let Some(net) = self.bus.virtio_net.as_mut() {
+                    net.backend().send(frame);
+                }
+                self.net_push_used(VIRTIO_NET_TX, head, 0);
+                used = true;
+            }
+        }
+
+        // Each receive chain gets an empty virtio_net_hdr followed by the frame. A frame
+        // waits in the backend until the driver posts a buffer for it.
+        while self.bus.virtio_net.as_mut().is_some_and(|net| net.backend().has_pending()) {
+            let head = match self.net_next_avail(VIRTIO_NET_RX) {
+                Some(head) => head,
+                None => break,
+            };
+            let frame = self.bus.virtio_net.as_mut().and_then(|net| net.backend().recv()).unwrap_or_default();
+            let mut data = vec![0u8; VIRTIO_NET_HDR_SIZE as usize];
+            data.extend_from_slice(&frame);
+
+            let mut written = 0;
+            for (addr, len, flags) in self.net_chain(VIRTIO_NET_RX, head) {
+                if flags & VIRTQ_DESC_F_WRITE == 0 {
+                    continue;
+                }
+                let n = len.min((data.len() - written) as u64);
+                for i in 0..n {
+                    let _ = self.bus.store(addr + i, 8, data[written + i as usize] as u64);
+                }
+                written += n as usize;
+            }
+            self.net_push_used(VIRTIO_NET_RX, head, written as u32);
+            used = true;
+        }
+
+        if used {
+            if let Some(net) = self.bus.virtio_net.as_mut() {
+                net.notify_used();
+            }
+        }
+        used
+    }
+
+    /// Take the next descriptor chain the driver made available on queue `q`.
+    fn net_next_avail(&mut self, q: usize) -> Option<u64> {
+        let net = self.bus.virtio_net.as_ref()?;
+        let (page_size, mut queue) = (net.page_size(), net.queue(q));
+        let head = queue.next_avail(&mut self.bus, page_size);
+        *self.bus.virtio_net.as_mut()?.queue_mut(q) = queue;
+        head
+    }
+
+    /// The (addr, len, flags) of each descriptor in the chain starting at `head`.
+    fn net_chain(&mut self, q: usize, head: u64) -> Vec<(u64, u64, u16)> {
+        let (page_size, queue) = match self.bus.virtio_net.as_ref() {
+            Some(net) => (net.page_size(), net.queue(q)),
+            None => return Vec::new(),
+        };
+        queue.chain(&mut self.bus, page_size, head)
+    }
+
+    /// Return the chain starting at `head` to the driver through the used ring of queue `q`.
+    fn net_push_used(&mut self, q: usize, head: u64, len: u32) {
+        let (page_size, mut queue) = match self.bus.virtio_net.as_ref() {
+            Some(net) => (net.page_size(), net.queue(q)),
+            None => return,
+        };
+        queue.push_used(&mut self.bus, page_size, head, len);
+        if let Some(net) = self.bus.virtio_net.as_mut() {
+            *net.queue_mut(q) = queue;
+        }
+    }
+
+    /// Whether the current mode may access `csr_addr`, as far as the unprivileged
+    /// counters go. They don't exist without Zicntr, below M-mode each is enabled by
+    /// its bit in mcounteren, and in U-mode by its bit in scounteren as well.
+    fn counter_accessible(&self, csr_addr: usize) -> bool {
+        if !(CYCLE..=HPMCOUNTER31).contains(&csr_addr) {
+            return true;
+        }
+        if csr_addr <= INSTRET && !self.zicntr {
+            return false;
+        }
+        let enabled = |counteren: usize| (self.csr.load(counteren) >> (csr_addr - CYCLE)) & 1 == 1;
+        self.mode == Machine || (enabled(MCOUNTEREN) && (self.mode == Supervisor || enabled(SCOUNTEREN)))
+    }
+
+    /// Read a csr as an instruction sees it. `cycle` and `instret` are views of the
+    /// machine counters and `time` of the CLINT's mtime, the other views (sstatus,
+    /// sie, sip) are applied by `Csr::load`.
+    fn load_csr(&mut self, csr_addr: usize) -> u64 {
+        match csr_addr {
+            CYCLE => self.csr.load(MCYCLE),
+            TIME => self.bus.clint.mtime(),
+            INSTRET => self.csr.load(MINSTRET),
+            _ => self.csr.load(csr_addr),
+        }
+    }
 
-        let new_id = self.bus.virtio_blk.get_new_id();
-        self.bus.store(&virtq_used.idx as *const _ as u64, 16, new_id % 8).unwrap();
+    /// Write a csr as an instruction does. Writes to views go through the mask of
+    /// `Csr::store`, and the unprivileged counters can't be written.
+    fn store_csr(&mut self, csr_addr: usize, value: u64) {
+        match csr_addr {
+            CYCLE | TIME | INSTRET => (),
+            // The instruction that writes minstret doesn't count, it is incremented
+            // when the instruction retires.
+            MINSTRET => self.csr.store(MINSTRET, value.wrapping_sub(1)),
+            _ => self.csr.store(csr_addr, value),
+        }
+        self.update_paging(csr_addr);
     }
 
     fn update_paging(&mut self, csr_addr: usize) {
@@ -391,13 +999,86 @@ impl Cpu {
 
         // Enable the SV39 paging if the value of the mode field is 8.
         self.enable_paging = mode == 8;
+
+        // Only sfence.vma has to flush the TLB, the ASID tells address spaces apart.
+        // Flushing here too keeps guests working that switch page tables without it.
+        self.tlb.flush(None, None);
+    }
+
+    /// The privilege mode an access is made in. "When MPRV=1, load and store memory
+    /// addresses are translated and protected as though the current privilege mode were
+    /// set to MPP."
+    fn effective_mode(&self, access_type: AccessType) -> Mode {
+        let mstatus = self.csr.load(MSTATUS);
+        match access_type {
+            AccessType::Load | AccessType::Store if mstatus & MASK_MPRV != 0 => (mstatus & MASK_MPP) >> 11,
+            _ => self.mode,
+        }
+    }
+
+    fn access_fault(addr: u64, access_type: AccessType) -> Exception {
+        match access_type {
+            AccessType::Instruction => Exception::InstructionAccessFault(addr),
+            AccessType::Load => Exception::LoadAccessFault(addr),
+            AccessType::Store => Exception::StoreAMOAccessFault(addr),
+        }
+    }
+
+    /// Translate `addr` and check the access of `len` bytes at the physical address
+    /// against PMP.
+    fn translate_checked(&mut self, addr: u64, len: u64, access_type: AccessType) -> Result<u64, Exception> {
+        let p_addr = self.translate(addr, access_type)?;
+        let perm = match access_type {
+            AccessType::Instruction => PMP_X,
+            AccessType::Load => PMP_R,
+            AccessType::Store => PMP_W,
+        };
+        if !self.csr.pmp_allows(p_addr, len, perm, self.effective_mode(access_type)) {
+            return Err(Self::access_fault(addr, access_type));
+        }
+        Ok(p_addr)
     }
 
     /// Translate a virtual address to a physical address for the paged virtual-dram system.
+    #[inline]
     pub fn translate(&mut self, addr: u64, access_type: AccessType) -> Result<u64, Exception> {
         if !self.enable_paging {
             return Ok(addr);
         }
+        self.translate_sv39(addr, access_type, true)
+    }
+
+    /// `translate` for a debugger, without side effects: PTE.A and PTE.D are left as
+    /// they are and the TLB isn't filled, so the guest's page tables don't change.
+    pub fn translate_inspect(&mut self, addr: u64, access_type: AccessType) -> Result<u64, Exception> {
+        if !self.enable_paging {
+            return Ok(addr);
+        }
+        self.translate_sv39(addr, access_type, false)
+    }
+
+    /// `translate` with Sv39 on, through the TLB. Unless `update` is set, the PTE and
+    /// the TLB are only read.
+    fn translate_sv39(&mut self, addr: u64, access_type: AccessType, update: bool) -> Result<u64, Exception> {
+        // Machine mode isn't translated.
+        let mstatus = self.csr.load(MSTATUS);
+        let mode = self.effective_mode(access_type);
+        if mode == Machine {
+            return Ok(addr);
+        }
+        let page_fault = || match access_type {
+            AccessType::Instruction => Exception::InstructionPageFault(addr),
+            AccessType::Load => Exception::LoadPageFault(addr),
+            AccessType::Store => Exception::StoreAMOPageFault(addr),
+        };
+        let access_fault = || Self::access_fault(addr, access_type);
+
+        // "Instruction fetch addresses and load and store effective addresses, which are
+        // 64 bits, must have bits 63–39 all equal to bit 38, or else a page-fault exception
+        // will occur."
+        if ((addr as i64) << 25 >> 25) as u64 != addr {
+            return Err(page_fault());
+        }
 
         // The following comments are cited from 4.3.2 Virtual Address Translation Process
         // in "The RISC-V Instruction Set Manual Volume II-Privileged Architecture_20190608".
@@ -410,50 +1091,67 @@ impl Cpu {
             (addr >> 30) & 0x1ff,
         ];
 
-        // "1. Let a be satp.ppn × PAGESIZE, and let i = LEVELS − 1. (For Sv39, PAGESIZE=212
-        //     and LEVELS=3.)"
-        let mut a = self.page_table;
-        let mut i: i64 = levels - 1;
-        let mut pte;
-        loop {
-            // "2. Let pte be the value of the PTE at address a+va.vpn[i]×PTESIZE. (For Sv39,
-            //     PTESIZE=8.) If accessing pte violates a PMA or PMP check, raise an access
-            //     exception corresponding to the original access type."
-            pte = self.bus.load(a + vpn[i as usize] * 8, 64)?;
-
-            // "3. If pte.v = 0, or if pte.r = 0 and pte.w = 1, stop and raise a page-fault
-            //     exception corresponding to the original access type."
-            let v = pte & 1;
-            let r = (pte >> 1) & 1;
-            let w = (pte >> 2) & 1;
-            let x = (pte >> 3) & 1;
-            if v == 0 || (r == 0 && w == 1) {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                    AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-                }
-            }
+        // A cached translation is used unless its PTE needs A or D set, which takes a walk.
+        let asid = (self.csr.load(SATP) >> 44) & 0xffff;
+        let page = (addr >> 12) & 0x7ff_ffff;
+        let cached = self.tlb.get(asid, page).filter(|entry| {
+            let dirty = entry.pte & PTE_D != 0 || !matches!(access_type, AccessType::Store);
+            entry.pte & PTE_A != 0 && dirty
+        });
 
-            // "4. Otherwise, the PTE is valid. If pte.r = 1 or pte.x = 1, go to step 5.
-            //     Otherwise, this PTE is a pointer to the next level of the page table.
-            //     Let i = i − 1. If i < 0, stop and raise a page-fault exception
-            //     corresponding to the original access type. Otherwise,
-            //     let a = pte.ppn × PAGESIZE and go to step 2."
-            if r == 1 || x == 1 {
-                break;
-            }
-            i -= 1;
-            let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
-            a = ppn * PAGE_SIZE;
-            if i < 0 {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                    AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
+        let (pte, pte_addr, i) = match cached {
+            Some(entry) => (entry.pte, entry.pte_addr, entry.level),
+            None => {
+                // "1. Let a be satp.ppn × PAGESIZE, and let i = LEVELS − 1. (For Sv39, PAGESIZE=212
+                //     and LEVELS=3.)"
+                let mut a = self.page_table;
+                let mut i: i64 = levels - 1;
+                let mut pte;
+                let mut pte_addr;
+                loop {
+                    // "2. Let pte be the value of the PTE at address a+va.vpn[i]×PTESIZE. (For Sv39,
+                    //     PTESIZE=8.) If accessing pte violates a PMA or PMP check, raise an access
+                    //     exception corresponding to the original access type."
+                    // The page table is read with S-mode privilege.
+                    pte_addr = a + vpn[i as usize] * 8;
+                    if !self.csr.pmp_allows(pte_addr, 8, PMP_R, Supervisor) {
+                        return Err(access_fault());
+                    }
+                    pte = self.bus.load(pte_addr, 64).map_err(|_| access_fault())?;
+                    // "For implicit accesses to supervisor-level memory management data
+                    // structures, such as page tables, endianness is always controlled by SBE."
+                    if mstatus & MASK_SBE != 0 {
+                        pte = pte.swap_bytes();
+                    }
+
+                    // "3. If pte.v = 0, or if pte.r = 0 and pte.w = 1, stop and raise a page-fault
+                    //     exception corresponding to the original access type."
+                    let v = pte & 1;
+                    let r = (pte >> 1) & 1;
+                    let w = (pte >> 2) & 1;
+                    let x = (pte >> 3) & 1;
+                    if v == 0 || (r == 0 && w == 1) {
+                        return Err(page_fault());
+                    }
+
+                    // "4. Otherwise, the PTE is valid. If pte.r = 1 or pte.x = 1, go to step 5.
+                    //     Otherwise, this PTE is a pointer to the next level of the page table.
+                    //     Let i = i − 1. If i < 0, stop and raise a page-fault exception
+                    //     corresponding to the original access type. Otherwise,
+                    //     let a = pte.ppn × PAGESIZE and go to step 2."
+                    if r == 1 || x == 1 {
+                        break;
+                    }
+                    i -= 1;
+                    let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
+                    a = ppn * PAGE_SIZE;
+                    if i < 0 {
+                        return Err(page_fault());
+                    }
                 }
+                (pte, pte_addr, i)
             }
-        }
+        };
 
         // A leaf PTE has been found.
         let ppn = [
@@ -462,15 +1160,36 @@ impl Cpu {
             (pte >> 28) & 0x03ff_ffff,
         ];
 
-        // We skip implementing from step 5 to 7.
-
         // "5. A leaf PTE has been found. Determine if the requested dram access is allowed by
         //     the pte.r, pte.w, pte.x, and pte.u bits, given the current privilege mode and the
         //     value of the SUM and MXR fields of the mstatus register. If not, stop and raise a
         //     page-fault exception corresponding to the original access type."
+        let r = pte & PTE_R != 0;
+        let w = pte & PTE_W != 0;
+        let x = pte & PTE_X != 0;
+        let user_page = pte & PTE_U != 0;
+        let allowed = match access_type {
+            AccessType::Instruction => x,
+            // "When MXR=1, loads from pages marked either readable or executable will succeed."
+            AccessType::Load => r || (x && mstatus & MASK_MXR != 0),
+            AccessType::Store => w,
+        };
+        // U-mode only accesses user pages. "When SUM=1, these accesses are permitted"
+        // from S-mode too, but S-mode never executes code from them.
+        let mode_allowed = match access_type {
+            _ if mode == User => user_page,
+            AccessType::Instruction => !user_page,
+            _ => !user_page || mstatus & MASK_SUM != 0,
+        };
+        if !allowed || !mode_allowed {
+            return Err(page_fault());
+        }
 
         // "6. If i > 0 and pte.ppn[i − 1 : 0] ̸= 0, this is a misaligned superpage; stop and
         //     raise a page-fault exception corresponding to the original access type."
+        if (i == 1 && ppn[0] != 0) || (i == 2 && (ppn[0] != 0 || ppn[1] != 0)) {
+            return Err(page_fault());
+        }
 
         // "7. If pte.a = 0, or if the dram access is a store and pte.d = 0, either raise a
         //     page-fault exception corresponding to the original access type, or:
@@ -479,6 +1198,21 @@ impl Cpu {
         //     corresponding to the original access type.
         //     • This update and the loading of pte in step 2 must be atomic; in particular, no
         //     intervening store to the PTE may be perceived to have occurred in-between."
+        // The harts take turns between instructions, so the update is atomic.
+        let mut updated = pte | PTE_A;
+        if let AccessType::Store = access_type {
+            updated |= PTE_D;
+        }
+        if update && updated != pte {
+            if !self.csr.pmp_allows(pte_addr, 8, PMP_W, Supervisor) {
+                return Err(access_fault());
+            }
+            let bytes = if mstatus & MASK_SBE != 0 { updated.swap_bytes() } else { updated };
+            self.bus.store(pte_addr, 64, bytes).map_err(|_| access_fault())?;
+        }
+        if update && self.use_tlb {
+            self.tlb.insert(asid, page, TlbEntry { pte: updated, pte_addr, level: i });
+        }
 
         // "8. The translation is successful. The translated physical address is given as
         //     follows:
@@ -502,77 +1236,360 @@ impl Cpu {
                 // ordinary page (4 KiB). It reduces TLB misses and improves performance.
                 Ok((ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset)
             }
-            _ => match access_type {
-                AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
-                AccessType::Load => return Err(Exception::LoadPageFault(addr)),
-                AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
-            },
+            _ => Err(page_fault()),
         }
     }
 
     /// Load a value from a dram.
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        let p_addr = self.translate(addr, AccessType::Load)?;
-        self.bus.load(p_addr, size)
+        // The trap value is the address the instruction computed, so a handler that
+        // emulates the access can redo it.
+        if !addr.is_multiple_of(size / 8) {
+            return match self.misaligned {
+                MisalignPolicy::Fault => Err(Exception::LoadAccessMisaligned(addr)),
+                MisalignPolicy::Emulate => self.load_bytes(addr, size).map(|value| self.data_byte_order(value, size)),
+            };
+        }
+        let p_addr = self.translate_checked(addr, size / 8, AccessType::Load)?;
+        self.add_cycles(self.bus.latency(p_addr));
+        let value = self.bus.load(p_addr, size)?;
+        Ok(self.data_byte_order(value, size))
     }
 
     /// Store a value to a dram.
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        let p_addr = self.translate(addr, AccessType::Store)?;
-        self.bus.store(p_addr, size, value)
+        let bytes = self.data_byte_order(value, size);
+        if !addr.is_multiple_of(size / 8) {
+            return match self.misaligned {
+                MisalignPolicy::Fault => Err(Exception::StoreAMOAddrMisaligned(addr)),
+                MisalignPolicy::Emulate => self.store_bytes(addr, size, bytes),
+            };
+        }
+        let p_addr = self.translate_checked(addr, size / 8, AccessType::Store)?;
+        self.add_cycles(self.bus.latency(p_addr));
+        self.bus.store(p_addr, size, bytes)?;
+        self.icache.invalidate(p_addr, size / 8);
+        self.invalidate_reservations(addr);
+        if self.tohost == Some(p_addr) && value & 1 == 1 {
+            self.tohost_exit = Some((value >> 1) as i32);
+        }
+        Ok(())
     }
 
-    /// Get an instruction from the dram.
-    pub fn fetch(&mut self) -> Result<u64, Exception> {
-        let p_pc = self.translate(self.pc, AccessType::Instruction)?;
-        match self.bus.load(p_pc, 32) {
-            Ok(inst) => Ok(inst),
-            Err(_e) => Err(Exception::InstructionAccessFault(self.pc)),
+    /// Convert between a `size`-bit value and its bytes in memory, which are in the order
+    /// of the mstatus endianness bit of the mode the access is made in: MBE, SBE or UBE.
+    /// A set bit makes data accesses big-endian. Instruction fetches are always
+    /// little-endian.
+    #[inline]
+    fn data_byte_order(&self, value: u64, size: u64) -> u64 {
+        let mstatus = self.csr.load(MSTATUS);
+        if mstatus & (MASK_MBE | MASK_SBE | MASK_UBE) == 0 {
+            return value;
+        }
+        let mode = self.effective_mode(AccessType::Load);
+        let bit = if mode == Machine {
+            MASK_MBE
+        } else if mode == Supervisor {
+            MASK_SBE
+        } else {
+            MASK_UBE
+        };
+        if mstatus & bit != 0 {
+            value.swap_bytes() >> (64 - size)
+        } else {
+            value
         }
     }
 
+    /// Load a misaligned value a byte at a time, little-endian. Each byte is translated
+    /// on its own, so the access may cross into another page.
+    fn load_bytes(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let mut value = 0;
+        for i in 0..size / 8 {
+            value |= self.load(addr.wrapping_add(i), 8)? << (i * 8);
+        }
+        Ok(value)
+    }
 
-    #[inline]
-    pub fn update_pc(&mut self) -> Result<u64, Exception> {
-        return Ok(self.pc + 4);
+    /// Store a misaligned value a byte at a time. Every byte is translated before the
+    /// first is stored, so a page fault on the second page leaves memory unchanged.
+    fn store_bytes(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        for i in 0..size / 8 {
+            self.translate_checked(addr.wrapping_add(i), 1, AccessType::Store)?;
+        }
+        for i in 0..size / 8 {
+            self.store(addr.wrapping_add(i), 8, value >> (i * 8))?;
+        }
+        Ok(())
     }
 
-    /// Execute an instruction after decoding. Return true if an error happens, otherwise false.
-    pub fn execute(&mut self, inst: u64) -> Result<u64, Exception> {
-        let opcode = inst & 0x0000007f;
-        let rd = ((inst & 0x00000f80) >> 7) as usize;
-        let rs1 = ((inst & 0x000f8000) >> 15) as usize;
-        let rs2 = ((inst & 0x01f00000) >> 20) as usize;
-        let funct3 = (inst & 0x00007000) >> 12;
-        let funct7 = (inst & 0xfe000000) >> 25;
+    /// Set how misaligned loads and stores are handled.
+    pub fn set_misaligned_policy(&mut self, policy: MisalignPolicy) {
+        self.misaligned = policy;
+    }
 
-        // Emulate that register x0 is hardwired with all bits equal to 0.
-        self.regs[0] = 0;
+    /// Read `size` bits of physical memory, for tools that inspect the guest. `size`
+    /// is 8, 16, 32 or 64 and `addr` must be aligned to it. The value is little-endian,
+    /// as the guest sees it, and no cycles are counted.
+    pub fn read_mem(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        match size {
+            8 | 16 | 32 | 64 if addr.is_multiple_of(size / 8) => self.bus.load(addr, size),
+            8 | 16 | 32 | 64 => Err(Exception::LoadAccessMisaligned(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
 
-        match opcode {
-            0x03 => {
-                // imm[11:0] = inst[31:20]
-                let imm = ((inst as i32 as i64) >> 20) as u64;
-                let addr = self.regs[rs1].wrapping_add(imm);
-                match funct3 {
-                    0x0 => {
-                        // lb
-                        let val = self.load(addr, 8)?;
-                        self.regs[rd] = val as i8 as i64 as u64;
-                        return self.update_pc();
-                    }
-                    0x1 => {
-                        // lh
-                        let val = self.load(addr, 16)?;
-                        self.regs[rd] = val as i16 as i64 as u64;
-                        return self.update_pc();
-                    }
-                    0x2 => {
-                        // lw
-                        let val = self.load(addr, 32)?;
-                        self.regs[rd] = val as i32 as i64 as u64;
-                        return self.update_pc();
-                    }
+    /// Write `size` bits of physical memory, checked like `read_mem`. The decode cache
+    /// is flushed, since the host may be writing code.
+    pub fn write_mem(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        match size {
+            8 | 16 | 32 | 64 if addr.is_multiple_of(size / 8) => self.bus.store(addr, size, value)?,
+            8 | 16 | 32 | 64 => return Err(Exception::StoreAMOAddrMisaligned(addr)),
+            _ => return Err(Exception::StoreAMOAccessFault(addr)),
+        }
+        self.icache.flush();
+        Ok(())
+    }
+
+    /// `read_mem` at a virtual address, translated as a load in the current mode. The
+    /// page tables aren't changed, see `translate_inspect`.
+    pub fn read_mem_virt(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let p_addr = self.translate_inspect(addr, AccessType::Load)?;
+        self.read_mem(p_addr, size)
+    }
+
+    /// `write_mem` at a virtual address, translated as a store in the current mode. Like
+    /// a store by the guest, it drops the reservations on the address, but it doesn't
+    /// set PTE.D.
+    pub fn write_mem_virt(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let p_addr = self.translate_inspect(addr, AccessType::Store)?;
+        self.write_mem(p_addr, size, value)?;
+        self.invalidate_reservations(addr);
+        Ok(())
+    }
+
+    /// Copy `len` bytes of physical memory starting at `addr`.
+    pub fn read_bytes(&mut self, addr: u64, len: u64) -> Result<Vec<u8>, Exception> {
+        (0..len).map(|i| self.read_mem(addr.wrapping_add(i), 8).map(|b| b as u8)).collect()
+    }
+
+    /// Place `bytes` in dram at physical address `paddr`, such as a kernel staged by a
+    /// bootloader. It must fit in dram. The decode cache is flushed.
+    pub fn load_payload_at(&mut self, paddr: u64, bytes: &[u8]) -> Result<(), Exception> {
+        self.bus.load_segment(paddr, bytes)?;
+        self.icache.flush();
+        Ok(())
+    }
+
+    /// Device memory doesn't support atomics, an AMO there is an access fault when
+    /// attributes are checked.
+    fn check_atomic(&mut self, addr: u64) -> Result<(), Exception> {
+        if !self.bus.check_attrs {
+            return Ok(());
+        }
+        let p_addr = self.translate(addr, AccessType::Store)?;
+        match self.bus.attr(p_addr) {
+            MemAttr::Device => Err(Exception::StoreAMOAccessFault(addr)),
+            MemAttr::Normal => Ok(()),
+        }
+    }
+
+    /// Advance `mcycle`. Each instruction costs one cycle plus the latency of the
+    /// memory it accesses.
+    #[inline]
+    fn add_cycles(&mut self, cycles: u64) {
+        self.csr.store(MCYCLE, self.csr.load(MCYCLE).wrapping_add(cycles));
+    }
+
+    /// Get an instruction from the dram.
+    /// A compressed instruction is returned in the low 16 bits, with the upper bits clear.
+    pub fn fetch(&mut self) -> Result<u64, Exception> {
+        let p_pc = self.translate_checked(self.pc, 2, AccessType::Instruction)?;
+        let split = self.pc & (PAGE_SIZE - 1) == PAGE_SIZE - 2;
+        let inst = match self.icache.get(p_pc) {
+            Some(inst) if self.use_decode_cache => inst,
+            _ => {
+                let inst = self.fetch_uncached(p_pc, true)?;
+                // Only code in dram is cached, a device may answer differently each time.
+                // Neither is an instruction split across pages, the second page may be
+                // remapped.
+                if self.use_decode_cache && (DRAM_BASE..=self.bus.dram_end()).contains(&p_pc) && !split {
+                    self.icache.insert(p_pc, inst);
+                }
+                inst
+            }
+        };
+        // The upper half of a 32-bit instruction may be in another PMP region. One split
+        // across pages has been checked with its second page.
+        if !is_compressed(inst) && !split {
+            let mode = self.effective_mode(AccessType::Instruction);
+            if !self.csr.pmp_allows(p_pc + 2, 2, PMP_X, mode) {
+                return Err(Exception::InstructionAccessFault(self.pc.wrapping_add(2)));
+            }
+        }
+        Ok(inst)
+    }
+
+    /// Fetch the instruction at the pc, at physical address `p_pc`, from the bus. Unless
+    /// `update` is set, a split instruction's second page is translated for inspection.
+    fn fetch_uncached(&mut self, p_pc: u64, update: bool) -> Result<u64, Exception> {
+        if self.bus.check_attrs && self.bus.attr(p_pc) == MemAttr::Device {
+            return Err(Exception::InstructionAccessFault(self.pc));
+        }
+        if self.pc & (PAGE_SIZE - 1) == PAGE_SIZE - 2 {
+            return self.fetch_split(p_pc, update);
+        }
+        // The upper half is only read for a 32-bit instruction, a compressed one may be
+        // the last halfword of memory.
+        let low = self.bus.load(p_pc, 16).map_err(|_| Exception::InstructionAccessFault(self.pc))?;
+        if is_compressed(low) {
+            return Ok(low);
+        }
+        let next = self.pc.wrapping_add(2);
+        let high = self.bus.load(p_pc + 2, 16).map_err(|_| Exception::InstructionAccessFault(next))?;
+        Ok(high << 16 | low)
+    }
+
+    /// The instruction at the pc, decoded and disassembled, for a debugger to show what
+    /// runs next. Nothing is executed and no state changes. Code in device memory is
+    /// not read, since reading a device register may change it.
+    pub fn peek_instruction(&mut self) -> Result<(u64, Instruction, String), Exception> {
+        let p_pc = self.translate_inspect(self.pc, AccessType::Instruction)?;
+        if self.bus.attr(p_pc) == MemAttr::Device {
+            return Err(Exception::InstructionAccessFault(self.pc));
+        }
+        let inst = match self.icache.get(p_pc) {
+            Some(inst) => inst,
+            None => self.fetch_uncached(p_pc, false)?,
+        };
+        Ok((inst, decode::decode(inst as u32), disasm::disassemble(inst as u32)))
+    }
+
+    /// Fetch from the last halfword of a page. A 32-bit instruction there continues on
+    /// the next page, which is translated on its own.
+    fn fetch_split(&mut self, p_pc: u64, update: bool) -> Result<u64, Exception> {
+        let low = self.bus.load(p_pc, 16).map_err(|_| Exception::InstructionAccessFault(self.pc))?;
+        if is_compressed(low) {
+            return Ok(low);
+        }
+        let next = self.pc.wrapping_add(2);
+        let p_next = if update {
+            self.translate_checked(next, 2, AccessType::Instruction)?
+        } else {
+            self.translate_inspect(next, AccessType::Instruction)?
+        };
+        let high = self.bus.load(p_next, 16).map_err(|_| Exception::InstructionAccessFault(next))?;
+        Ok(high << 16 | low)
+    }
+
+
+    #[inline]
+    pub fn update_pc(&mut self) -> Result<u64, Exception> {
+        return Ok(self.pc + 4);
+    }
+
+    /// Map the mailbox and let `handler` answer the commands the guest writes to it.
+    pub fn set_mailbox_handler(&mut self, handler: MailboxHandler) {
+        self.bus.mailbox = Some(Mailbox::new(handler));
+    }
+
+    /// Stop `run` and `step` when the pc reaches `pc`, before the instruction there is
+    /// fetched. Unlike an `ebreak` patched into memory, this works for any code.
+    pub fn add_breakpoint(&mut self, pc: u64) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u64) {
+        self.breakpoints.remove(&pc);
+    }
+
+    /// Write the trace in `formatter`'s format instead of `trace::Pretty`.
+    pub fn set_trace_formatter(&mut self, formatter: Box<dyn TraceFormatter>) {
+        self.tracer.set_formatter(formatter);
+    }
+
+    /// Trace every executed instruction to `out`, or stop tracing with `None`.
+    pub fn trace_to(&mut self, out: Option<Box<dyn io::Write>>) {
+        self.tracer.set_output(out);
+    }
+
+    /// Trace every executed instruction to the debug log, see `trace::DebugLog`.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace_to(if trace { Some(Box::<DebugLog>::default()) } else { None });
+    }
+
+    fn trace(&mut self, pc: u64, mode: Mode, inst: u64, regs: &[u64; 32]) {
+        // An instruction writes at most one register, and a write of the old value
+        // is not told apart from no write at all.
+        let write = (1..32).find(|&i| self.regs[i] != regs[i]).map(|i| (i, self.regs[i]));
+        self.tracer.record(&TraceRecord {
+            hartid: self.csr.load(MHARTID),
+            mode,
+            pc,
+            inst,
+            name: decode::lookup(inst as u32).map(|i| decode::INSTRUCTIONS[i].name),
+            write,
+        });
+    }
+
+    /// Let `handler` implement instructions that would otherwise be illegal, such as a
+    /// custom extension.
+    pub fn set_custom_insn_handler(&mut self, handler: CustomInsnHandler) {
+        self.custom_insn_handler = Some(handler);
+    }
+
+    /// Execute an instruction and return the pc of the next one.
+    pub fn execute(&mut self, inst: u64) -> Result<u64, Exception> {
+        match self.execute_base(inst) {
+            Err(Exception::IllegalInstruction(_)) if self.custom_insn_handler.is_some() => {
+                // The handler gets the whole cpu, so it is out of it during the call.
+                let mut handler = self.custom_insn_handler.take().unwrap();
+                let result = handler(self, inst);
+                self.custom_insn_handler = Some(handler);
+                result.unwrap_or(Err(Exception::IllegalInstruction(inst)))
+            }
+            result => result,
+        }
+    }
+
+    /// Execute an instruction after decoding. Return true if an error happens, otherwise false.
+    fn execute_base(&mut self, inst: u64) -> Result<u64, Exception> {
+        // Emulate that register x0 is hardwired with all bits equal to 0.
+        self.regs[0] = 0;
+
+        if is_compressed(inst) {
+            return self.execute_compressed(inst);
+        }
+        let DecodedInst { opcode, rd, rs1, rs2, funct3, funct7, imm, .. } = decode::decode_fields(inst as u32);
+
+        // Integer computational instructions with rd = x0 are HINTs. They execute like any
+        // other instruction and the result is dropped. `addi x0, x0, 0` is the canonical nop.
+        if self.strict && rd == 0 && matches!(opcode, 0x13 | 0x17 | 0x1b | 0x33 | 0x37 | 0x3b) && inst != 0x13 {
+            debug!("HINT {:#x} at {:#x}", inst, self.pc);
+        }
+
+        match opcode {
+            0x03 => {
+                let addr = self.regs[rs1].wrapping_add(imm);
+                match funct3 {
+                    0x0 => {
+                        // lb
+                        let val = self.load(addr, 8)?;
+                        self.regs[rd] = val as i8 as i64 as u64;
+                        return self.update_pc();
+                    }
+                    0x1 => {
+                        // lh
+                        let val = self.load(addr, 16)?;
+                        self.regs[rd] = val as i16 as i64 as u64;
+                        return self.update_pc();
+                    }
+                    0x2 => {
+                        // lw
+                        let val = self.load(addr, 32)?;
+                        self.regs[rd] = val as i32 as i64 as u64;
+                        return self.update_pc();
+                    }
                     0x3 => {
                         // ld
                         let val = self.load(addr, 64)?;
@@ -608,12 +1625,17 @@ impl Cpu {
                     0x0 => { // fence
                         return self.update_pc();
                     }
+                    0x1 => { // fence.i
+                        // Stores of the hart drop what they overwrite from the decode cache,
+                        // but code written by a device, such as the disk, is only seen after
+                        // flushing it.
+                        self.icache.flush();
+                        self.update_pc()
+                    }
                     _ => Err(Exception::IllegalInstruction(inst)),
                 }
             }
             0x13 => {
-                // imm[11:0] = inst[31:20]
-                let imm = ((inst & 0xfff00000) as i32 as i64 >> 20) as u64;
                 // "The shift amount is encoded in the lower 6 bits of the I-immediate field for RV64I."
                 let shamt = (imm & 0x3f) as u32;
                 match funct3 {
@@ -624,6 +1646,10 @@ impl Cpu {
                     }
                     0x1 => {
                         // slli
+                        // "imm[11:6] must be zero", other values are reserved.
+                        if self.strict && (funct7 >> 1) != 0 {
+                            return Err(Exception::IllegalInstruction(inst));
+                        }
                         self.regs[rd] = self.regs[rs1] << shamt;
                         return self.update_pc();
                     }
@@ -670,12 +1696,10 @@ impl Cpu {
             }
             0x17 => {
                 // auipc
-                let imm = (inst & 0xfffff000) as i32 as i64 as u64;
                 self.regs[rd] = self.pc.wrapping_add(imm);
                 return self.update_pc();
             }
             0x1b => {
-                let imm = ((inst as i32 as i64) >> 20) as u64;
                 // "SLLIW, SRLIW, and SRAIW encodings with imm[5] ̸= 0 are reserved."
                 let shamt = (imm & 0x1f) as u32;
                 match funct3 {
@@ -686,6 +1710,9 @@ impl Cpu {
                     }
                     0x1 => {
                         // slliw
+                        if self.strict && funct7 != 0 {
+                            return Err(Exception::IllegalInstruction(inst));
+                        }
                         self.regs[rd] = self.regs[rs1].wrapping_shl(shamt) as i32 as i64 as u64;
                         return self.update_pc();
                     }
@@ -711,8 +1738,6 @@ impl Cpu {
                 }
             }
             0x23 => {
-                // imm[11:5|4:0] = inst[31:25|11:7]
-                let imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64) | ((inst >> 7) & 0x1f);
                 let addr = self.regs[rs1].wrapping_add(imm);
                 match funct3 {
                     0x0 => {self.store(addr, 8, self.regs[rs2])?;  self.update_pc()}, // sb
@@ -727,34 +1752,74 @@ impl Cpu {
                 let funct5 = (funct7 & 0b1111100) >> 2;
                 let _aq = (funct7 & 0b0000010) >> 1; // acquire access
                 let _rl = funct7 & 0b0000001; // release access
+                self.check_atomic(self.regs[rs1])?;
                 match (funct3, funct5) {
-                    (0x2, 0x00) => {
-                        // amoadd.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
-                        return self.update_pc();
-                    }
-                    (0x3, 0x00) => {
-                        // amoadd.d
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, t.wrapping_add(self.regs[rs2]))?;
+                    (0x2 | 0x3, 0x00 | 0x01 | 0x04 | 0x08 | 0x0c | 0x10 | 0x14 | 0x18 | 0x1c) => {
+                        // amoadd, amoswap, amoxor, amoor, amoand, amomin, amomax, amominu and
+                        // amomaxu, .w and .d
+                        let addr = self.regs[rs1];
+                        let size = if funct3 == 0x2 { 32 } else { 64 };
+                        if addr & (size / 8 - 1) != 0 {
+                            return Err(Exception::StoreAMOAddrMisaligned(addr));
+                        }
+                        // An AMO faults like a store, also for the read. Neither page tables
+                        // nor PMP can grant writes without reads, so once it may store, the
+                        // read can only fail on the bus.
+                        self.translate_checked(addr, size / 8, AccessType::Store)?;
+                        let t = self.load(addr, size).map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+                        // The words are sign-extended, which keeps their unsigned order too.
+                        let (t, src) = if size == 32 {
+                            (t as i32 as i64 as u64, self.regs[rs2] as i32 as i64 as u64)
+                        } else {
+                            (t, self.regs[rs2])
+                        };
+                        let value = match funct5 {
+                            0x00 => t.wrapping_add(src),
+                            0x01 => src,
+                            0x04 => t ^ src,
+                            0x08 => t | src,
+                            0x0c => t & src,
+                            0x10 => (t as i64).min(src as i64) as u64,
+                            0x14 => (t as i64).max(src as i64) as u64,
+                            0x18 => t.min(src),
+                            _ => t.max(src),
+                        };
+                        self.store(addr, size, value)?;
                         self.regs[rd] = t;
-                        return self.update_pc();
+                        self.update_pc()
                     }
-                    (0x2, 0x01) => {
-                        // amoswap.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, self.regs[rs2])?;
+                    (0x2, 0x02) | (0x3, 0x02) if rs2 == 0 => {
+                        // lr.w and lr.d
+                        let addr = self.regs[rs1];
+                        if addr & (if funct3 == 0x2 { 3 } else { 7 }) != 0 {
+                            return Err(Exception::LoadAccessMisaligned(addr));
+                        }
+                        let t = if funct3 == 0x2 {
+                            self.load(addr, 32)? as i32 as i64 as u64
+                        } else {
+                            self.load(addr, 64)?
+                        };
+                        self.reservation = Some(addr);
                         self.regs[rd] = t;
-                        return self.update_pc();
+                        self.update_pc()
                     }
-                    (0x3, 0x01) => {
-                        // amoswap.d
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, self.regs[rs2])?;
-                        self.regs[rd] = t;
-                        return self.update_pc();
+                    (0x2, 0x03) | (0x3, 0x03) => {
+                        // sc.w and sc.d
+                        // "Regardless of success or failure, executing an SC instruction
+                        // invalidates any reservation held by this hart." It only stores
+                        // to the address the reservation is for.
+                        let addr = self.regs[rs1];
+                        let size = if funct3 == 0x2 { 32 } else { 64 };
+                        if addr & (size / 8 - 1) != 0 {
+                            return Err(Exception::StoreAMOAddrMisaligned(addr));
+                        }
+                        if self.reservation.take() == Some(addr) {
+                            self.store(addr, size, self.regs[rs2])?;
+                            self.regs[rd] = 0;
+                        } else {
+                            self.regs[rd] = 1;
+                        }
+                        self.update_pc()
                     }
                     _ => Err(Exception::IllegalInstruction(inst)),
                     
@@ -786,26 +1851,54 @@ impl Cpu {
                         self.regs[rd] = self.regs[rs1].wrapping_shl(shamt);
                         return self.update_pc();
                     }
+                    (0x1, 0x01) => {
+                        // mulh
+                        self.regs[rd] = ((self.regs[rs1] as i64 as i128 * self.regs[rs2] as i64 as i128) >> 64) as u64;
+                        self.update_pc()
+                    }
                     (0x2, 0x00) => {
                         // slt
                         self.regs[rd] = if (self.regs[rs1] as i64) < (self.regs[rs2] as i64) { 1 } else { 0 };
                         return self.update_pc();
                     }
+                    (0x2, 0x01) => {
+                        // mulhsu
+                        self.regs[rd] = ((self.regs[rs1] as i64 as i128 * self.regs[rs2] as i128) >> 64) as u64;
+                        self.update_pc()
+                    }
                     (0x3, 0x00) => {
                         // sltu
                         self.regs[rd] = if self.regs[rs1] < self.regs[rs2] { 1 } else { 0 };
                         return self.update_pc();
                     }
+                    (0x3, 0x01) => {
+                        // mulhu
+                        self.regs[rd] = ((self.regs[rs1] as u128 * self.regs[rs2] as u128) >> 64) as u64;
+                        self.update_pc()
+                    }
                     (0x4, 0x00) => {
                         // xor
                         self.regs[rd] = self.regs[rs1] ^ self.regs[rs2];
                         return self.update_pc();
                     }
+                    (0x4, 0x01) => {
+                        // div
+                        // "The quotient of division by zero has all bits set", and the
+                        // overflow of the most negative number divided by -1 is itself.
+                        let (dividend, divisor) = (self.regs[rs1] as i64, self.regs[rs2] as i64);
+                        self.regs[rd] = if divisor == 0 { u64::MAX } else { dividend.wrapping_div(divisor) as u64 };
+                        self.update_pc()
+                    }
                     (0x5, 0x00) => {
                         // srl
                         self.regs[rd] = self.regs[rs1].wrapping_shr(shamt);
                         return self.update_pc();
                     }
+                    (0x5, 0x01) => {
+                        // divu
+                        self.regs[rd] = self.regs[rs1].checked_div(self.regs[rs2]).unwrap_or(u64::MAX);
+                        self.update_pc()
+                    }
                     (0x5, 0x20) => {
                         // sra
                         self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr(shamt) as u64;
@@ -816,17 +1909,30 @@ impl Cpu {
                         self.regs[rd] = self.regs[rs1] | self.regs[rs2];
                         return self.update_pc();
                     }
+                    (0x6, 0x01) => {
+                        // rem
+                        // "The remainder of division by zero equals the dividend", and the
+                        // remainder of the overflowing division is 0.
+                        let (dividend, divisor) = (self.regs[rs1] as i64, self.regs[rs2] as i64);
+                        self.regs[rd] = if divisor == 0 { dividend as u64 } else { dividend.wrapping_rem(divisor) as u64 };
+                        self.update_pc()
+                    }
                     (0x7, 0x00) => {
                         // and
                         self.regs[rd] = self.regs[rs1] & self.regs[rs2];
                         return self.update_pc();
                     }
+                    (0x7, 0x01) => {
+                        // remu
+                        self.regs[rd] = self.regs[rs1].checked_rem(self.regs[rs2]).unwrap_or(self.regs[rs1]);
+                        self.update_pc()
+                    }
                     _ => Err(Exception::IllegalInstruction(inst)),
                 }
             }
             0x37 => {
                 // lui
-                self.regs[rd] = (inst & 0xfffff000) as i32 as i64 as u64;
+                self.regs[rd] = imm;
                 return self.update_pc();
             }
             0x3b => {
@@ -845,6 +1951,11 @@ impl Cpu {
                             ((self.regs[rs1].wrapping_sub(self.regs[rs2])) as i32) as u64;
                         return self.update_pc();
                     }
+                    (0x0, 0x01) => {
+                        // mulw
+                        self.regs[rd] = (self.regs[rs1] as i32).wrapping_mul(self.regs[rs2] as i32) as i64 as u64;
+                        self.update_pc()
+                    }
                     (0x1, 0x00) => {
                         // sllw
                         self.regs[rd] = (self.regs[rs1] as u32).wrapping_shl(shamt) as i32 as u64;
@@ -855,17 +1966,39 @@ impl Cpu {
                         self.regs[rd] = (self.regs[rs1] as u32).wrapping_shr(shamt) as i32 as u64;
                         return self.update_pc();
                     }
+                    (0x4, 0x01) => {
+                        // divw
+                        // Only the low 32 bits of the operands count. Division by zero gives -1, and
+                        // the overflow of i32::MIN / -1 gives i32::MIN, as wrapping_div does.
+                        let dividend = self.regs[rs1] as i32;
+                        let divisor = self.regs[rs2] as i32;
+                        self.regs[rd] = match divisor {
+                            0 => u64::MAX,
+                            _ => dividend.wrapping_div(divisor) as i64 as u64,
+                        };
+                        self.update_pc()
+                    }
+                    (0x6, 0x01) => {
+                        // remw
+                        // The remainder of a division by zero is the dividend, the remainder of
+                        // i32::MIN / -1 is 0.
+                        let dividend = self.regs[rs1] as i32;
+                        let divisor = self.regs[rs2] as i32;
+                        self.regs[rd] = match divisor {
+                            0 => dividend as i64 as u64,
+                            _ => dividend.wrapping_rem(divisor) as i64 as u64,
+                        };
+                        self.update_pc()
+                    }
                     (0x5, 0x01) => {
-                        // divu
-                        self.regs[rd] = match self.regs[rs2] {
-                            0 => 0xffffffff_ffffffff,
-                            _ => {
-                                let dividend = self.regs[rs1];
-                                let divisor = self.regs[rs2];
-                                dividend.wrapping_div(divisor)
-                            }
+                        // divuw
+                        let dividend = self.regs[rs1] as u32;
+                        let divisor = self.regs[rs2] as u32;
+                        self.regs[rd] = match divisor {
+                            0 => u64::MAX,
+                            _ => (dividend / divisor) as i32 as i64 as u64,
                         };
-                        return self.update_pc();
+                        self.update_pc()
                     }
                     (0x5, 0x20) => {
                         // sraw
@@ -874,26 +2007,18 @@ impl Cpu {
                     }
                     (0x7, 0x01) => {
                         // remuw
-                        self.regs[rd] = match self.regs[rs2] {
-                            0 => self.regs[rs1],
-                            _ => {
-                                let dividend = self.regs[rs1] as u32;
-                                let divisor = self.regs[rs2] as u32;
-                                dividend.wrapping_rem(divisor) as i32 as u64
-                            }
+                        let dividend = self.regs[rs1] as u32;
+                        let divisor = self.regs[rs2] as u32;
+                        self.regs[rd] = match divisor {
+                            0 => dividend as i32 as i64 as u64,
+                            _ => (dividend % divisor) as i32 as i64 as u64,
                         };
-                        return self.update_pc();
+                        self.update_pc()
                     }
                     _ => Err(Exception::IllegalInstruction(inst)),
                 }
             }
             0x63 => {
-                // imm[12|10:5|4:1|11] = inst[31|30:25|11:8|7]
-                let imm = (((inst & 0x80000000) as i32 as i64 >> 19) as u64)
-                    | ((inst & 0x80) << 4) // imm[11]
-                    | ((inst >> 20) & 0x7e0) // imm[10:5]
-                    | ((inst >> 7) & 0x1e); // imm[4:1]
-
                 match funct3 {
                     0x0 => {
                         // beq
@@ -944,8 +2069,6 @@ impl Cpu {
             0x67 => {
                 // jalr
                 let t = self.pc + 4;
-
-                let imm = ((((inst & 0xfff00000) as i32) as i64) >> 20) as u64;
                 let new_pc = (self.regs[rs1].wrapping_add(imm)) & !1;
 
                 self.regs[rd] = t;
@@ -954,17 +2077,20 @@ impl Cpu {
             0x6f => {
                 // jal
                 self.regs[rd] = self.pc + 4;
-
-                // imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
-                let imm = (((inst & 0x80000000) as i32 as i64 >> 11) as u64) // imm[20]
-                    | (inst & 0xff000) // imm[19:12]
-                    | ((inst >> 9) & 0x800) // imm[11]
-                    | ((inst >> 20) & 0x7fe); // imm[10:1]
-
                 return Ok(self.pc.wrapping_add(imm));
             }
             0x73 => {
                 let csr_addr = ((inst & 0xfff00000) >> 20) as usize;
+                if funct3 != 0x0 && (is_rv32_only(csr_addr) || !self.counter_accessible(csr_addr)) {
+                    return Err(Exception::IllegalInstruction(inst));
+                }
+                // Bits 9:8 of the address are the lowest mode that may access the csr, and
+                // with bits 11:10 set it is read-only. csrrs and csrrc with x0, and their
+                // immediate forms with 0, only read.
+                let writes = funct3 & 0b11 == 0b01 || rs1 != 0;
+                if funct3 != 0x0 && ((csr_addr as u64 >> 8) & 0b11 > self.mode || (writes && csr_addr >> 10 == 0b11)) {
+                    return Err(Exception::IllegalInstruction(inst));
+                }
                 match funct3 {
                     0x0 => {
                         match (rs2, funct7) {
@@ -1003,9 +2129,9 @@ impl Cpu {
                                 sstatus &= !MASK_SPP;
                                 self.csr.store(SSTATUS, sstatus);
                                 // set the pc to CSRs[sepc].
-                                // whenever IALIGN=32, bit sepc[1] is masked on reads so that it appears to be 0. This
-                                // masking occurs also for the implicit read by the SRET instruction. 
-                                let new_pc = self.csr.load(SEPC) & !0b11;
+                                // sepc[0] is always 0. With the C extension IALIGN=16, so sepc[1] is
+                                // kept: a trap may be taken at a halfword-aligned instruction.
+                                let new_pc = self.csr.load(SEPC) & !1;
                                 return Ok(new_pc);
                             }
                             (0x2, 0x18) => {
@@ -1022,15 +2148,35 @@ impl Cpu {
                                 // set MPP the least privilege mode (u-mode)
                                 mstatus &= !MASK_MPP;
                                 // If MPP != M, sets MPRV=0
-                                mstatus &= !MASK_MPRV;
+                                if self.mode != Machine {
+                                    mstatus &= !MASK_MPRV;
+                                }
                                 self.csr.store(MSTATUS, mstatus);
-                                // set the pc to CSRs[mepc].
-                                let new_pc = self.csr.load(MEPC) & !0b11;
+                                // set the pc to CSRs[mepc], which is halfword-aligned like sepc.
+                                let new_pc = self.csr.load(MEPC) & !1;
                                 return Ok(new_pc);
                             }
+                            (0x5, 0x8) => {
+                                // wfi
+                                // With TW set it is illegal below M-mode. Otherwise the hart
+                                // stalls after it until an interrupt is pending, see `wait_step`.
+                                if self.mode != Machine && self.csr.load(MSTATUS) & MASK_TW != 0 {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
+                                self.waiting = true;
+                                self.update_pc()
+                            }
                             (_, 0x9) => {
                                 // sfence.vma
-                                // Do nothing.
+                                // rs1 selects the page of an address and rs2 an address
+                                // space, x0 means all of them.
+                                let vaddr = (rs1 != 0).then_some(self.regs[rs1]);
+                                let asid = (rs2 != 0).then_some(self.regs[rs2] & 0xffff);
+                                self.tlb.flush(vaddr, asid);
+                                // xv6 writes user code with ordinary stores and relies on
+                                // sfence.vma before running it, so it also flushes the
+                                // decode cache.
+                                self.icache.flush();
                                 return self.update_pc();
                             }
                             _ => Err(Exception::IllegalInstruction(inst)),
@@ -1038,58 +2184,58 @@ impl Cpu {
                     }
                     0x1 => {
                         // csrrw
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, self.regs[rs1]);
+                        // With rd = x0 the csr is only written, not read.
+                        let t = if rd != 0 { self.load_csr(csr_addr) } else { 0 };
+                        self.store_csr(csr_addr, self.regs[rs1]);
                         self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
                         return self.update_pc();
                     }
                     0x2 => {
                         // csrrs
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t | self.regs[rs1]);
+                        // With rs1 = x0 the csr is only read, not written back.
+                        let t = self.load_csr(csr_addr);
+                        if rs1 != 0 {
+                            self.store_csr(csr_addr, t | self.regs[rs1]);
+                        }
                         self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
                         return self.update_pc();
                     }
                     0x3 => {
                         // csrrc
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t & (!self.regs[rs1]));
+                        let t = self.load_csr(csr_addr);
+                        if rs1 != 0 {
+                            self.store_csr(csr_addr, t & (!self.regs[rs1]));
+                        }
                         self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
                         return self.update_pc();
                     }
                     0x5 => {
                         // csrrwi
                         let zimm = rs1 as u64;
-                        self.regs[rd] = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, zimm);
-
-                        self.update_paging(csr_addr);
+                        if rd != 0 {
+                            self.regs[rd] = self.load_csr(csr_addr);
+                        }
+                        self.store_csr(csr_addr, zimm);
                         return self.update_pc();
                     }
                     0x6 => {
                         // csrrsi
                         let zimm = rs1 as u64;
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t | zimm);
+                        let t = self.load_csr(csr_addr);
+                        if zimm != 0 {
+                            self.store_csr(csr_addr, t | zimm);
+                        }
                         self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
                         return self.update_pc();
                     }
                     0x7 => {
                         // csrrci
                         let zimm = rs1 as u64;
-                        let t = self.csr.load(csr_addr);
-                        self.csr.store(csr_addr, t & (!zimm));
+                        let t = self.load_csr(csr_addr);
+                        if zimm != 0 {
+                            self.store_csr(csr_addr, t & (!zimm));
+                        }
                         self.regs[rd] = t;
-
-                        self.update_paging(csr_addr);
                         return self.update_pc();
                     }
                     _ => Err(Exception::IllegalInstruction(inst)),
@@ -1098,6 +2244,203 @@ impl Cpu {
             _ => Err(Exception::IllegalInstruction(inst)),
         }
     }
+
+    /// Execute a 16-bit instruction of the C extension.
+    fn execute_compressed(&mut self, inst: u64) -> Result<u64, Exception> {
+        let quadrant = inst & 0b11;
+        let funct4 = (inst >> 12) & 0xf;
+        let rd = ((inst >> 7) & 0x1f) as usize;
+        let rs2 = ((inst >> 2) & 0x1f) as usize;
+
+        match (quadrant, funct4) {
+            (0b10, 0b1000) => {
+                if rs2 == 0 {
+                    // c.jr
+                    // "C.JR is only valid when rs1≠x0; the code point with rs1=x0 is reserved."
+                    if rd == 0 {
+                        return Err(Exception::IllegalInstruction(inst));
+                    }
+                    Ok(self.regs[rd] & !1)
+                } else {
+                    // c.mv
+                    // "C.MV with rd=x0 is a HINT."
+                    self.regs[rd] = self.regs[rs2];
+                    Ok(self.pc.wrapping_add(2))
+                }
+            }
+            (0b10, 0b1001) => {
+                if rd == 0 && rs2 == 0 {
+                    // c.ebreak
+                    Err(Exception::Breakpoint(self.pc))
+                } else if rs2 == 0 {
+                    // c.jalr
+                    // Read the target before writing the link, rs1 may be ra.
+                    let target = self.regs[rd] & !1;
+                    self.regs[1] = self.pc.wrapping_add(2);
+                    Ok(target)
+                } else {
+                    // c.add
+                    self.regs[rd] = self.regs[rd].wrapping_add(self.regs[rs2]);
+                    Ok(self.pc.wrapping_add(2))
+                }
+            }
+            (0b01, 0b1010) | (0b01, 0b1011) => {
+                // c.j
+                Ok(self.pc.wrapping_add(cj_offset(inst as u32) as u64))
+            }
+            (0b01, 0b1100..=0b1111) => {
+                // c.beqz and c.bnez
+                let rs1 = ((inst >> 7) & 0x7) as usize + 8;
+                if (self.regs[rs1] == 0) == (funct4 < 0b1110) {
+                    Ok(self.pc.wrapping_add(cb_offset(inst as u32) as u64))
+                } else {
+                    Ok(self.pc.wrapping_add(2))
+                }
+            }
+            // The others don't touch the pc, they run as the instruction they stand for.
+            _ => match decompress(inst as u16) {
+                Some(expanded) => {
+                    self.execute_base(expanded as u64)?;
+                    Ok(self.pc.wrapping_add(2))
+                }
+                None => Err(Exception::IllegalInstruction(inst)),
+            },
+        }
+    }
+}
+
+/// Expand the compressed instruction `half` to the 32-bit instruction it stands for, or
+/// `None` if its encoding is reserved or belongs to an extension the cpu lacks.
+pub fn decompress(half: u16) -> Option<u32> {
+    let inst = half as u32;
+    let bits = |hi: u32, lo: u32| (inst >> lo) & ((1 << (hi - lo + 1)) - 1);
+    // The full register fields, and the 3-bit ones naming x8-x15.
+    let (rd, rs2) = (bits(11, 7), bits(6, 2));
+    let (rs1_, rd_) = (bits(9, 7) + 8, bits(4, 2) + 8);
+    // The 6-bit immediate of CI and CB, sign-extended, and the shift amount.
+    let imm6 = ((bits(12, 12) << 5 | bits(6, 2)) << 26) as i32 >> 26;
+    let shamt = bits(12, 12) << 5 | bits(6, 2);
+    // The offsets of c.lw/c.sw and c.ld/c.sd.
+    let word = bits(5, 5) << 6 | bits(12, 10) << 3 | bits(6, 6) << 2;
+    let double = bits(6, 5) << 6 | bits(12, 10) << 3;
+
+    let i_type = |imm: i32, rs1: u32, funct3: u32, rd: u32, opcode: u32| {
+        (imm as u32) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+    };
+    let s_type = |imm: u32, rs2: u32, rs1: u32, funct3: u32| {
+        (imm >> 5) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | 0x23
+    };
+    let b_type = |rs1: u32, funct3: u32| {
+        let imm = cb_offset(inst) as u32;
+        (imm >> 12 & 1) << 31 | (imm >> 5 & 0x3f) << 25 | rs1 << 15 | funct3 << 12 | (imm >> 1 & 0xf) << 8
+            | (imm >> 11 & 1) << 7 | 0x63
+    };
+
+    let expanded = match (bits(1, 0), bits(15, 13)) {
+        (0b00, 0b000) => {
+            // c.addi4spn
+            let imm = bits(10, 7) << 6 | bits(12, 11) << 4 | bits(5, 5) << 3 | bits(6, 6) << 2;
+            if imm == 0 {
+                return None;
+            }
+            i_type(imm as i32, 2, 0x0, rd_, 0x13)
+        }
+        (0b00, 0b010) => i_type(word as i32, rs1_, 0x2, rd_, 0x03), // c.lw
+        (0b00, 0b011) => i_type(double as i32, rs1_, 0x3, rd_, 0x03), // c.ld
+        (0b00, 0b110) => s_type(word, rd_, rs1_, 0x2), // c.sw
+        (0b00, 0b111) => s_type(double, rd_, rs1_, 0x3), // c.sd
+        (0b01, 0b000) => i_type(imm6, rd, 0x0, rd, 0x13), // c.addi, c.nop
+        (0b01, 0b001) if rd != 0 => i_type(imm6, rd, 0x0, rd, 0x1b), // c.addiw
+        (0b01, 0b010) => i_type(imm6, 0, 0x0, rd, 0x13), // c.li
+        (0b01, 0b011) if rd == 2 => {
+            // c.addi16sp
+            let imm = bits(12, 12) << 9 | bits(4, 3) << 7 | bits(5, 5) << 6 | bits(2, 2) << 5 | bits(6, 6) << 4;
+            if imm == 0 {
+                return None;
+            }
+            i_type((imm << 22) as i32 >> 22, 2, 0x0, 2, 0x13)
+        }
+        (0b01, 0b011) if imm6 != 0 => (imm6 as u32) << 12 | rd << 7 | 0x37, // c.lui
+        (0b01, 0b100) => match bits(11, 10) {
+            0b00 => i_type(shamt as i32, rs1_, 0x5, rs1_, 0x13), // c.srli
+            0b01 => i_type((0x400 | shamt) as i32, rs1_, 0x5, rs1_, 0x13), // c.srai
+            0b10 => i_type(imm6, rs1_, 0x7, rs1_, 0x13), // c.andi
+            _ => return expand_ca(inst),
+        },
+        (0b01, 0b101) => {
+            // c.j
+            let imm = cj_offset(inst) as u32;
+            (imm >> 20 & 1) << 31 | (imm >> 1 & 0x3ff) << 21 | (imm >> 11 & 1) << 20 | (imm >> 12 & 0xff) << 12 | 0x6f
+        }
+        (0b01, 0b110) => b_type(rs1_, 0x0), // c.beqz
+        (0b01, 0b111) => b_type(rs1_, 0x1), // c.bnez
+        (0b10, 0b000) => i_type(shamt as i32, rd, 0x1, rd, 0x13), // c.slli
+        (0b10, 0b010) if rd != 0 => {
+            // c.lwsp
+            let imm = bits(3, 2) << 6 | bits(12, 12) << 5 | bits(6, 4) << 2;
+            i_type(imm as i32, 2, 0x2, rd, 0x03)
+        }
+        (0b10, 0b011) if rd != 0 => {
+            // c.ldsp
+            let imm = bits(4, 2) << 6 | bits(12, 12) << 5 | bits(6, 5) << 3;
+            i_type(imm as i32, 2, 0x3, rd, 0x03)
+        }
+        (0b10, 0b100) => match (bits(12, 12), rd, rs2) {
+            (0, 0, 0) => return None,
+            (0, _, 0) => i_type(0, rd, 0x0, 0, 0x67), // c.jr
+            (0, _, _) => rs2 << 20 | rd << 7 | 0x33, // c.mv
+            (_, 0, 0) => 0x0010_0073, // c.ebreak
+            (_, _, 0) => i_type(0, rd, 0x0, 1, 0x67), // c.jalr
+            _ => rs2 << 20 | rd << 15 | rd << 7 | 0x33, // c.add
+        },
+        (0b10, 0b110) => s_type(bits(8, 7) << 6 | bits(12, 9) << 2, rs2, 2, 0x2), // c.swsp
+        (0b10, 0b111) => s_type(bits(9, 7) << 6 | bits(12, 10) << 3, rs2, 2, 0x3), // c.sdsp
+        _ => return None,
+    };
+    Some(expanded)
+}
+
+/// The offset of `c.j`, sign-extended.
+fn cj_offset(inst: u32) -> i32 {
+    let bits = |hi: u32, lo: u32| (inst >> lo) & ((1 << (hi - lo + 1)) - 1);
+    let imm = bits(12, 12) << 11 | bits(8, 8) << 10 | bits(10, 9) << 8 | bits(6, 6) << 7 | bits(7, 7) << 6
+        | bits(2, 2) << 5 | bits(11, 11) << 4 | bits(5, 3) << 1;
+    (imm << 20) as i32 >> 20
+}
+
+/// The offset of `c.beqz` and `c.bnez`, sign-extended.
+fn cb_offset(inst: u32) -> i32 {
+    let bits = |hi: u32, lo: u32| (inst >> lo) & ((1 << (hi - lo + 1)) - 1);
+    let imm = bits(12, 12) << 8 | bits(6, 5) << 6 | bits(2, 2) << 5 | bits(11, 10) << 3 | bits(4, 3) << 1;
+    (imm << 23) as i32 >> 23
+}
+
+/// Expand an instruction of the compressed CA format (`c.sub`, `c.xor`, `c.or`, `c.and`,
+/// `c.subw` and `c.addw`) to the R-type instruction it stands for.
+fn expand_ca(inst: u32) -> Option<u32> {
+    // The 3-bit register fields name x8-x15.
+    let rd = ((inst >> 7) & 0x7) + 8;
+    let rs2 = ((inst >> 2) & 0x7) + 8;
+    let funct2 = (inst >> 5) & 0x3;
+    if (inst >> 10) & 0x3 != 0b11 {
+        return None;
+    }
+    let (funct7, funct3, opcode) = match ((inst >> 12) & 1, funct2) {
+        (0, 0b00) => (0x20, 0x0, 0x33),
+        (0, 0b01) => (0x00, 0x4, 0x33),
+        (0, 0b10) => (0x00, 0x6, 0x33),
+        (0, 0b11) => (0x00, 0x7, 0x33),
+        (1, 0b00) => (0x20, 0x0, 0x3b),
+        (1, 0b01) => (0x00, 0x0, 0x3b),
+        _ => return None,
+    };
+    Some(funct7 << 25 | rs2 << 20 | rd << 15 | funct3 << 12 | rd << 7 | opcode)
+}
+
+/// Instructions of the C extension are 16 bits and don't have 0b11 in their lowest bits.
+#[inline]
+pub(crate) fn is_compressed(inst: u64) -> bool {
+    inst & 0b11 != 0b11
 }
 
 
@@ -1108,6 +2451,8 @@ mod test {
     use std::io::{Write, Read};
     use std::process::Command;
     use super::*;
+    use crate::semihosting::ADP_STOPPED_APPLICATION_EXIT;
+    use crate::watchdog::Watchdog;
 
     fn generate_rv_assembly(c_src: &str) {
         let cc = "clang";
@@ -1176,6 +2521,28 @@ mod test {
         return Ok(cpu);
     }
 
+    fn rv_code(insts: &[u32]) -> Vec<u8> {
+        insts.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+    }
+
+    /// Run already encoded instructions, for tests that don't need clang.
+    fn rv_inst_helper(insts: &[u32], n_clock: usize) -> Cpu {
+        let mut cpu = CpuBuilder::new().code(rv_code(insts)).headless_uart(true).build();
+
+        for _i in 0..n_clock {
+            let inst = match cpu.fetch() {
+                Ok(inst) => inst,
+                Err(_err) => break,
+            };
+            match cpu.execute(inst) {
+                Ok(new_pc) => cpu.pc = new_pc,
+                Err(err) => println!("{}", err),
+            };
+        }
+
+        cpu
+    }
+
     macro_rules! riscv_test {
         ( $code:expr, $name:expr, $clock:expr, $($real:expr => $expect:expr),* ) => {
             match rv_helper($code, $name, $clock) {
@@ -1305,6 +2672,30 @@ mod test {
         riscv_test!(code, "test_store_load1", 10, "t1" => 0, "t2" => 256);
     }
 
+    #[test]
+    fn test_store_negative_offset() {
+        let mut cpu = rv_inst_helper(&[
+            0xff010113, // addi sp, sp, -16
+            0x123450b7, // lui  ra, 0x12345
+            0x67808093, // addi ra, ra, 0x678
+            0xfe113c23, // sd   ra, -8(sp)
+            0xfe110fa3, // sb   ra, -1(sp)
+        ], 5);
+        let sp = DRAM_BASE + DRAM_SIZE - 16;
+        assert_eq!(cpu.reg("sp"), sp);
+        assert_eq!(cpu.bus.load(sp - 8, 64).unwrap(), 0x78000000_12345678);
+        assert_eq!(cpu.bus.load(sp, 8).unwrap(), 0);
+
+        // imm[4:0] = 0b11100 comes from inst[11:7] alone, bits 4:0 of the high part are 0.
+        // sw ra, -4(sp) and sd ra, -4(sp), which is misaligned at that address.
+        assert_eq!(cpu.execute(0xfe112e23), Ok(cpu.pc + 4));
+        assert_eq!(cpu.bus.load(sp - 4, 32).unwrap(), 0x12345678);
+        assert_eq!(cpu.execute(0xfe113e23), Err(Exception::StoreAMOAddrMisaligned(sp - 4)));
+        // sw ra, -2048(sp)
+        assert_eq!(cpu.execute(0x80112023), Ok(cpu.pc + 4));
+        assert_eq!(cpu.bus.load(sp - 2048, 32).unwrap(), 0x12345678);
+    }
+
     #[test]
     fn test_slt() {
         let code = "
@@ -1374,6 +2765,27 @@ mod test {
                                               "a4" => -8 as i64 as u64 >> 2, "a5" => -8 as i64 as u64 >> 1);
     }
 
+    #[test]
+    fn test_shift_amount_mask() {
+        // Register shifts use the low 6 bits of rs2: 63 is the largest shift, 64 is no shift.
+        let cpu = rv_inst_helper(&[
+            0xff800513, // addi a0, zero, -8
+            0x03f00293, // addi t0, zero, 63
+            0x04000313, // addi t1, zero, 64
+            0x005555b3, // srl a1, a0, t0
+            0x00655633, // srl a2, a0, t1
+            0x405556b3, // sra a3, a0, t0
+            0x40655733, // sra a4, a0, t1
+            0x00100493, // addi s1, zero, 1
+            0x005497b3, // sll a5, s1, t0
+        ], 9);
+        assert_eq!(cpu.reg("a1"), 1);
+        assert_eq!(cpu.reg("a2"), -8i64 as u64);
+        assert_eq!(cpu.reg("a3"), u64::MAX);
+        assert_eq!(cpu.reg("a4"), -8i64 as u64);
+        assert_eq!(cpu.reg("a5"), 1 << 63);
+    }
+
     #[test]
     fn test_word_op() {
         let code = "
@@ -1403,6 +2815,2245 @@ mod test {
                                             "sstatus" => 0, "stvec" => 5, "sepc" => 6);
     }
 
+    #[test]
+    fn test_exit_on_ebreak() {
+        let code = rv_code(&[
+            0x00700513, // li     a0, 7
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        assert_eq!(cpu.run(), HaltReason::Exit(7));
+        assert_eq!(cpu.pc, DRAM_BASE + 4);
+    }
+
+    #[test]
+    fn test_semihosting() {
+        let mut code = rv_code(&[
+            0x00000597, // auipc a1, 0
+            0x06058593, // addi a1, a1, 96
+            0x00400513, // li a0, 4 (SYS_WRITE0)
+            0x01f01013, // slli x0, x0, 0x1f
+            0x00100073, // ebreak
+            0x40705013, // srai x0, x0, 7
+            0x00050413, // mv s0, a0
+            0x09900513, // li a0, 0x99
+            0x01f01013, // slli x0, x0, 0x1f
+            0x00100073, // ebreak
+            0x40705013, // srai x0, x0, 7
+            0x00050493, // mv s1, a0
+            0x00000597, // auipc a1, 0
+            0x02058593, // addi a1, a1, 32
+            0x01800513, // li a0, 0x18 (SYS_EXIT)
+            0x01f01013, // slli x0, x0, 0x1f
+            0x00100073, // ebreak
+            0x40705013, // srai x0, x0, 7
+        ]);
+        // The parameter block of SYS_EXIT at 0x50, then the string.
+        code.resize(0x50, 0);
+        code.extend_from_slice(&ADP_STOPPED_APPLICATION_EXIT.to_le_bytes());
+        code.extend_from_slice(&42u64.to_le_bytes());
+        code.extend_from_slice(b"hello\n\0");
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).semihosting(true).build();
+        assert_eq!(cpu.run(), HaltReason::Exit(42));
+        assert_eq!(cpu.bus.uart.output(), b"hello\n");
+        assert_eq!(cpu.reg("s0"), 0);
+        // An operation that isn't served fails.
+        assert_eq!(cpu.reg("s1"), u64::MAX);
+        assert_eq!(cpu.pc, DRAM_BASE + 0x40);
+
+        // A plain ebreak is a breakpoint, the exit convention works alongside.
+        let code = rv_code(&[
+            0x00400513, // li a0, 4
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).semihosting(true).build();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.csr.load(MCAUSE), 3);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 4);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).semihosting(true).exit_on_ebreak(true).build();
+        assert_eq!(cpu.run(), HaltReason::Exit(4));
+        assert!(cpu.bus.uart.output().is_empty());
+    }
+
+    #[test]
+    fn test_plic_level_sensitive_uart() {
+        let code = rv_code(&[
+            0x0c2012b7, // lui  t0, 0x0c201
+            0x10000337, // lui  t1, 0x10000
+            0x0042a503, // lw   a0, 4(t0)    claim
+            0x00034583, // lbu  a1, 0(t1)
+            0x00a2a223, // sw   a0, 4(t0)    complete
+            0x0042a603, // lw   a2, 4(t0)    claim
+            0x00034683, // lbu  a3, 0(t1)
+            0x00c2a223, // sw   a2, 4(t0)    complete
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        // The S-mode context of hart 0 takes the UART.
+        cpu.bus.store(PLIC_PRIORITY + UART_IRQ * 4, 32, 1).unwrap();
+        cpu.bus.store(PLIC_ENABLE + PLIC_ENABLE_STRIDE, 32, 1 << UART_IRQ).unwrap();
+        cpu.bus.store(UART_BASE + UART_IER, 8, MASK_UART_IER_RX as u64).unwrap();
+        cpu.bus.uart.push_input(b"ab");
+
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.reg("a0"), UART_IRQ);
+        assert_eq!(cpu.reg("a1"), b'a' as u64);
+        // The second byte keeps the line up, so the source is pending again after completion.
+        assert_eq!(cpu.bus.load(PLIC_PENDING, 32).unwrap(), 1 << UART_IRQ);
+        assert_ne!(cpu.csr.load(MIP) & MASK_SEIP, 0);
+
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.reg("a2"), UART_IRQ);
+        assert_eq!(cpu.reg("a3"), b'b' as u64);
+        assert_eq!(cpu.bus.load(PLIC_PENDING, 32).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_uart_thr_empty_interrupt() {
+        let code = rv_code(&[
+            0x0c2012b7, // lui  t0, 0x0c201
+            0x0042a503, // lw   a0, 4(t0)    claim
+            0x00a2a223, // sw   a0, 4(t0)    complete
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.bus.store(PLIC_PRIORITY + UART_IRQ * 4, 32, 1).unwrap();
+        cpu.bus.store(PLIC_ENABLE + PLIC_ENABLE_STRIDE, 32, 1 << UART_IRQ).unwrap();
+        cpu.bus.store(UART_BASE + UART_IER, 8, MASK_UART_IER_TX as u64).unwrap();
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.load(PLIC_PENDING, 32).unwrap(), 1 << UART_IRQ);
+        // The claim acknowledges it, so it isn't pending again after completion.
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), UART_IRQ);
+        assert_eq!(cpu.bus.load(PLIC_PENDING, 32).unwrap(), 0);
+        assert_eq!(cpu.csr.load(MIP) & MASK_SEIP, 0);
+
+        // Until the next byte is sent.
+        cpu.bus.store(UART_BASE + UART_THR, 8, b'a' as u64).unwrap();
+        cpu.pc = DRAM_BASE;
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.load(PLIC_PENDING, 32).unwrap(), 1 << UART_IRQ);
+    }
+
+    #[test]
+    fn test_plic_priority() {
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+        let claim = |cpu: &mut Cpu, context: u64| cpu.bus.load(PLIC_CLAIM + context * PLIC_CONTEXT_STRIDE, 32).unwrap();
+        cpu.bus.store(PLIC_PRIORITY + VIRTIO_IRQ * 4, 32, 1).unwrap();
+        cpu.bus.store(PLIC_PRIORITY + VIRTIO_NET_IRQ * 4, 32, 3).unwrap();
+        cpu.bus.store(PLIC_ENABLE + PLIC_ENABLE_STRIDE, 32, 1 << VIRTIO_IRQ | 1 << VIRTIO_NET_IRQ).unwrap();
+        cpu.bus.plic.raise(VIRTIO_IRQ);
+        cpu.bus.plic.raise(VIRTIO_NET_IRQ);
+        assert_eq!(cpu.bus.load(PLIC_PENDING, 32), Ok(1 << VIRTIO_IRQ | 1 << VIRTIO_NET_IRQ));
+
+        // Only the sources above the threshold interrupt, the highest priority first, and
+        // a context that enables nothing never gets one.
+        cpu.bus.store(PLIC_THRESHOLD + PLIC_CONTEXT_STRIDE, 32, 1).unwrap();
+        assert_eq!(claim(&mut cpu, 0), 0);
+        cpu.check_pending_interrupt();
+        assert_eq!(cpu.csr.load(MIP) & (MASK_MEIP | MASK_SEIP), MASK_SEIP);
+        assert_eq!(claim(&mut cpu, 1), VIRTIO_NET_IRQ);
+        assert_eq!(claim(&mut cpu, 1), 0);
+        cpu.check_pending_interrupt();
+        assert_eq!(cpu.csr.load(MIP) & MASK_SEIP, 0);
+        cpu.bus.store(PLIC_THRESHOLD + PLIC_CONTEXT_STRIDE, 32, 0).unwrap();
+        assert_eq!(claim(&mut cpu, 1), VIRTIO_IRQ);
+        assert_eq!(cpu.bus.load(PLIC_PENDING, 32), Ok(0));
+    }
+
+    #[test]
+    fn test_uart_echo_interrupt() {
+        // Spin while a machine external interrupt handler echoes each received byte.
+        let code = rv_code(&[
+            0x0000006f, // j .
+            0x0c2002b7, // lui t0, 0x0c200 (trap handler)
+            0x0042a503, // lw a0, 4(t0)    claim
+            0x10000337, // lui t1, 0x10000
+            0x00034583, // lbu a1, 0(t1)
+            0x00b30023, // sb a1, 0(t1)
+            0x00a2a223, // sw a0, 4(t0)    complete
+            0x30200073, // mret
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.bus.store(PLIC_PRIORITY + UART_IRQ * 4, 32, 1).unwrap();
+        cpu.bus.store(PLIC_ENABLE, 32, 1 << UART_IRQ).unwrap();
+        cpu.bus.store(UART_BASE + UART_IER, 8, MASK_UART_IER_RX as u64).unwrap();
+        cpu.csr.store(MTVEC, DRAM_BASE + 4);
+        cpu.csr.store(MSTATUS, MASK_MIE);
+        cpu.csr.store(MIE, MASK_MEIP);
+        cpu.bus.uart.push_input(b"echo");
+
+        assert_eq!(cpu.run_steps(100), None);
+        assert_eq!(cpu.bus.uart.output(), b"echo");
+        assert_eq!(cpu.pc, DRAM_BASE);
+    }
+
+    #[test]
+    fn test_strict_reserved_shift() {
+        // slli a0, a0, 1 with imm[6] set, which is reserved.
+        let inst = 0x04151513;
+        let code = rv_code(&[
+            0x00500013, // addi x0, x0, 5
+            inst,
+        ]);
+
+        let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).strict(true).build();
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("zero"), 0);
+        assert_eq!(cpu.step(), Err(HaltReason::Fault(Exception::IllegalInstruction(inst as u64))));
+
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Ok(()));
+    }
+
+    #[test]
+    fn test_virtio_net_loopback() {
+        use crate::virtio_net::Loopback;
+
+        let mut cpu = CpuBuilder::new()
+            .headless_uart(true)
+            .net(Box::new(Loopback::new()))
+            .build();
+        let reg = |offset: u64| VIRTIO_NET_BASE + offset - VIRTIO_BASE;
+        let mmio = |cpu: &mut Cpu, offset: u64, value: u64| cpu.bus.store(reg(offset), 32, value).unwrap();
+
+        // One page per queue, with 8 descriptors and the used ring on the next page.
+        let rxq = DRAM_BASE + 0x10000;
+        let txq = DRAM_BASE + 0x20000;
+        let rx_buf = DRAM_BASE + 0x30000;
+        let tx_buf = DRAM_BASE + 0x40000;
+        assert_eq!(cpu.bus.load(reg(VIRTIO_DEVICE_ID), 32), Ok(1));
+        mmio(&mut cpu, VIRTIO_GUEST_PAGE_SIZE, PAGE_SIZE);
+        for (q, base) in [(VIRTIO_NET_RX, rxq), (VIRTIO_NET_TX, txq)] {
+            mmio(&mut cpu, VIRTIO_QUEUE_SEL, q as u64);
+            mmio(&mut cpu, VIRTIO_QUEUE_NUM, DESC_NUM as u64);
+            mmio(&mut cpu, VIRTIO_QUEUE_PFN, base / PAGE_SIZE);
+        }
+        mmio(&mut cpu, VIRTIO_STATUS, 0xf);
+
+        // Post a receive buffer: desc[0] is device-writable, avail.ring[0] = 0, avail.idx = 1.
+        cpu.bus.store(rxq, 64, rx_buf).unwrap();
+        cpu.bus.store(rxq + 8, 32, 1526).unwrap();
+        cpu.bus.store(rxq + 12, 16, VIRTQ_DESC_F_WRITE as u64).unwrap();
+        cpu.bus.store(rxq + 128 + 4, 16, 0).unwrap();
+        cpu.bus.store(rxq + 128 + 2, 16, 1).unwrap();
+
+        // Transmit a header and a frame from a single descriptor.
+        let frame = b"hello, net";
+        let len = VIRTIO_NET_HDR_SIZE + frame.len() as u64;
+        for (i, &b) in frame.iter().enumerate() {
+            cpu.bus.store(tx_buf + VIRTIO_NET_HDR_SIZE + i as u64, 8, b as u64).unwrap();
+        }
+        cpu.bus.store(txq, 64, tx_buf).unwrap();
+        cpu.bus.store(txq + 8, 32, len).unwrap();
+        cpu.bus.store(txq + 128 + 4, 16, 0).unwrap();
+        cpu.bus.store(txq + 128 + 2, 16, 1).unwrap();
+        mmio(&mut cpu, VIRTIO_QUEUE_NOTIFY, VIRTIO_NET_TX as u64);
+
+        cpu.check_pending_interrupt();
+
+        // The transmit buffer came back used, and the frame arrived in the receive buffer.
+        assert_eq!(cpu.bus.load(txq + PAGE_SIZE + 2, 16), Ok(1));
+        assert_eq!(cpu.bus.load(rxq + PAGE_SIZE + 2, 16), Ok(1));
+        assert_eq!(cpu.bus.load(rxq + PAGE_SIZE + 4, 32), Ok(0));
+        assert_eq!(cpu.bus.load(rxq + PAGE_SIZE + 8, 32), Ok(len));
+        let received: Vec<u8> = (0..frame.len() as u64)
+            .map(|i| cpu.bus.load(rx_buf + VIRTIO_NET_HDR_SIZE + i, 8).unwrap() as u8)
+            .collect();
+        assert_eq!(received, frame);
+        assert_eq!(cpu.bus.load(reg(VIRTIO_INTERRUPT_STATUS), 32), Ok(1));
+        assert!(cpu.bus.plic.is_pending(VIRTIO_NET_IRQ));
+    }
+
+    #[test]
+    fn test_virtio_blk_read_write() {
+        let disk: Vec<u8> = (0..4 * SECTOR_SIZE).map(|i| (i % 251) as u8).collect();
+        let mut cpu = CpuBuilder::new().headless_uart(true).disk(disk.clone()).build();
+        let mmio = |cpu: &mut Cpu, reg: u64, value: u64| cpu.bus.store(reg, 32, value).unwrap();
+
+        // Eight descriptors and the used ring on the next page.
+        let queue = DRAM_BASE + 0x10000;
+        let header = DRAM_BASE + 0x20000;
+        let data = DRAM_BASE + 0x30000;
+        let status = DRAM_BASE + 0x40000;
+        assert_eq!(cpu.bus.load(VIRTIO_DEVICE_ID, 32), Ok(2));
+        mmio(&mut cpu, VIRTIO_GUEST_PAGE_SIZE, PAGE_SIZE);
+        mmio(&mut cpu, VIRTIO_QUEUE_SEL, 0);
+        mmio(&mut cpu, VIRTIO_QUEUE_NUM, DESC_NUM as u64);
+        mmio(&mut cpu, VIRTIO_QUEUE_PFN, queue / PAGE_SIZE);
+        mmio(&mut cpu, VIRTIO_STATUS, 0xf);
+
+        // Submit a chain of header, data and status, and return what the device wrote.
+        let request = |cpu: &mut Cpu, iotype: u32, sector: u64, n: u64| {
+            cpu.bus.store(header, 32, iotype as u64).unwrap();
+            cpu.bus.store(header + 8, 64, sector).unwrap();
+            let descs = [
+                (header, 16, VIRTQ_DESC_F_NEXT),
+                (data, SECTOR_SIZE, VIRTQ_DESC_F_NEXT | if iotype == VIRTIO_BLK_T_IN { VIRTQ_DESC_F_WRITE } else { 0 }),
+                (status, 1, VIRTQ_DESC_F_WRITE),
+            ];
+            for (i, (addr, len, flags)) in descs.into_iter().enumerate() {
+                let desc = queue + 16 * i as u64;
+                cpu.bus.store(desc, 64, addr).unwrap();
+                cpu.bus.store(desc + 8, 32, len).unwrap();
+                cpu.bus.store(desc + 12, 16, flags as u64).unwrap();
+                cpu.bus.store(desc + 14, 16, i as u64 + 1).unwrap();
+            }
+            cpu.bus.store(queue + 128 + 4 + 2 * (n % 8), 16, 0).unwrap();
+            cpu.bus.store(queue + 128 + 2, 16, n + 1).unwrap();
+            mmio(cpu, VIRTIO_QUEUE_NOTIFY, 0);
+            cpu.check_pending_interrupt();
+            // The used ring has the chain and how much the device wrote to it.
+            assert_eq!(cpu.bus.load(queue + PAGE_SIZE + 2, 16), Ok(n + 1));
+            assert_eq!(cpu.bus.load(queue + PAGE_SIZE + 4 + 8 * (n % 8), 32), Ok(0));
+            let written = cpu.bus.load(queue + PAGE_SIZE + 8 + 8 * (n % 8), 32).unwrap();
+            (cpu.bus.load(status, 8).unwrap(), written)
+        };
+
+        assert_eq!(request(&mut cpu, VIRTIO_BLK_T_IN, 1, 0), (VIRTIO_BLK_S_OK, SECTOR_SIZE + 1));
+        let read: Vec<u8> = (0..SECTOR_SIZE).map(|i| cpu.bus.load(data + i, 8).unwrap() as u8).collect();
+        assert_eq!(read, &disk[SECTOR_SIZE as usize..2 * SECTOR_SIZE as usize]);
+        assert!(cpu.bus.plic.is_pending(VIRTIO_IRQ));
+        assert_eq!(cpu.bus.load(VIRTIO_INTERRUPT_STATUS, 32), Ok(1));
+        mmio(&mut cpu, VIRTIO_INTERRUPT_ACK, 1);
+        assert_eq!(cpu.bus.load(VIRTIO_INTERRUPT_STATUS, 32), Ok(0));
+
+        // Write the sector back to sector 3.
+        assert_eq!(request(&mut cpu, VIRTIO_BLK_T_OUT, 3, 1), (VIRTIO_BLK_S_OK, 1));
+        let disk = cpu.bus.virtio_blk.disk();
+        assert_eq!(disk[3 * SECTOR_SIZE as usize..], disk[SECTOR_SIZE as usize..2 * SECTOR_SIZE as usize]);
+
+        // Past the end of the disk, and an unknown request.
+        assert_eq!(request(&mut cpu, VIRTIO_BLK_T_IN, 4, 2), (VIRTIO_BLK_S_IOERR, 1));
+        assert_eq!(request(&mut cpu, 8, 0, 3), (VIRTIO_BLK_S_UNSUPP, 1));
+    }
+
+    #[test]
+    fn test_exec_bytes() {
+        let mut cpu = CpuBuilder::new().headless_uart(true).build();
+        // addi a0, zero, 5
+        cpu.exec_bytes(&0x00500513u32.to_le_bytes()).unwrap();
+        assert_eq!(cpu.reg("a0"), 5);
+        assert_eq!(cpu.pc, DRAM_BASE + 4);
+
+        // j 0 never leaves the fragment, the limit hands control back.
+        cpu.exec_bytes(&0x0000006fu32.to_le_bytes()).unwrap();
+        assert_eq!(cpu.pc, DRAM_BASE + 4);
+    }
+
+    #[test]
+    fn test_fence() {
+        let code = rv_code(&[
+            0x00000297, // auipc t0, 0
+            0x10028293, // addi t0, t0, 256
+            0x00700313, // li t1, 7
+            0x0062a023, // sw t1, 0(t0)
+            0x0ff0000f, // fence
+            0x0062a223, // sw t1, 4(t0)
+            0x0210000f, // fence r, w
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        assert_eq!(cpu.run(), HaltReason::Exit(0));
+        assert_eq!(cpu.load(DRAM_BASE + 0x100, 64).unwrap(), 7 << 32 | 7);
+    }
+
+    #[test]
+    fn test_fence_i() {
+        let code = rv_code(&[
+            0x00000297, // auipc t0, 0
+            0x0302a303, // lw t1, 48(t0)
+            0x020000ef, // jal ra, 32
+            0x0262a423, // sw t1, 40(t0)
+            0x018000ef, // jal ra, 24
+            0x00050593, // mv a1, a0
+            0x0000100f, // fence.i
+            0x00c000ef, // jal ra, 12
+            0x00100073, // ebreak
+            0x00000013, // nop
+            0x00150513, // addi a0, a0, 1
+            0x00008067, // ret
+            0x06450513, // addi a0, a0, 100
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+
+        // The store drops the cached `addi a0, a0, 1`, so the patched instruction runs
+        // already in the call before fence.i.
+        assert_eq!(cpu.run(), HaltReason::Exit(201));
+        assert_eq!(cpu.reg("a1"), 101);
+
+        // Code a device writes is only seen after fence.i.
+        let code = rv_code(&[
+            0x0000100f, // fence.i
+            0x00150513, // addi a0, a0, 1
+            0xffdff06f, // j -4
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        assert_eq!(cpu.run_steps(3), None);
+        cpu.bus.store(DRAM_BASE + 4, 32, 0x06450513).unwrap(); // addi a0, a0, 100
+        assert_eq!(cpu.run_steps(2), None);
+        assert_eq!(cpu.reg("a0"), 2);
+        cpu.pc = DRAM_BASE;
+        assert_eq!(cpu.run_steps(2), None);
+        assert_eq!(cpu.reg("a0"), 102);
+
+        // Code written where nothing ran yet, here two compressed instructions in place
+        // of a 32-bit one.
+        let code = rv_code(&[
+            0x00000297, // auipc t0, 0
+            0x05150337, // lui t1, 0x5150
+            0x51530313, // addi t1, t1, 0x515
+            0x0062ae23, // sw t1, 28(t0)
+            0x0000100f, // fence.i
+            0x008000ef, // jal ra, 8
+            0x00100073, // ebreak
+            0x00000013, // nop, becomes c.addi a0, 5; c.addi a0, 5
+            0x00008067, // ret
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        assert_eq!(cpu.run(), HaltReason::Exit(10));
+
+        // Without the decode cache nothing is cached, a device write is seen at once.
+        let code = rv_code(&[
+            0x00150513, // addi a0, a0, 1
+            0xffdff06f, // j -4
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).decode_cache(false).build();
+        assert_eq!(cpu.run_steps(2), None);
+        assert_eq!(cpu.icache.get(DRAM_BASE), None);
+        cpu.bus.store(DRAM_BASE, 32, 0x06450513).unwrap(); // addi a0, a0, 100
+        assert_eq!(cpu.run_steps(1), None);
+        assert_eq!(cpu.reg("a0"), 101);
+    }
+
+    #[test]
+    fn test_mmio_latency() {
+        let poll = |base| {
+            let code = rv_code(&[
+                base,       // lui t0, 0x10000 (uart) or auipc t0, 0 (dram)
+                0x06400393, // li t2, 100
+                0x0052c303, // lbu t1, 5(t0)
+                0xfff38393, // addi t2, t2, -1
+                0xfe039ce3, // bnez t2, -8
+                0xb0002573, // csrr a0, mcycle
+                0x00100073, // ebreak
+            ]);
+            let mut cpu = CpuBuilder::new()
+                .code(code)
+                .headless_uart(true)
+                .exit_on_ebreak(true)
+                .dram_latency(1)
+                .mmio_latency(10)
+                .build();
+            match cpu.run() {
+                HaltReason::Exit(cycles) => cycles,
+                reason => panic!("unexpected halt {:?}", reason),
+            }
+        };
+
+        let uart = poll(0x100002b7);
+        let dram = poll(0x00000297);
+        // 303 instructions up to the csrr, plus 100 loads from the uart (LSR) or from dram.
+        assert_eq!(dram, 303 + 100);
+        assert_eq!(uart, 303 + 100 * 10);
+    }
+
+    #[test]
+    fn test_csr_privilege() {
+        let mut cpu = CpuBuilder::new().headless_uart(true).build();
+        let illegal = |inst: u32| Err(Exception::IllegalInstruction(inst as u64));
+
+        // An M-mode csr is off limits below M-mode, an S-mode csr only to U-mode.
+        cpu.mode = User;
+        assert_eq!(cpu.execute(0x30051073), illegal(0x30051073)); // csrw mstatus, a0
+        assert_eq!(cpu.execute(0x10002573), illegal(0x10002573)); // csrr a0, sstatus
+        cpu.mode = Supervisor;
+        assert_eq!(cpu.execute(0x30002573), illegal(0x30002573)); // csrr a0, mstatus
+        assert!(cpu.execute(0x10002573).is_ok());
+
+        // mhartid is read-only: reading it, also with csrsi 0, is fine, writing it is not.
+        cpu.mode = Machine;
+        assert_eq!(cpu.execute(0xf1451073), illegal(0xf1451073)); // csrw mhartid, a0
+        assert_eq!(cpu.execute(0xf140e073), illegal(0xf140e073)); // csrsi mhartid, 1
+        assert!(cpu.execute(0xf1406073).is_ok()); // csrsi mhartid, 0
+        assert!(cpu.execute(0xf1402573).is_ok()); // csrr a0, mhartid
+    }
+
+    #[test]
+    fn test_csr_views() {
+        let code = rv_code(&[
+            0x100022f3, // csrr t0, sstatus
+            0x10402373, // csrr t1, sie
+            0xc01023f3, // csrr t2, time
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        // MIE and MPIE only exist in mstatus.
+        cpu.csr.store(MSTATUS, MASK_MIE | MASK_MPIE | MASK_SIE);
+        // Only delegated interrupts show up in sie.
+        cpu.csr.store(MIE, MASK_MTIP | MASK_STIP);
+        cpu.csr.store(MIDELEG, MASK_STIP);
+        cpu.bus.store(CLINT_MTIME, 64, 1234).unwrap();
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        // UXL says U-mode is 64-bit.
+        assert_eq!(cpu.reg("t0"), MASK_SIE | 2 << 32);
+        assert_eq!(cpu.reg("t1"), MASK_STIP);
+        // mtime advanced once for each of the two instructions before.
+        assert_eq!(cpu.reg("t2"), 1236);
+
+        // A write through sip only changes SSIP, and only when it is delegated.
+        cpu.csr.store(MIP, MASK_MTIP);
+        cpu.csr.store(SIP, MASK_SSIP | MASK_STIP | MASK_MTIP);
+        assert_eq!(cpu.csr.load(MIP), MASK_MTIP);
+        cpu.csr.store(MIDELEG, MASK_SSIP | MASK_STIP);
+        cpu.csr.store(SIP, MASK_SSIP | MASK_STIP | MASK_MTIP);
+        assert_eq!(cpu.csr.load(MIP), MASK_MTIP | MASK_SSIP);
+        assert_eq!(cpu.csr.load(SIP), MASK_SSIP);
+
+        // sie is the delegated part of mie, written back only there.
+        cpu.csr.store(MIE, MASK_MEIP | MASK_SEIP | MASK_SSIP);
+        assert_eq!(cpu.csr.load(SIE), MASK_SSIP);
+        cpu.csr.store(SIE, MASK_STIP);
+        assert_eq!(cpu.csr.load(MIE), MASK_MEIP | MASK_SEIP | MASK_STIP);
+    }
+
+    #[test]
+    fn test_mstatus_fields() {
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+        let xl64 = 2 << 32 | 2 << 34;
+        // Only the S-level fields are written through sstatus. Of those in the low 32
+        // bits, XS is read-only 0, and SD follows FS.
+        cpu.csr.store(SSTATUS, 0xffff_ffff);
+        let sstatus = MASK_SIE | MASK_SPIE | MASK_UBE | MASK_SPP | MASK_FS | MASK_SUM | MASK_MXR;
+        assert_eq!(cpu.csr.load(SSTATUS), sstatus | MASK_SD | 2 << 32);
+        assert_eq!(cpu.csr.load(MSTATUS), sstatus | MASK_SD | xl64);
+
+        // Reserved bits, VS, UXL and SXL don't change.
+        cpu.csr.store(MSTATUS, !MASK_FS);
+        let mstatus = MASK_SIE | MASK_MIE | MASK_SPIE | MASK_UBE | MASK_MPIE | MASK_SPP | MASK_MPP | MASK_MPRV
+            | MASK_SUM | MASK_MXR | MASK_TVM | MASK_TW | MASK_TSR | MASK_SBE | MASK_MBE;
+        assert_eq!(cpu.csr.load(MSTATUS), mstatus | xl64);
+
+        // MPP keeps its mode when written the reserved 0b10.
+        cpu.csr.store(MSTATUS, 0b01 << 11);
+        cpu.csr.store(MSTATUS, 0b10 << 11);
+        assert_eq!(cpu.csr.load(MSTATUS), 0b01 << 11 | xl64);
+    }
+
+    #[test]
+    fn test_mret_mprv() {
+        // mret keeps MPRV when it returns to M-mode and clears it otherwise.
+        let mut cpu = CpuBuilder::new().headless_uart(true).build();
+        cpu.csr.store(MSTATUS, MASK_MPRV | MASK_MPP);
+        cpu.execute(0x30200073).unwrap(); // mret
+        assert_eq!((cpu.mode, cpu.csr.load(MSTATUS) & MASK_MPRV), (Machine, MASK_MPRV));
+        cpu.csr.store(MSTATUS, MASK_MPRV | 0b01 << 11);
+        cpu.execute(0x30200073).unwrap(); // mret
+        assert_eq!((cpu.mode, cpu.csr.load(MSTATUS) & MASK_MPRV), (Supervisor, 0));
+    }
+
+    #[test]
+    fn test_endianness() {
+        let code = rv_code(&[
+            0x00000517, // auipc a0, 0
+            0x112232b7, // lui t0, 0x11223
+            0x3442829b, // addiw t0, t0, 0x344
+            0x10552023, // sw t0, 0x100(a0)
+            0x10054303, // lbu t1, 0x100(a0)
+            0x10354383, // lbu t2, 0x103(a0)
+            0x10052e03, // lw t3, 0x100(a0)
+            0x10151e83, // lh t4, 0x101(a0) (misaligned)
+        ]);
+        let run = |mode: u64, mstatus: u64| {
+            let mut cpu = CpuBuilder::new()
+                .code(code.clone())
+                .headless_uart(true)
+                .misaligned(MisalignPolicy::Emulate)
+                .build();
+            cpu.mode = mode;
+            cpu.csr.store(MSTATUS, mstatus);
+            for _ in 0..8 {
+                cpu.step().unwrap();
+            }
+            let bytes = cpu.bus.dram()[0x100..0x104].to_vec();
+            (bytes, [cpu.reg("t1"), cpu.reg("t2"), cpu.reg("t3"), cpu.reg("t4")])
+        };
+
+        let (bytes, regs) = run(Machine, 0);
+        assert_eq!(bytes, [0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(regs, [0x44, 0x11, 0x11223344, 0x2233]);
+
+        // Instructions are still fetched little-endian.
+        let (bytes, regs) = run(Machine, MASK_MBE);
+        assert_eq!(bytes, [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(regs, [0x11, 0x44, 0x11223344, 0x2233]);
+
+        // Each mode has its own bit.
+        assert_eq!(run(Supervisor, MASK_MBE | MASK_UBE).0, [0x44, 0x33, 0x22, 0x11]);
+        assert_eq!(run(Supervisor, MASK_SBE).0, [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(run(User, MASK_UBE).0, [0x11, 0x22, 0x33, 0x44]);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let code = rv_code(&[0x00000013, 0x00000013]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        // Vectored: the interrupt with cause 7 jumps to BASE + 7 * 4.
+        cpu.csr.store(MTVEC, (DRAM_BASE + 0x100) | 1);
+        cpu.csr.store(MSTATUS, MASK_MIE);
+        cpu.csr.store(MIE, MASK_MTIP);
+        cpu.bus.store(CLINT_MTIMECMP, 64, 0).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, DRAM_BASE + 0x100 + 7 * 4);
+        assert_eq!(cpu.csr.load(MCAUSE), MASK_INTERRUPT_BIT | 7);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 4);
+
+        // S-mode with SIE clear still takes an interrupt for M-mode.
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+        cpu.mode = Supervisor;
+        cpu.csr.store(MIE, MASK_MTIP);
+        cpu.bus.store(CLINT_MTIMECMP, 64, 0).unwrap();
+        cpu.step().unwrap();
+        assert_eq!((cpu.mode, cpu.csr.load(MCAUSE)), (Machine, MASK_INTERRUPT_BIT | 7));
+
+        // M-mode never takes an interrupt delegated to S-mode.
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+        cpu.csr.store(MSTATUS, MASK_MIE | MASK_SIE);
+        cpu.csr.store(MIDELEG, MASK_STIP);
+        cpu.csr.store(MIE, MASK_STIP);
+        cpu.csr.store(MIP, MASK_STIP);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, DRAM_BASE + 4);
+    }
+
+    #[test]
+    fn test_interrupt_delegation() {
+        // A delegated supervisor timer interrupt traps to S-mode, from U-mode even with
+        // SIE clear.
+        for (mode, mstatus) in [(Supervisor, MASK_SIE), (User, 0)] {
+            let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+            cpu.mode = mode;
+            cpu.csr.store(STVEC, DRAM_BASE + 0x100);
+            cpu.csr.store(MSTATUS, mstatus);
+            cpu.csr.store(MIDELEG, MASK_STIP);
+            cpu.csr.store(MIE, MASK_STIP);
+            cpu.csr.store(MIP, MASK_STIP);
+            cpu.step().unwrap();
+            assert_eq!((cpu.mode, cpu.pc), (Supervisor, DRAM_BASE + 0x100));
+            assert_eq!(cpu.csr.load(SCAUSE), MASK_INTERRUPT_BIT | 5);
+            assert_eq!(cpu.csr.load(SEPC), DRAM_BASE + 4);
+            assert_eq!(cpu.csr.load(MCAUSE), 0);
+            assert_eq!(cpu.csr.load(SSTATUS) & MASK_SPP, (mode & 1) << 8);
+        }
+
+        // S-mode with SIE clear doesn't take it.
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+        cpu.mode = Supervisor;
+        cpu.csr.store(MIDELEG, MASK_STIP);
+        cpu.csr.store(MIE, MASK_STIP);
+        cpu.csr.store(MIP, MASK_STIP);
+        cpu.step().unwrap();
+        assert_eq!((cpu.mode, cpu.pc), (Supervisor, DRAM_BASE + 4));
+
+        // An interrupt for M-mode goes first, even one of lower priority than a pending
+        // interrupt for S-mode.
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+        cpu.mode = Supervisor;
+        cpu.csr.store(MSTATUS, MASK_SIE);
+        cpu.csr.store(MIDELEG, MASK_SSIP);
+        cpu.csr.store(MIE, MASK_SSIP | MASK_STIP);
+        cpu.csr.store(MIP, MASK_SSIP | MASK_STIP);
+        cpu.step().unwrap();
+        assert_eq!((cpu.mode, cpu.csr.load(MCAUSE)), (Machine, MASK_INTERRUPT_BIT | 5));
+    }
+
+    #[test]
+    fn test_mtime_divisor() {
+        let code = rv_code(&[
+            0xc01022f3, // rdtime t0
+            0x01400393, // li t2, 20
+            0xfff38393, // addi t2, t2, -1
+            0xfe039ee3, // bnez t2, -4
+            0xc0102373, // rdtime t1
+            0x0200ceb7, // lui t4, 0x200c
+            0xff8ebe03, // ld t3, -8(t4) (mtime)
+            0xc0102f73, // rdtime t5
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).mtime_divisor(4).build();
+        for _ in 0..46 {
+            cpu.step().unwrap();
+        }
+        // 42 steps ran before the second rdtime, 44 before the load of mtime.
+        assert_eq!(cpu.reg("t0"), 0);
+        assert_eq!(cpu.reg("t1"), 10);
+        assert_eq!(cpu.reg("t3"), 11);
+        assert_eq!(cpu.reg("t5"), cpu.reg("t3"));
+        assert_eq!(cpu.bus.clint.mtime(), 11);
+    }
+
+    #[test]
+    fn test_clint_interrupts() {
+        // Arm the timer for mtime 20 and spin until it fires.
+        let code = rv_code(&[
+            0x020042b7, // lui t0, 0x2004
+            0x01400313, // li t1, 20
+            0x0062b023, // sd t1, 0(t0)
+            0x0000006f, // j .
+            0x34202573, // csrr a0, mcause (trap handler)
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        cpu.csr.store(MTVEC, DRAM_BASE + 16);
+        cpu.csr.store(MSTATUS, MASK_MIE);
+        cpu.csr.store(MIE, MASK_MTIP | MASK_MSIP);
+        assert_eq!(cpu.run(), HaltReason::Exit(7));
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 12);
+        assert_eq!(cpu.bus.load(CLINT_MTIME, 64), Ok(21));
+        // The halves of a 64-bit register are 32-bit registers of their own.
+        assert_eq!(cpu.bus.load(CLINT_MTIMECMP, 32), Ok(20));
+        assert_eq!(cpu.bus.load(CLINT_MTIMECMP + 4, 32), Ok(0));
+
+        // A write to msip raises the software interrupt.
+        let code = rv_code(&[
+            0x020002b7, // lui t0, 0x2000
+            0x00100313, // li t1, 1
+            0x0062a023, // sw t1, 0(t0)
+            0x0000006f, // j .
+            0x34202573, // csrr a0, mcause (trap handler)
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        cpu.csr.store(MTVEC, DRAM_BASE + 16);
+        cpu.csr.store(MSTATUS, MASK_MIE);
+        cpu.csr.store(MIE, MASK_MTIP | MASK_MSIP);
+        assert_eq!(cpu.run(), HaltReason::Exit(3));
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 12);
+        // msip is only 32 bits wide, and the timer of another hart is off.
+        assert_eq!(cpu.bus.load(CLINT_MSIP, 64), Err(Exception::LoadAccessFault(CLINT_MSIP)));
+        assert_eq!(cpu.bus.load(CLINT_MTIMECMP + 8, 64), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn test_wfi() {
+        let code = rv_code(&[
+            0x020042b7, // lui t0, 0x2004
+            0x03200313, // li t1, 50
+            0x0062b023, // sd t1, 0(t0)
+            0x10500073, // wfi
+            0x00100513, // li a0, 1
+            0x00100073, // ebreak
+            0x34202573, // csrr a0, mcause (trap handler)
+            0x00100073, // ebreak
+        ]);
+        let run = |mstatus: u64| {
+            let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).exit_on_ebreak(true).build();
+            cpu.csr.store(MTVEC, DRAM_BASE + 24);
+            cpu.csr.store(MSTATUS, mstatus);
+            cpu.csr.store(MIE, MASK_MTIP);
+            cpu.coverage = Some(vec![0; decode::INSTRUCTIONS.len()]);
+            let reason = cpu.run();
+            // Nothing ran while the hart waited for the timer.
+            let ran: u64 = cpu.coverage.as_ref().unwrap().iter().sum();
+            (reason, cpu.bus.load(CLINT_MTIME, 64).unwrap(), ran)
+        };
+
+        // The timer interrupt wakes the hart and is taken after the wfi.
+        assert_eq!(run(MASK_MIE), (HaltReason::Exit(7), 51, 6));
+        // Disabled globally, it still wakes the hart, which goes on after the wfi.
+        assert_eq!(run(0), (HaltReason::Exit(1), 51, 6));
+
+        // An interrupt taken right after the wfi, here one that taking it clears from
+        // mip, runs its handler without stalling first.
+        let code = rv_code(&[
+            0x10500073, // wfi
+            0x00100513, // li a0, 1
+            0x00100073, // ebreak
+            0x34202573, // csrr a0, mcause (trap handler)
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        cpu.csr.store(MTVEC, DRAM_BASE + 12);
+        cpu.csr.store(MSTATUS, MASK_MIE);
+        cpu.csr.store(MIE, MASK_SSIP);
+        cpu.csr.store(MIP, MASK_SSIP);
+        cpu.step().unwrap();
+        assert_eq!((cpu.pc, cpu.waiting), (DRAM_BASE + 12, false));
+        assert_eq!(cpu.run_steps(10), Some(HaltReason::Exit(1)));
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 4);
+
+        // With TW, wfi is illegal below M-mode.
+        let mut cpu = CpuBuilder::new().headless_uart(true).build();
+        cpu.mode = Supervisor;
+        cpu.csr.store(MSTATUS, MASK_TW);
+        assert_eq!(cpu.execute(0x10500073), Err(Exception::IllegalInstruction(0x10500073)));
+        cpu.mode = Machine;
+        assert!(cpu.execute(0x10500073).is_ok());
+        assert!(cpu.waiting);
+    }
+
+    #[test]
+    fn test_hartid() {
+        let code = rv_code(&[
+            0xf1402573, // csrr a0, mhartid
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).hartid(3).build();
+        assert_eq!(cpu.reg("mhartid"), 3);
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), 3);
+
+        // The guest can't change it.
+        assert!(cpu.execute(0x00700513).is_ok()); // li a0, 7
+        assert_eq!(cpu.execute(0xf1451073), Err(Exception::IllegalInstruction(0xf1451073))); // csrw mhartid, a0
+        assert_eq!(cpu.reg("mhartid"), 3);
+    }
+
+    #[test]
+    fn test_write_coverage() {
+        let code = rv_code(&[
+            0x00500513, // addi a0, zero, 5
+            0x00a50533, // add a0, a0, a0
+            0x00a50533, // add a0, a0, a0
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new()
+            .code(code)
+            .headless_uart(true)
+            .exit_on_ebreak(true)
+            .coverage(true)
+            .build();
+        assert_eq!(cpu.run(), HaltReason::Exit(20));
+
+        let path = std::env::temp_dir().join(format!("rvemu-coverage-{}.json", std::process::id()));
+        cpu.write_coverage(&path).unwrap();
+        let report = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Each instruction is on a line of its own: {"name": "add", "executed": true, "count": 2}
+        let field = |line: &str, key: &str| -> String {
+            let start = line.find(&format!("\"{}\": ", key)).unwrap() + key.len() + 4;
+            line[start..].split([',', '}']).next().unwrap().trim_matches('"').to_string()
+        };
+        let mut counts = std::collections::HashMap::new();
+        for line in report.lines().filter(|l| l.contains("\"name\"")) {
+            let executed = field(line, "executed") == "true";
+            let count: u64 = field(line, "count").parse().unwrap();
+            assert_eq!(executed, count > 0);
+            counts.insert(field(line, "name"), count);
+        }
+        assert_eq!(counts.len(), decode::INSTRUCTIONS.len());
+        assert_eq!(counts["addi"], 1);
+        assert_eq!(counts["add"], 2);
+        assert_eq!(counts["ebreak"], 1);
+        assert_eq!(counts["sub"], 0);
+        assert!(report.contains("\"executed\": 3,"));
+    }
+
+    #[test]
+    fn test_compressed_jr_jalr() {
+        let mut cpu = CpuBuilder::new().headless_uart(true).build();
+        let target = DRAM_BASE + 0x100;
+
+        // c.jr ra
+        cpu.regs[1] = target;
+        assert_eq!(cpu.execute(0x8082), Ok(target));
+        assert_eq!(cpu.reg("ra"), target);
+
+        // c.jalr ra links to the next halfword and jumps to the old ra.
+        assert_eq!(cpu.execute(0x9082), Ok(target));
+        assert_eq!(cpu.reg("ra"), DRAM_BASE + 2);
+
+        // c.jr x0 is reserved, and the same bits with bit 12 set are c.ebreak.
+        assert_eq!(cpu.execute(0x8002), Err(Exception::IllegalInstruction(0x8002)));
+        assert_eq!(cpu.execute(0x9002), Err(Exception::Breakpoint(DRAM_BASE)));
+
+        // c.mv a0, a1 and c.add a0, a1
+        cpu.regs[11] = 7;
+        assert_eq!(cpu.execute(0x852e), Ok(DRAM_BASE + 2));
+        assert_eq!(cpu.execute(0x952e), Ok(DRAM_BASE + 2));
+        assert_eq!(cpu.reg("a0"), 14);
+    }
+
+    #[test]
+    fn test_compressed_fetch() {
+        let mut code = rv_code(&[0x00500513]); // addi a0, zero, 5
+        code.extend_from_slice(&0x952au16.to_le_bytes()); // c.add a0, a0
+        code.extend_from_slice(&0x9002u16.to_le_bytes()); // c.ebreak
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        assert_eq!(cpu.run(), HaltReason::Exit(10));
+        assert_eq!(cpu.pc, DRAM_BASE + 6);
+
+        // A compressed instruction may be the last halfword of dram.
+        let mut code = vec![0; 0xffe];
+        code.extend_from_slice(&0x9002u16.to_le_bytes()); // c.ebreak
+        let mut cpu = CpuBuilder::new()
+            .code(code)
+            .headless_uart(true)
+            .exit_on_ebreak(true)
+            .dram_size(0x1000)
+            .entry_pc(DRAM_BASE + 0xffe)
+            .build();
+        assert_eq!(cpu.run(), HaltReason::Exit(0));
+    }
+
+    #[test]
+    fn test_device_memory_amo() {
+        let device = DRAM_BASE + 0x1000;
+        let mut cpu = CpuBuilder::new()
+            .headless_uart(true)
+            .check_mem_attrs(true)
+            .mem_attr(device, device + 0xfff, MemAttr::Device)
+            .build();
+        cpu.regs[5] = device; // t0
+
+        // lw t1, 0(t0) is a plain access, device memory allows it.
+        assert_eq!(cpu.execute(0x0002a303), Ok(DRAM_BASE + 4));
+        // amoadd.w t2, t1, (t0)
+        assert_eq!(cpu.execute(0x0062a3af), Err(Exception::StoreAMOAccessFault(device)));
+
+        // The rest of dram is still normal memory.
+        cpu.regs[5] = DRAM_BASE + 0x2000;
+        assert_eq!(cpu.execute(0x0062a3af), Ok(DRAM_BASE + 4));
+    }
+
+    #[test]
+    fn test_misaligned_mtval() {
+        let code = rv_code(&[
+            0x0035a503, // lw a0, 3(a1)
+            0x00a5a2a3, // sw a0, 5(a1)
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.csr.store(MTVEC, DRAM_BASE + 4);
+        cpu.regs[11] = DRAM_BASE + 0x1000;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.csr.load(MCAUSE), Exception::LoadAccessMisaligned(0).code());
+        assert_eq!(cpu.csr.load(MTVAL), DRAM_BASE + 0x1003);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.csr.load(MCAUSE), Exception::StoreAMOAddrMisaligned(0).code());
+        assert_eq!(cpu.csr.load(MTVAL), DRAM_BASE + 0x1005);
+    }
+
+    #[test]
+    fn test_misaligned_policy() {
+        let code = rv_code(&[
+            0x0035a503, // lw a0, 3(a1)
+            0x00a5a2a3, // sw a0, 5(a1)
+            0x0005a52f, // amoadd.w a0, zero, (a1)
+        ]);
+        let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).build();
+        cpu.regs[11] = DRAM_BASE + 0x1000;
+        cpu.bus.store(DRAM_BASE + 0x1000, 64, 0x8877_6655_4433_2211).unwrap();
+        assert_eq!(cpu.execute(0x0035a503), Err(Exception::LoadAccessMisaligned(DRAM_BASE + 0x1003)));
+
+        cpu.set_misaligned_policy(MisalignPolicy::Emulate);
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), 0x7766_5544);
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.load(DRAM_BASE + 0x1005, 32).unwrap(), 0x7766_5544);
+        assert_eq!(cpu.pc, DRAM_BASE + 8);
+        // An AMO still has to be aligned.
+        cpu.regs[11] = DRAM_BASE + 0x1001;
+        assert_eq!(cpu.execute(0x0005a52f), Err(Exception::StoreAMOAddrMisaligned(DRAM_BASE + 0x1001)));
+
+        // A word across a page boundary.
+        let mut cpu = CpuBuilder::new()
+            .code(code)
+            .headless_uart(true)
+            .misaligned(MisalignPolicy::Emulate)
+            .build();
+        cpu.regs[11] = DRAM_BASE + 0xffa;
+        cpu.bus.store(DRAM_BASE + 0xff8, 64, 0x8877_6655_4433_2211).unwrap();
+        cpu.bus.store(DRAM_BASE + 0x1000, 64, 0xffff_ffff_ffff_ffff).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), 0xffff_ffff_ff88_7766);
+    }
+
+    #[test]
+    fn test_tohost() {
+        let code = rv_code(&[
+            0x00000297, // auipc t0, 0
+            0x00200313, // li t1, 2
+            0x0262a023, // sw t1, 32(t0)
+            0x00700313, // li t1, 7
+            0x0262a023, // sw t1, 32(t0)
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).tohost(DRAM_BASE + 32).build();
+        // Even values are not exit codes.
+        assert_eq!(cpu.run_steps(3), None);
+        assert_eq!(cpu.run(), HaltReason::Exit(3));
+        assert_eq!(cpu.pc, DRAM_BASE + 20);
+    }
+
+    #[test]
+    fn test_halt_on_self_loop() {
+        let code = rv_code(&[
+            0x00100513, // li a0, 1
+            0x00000063, // beqz zero, 0
+        ]);
+        let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).halt_on_self_loop(true).build();
+        assert_eq!(cpu.run(), HaltReason::SelfLoop(DRAM_BASE + 4));
+        assert_eq!(cpu.pc, DRAM_BASE + 4);
+
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        assert_eq!(cpu.run_steps(10), None);
+    }
+
+    #[test]
+    fn test_custom_insn_handler() {
+        let code = rv_code(&[
+            0x00500513, // addi a0, zero, 5
+            0x0000050b, // custom-0 with rd = a0: negate a0
+            0x0000052b, // custom-1, not handled
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.set_custom_insn_handler(Box::new(|cpu, inst| {
+            if inst & 0x7f != 0x0b {
+                return None;
+            }
+            let rd = ((inst >> 7) & 0x1f) as usize;
+            cpu.regs[rd] = cpu.regs[rd].wrapping_neg();
+            Some(Ok(cpu.pc + 4))
+        }));
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), -5i64 as u64);
+        assert_eq!(cpu.pc, DRAM_BASE + 8);
+        assert_eq!(cpu.step(), Err(HaltReason::Fault(Exception::IllegalInstruction(0x52b))));
+    }
+
+    #[test]
+    fn test_pc_relative_base() {
+        let code = rv_code(&[
+            0x00000663, // beq zero, zero, 12
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00001517, // auipc a0, 1
+            0x008005ef, // jal a1, 8
+            0x00000013, // nop
+            0x00000617, // auipc a2, 0
+            0x00100073, // ebreak
+            0x00000697, // auipc a3, 0 (trap handler)
+            0x30200073, // mret
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        cpu.csr.store(MTVEC, DRAM_BASE + 32);
+        cpu.csr.store(MSTATUS, MASK_MIE);
+        cpu.csr.store(MIE, MASK_MTIP);
+
+        // The branch changes the pc, then a timer interrupt is taken right after the
+        // auipc at the branch target, before the jal.
+        cpu.step().unwrap();
+        cpu.bus.store(CLINT_MTIMECMP, 64, 0).unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, DRAM_BASE + 32);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 16);
+        cpu.bus.store(CLINT_MTIMECMP, 64, u64::MAX).unwrap();
+
+        assert_eq!(cpu.run(), HaltReason::Exit((DRAM_BASE + 12 + 0x1000) as i32));
+        // Every pc-relative result is based on the address of its own instruction.
+        assert_eq!(cpu.reg("a0"), DRAM_BASE + 12 + 0x1000);
+        assert_eq!(cpu.reg("a1"), DRAM_BASE + 20);
+        assert_eq!(cpu.reg("a2"), DRAM_BASE + 24);
+        assert_eq!(cpu.reg("a3"), DRAM_BASE + 32);
+    }
+
+    #[test]
+    fn test_load_to_zero() {
+        let mut cpu = CpuBuilder::new().headless_uart(true).build();
+
+        // ld x0, 0(a0) from an unmapped address still faults.
+        cpu.regs[10] = 0x1000;
+        assert_eq!(cpu.execute(0x00053003), Err(Exception::LoadAccessFault(0x1000)));
+
+        // lbu x0, 0(a0) from the UART RHR still consumes the byte.
+        cpu.bus.uart.push_input(b"ab");
+        cpu.regs[10] = UART_BASE + UART_RHR;
+        assert_eq!(cpu.execute(0x00054003), Ok(DRAM_BASE + 4));
+        assert_eq!(cpu.bus.load(UART_BASE + UART_RHR, 8), Ok(b'b' as u64));
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let run = |body| {
+            let code = rv_code(&[
+                0x001022b7, // lui t0, 0x102 (watchdog)
+                0x3e800393, // li t2, 1000
+                body,       // sw zero, 0(t0) (kick) or nop
+                0xfff38393, // addi t2, t2, -1
+                0xfe039ce3, // bnez t2, -8
+                0x00100073, // ebreak
+            ]);
+            let mut cpu = CpuBuilder::new()
+                .code(code)
+                .headless_uart(true)
+                .exit_on_ebreak(true)
+                .watchdog(100)
+                .build();
+            let reason = cpu.run();
+            (reason, cpu.csr.load(MCYCLE))
+        };
+
+        // Without kicks the watchdog expires after 100 instructions.
+        let (reason, cycles) = run(0x00000013);
+        assert_eq!(reason, HaltReason::WatchdogReset);
+        assert_eq!(cycles, 100);
+
+        // A kick every 3 instructions keeps it from expiring.
+        let (reason, _) = run(0x0002a023);
+        assert_eq!(reason, HaltReason::Exit(0));
+    }
+
+    #[test]
+    fn test_watchdog_fetch_fault_loop() {
+        // stvec is on an unmapped page, so every fetch page faults back to it. The
+        // watchdog still counts the steps and expires.
+        let (mut cpu, _) = sv39(PTE_X);
+        cpu.bus.watchdog = Some(Watchdog::new(100));
+        cpu.csr.store(MEDELEG, 1 << 12);
+        cpu.csr.store(STVEC, 0x4000_2000);
+        cpu.pc = 0x4000_2000;
+        assert_eq!(cpu.run_steps(1000), Some(HaltReason::WatchdogReset));
+        assert_eq!(cpu.csr.load(SCAUSE), 12);
+        assert_eq!(cpu.csr.load(MCYCLE), 100);
+    }
+
+    #[test]
+    fn test_finisher() {
+        let cpu = |value: [u32; 2], finisher| {
+            let code = rv_code(&[
+                0x001002b7, // lui t0, 0x100 (test finisher)
+                value[0],   // li t1, value
+                value[1],
+                0x0062a023, // sw t1, 0(t0)
+            ]);
+            let builder = CpuBuilder::new().code(code).headless_uart(true);
+            match finisher {
+                true => builder.test_finisher(TEST_FINISHER_BASE).build(),
+                false => builder.build(),
+            }
+        };
+        let pass = [0x00005337, 0x55530313]; // 0x5555
+        let fail = [0x00033337, 0x33330313]; // 0x3333 | 3 << 16
+        let reset = [0x00007337, 0x77730313]; // 0x7777
+        let other = [0x00001337, 0x2343031b]; // 0x1234
+
+        // The halt comes once the store has completed.
+        let mut pass = cpu(pass, true);
+        assert_eq!(pass.run_steps(4), Some(HaltReason::Exit(0)));
+        assert_eq!(pass.pc, DRAM_BASE + 16);
+        assert_eq!(cpu(fail, true).run_steps(4), Some(HaltReason::Exit(3)));
+        assert_eq!(cpu(reset, true).run_steps(4), Some(HaltReason::Reset));
+        let mut other = cpu(other, true);
+        assert_eq!(other.run_steps(4), None);
+        assert_eq!(other.bus.load(TEST_FINISHER_BASE, 32), Ok(0));
+
+        // Without a finisher the address is unmapped.
+        let fault = HaltReason::Fault(Exception::StoreAMOAccessFault(TEST_FINISHER_BASE));
+        assert_eq!(cpu([0x00005337, 0x55530313], false).run_steps(4), Some(fault));
+    }
+
+    #[test]
+    fn test_divw_remw_edge_cases() {
+        const DIVW: u64 = 0x02b5463b; // divw a2, a0, a1
+        const REMW: u64 = 0x02b5663b; // remw a2, a0, a1
+        let mut cpu = CpuBuilder::new().headless_uart(true).build();
+        let mut run = |inst, a0: u64, a1: u64| {
+            cpu.regs[10] = a0;
+            cpu.regs[11] = a1;
+            cpu.execute(inst).unwrap();
+            cpu.reg("a2")
+        };
+        // The upper 32 bits of the operands are ignored.
+        let int_min = 0xdead_beef_8000_0000;
+        let minus_one = 0x0000_0000_ffff_ffff;
+
+        assert_eq!(run(DIVW, int_min, minus_one), i32::MIN as i64 as u64);
+        assert_eq!(run(REMW, int_min, minus_one), 0);
+        assert_eq!(run(DIVW, int_min, 0xffff_ffff_0000_0000), u64::MAX);
+        assert_eq!(run(REMW, int_min, 0xffff_ffff_0000_0000), i32::MIN as i64 as u64);
+        assert_eq!(run(DIVW, 7, -2i64 as u64), -3i64 as u64);
+        assert_eq!(run(REMW, -7i64 as u64, 2), -1i64 as u64);
+    }
+
+    #[test]
+    fn test_trace_csv() {
+        use crate::trace::Csv;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        #[derive(Clone, Default)]
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+        impl io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Shared::default();
+        let mut cpu = CpuBuilder::new()
+            .code(rv_code(&[0x00500513])) // li a0, 5
+            .headless_uart(true)
+            .build();
+        cpu.set_trace_formatter(Box::new(Csv::new()));
+        cpu.trace_to(Some(Box::new(out.clone())));
+        cpu.step().unwrap();
+
+        let text = String::from_utf8(out.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "pc,inst,name,mode,rd,value");
+        let fields: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(fields, ["0x80000000", "0x00500513", "addi", "M", "a0", "0x5"]);
+    }
+
+    #[test]
+    fn test_trace_debug_log() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Shared::default();
+        let writer = out.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let mut cpu = CpuBuilder::new()
+                .code(rv_code(&[0x00500513])) // li a0, 5
+                .headless_uart(true)
+                .trace(true)
+                .build();
+            cpu.step().unwrap();
+        });
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let line = text.lines().find(|line| line.contains(" trace: ")).unwrap();
+        assert!(line.contains("DEBUG"));
+        assert!(line.ends_with("M 0x0000000080000000 00500513 addi       a0 = 0x5"));
+    }
+
+    #[test]
+    fn test_mmio_trace_log() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Shared::default();
+        let writer = out.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let mut cpu = CpuBuilder::new().code(rv_code(&[])).headless_uart(true).build();
+            // Only the low byte is stored.
+            cpu.bus.store(UART_BASE + UART_IER, 8, 0x100 | MASK_UART_IER_RX as u64).unwrap();
+            cpu.bus.load(PLIC_BASE + 4 * UART_IRQ, 32).unwrap();
+            cpu.bus.store(DRAM_BASE + 0x100, 64, 1).unwrap();
+            cpu.bus.load(DRAM_BASE + 0x100, 64).unwrap();
+            // Past the end of the UART.
+            cpu.bus.load(UART_END, 16).unwrap_err();
+            // Not a device.
+            cpu.bus.load(0, 32).unwrap_err();
+        });
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().filter(|line| line.contains("rusty_riscv_ave::bus")).collect();
+        assert_eq!(lines.len(), 3, "{}", text);
+        assert!(lines.iter().all(|line| line.contains("TRACE")));
+        assert!(lines[0].ends_with("store uart+0x1 size 1 = 0x1"));
+        assert!(lines[1].ends_with(&format!("load plic+{:#x} size 4 = 0x0", 4 * UART_IRQ)));
+        assert!(lines[2].ends_with(&format!("load uart+{:#x} size 2 faulted: {}", UART_END - UART_BASE, Exception::LoadAccessFault(UART_END))));
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let code = rv_code(&[
+            0x00100513, // li a0, 1
+            0x00200593, // li a1, 2
+            0x00300613, // li a2, 3
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        cpu.add_breakpoint(DRAM_BASE + 8);
+
+        assert_eq!(cpu.run(), HaltReason::Breakpoint(DRAM_BASE + 8));
+        assert_eq!(cpu.pc, DRAM_BASE + 8);
+        assert_eq!(cpu.reg("a0"), 1);
+        assert_eq!(cpu.reg("a1"), 2);
+        assert_eq!(cpu.reg("a2"), 0);
+
+        // Resuming runs the instruction at the breakpoint.
+        assert_eq!(cpu.run(), HaltReason::Exit(1));
+        assert_eq!(cpu.reg("a2"), 3);
+
+        cpu.pc = DRAM_BASE;
+        cpu.remove_breakpoint(DRAM_BASE + 8);
+        assert_eq!(cpu.run(), HaltReason::Exit(1));
+    }
+
+    #[test]
+    fn test_compressed_ca() {
+        // (compressed, expanded, rd, rs2)
+        let cases: [(u64, u64, &str, &str); 2] = [
+            (0x8c05, 0x40940433, "s0", "s1"), // c.sub s0, s1 / sub s0, s0, s1
+            (0x8cf9, 0x00e4f4b3, "s1", "a4"), // c.and s1, a4 / and s1, s1, a4
+        ];
+        for (compressed, expanded, rd, rs2) in cases {
+            for (a, b) in [(10u64, 3u64), (0x0f0f, u64::MAX - 1)] {
+                let run = |inst: u64| {
+                    let mut cpu = CpuBuilder::new().headless_uart(true).build();
+                    let index = |name| RVABI.iter().position(|&r| r == name).unwrap();
+                    let (rd, rs2) = (index(rd), index(rs2));
+                    cpu.regs[rd] = a;
+                    cpu.regs[rs2] = b;
+                    let next = cpu.execute(inst).unwrap();
+                    (cpu.regs[rd], next - DRAM_BASE)
+                };
+                let (c_value, c_len) = run(compressed);
+                let (value, len) = run(expanded);
+                assert_eq!(c_value, value, "{:#x} with {:#x}, {:#x}", compressed, a, b);
+                assert_eq!((c_len, len), (2, 4));
+            }
+        }
+    }
+
+    #[test]
+    fn test_decompress() {
+        // (compressed, expanded), encoded by llvm-mc with and without +c.
+        let cases = [
+            (0x0808, 0x01010513), // addi a0, sp, 16
+            (0x414c, 0x00452583), // lw a1, 4(a0)
+            (0x650c, 0x00853583), // ld a1, 8(a0)
+            (0xc14c, 0x00b52223), // sw a1, 4(a0)
+            (0xe50c, 0x00b53423), // sd a1, 8(a0)
+            (0x0001, 0x00000013), // nop
+            (0x1575, 0xffd50513), // addi a0, a0, -3
+            (0x2505, 0x0015051b), // addiw a0, a0, 1
+            (0x556d, 0xffb00513), // li a0, -5
+            (0x7139, 0xfc010113), // addi sp, sp, -64
+            (0x757d, 0xfffff537), // lui a0, 0xfffff
+            (0x6585, 0x000015b7), // lui a1, 1
+            (0x9105, 0x02155513), // srli a0, a0, 33
+            (0x8505, 0x40155513), // srai a0, a0, 1
+            (0x9979, 0xffe57513), // andi a0, a0, -2
+            (0x8c05, 0x40940433), // sub s0, s0, s1
+            (0xbfe5, 0xff9ff06f), // j -8
+            (0xc119, 0x00050363), // beqz a0, 6
+            (0xfd75, 0xfe051ee3), // bnez a0, -4
+            (0x1502, 0x02051513), // slli a0, a0, 32
+            (0x4512, 0x00412503), // lw a0, 4(sp)
+            (0x6522, 0x00813503), // ld a0, 8(sp)
+            (0xc22a, 0x00a12223), // sw a0, 4(sp)
+            (0xe42a, 0x00a13423), // sd a0, 8(sp)
+            (0x8082, 0x00008067), // jr ra
+            (0x9082, 0x000080e7), // jalr ra
+            (0x852e, 0x00b00533), // mv a0, a1
+            (0x952e, 0x00b50533), // add a0, a0, a1
+            (0x9002, 0x00100073), // ebreak
+        ];
+        for (compressed, expanded) in cases {
+            assert_eq!(decompress(compressed), Some(expanded), "{:#06x}", compressed);
+        }
+
+        // Reserved: all zeros, c.addi16sp and c.lui with a zero immediate, c.lwsp and c.jr
+        // of x0. c.fld needs the D extension.
+        for reserved in [0x0000, 0x6101, 0x6501, 0x4002, 0x8002, 0x2000] {
+            assert_eq!(decompress(reserved), None, "{:#06x}", reserved);
+        }
+    }
+
+    #[test]
+    fn test_compressed_branches() {
+        let mut cpu = CpuBuilder::new().headless_uart(true).build();
+        let pc = DRAM_BASE;
+
+        // c.j -8
+        assert_eq!(cpu.execute(0xbfe5), Ok(pc - 8));
+        // c.beqz a0, 4 is taken, so it ends up where the fallthrough of a 32-bit branch would.
+        assert_eq!(cpu.execute(0xc111), Ok(pc + 4));
+        // c.bnez a0, -4 falls through to the next halfword.
+        assert_eq!(cpu.execute(0xfd75), Ok(pc + 2));
+        cpu.regs[10] = 1;
+        assert_eq!(cpu.execute(0xc111), Ok(pc + 2));
+        assert_eq!(cpu.execute(0xfd75), Ok(pc - 4));
+    }
+
+    #[test]
+    fn test_compressed_program() {
+        let mut code = Vec::new();
+        for half in [
+            0x1141u16, // addi sp, sp, -16
+            0x4529,    // li a0, 10
+            0x4581,    // li a1, 0
+            0x95aa,    // add a1, a1, a0 (loop)
+            0x157d,    // addi a0, a0, -1
+            0xfd75,    // bnez a0, -4
+            0xe42e,    // sd a1, 8(sp)
+            0x6522,    // ld a0, 8(sp)
+            0x0141,    // addi sp, sp, 16
+            0x9002,    // ebreak
+        ] {
+            code.extend_from_slice(&half.to_le_bytes());
+        }
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        assert_eq!(cpu.run(), HaltReason::Exit(55));
+        assert_eq!(cpu.pc, DRAM_BASE + 18);
+        assert_eq!(cpu.reg("sp"), DRAM_BASE + DRAM_SIZE);
+    }
+
+    #[test]
+    fn test_trap_return_halfword() {
+        // An ecall at a pc ending in 2, whose handler returns past it with mret or sret.
+        let mut code = 0x0001u16.to_le_bytes().to_vec(); // c.nop
+        code.extend(rv_code(&[
+            0x00000073, // ecall
+            0x02a00513, // li a0, 42
+            0x00100073, // ebreak
+        ]));
+        code.extend(0x0001u16.to_le_bytes()); // c.nop
+        code.extend(rv_code(&[
+            0x341022f3, // csrr t0, mepc (M-mode handler)
+            0x00428293, // addi t0, t0, 4
+            0x34129073, // csrw mepc, t0
+            0x30200073, // mret
+            0x141022f3, // csrr t0, sepc (S-mode handler)
+            0x00428293, // addi t0, t0, 4
+            0x14129073, // csrw sepc, t0
+            0x10200073, // sret
+        ]));
+
+        let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).exit_on_ebreak(true).build();
+        cpu.csr.store(MTVEC, DRAM_BASE + 16);
+        assert_eq!(cpu.run(), HaltReason::Exit(42));
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 6);
+
+        let mut cpu = CpuBuilder::new()
+            .code(code)
+            .headless_uart(true)
+            .exit_on_ebreak(true)
+            .initial_mode(Supervisor)
+            .build();
+        cpu.csr.store(MEDELEG, 1 << 9);
+        cpu.csr.store(STVEC, DRAM_BASE + 32);
+        assert_eq!(cpu.run(), HaltReason::Exit(42));
+        assert_eq!((cpu.csr.load(SEPC), cpu.mode), (DRAM_BASE + 6, Supervisor));
+    }
+
+    #[test]
+    fn test_null_guard() {
+        let code = rv_code(&[
+            0x00000013, // nop
+            0x00003503, // ld a0, 0(zero)
+        ]);
+        let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).null_guard(true).build();
+        assert_eq!(cpu.run(), HaltReason::NullDeref { addr: 0, pc: DRAM_BASE + 4 });
+
+        // Without the guard it is an ordinary access fault.
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        assert_eq!(cpu.run(), HaltReason::Fault(Exception::LoadAccessFault(0)));
+    }
+
+    #[test]
+    fn test_break_on_exception() {
+        let code = rv_code(&[
+            0x00000073, // ecall
+            0x00000000, // illegal
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).break_on_exception(vec![2]).build();
+        cpu.csr.store(MTVEC, DRAM_BASE + 4);
+        // The ecall isn't caught, it traps to the illegal instruction, which is.
+        let reason = cpu.run();
+        assert_eq!(reason, HaltReason::Caught { exception: Exception::IllegalInstruction(0), pc: DRAM_BASE + 4 });
+        assert_eq!(cpu.pc, DRAM_BASE + 4);
+        assert_eq!(cpu.csr.load(MCAUSE), 11);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE);
+
+        // Resuming takes the trap.
+        assert_eq!(cpu.run(), HaltReason::Fault(Exception::IllegalInstruction(0)));
+        assert_eq!(cpu.csr.load(MCAUSE), 2);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 4);
+    }
+
+    #[test]
+    fn test_rv32_only_csr() {
+        let code = rv_code(&[
+            0xc8002573, // csrr a0, cycleh
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.regs[10] = 0x1234;
+        assert_eq!(cpu.step(), Err(HaltReason::Fault(Exception::IllegalInstruction(0xc8002573))));
+        assert_eq!(cpu.csr.load(MCAUSE), 2);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE);
+        assert_eq!(cpu.reg("a0"), 0x1234);
+    }
+
+    #[test]
+    fn test_mailbox() {
+        let code = rv_code(&[
+            0x001032b7, // lui t0, 0x103 (mailbox)
+            0x676e7337, // lui t1, 0x676e7
+            0x97030313, // addi t1, t1, -1680 ("ping")
+            0x1062a023, // sw t1, 256(t0) (data)
+            0x00100393, // li t2, 1
+            0x0072b023, // sd t2, 0(t0) (command)
+            0x1002a503, // lw a0, 256(t0)
+            0x0082b583, // ld a1, 8(t0) (result)
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        cpu.set_mailbox_handler(Box::new(|cmd, data| {
+            if cmd != 1 || &data[..4] != b"ping" {
+                return 1;
+            }
+            data[..4].copy_from_slice(b"pong");
+            0
+        }));
+        cpu.run();
+        assert_eq!(&cpu.reg("a0").to_le_bytes()[..4], b"pong");
+        assert_eq!(cpu.reg("a1"), 0);
+    }
+
+    #[test]
+    fn test_sc_other_address() {
+        let code = rv_code(&[
+            0x00000297, // auipc t0, 0
+            0x10028293, // addi t0, t0, 256 (A)
+            0x00828313, // addi t1, t0, 8 (B)
+            0x02a00393, // li t2, 42
+            0x1002a52f, // lr.w a0, (t0)
+            0x187325af, // sc.w a1, t2, (t1)
+            0x00032683, // lw a3, 0(t1)
+            0x1872a62f, // sc.w a2, t2, (t0)
+            0x1002a52f, // lr.w a0, (t0)
+            0x1872a72f, // sc.w a4, t2, (t0)
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        cpu.run();
+
+        // sc.w to B fails without storing, though A is reserved.
+        assert_eq!(cpu.reg("a1"), 1);
+        assert_eq!(cpu.reg("a3"), 0);
+        assert_eq!(cpu.load(DRAM_BASE + 0x108, 32).unwrap(), 0);
+        // The failed sc.w dropped the reservation of A as well.
+        assert_eq!(cpu.reg("a2"), 1);
+        // A new reservation lets it succeed.
+        assert_eq!(cpu.reg("a4"), 0);
+        assert_eq!(cpu.load(DRAM_BASE + 0x100, 32).unwrap(), 42);
+    }
+
+    #[test]
+    fn test_store_breaks_reservation() {
+        let code = rv_code(&[
+            0x00000297, // auipc t0, 0
+            0x10028293, // addi t0, t0, 256
+            0x02a00393, // li t2, 42
+            0x1002b52f, // lr.d a0, (t0)
+            0x1872b5af, // sc.d a1, t2, (t0)
+            0x1002b52f, // lr.d a0, (t0)
+            0x0072a223, // sw t2, 4(t0)
+            0x1872b62f, // sc.d a2, t2, (t0)
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        cpu.run();
+
+        assert_eq!(cpu.reg("a1"), 0);
+        // The store to the upper word of the reserved doubleword made sc.d fail.
+        assert_eq!(cpu.reg("a2"), 1);
+        assert_eq!(cpu.reservation, None);
+    }
+
+    #[test]
+    fn test_ecall_cause() {
+        // Without delegation every ecall traps to M-mode, with the mode it came from.
+        for (mode, cause) in [(Machine, 11), (Supervisor, 9), (User, 8)] {
+            let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000073])).headless_uart(true).build();
+            cpu.mode = mode;
+            cpu.step().unwrap();
+            assert_eq!(cpu.csr.load(MCAUSE), cause);
+            assert_eq!(cpu.csr.load(MEPC), DRAM_BASE);
+            assert_eq!(cpu.mode, Machine);
+        }
+
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00100073])).headless_uart(true).build();
+        cpu.step().unwrap();
+        assert_eq!(cpu.csr.load(MCAUSE), 3);
+    }
+
+    #[test]
+    fn test_exit_ecall() {
+        let code = rv_code(&[
+            0x05d00893, // li a7, 93
+            0x00000513, // li a0, 0
+            0x00000073, // ecall
+        ]);
+        let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).exit_ecall(93).build();
+        assert_eq!(cpu.run(), HaltReason::Exit(0));
+        assert_eq!(cpu.pc, DRAM_BASE + 8);
+
+        // Other ecalls still trap.
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_ecall(94).build();
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.csr.load(MCAUSE), 11);
+    }
+
+    #[test]
+    fn test_tvec_warl() {
+        let code = rv_code(&[
+            0x30529073, // csrw mtvec, t0
+            0x30502573, // csrr a0, mtvec
+            0x10531073, // csrw stvec, t1
+            0x105025f3, // csrr a1, stvec
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        // Reserved modes 3 and 2 with the base next to them.
+        cpu.regs[5] = DRAM_BASE + 0x103;
+        cpu.regs[6] = DRAM_BASE + 0x206;
+        for _ in 0..4 {
+            cpu.step().unwrap();
+        }
+        // Bit 1 is dropped, the mode becomes legal and the base is kept.
+        assert_eq!(cpu.reg("a0"), DRAM_BASE + 0x101);
+        assert_eq!(cpu.reg("a1"), DRAM_BASE + 0x204);
+    }
+
+    #[test]
+    fn test_peek_instruction() {
+        let code = rv_code(&[
+            0x00158513, // addi a0, a1, 1
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.regs[11] = 41;
+
+        let (raw, inst, text) = cpu.peek_instruction().unwrap();
+        assert_eq!(raw, 0x00158513);
+        assert_eq!(inst.entry.map(|e| e.name), Some("addi"));
+        assert_eq!(inst.len, 4);
+        assert_eq!(text, "addi a0, a1, 1");
+        assert_eq!(cpu.pc, DRAM_BASE);
+        assert_eq!(cpu.reg("a0"), 0);
+        assert_eq!(cpu.csr.load(MCYCLE), 0);
+
+        // A fetch fault comes back as the error.
+        cpu.pc = 0;
+        assert_eq!(cpu.peek_instruction().unwrap_err(), Exception::InstructionAccessFault(0));
+    }
+
+    #[test]
+    fn test_memory_dump() {
+        let code = rv_code(&[
+            0x00158513, // addi a0, a1, 1
+            0x00000505, // c.addi a0, 1; c.unimp
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        let dump = cpu.memory_dump(DRAM_BASE, 2);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines, ["0x0000000080000000: 00158513  addi a0, a1, 1", "0x0000000080000004: 0505      addi a0, a0, 1"]);
+        assert_eq!(cpu.pc, DRAM_BASE);
+
+        // Device memory isn't read, unmapped memory faults.
+        let dump = cpu.memory_dump(UART_BASE, 1) + &cpu.memory_dump(0, 1);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines, ["0x0000000010000000: <device>", "0x0000000000000000: <Instruction access fault 0x0>"]);
+    }
+
+    #[test]
+    fn test_zicntr() {
+        let code = rv_code(&[
+            0x00000013, // nop
+            0xc0002573, // rdcycle a0
+        ]);
+        let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).build();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        // The nop and the rdcycle itself.
+        assert_eq!(cpu.reg("a0"), 2);
+
+        // Below M-mode it takes the CY bit of mcounteren.
+        let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).build();
+        cpu.mode = Supervisor;
+        cpu.step().unwrap();
+        assert!(cpu.step().is_err());
+        assert_eq!(cpu.csr.load(MCAUSE), 2);
+
+        let mut cpu = CpuBuilder::new().code(code.clone()).headless_uart(true).zicntr(false).build();
+        cpu.step().unwrap();
+        assert_eq!(cpu.step(), Err(HaltReason::Fault(Exception::IllegalInstruction(0xc0002573))));
+    }
+
+    #[test]
+    fn test_instret() {
+        const N: usize = 20;
+        let mut insts = vec![0x00000013; N]; // nop
+        insts.extend([
+            0xc0202573, // rdinstret a0
+            0x06400293, // li t0, 100
+            0xb0229073, // csrw minstret, t0
+            0xc02025f3, // rdinstret a1
+            0x00000073, // ecall
+        ]);
+        let mut cpu = CpuBuilder::new().code(rv_code(&insts)).headless_uart(true).build();
+        cpu.csr.store(MTVEC, DRAM_BASE + 4 * N as u64 + 16);
+        for _ in 0..N + 5 {
+            cpu.step().unwrap();
+        }
+        // The instructions before it. The one writing minstret doesn't count, and neither
+        // does the ecall, which traps.
+        assert_eq!(cpu.reg("a0"), N as u64);
+        assert_eq!(cpu.reg("a1"), 100);
+        assert_eq!(cpu.csr.load(MINSTRET), 101);
+
+        // In U-mode it takes the IR bit of scounteren as well as of mcounteren.
+        let code = rv_code(&[0xc0202573, 0xc0202573]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.csr.store(MCOUNTEREN, 1 << 2);
+        cpu.mode = User;
+        assert!(cpu.step().is_err());
+        assert_eq!(cpu.csr.load(MCAUSE), 2);
+        cpu.csr.store(SCOUNTEREN, 1 << 2);
+        cpu.mode = User;
+        cpu.pc = DRAM_BASE;
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), 0);
+    }
+
+    #[test]
+    fn test_fregs_dump() {
+        let mut cpu = CpuBuilder::new().headless_uart(true).build();
+        cpu.fregs[10] = 0xffff_ffff_3fc0_0000; // 1.5f, NaN-boxed
+        cpu.fregs[11] = 2.5f64.to_bits();
+
+        let dump = cpu.fregs_dump();
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert!(lines[10].starts_with("f10(fa0 ) = 0xffffffff3fc00000"), "{}", lines[10]);
+        assert!(lines[10].contains("single = 1.5 (NaN-boxed)"), "{}", lines[10]);
+        assert!(lines[11].contains("single = NaN (not NaN-boxed)"), "{}", lines[11]);
+        assert!(lines[11].ends_with("double = 2.5"), "{}", lines[11]);
+    }
+
+    /// Runs `amo` on the value `mem` in memory with a1 = `src`, returning rd (a2) and
+    /// what is in memory after it.
+    fn amo(amo: u32, mem: u64, src: u64) -> (u64, u64) {
+        let code = rv_code(&[
+            0x00000517, // auipc a0, 0
+            0x10050513, // addi a0, a0, 256
+            amo,
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.bus.store(DRAM_BASE + 256, 64, mem).unwrap();
+        cpu.regs[11] = src;
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        (cpu.reg("a2"), cpu.bus.load(DRAM_BASE + 256, 64).unwrap())
+    }
+
+    #[test]
+    fn test_amo() {
+        const AMOADD_W: u32 = 0x00b5262f; // amoadd.w a2, a1, (a0)
+        const AMOXOR_D: u32 = 0x20b5362f; // amoxor.d a2, a1, (a0)
+        const AMOAND_W: u32 = 0x60b5262f; // amoand.w a2, a1, (a0)
+        const AMOOR_D: u32 = 0x40b5362f; // amoor.d a2, a1, (a0)
+        const AMOMIN_W: u32 = 0x80b5262f; // amomin.w a2, a1, (a0)
+        const AMOMAX_D: u32 = 0xa0b5362f; // amomax.d a2, a1, (a0)
+        const AMOMINU_D: u32 = 0xc0b5362f; // amominu.d a2, a1, (a0)
+        const AMOMAXU_W: u32 = 0xe0b5262f; // amomaxu.w a2, a1, (a0)
+
+        // A word is sign-extended into rd and only the word in memory changes.
+        assert_eq!(amo(AMOADD_W, 0x1234_5678_7fff_ffff, 1), (0x7fff_ffff, 0x1234_5678_8000_0000));
+        assert_eq!(amo(AMOXOR_D, 0xff00, 0x0ff0), (0xff00, 0xf0f0));
+        assert_eq!(amo(AMOAND_W, 0xffff_ffff, 0xf0f0), (u64::MAX, 0xf0f0));
+        assert_eq!(amo(AMOOR_D, 0xff00, 0x0ff0), (0xff00, 0xfff0));
+
+        // The signed and unsigned comparisons of words and doublewords.
+        assert_eq!(amo(AMOMIN_W, 0x8000_0000, 1), (0xffff_ffff_8000_0000, 0x8000_0000));
+        assert_eq!(amo(AMOMAXU_W, 0x8000_0000, 1), (0xffff_ffff_8000_0000, 0x8000_0000));
+        assert_eq!(amo(AMOMAX_D, u64::MAX, 1), (u64::MAX, 1));
+        assert_eq!(amo(AMOMINU_D, u64::MAX, 1), (u64::MAX, 1));
+    }
+
+    #[test]
+    fn test_amo_translation() {
+        let (mut cpu, _) = sv39(PTE_R | PTE_W);
+        let unmapped = 0x4000_1000;
+        cpu.regs[5] = unmapped;
+        // amoadd.d a0, a1, (t0)
+        assert_eq!(cpu.execute(0x00b2b52f), Err(Exception::StoreAMOPageFault(unmapped)));
+        // lr.d a0, (t0) is a load, sc.d a0, a1, (t0) a store.
+        assert_eq!(cpu.execute(0x1002b52f), Err(Exception::LoadPageFault(unmapped)));
+        cpu.reservation = Some(unmapped);
+        assert_eq!(cpu.execute(0x18b2b52f), Err(Exception::StoreAMOPageFault(unmapped)));
+        cpu.regs[5] = 0x4000_0004;
+        assert_eq!(cpu.execute(0x18b2b52f), Err(Exception::StoreAMOAddrMisaligned(0x4000_0004)));
+
+        // An AMO on a read-only page faults before it reads.
+        let (mut cpu, pte_addr) = sv39(PTE_R);
+        cpu.regs[5] = 0x4000_0000;
+        cpu.regs[10] = 7;
+        // amoswap.w a0, a1, (t0)
+        assert_eq!(cpu.execute(0x08b2a52f), Err(Exception::StoreAMOPageFault(0x4000_0000)));
+        assert_eq!(cpu.regs[10], 7);
+        assert_eq!(cpu.bus.load(pte_addr, 64).unwrap() & PTE_A, 0);
+    }
+
+    #[test]
+    fn test_amo_misaligned() {
+        let code = rv_code(&[
+            0x00000517, // auipc a0, 0
+            0x10250513, // addi a0, a0, 258
+            0x00b5262f, // amoadd.w a2, a1, (a0)
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.csr.load(MCAUSE), 6);
+        assert_eq!(cpu.csr.load(MTVAL), DRAM_BASE + 258);
+    }
+
+    /// Runs the M extension's high multiplies, divisions and remainders of a0 by a1,
+    /// returning a2-a7 and t3.
+    fn rv64m(dividend: u64, divisor: u64) -> [u64; 7] {
+        let code = rv_code(&[
+            0x02b51633, // mulh a2, a0, a1
+            0x02b526b3, // mulhsu a3, a0, a1
+            0x02b53733, // mulhu a4, a0, a1
+            0x02b547b3, // div a5, a0, a1
+            0x02b55833, // divu a6, a0, a1
+            0x02b568b3, // rem a7, a0, a1
+            0x02b57e33, // remu t3, a0, a1
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.regs[10] = dividend;
+        cpu.regs[11] = divisor;
+        for _ in 0..7 {
+            cpu.step().unwrap();
+        }
+        ["a2", "a3", "a4", "a5", "a6", "a7", "t3"].map(|name| cpu.reg(name))
+    }
+
+    #[test]
+    fn test_rv64m() {
+        let [mulh, mulhsu, mulhu, div, divu, rem, remu] = rv64m(-7i64 as u64, 2);
+        assert_eq!((mulh, mulhsu, mulhu), (u64::MAX, u64::MAX, 1));
+        assert_eq!((div, rem), (-3i64 as u64, -1i64 as u64));
+        assert_eq!((divu, remu), ((-7i64 as u64) / 2, 1));
+
+        // Division by zero gives all ones and leaves the dividend as the remainder.
+        let [_, _, _, div, divu, rem, remu] = rv64m(42, 0);
+        assert_eq!((div, divu, rem, remu), (u64::MAX, u64::MAX, 42, 42));
+
+        // The signed overflow gives the dividend and no remainder.
+        let [mulh, _, _, div, _, rem, _] = rv64m(i64::MIN as u64, -1i64 as u64);
+        assert_eq!((mulh, div, rem), (0, i64::MIN as u64, 0));
+    }
+
+    /// Runs the M extension's word operations on a0 and a1, returning a2-a6.
+    fn rv64m_word(a: u64, b: u64) -> [u64; 5] {
+        let code = rv_code(&[
+            0x02b5063b, // mulw a2, a0, a1
+            0x02b546bb, // divw a3, a0, a1
+            0x02b5573b, // divuw a4, a0, a1
+            0x02b567bb, // remw a5, a0, a1
+            0x02b5783b, // remuw a6, a0, a1
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        cpu.regs[10] = a;
+        cpu.regs[11] = b;
+        for _ in 0..5 {
+            cpu.step().unwrap();
+        }
+        ["a2", "a3", "a4", "a5", "a6"].map(|name| cpu.reg(name))
+    }
+
+    #[test]
+    fn test_rv64m_word() {
+        // The upper halves of the operands are ignored.
+        let [mulw, divw, divuw, remw, remuw] = rv64m_word(0xdead_beef_ffff_fff9, 0x1234_0000_0000_0002);
+        assert_eq!((mulw, divw, remw), (-14i64 as u64, -3i64 as u64, -1i64 as u64));
+        // 0xfffffff9 / 2 = 0x7ffffffc, 0xfffffff9 % 2 = 1.
+        assert_eq!((divuw, remuw), (0x7fff_fffc, 1));
+        // A 32-bit product and unsigned quotient with bit 31 set are sign-extended.
+        let [mulw, _, divuw, _, _] = rv64m_word(0x4000_0000, 0x1_0000_0001);
+        assert_eq!((mulw, divuw), (0x4000_0000, 0x4000_0000));
+        let [mulw, _, divuw, _, _] = rv64m_word(0x8000_0000, 1);
+        assert_eq!((mulw, divuw), (0xffff_ffff_8000_0000, 0xffff_ffff_8000_0000));
+
+        // A divisor that is zero in its low 32 bits divides by zero.
+        let [_, divw, divuw, remw, remuw] = rv64m_word(0xffff_ffff_8000_0007, 0x1_0000_0000);
+        assert_eq!((divw, divuw), (u64::MAX, u64::MAX));
+        assert_eq!((remw, remuw), (0xffff_ffff_8000_0007, 0xffff_ffff_8000_0007));
+
+        // The signed overflow gives the dividend and no remainder.
+        let [_, divw, _, remw, _] = rv64m_word(i32::MIN as u64, u64::MAX);
+        assert_eq!((divw, remw), (i32::MIN as i64 as u64, 0));
+    }
+
+    /// A cpu in S-mode with Sv39 on. 0x4000_0000 maps a 4 KiB page of dram with `flags`,
+    /// 0x4020_0000 a 2 MiB superpage that isn't aligned. Returns the address of the 4 KiB
+    /// page's PTE.
+    fn sv39(flags: u64) -> (Cpu, u64) {
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+        let (root, l1, l0) = (DRAM_BASE + 0x10000, DRAM_BASE + 0x11000, DRAM_BASE + 0x12000);
+        let pte = |addr: u64, flags: u64| (addr >> 12) << 10 | flags;
+        cpu.bus.store(root + 8, 64, pte(l1, PTE_V)).unwrap();
+        cpu.bus.store(l1, 64, pte(l0, PTE_V)).unwrap();
+        cpu.bus.store(l1 + 8, 64, pte(DRAM_BASE + 0x1000, PTE_V | PTE_R | PTE_W)).unwrap();
+        cpu.bus.store(l0, 64, pte(DRAM_BASE + 0x20000, PTE_V | flags)).unwrap();
+        cpu.csr.store(SATP, 8 << 60 | root >> 12);
+        cpu.update_paging(SATP);
+        cpu.mode = Supervisor;
+        (cpu, l0)
+    }
+
+    #[test]
+    fn test_sv39_accessed_dirty() {
+        let (mut cpu, pte_addr) = sv39(PTE_R | PTE_W);
+        assert_eq!(cpu.translate(0x4000_0123, AccessType::Load), Ok(DRAM_BASE + 0x20123));
+        let pte = cpu.bus.load(pte_addr, 64).unwrap();
+        assert_eq!((pte & PTE_A != 0, pte & PTE_D != 0), (true, false));
+
+        cpu.store(0x4000_0008, 64, 42).unwrap();
+        assert_ne!(cpu.bus.load(pte_addr, 64).unwrap() & PTE_D, 0);
+        assert_eq!(cpu.bus.load(DRAM_BASE + 0x20008, 64), Ok(42));
+    }
+
+    #[test]
+    fn test_sv39_inspect() {
+        // A debugger looking through a PTE with A clear leaves it clear and caches nothing.
+        let (mut cpu, pte_addr) = sv39(PTE_R | PTE_W | PTE_X);
+        let pte = cpu.bus.load(pte_addr, 64).unwrap();
+        cpu.bus.store(DRAM_BASE + 0x20000, 32, 0x00158513).unwrap(); // addi a0, a1, 1
+        cpu.pc = 0x4000_0000;
+        assert_eq!(cpu.peek_instruction().map(|(raw, _, _)| raw), Ok(0x00158513));
+        assert!(cpu.memory_dump(0x4000_0000, 1).contains("addi a0, a1, 1"));
+        assert_eq!(cpu.read_mem_virt(0x4000_0000, 32), Ok(0x00158513));
+        cpu.write_mem_virt(0x4000_0008, 64, 42).unwrap();
+        assert_eq!(cpu.bus.load(pte_addr, 64), Ok(pte));
+        assert!(cpu.tlb.get(0, 0x4000_0000 >> 12).is_none());
+
+        // The guest's own fetch sets A as usual.
+        cpu.fetch().unwrap();
+        assert_ne!(cpu.bus.load(pte_addr, 64).unwrap() & PTE_A, 0);
+    }
+
+    #[test]
+    fn test_sv39_pte_outside_memory() {
+        // A PTE the bus can't access faults like the access, at its virtual address.
+        let (mut cpu, pte_addr) = sv39(PTE_R | PTE_W | PTE_X);
+        let l1 = pte_addr - 0x1000;
+        cpu.bus.store(l1, 64, (0x2000_0000 >> 12) << 10 | PTE_V).unwrap();
+        cpu.pc = 0x4000_0000;
+        assert_eq!(cpu.fetch(), Err(Exception::InstructionAccessFault(0x4000_0000)));
+        assert_eq!(cpu.store(0x4000_0008, 64, 1), Err(Exception::StoreAMOAccessFault(0x4000_0008)));
+
+        // So does setting A or D in a PTE that can be read but not written, here in ROM.
+        let (mut cpu, pte_addr) = sv39(PTE_R | PTE_W | PTE_X);
+        cpu.bus.boot_rom = Some(cpu.bus.load(pte_addr, 64).unwrap().to_le_bytes().to_vec());
+        cpu.bus.store(pte_addr - 0x1000, 64, (MROM_BASE >> 12) << 10 | PTE_V).unwrap();
+        assert_eq!(cpu.translate_inspect(0x4000_0008, AccessType::Store), Ok(DRAM_BASE + 0x20008));
+        assert_eq!(cpu.store(0x4000_0008, 64, 1), Err(Exception::StoreAMOAccessFault(0x4000_0008)));
+    }
+
+    #[test]
+    fn test_sfence_vma() {
+        let (mut cpu, pte_addr) = sv39(PTE_R | PTE_W);
+        let remap = |cpu: &mut Cpu, page: u64| {
+            let pte = (page >> 12) << 10 | PTE_V | PTE_R | PTE_W | PTE_A;
+            cpu.bus.store(pte_addr, 64, pte).unwrap();
+        };
+        assert_eq!(cpu.translate(0x4000_0123, AccessType::Load), Ok(DRAM_BASE + 0x20123));
+
+        // The old translation is used until sfence.vma flushes its page.
+        remap(&mut cpu, DRAM_BASE + 0x30000);
+        assert_eq!(cpu.translate(0x4000_0123, AccessType::Load), Ok(DRAM_BASE + 0x20123));
+        cpu.regs[10] = 0x4000_1000;
+        cpu.execute(0x12050073).unwrap(); // sfence.vma a0, zero
+        assert_eq!(cpu.translate(0x4000_0123, AccessType::Load), Ok(DRAM_BASE + 0x20123));
+        cpu.regs[10] = 0x4000_0fff;
+        cpu.execute(0x12050073).unwrap(); // sfence.vma a0, zero
+        assert_eq!(cpu.translate(0x4000_0123, AccessType::Load), Ok(DRAM_BASE + 0x30123));
+
+        // Or its address space, which is 0 here.
+        remap(&mut cpu, DRAM_BASE + 0x40000);
+        cpu.regs[11] = 5;
+        cpu.execute(0x12b00073).unwrap(); // sfence.vma zero, a1
+        assert_eq!(cpu.translate(0x4000_0123, AccessType::Load), Ok(DRAM_BASE + 0x30123));
+        cpu.regs[11] = 0;
+        cpu.execute(0x12b00073).unwrap(); // sfence.vma zero, a1
+        assert_eq!(cpu.translate(0x4000_0123, AccessType::Load), Ok(DRAM_BASE + 0x40123));
+
+        // Or everything.
+        remap(&mut cpu, DRAM_BASE + 0x50000);
+        cpu.execute(0x12000073).unwrap(); // sfence.vma
+        assert_eq!(cpu.translate(0x4000_0123, AccessType::Load), Ok(DRAM_BASE + 0x50123));
+
+        // A store to a page cached without D walks the table again to set it.
+        cpu.store(0x4000_0008, 64, 42).unwrap();
+        assert_ne!(cpu.bus.load(pte_addr, 64).unwrap() & PTE_D, 0);
+    }
+
+    #[test]
+    fn test_tlb_permissions() {
+        let (mut cpu, pte_addr) = sv39(PTE_R | PTE_W);
+        let protect = |cpu: &mut Cpu, flags: u64| {
+            let pte = cpu.bus.load(pte_addr, 64).unwrap();
+            cpu.bus.store(pte_addr, 64, pte & !(PTE_R | PTE_W | PTE_X | PTE_U) | flags).unwrap();
+        };
+        cpu.store(0x4000_0008, 64, 42).unwrap();
+
+        // Taking write permission away takes effect with sfence.vma on the page.
+        protect(&mut cpu, PTE_R);
+        cpu.regs[10] = 0x4000_0000;
+        cpu.execute(0x12050073).unwrap(); // sfence.vma a0, zero
+        assert_eq!(cpu.store(0x4000_0008, 64, 43), Err(Exception::StoreAMOPageFault(0x4000_0008)));
+        assert_eq!(cpu.load(0x4000_0008, 64), Ok(42));
+
+        // So does making it a user page, which S-mode can't access without SUM.
+        protect(&mut cpu, PTE_R | PTE_W | PTE_U);
+        cpu.execute(0x12000073).unwrap(); // sfence.vma
+        assert_eq!(cpu.load(0x4000_0008, 64), Err(Exception::LoadPageFault(0x4000_0008)));
+        cpu.csr.store(SSTATUS, MASK_SUM);
+        assert_eq!(cpu.load(0x4000_0008, 64), Ok(42));
+
+        // A write to satp flushes too, and without the TLB nothing is cached.
+        protect(&mut cpu, PTE_X);
+        cpu.regs[5] = cpu.csr.load(SATP);
+        cpu.execute(0x18029073).unwrap(); // csrw satp, t0
+        assert_eq!(cpu.load(0x4000_0008, 64), Err(Exception::LoadPageFault(0x4000_0008)));
+        let (mut cpu, pte_addr) = sv39(PTE_R);
+        cpu.use_tlb = false;
+        assert_eq!(cpu.load(0x4000_0008, 64), Ok(0));
+        cpu.bus.store(pte_addr, 64, 0).unwrap();
+        assert_eq!(cpu.load(0x4000_0008, 64), Err(Exception::LoadPageFault(0x4000_0008)));
+    }
+
+    #[test]
+    fn test_sv39_big_endian() {
+        // With SBE set the page tables are big-endian too.
+        let (mut cpu, pte_addr) = sv39(PTE_R | PTE_W);
+        let root = DRAM_BASE + 0x10000;
+        for addr in [root + 8, root + 0x1000, root + 0x1008, pte_addr] {
+            let pte = cpu.bus.load(addr, 64).unwrap();
+            cpu.bus.store(addr, 64, pte.swap_bytes()).unwrap();
+        }
+        cpu.csr.store(MSTATUS, MASK_SBE);
+        cpu.store(0x4000_0008, 64, 42).unwrap();
+        let pte = cpu.bus.load(pte_addr, 64).unwrap().swap_bytes();
+        assert_eq!(pte & (PTE_A | PTE_D), PTE_A | PTE_D);
+        assert_eq!(cpu.bus.load(DRAM_BASE + 0x20008, 64), Ok(42u64.swap_bytes()));
+        assert_eq!(cpu.load(0x4000_0008, 64), Ok(42));
+    }
+
+    #[test]
+    fn test_sv39_permissions() {
+        // Read-only and not executable.
+        let (mut cpu, _) = sv39(PTE_R);
+        assert_eq!(cpu.translate(0x4000_0000, AccessType::Store), Err(Exception::StoreAMOPageFault(0x4000_0000)));
+        assert_eq!(
+            cpu.translate(0x4000_0000, AccessType::Instruction),
+            Err(Exception::InstructionPageFault(0x4000_0000))
+        );
+        // Machine mode isn't translated, unless MPRV makes a load use MPP.
+        cpu.mode = Machine;
+        assert_eq!(cpu.translate(0x4000_0000, AccessType::Store), Ok(0x4000_0000));
+        cpu.csr.store(MSTATUS, MASK_MPRV | 0b01 << 11);
+        assert_eq!(cpu.translate(0x4000_0000, AccessType::Load), Ok(DRAM_BASE + 0x20000));
+
+        // A user page is off limits to S-mode without SUM, and to S-mode fetches always.
+        let (mut cpu, _) = sv39(PTE_R | PTE_X | PTE_U);
+        assert_eq!(cpu.translate(0x4000_0000, AccessType::Load), Err(Exception::LoadPageFault(0x4000_0000)));
+        cpu.csr.store(SSTATUS, MASK_SUM);
+        assert!(cpu.translate(0x4000_0000, AccessType::Load).is_ok());
+        assert!(cpu.translate(0x4000_0000, AccessType::Instruction).is_err());
+        cpu.mode = User;
+        assert!(cpu.translate(0x4000_0000, AccessType::Instruction).is_ok());
+    }
+
+    #[test]
+    fn test_sv39_misaligned_superpage() {
+        let (mut cpu, _) = sv39(PTE_R);
+        assert_eq!(cpu.translate(0x4020_0000, AccessType::Load), Err(Exception::LoadPageFault(0x4020_0000)));
+        // Neither are addresses whose upper bits don't repeat bit 38.
+        assert_eq!(cpu.translate(0x80_4000_0000, AccessType::Load), Err(Exception::LoadPageFault(0x80_4000_0000)));
+    }
+
+    #[test]
+    fn test_pmp() {
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+        let (region, na4) = (DRAM_BASE + 0x1000, DRAM_BASE + 0x3000);
+        // A read-only 4 KiB NAPOT region, a read-write NA4 one and then all of memory.
+        cpu.csr.store(PMPADDR0, (region + 0x7ff) >> 2);
+        cpu.csr.store(PMPADDR0 + 1, na4 >> 2);
+        cpu.csr.store(PMPADDR0 + 2, 0x3f_ffff_ffff_ffff);
+        let cfg = [PMP_NAPOT | PMP_R, PMP_NA4 | PMP_R | PMP_W, PMP_NAPOT | PMP_R | PMP_W | PMP_X];
+        cpu.csr.store(PMPCFG0, u64::from_le_bytes([cfg[0], cfg[1], cfg[2], 0, 0, 0, 0, 0]));
+        cpu.mode = Supervisor;
+
+        assert_eq!(cpu.store(region + 8, 64, 1), Err(Exception::StoreAMOAccessFault(region + 8)));
+        assert_eq!(cpu.load(region + 8, 64), Ok(0));
+        assert_eq!(cpu.store(region + 0x1000, 64, 1), Ok(()));
+        // The first entry that matches any byte decides, and all of them must match.
+        assert_eq!(cpu.store(na4, 32, 1), Ok(()));
+        assert_eq!(cpu.load(na4, 64), Err(Exception::LoadAccessFault(na4)));
+
+        // Unlocked entries don't apply to M-mode, locked ones do and can't be changed.
+        cpu.mode = Machine;
+        assert_eq!(cpu.store(region + 8, 64, 1), Ok(()));
+        cpu.csr.store(PMPCFG0, cpu.csr.load(PMPCFG0) | PMP_L as u64);
+        cpu.csr.store(PMPCFG0, 0);
+        cpu.csr.store(PMPADDR0, 0);
+        assert_eq!(cpu.store(region + 8, 64, 2), Err(Exception::StoreAMOAccessFault(region + 8)));
+        assert_eq!(cpu.load(region + 8, 64), Ok(1));
+
+        // The other entries are off now. Nothing matches outside the locked one, which
+        // only M-mode may access.
+        assert_eq!(cpu.load(DRAM_BASE + 0x2000, 64), Ok(1));
+        cpu.mode = User;
+        assert_eq!(cpu.load(DRAM_BASE + 0x2000, 64), Err(Exception::LoadAccessFault(DRAM_BASE + 0x2000)));
+        cpu.pc = DRAM_BASE;
+        assert_eq!(cpu.fetch(), Err(Exception::InstructionAccessFault(DRAM_BASE)));
+
+        // A 32-bit instruction whose upper half is in a region without X isn't fetched,
+        // even from the decode cache. A compressed one before the region is.
+        let mut cpu = CpuBuilder::new().code(vec![0; 0x2000]).headless_uart(true).build();
+        let region = DRAM_BASE + 0x1000;
+        cpu.csr.store(PMPADDR0, region >> 2);
+        cpu.csr.store(PMPADDR0 + 1, 0x3f_ffff_ffff_ffff);
+        let cfg = [PMP_TOR | PMP_R | PMP_X, PMP_NAPOT | PMP_R];
+        cpu.csr.store(PMPCFG0, u64::from_le_bytes([cfg[0], cfg[1], 0, 0, 0, 0, 0, 0]));
+        cpu.mode = Supervisor;
+        cpu.bus.store(region - 4, 32, 0x00130001).unwrap(); // c.nop; lower half of nop
+        cpu.bus.store(region, 16, 0x0000).unwrap();
+        cpu.icache.flush();
+        cpu.pc = region - 4;
+        assert_eq!(cpu.fetch(), Ok(0x0001));
+        cpu.pc = region - 2;
+        assert_eq!(cpu.fetch(), Err(Exception::InstructionAccessFault(region)));
+        cpu.mode = Machine;
+        assert_eq!(cpu.fetch(), Ok(0x00000013));
+        cpu.mode = Supervisor;
+        assert_eq!(cpu.fetch(), Err(Exception::InstructionAccessFault(region)));
+    }
+
+    #[test]
+    fn test_load_payload_at() {
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+        let (kernel, initrd) = (DRAM_BASE + 0x20_0000, DRAM_BASE + 0x40_0000);
+        cpu.load_payload_at(kernel, &[0x13, 0x05, 0xa0, 0x02]).unwrap(); // li a0, 42
+        cpu.load_payload_at(initrd, b"initrd").unwrap();
+        assert_eq!(cpu.read_bytes(kernel, 4), Ok(vec![0x13, 0x05, 0xa0, 0x02]));
+        assert_eq!(cpu.read_bytes(initrd, 6), Ok(b"initrd".to_vec()));
+        // The code at the start of dram is still there.
+        assert_eq!(cpu.read_mem(DRAM_BASE, 32), Ok(0x00000013));
+        let end = DRAM_BASE + DRAM_SIZE;
+        assert_eq!(cpu.load_payload_at(end - 2, &[0; 4]), Err(Exception::StoreAMOAccessFault(end - 2)));
+
+        cpu.pc = kernel;
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), 42);
+    }
+
+    #[test]
+    fn test_mem_api() {
+        let (mut cpu, _) = sv39(PTE_R | PTE_W);
+        let cycles = cpu.csr.load(MCYCLE);
+        cpu.write_mem(DRAM_BASE + 0x20000, 64, 0x0807_0605_0403_0201).unwrap();
+        assert_eq!(cpu.read_mem(DRAM_BASE + 0x20004, 32), Ok(0x0807_0605));
+        assert_eq!(cpu.read_bytes(DRAM_BASE + 0x20000, 3), Ok(vec![1, 2, 3]));
+        assert_eq!(cpu.csr.load(MCYCLE), cycles);
+
+        // The virtual ones go through the page table.
+        assert_eq!(cpu.read_mem_virt(0x4000_0002, 16), Ok(0x0403));
+        cpu.write_mem_virt(0x4000_0001, 8, 0xff).unwrap();
+        assert_eq!(cpu.read_mem(DRAM_BASE + 0x20000, 16), Ok(0xff01));
+        assert_eq!(cpu.read_mem_virt(0x4000_2000, 8), Err(Exception::LoadPageFault(0x4000_2000)));
+
+        // Widths the bus doesn't have, misaligned and unmapped addresses fail.
+        assert_eq!(cpu.read_mem(DRAM_BASE, 24), Err(Exception::LoadAccessFault(DRAM_BASE)));
+        assert_eq!(cpu.read_mem(DRAM_BASE + 2, 32), Err(Exception::LoadAccessMisaligned(DRAM_BASE + 2)));
+        assert_eq!(cpu.write_mem(DRAM_BASE + 4, 64, 0), Err(Exception::StoreAMOAddrMisaligned(DRAM_BASE + 4)));
+        assert_eq!(cpu.read_bytes(DRAM_BASE - 1, 2), Err(Exception::LoadAccessFault(DRAM_BASE - 1)));
+    }
+
+    #[test]
+    fn test_run_steps() {
+        // A loop that never halts gives control back after the budget.
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x0000006f])).headless_uart(true).build();
+        assert_eq!(cpu.run_steps(10), None);
+        assert_eq!(cpu.pc, DRAM_BASE);
+
+        let code = rv_code(&[
+            0x00700513, // li a0, 7
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        assert_eq!(cpu.run_steps(10), Some(HaltReason::Exit(7)));
+    }
+
+    #[test]
+    fn test_addiw_overflow() {
+        let code = rv_code(&[
+            0x0015851b, // addiw a0, a1, 1
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+        // The upper bits of rs1 don't take part in the 32-bit sum.
+        cpu.regs[11] = 0x1234_5678_7fff_ffff;
+        cpu.step().unwrap();
+        assert_eq!(cpu.reg("a0"), 0xffff_ffff_8000_0000);
+    }
+
+    #[test]
+    fn test_access_past_device_end() {
+        let mut cpu = CpuBuilder::new().code(rv_code(&[0x00000013])).headless_uart(true).build();
+        // Seven of the eight bytes would be past the virtio registers.
+        assert_eq!(cpu.bus.load(VIRTIO_END - 1, 64), Err(Exception::LoadAccessFault(VIRTIO_END - 1)));
+        assert_eq!(cpu.bus.store(VIRTIO_END - 1, 64, 0), Err(Exception::StoreAMOAccessFault(VIRTIO_END - 1)));
+        assert_eq!(cpu.bus.load(DRAM_END - 3, 64), Err(Exception::LoadAccessFault(DRAM_END - 3)));
+        assert!(cpu.bus.load(DRAM_END - 7, 64).is_ok());
+    }
+
     #[test]
     fn compile_hello_world() {
         // You should run it by
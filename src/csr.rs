@@ -1,3 +1,5 @@
+use crate::cpu::Machine;
+
 pub const MHARTID: usize = 0xf14;
 /// Machine status register.
 pub const MSTATUS: usize = 0x300;
@@ -21,6 +23,27 @@ pub const MCAUSE: usize = 0x342;
 pub const MTVAL: usize = 0x343;
 /// Machine interrupt pending.
 pub const MIP: usize = 0x344;
+/// Machine cycle counter.
+pub const MCYCLE: usize = 0xb00;
+/// Machine instructions-retired counter.
+pub const MINSTRET: usize = 0xb02;
+/// Physical memory protection configuration. On RV64 only the even ones exist, each
+/// configures 8 entries.
+pub const PMPCFG0: usize = 0x3a0;
+pub const PMPCFG15: usize = 0x3af;
+/// Physical memory protection addresses, bits 55:2 of the address of each entry.
+pub const PMPADDR0: usize = 0x3b0;
+pub const PMPADDR63: usize = 0x3ef;
+
+// Unprivileged counters/timers.
+/// Cycle counter for the rdcycle instruction, a read-only view of mcycle.
+pub const CYCLE: usize = 0xc00;
+/// Timer for the rdtime instruction, a read-only view of the CLINT's mtime.
+pub const TIME: usize = 0xc01;
+/// Instructions-retired counter for the rdinstret instruction, a read-only view of minstret.
+pub const INSTRET: usize = 0xc02;
+/// The last hardware performance-monitoring counter, hpmcounter31.
+pub const HPMCOUNTER31: usize = 0xc1f;
 
 // Supervisor-level CSRs.
 /// Supervisor status register.
@@ -29,6 +52,8 @@ pub const SSTATUS: usize = 0x100;
 pub const SIE: usize = 0x104;
 /// Supervisor trap handler base address.
 pub const STVEC: usize = 0x105;
+/// Supervisor counter enable.
+pub const SCOUNTEREN: usize = 0x106;
 /// Scratch register for supervisor trap handlers.
 pub const SSCRATCH: usize = 0x140;
 /// Supervisor exception program counter.
@@ -74,6 +99,26 @@ pub const MASK_SSTATUS: u64 = MASK_SIE
     | MASK_MXR
     | MASK_UXL
     | MASK_SD;
+/// The fields of mstatus that software writes. The others are read-only: UXL and SXL
+/// say 64 bits, SD tells whether FS is dirty, and the rest are 0.
+const MASK_MSTATUS_WRITABLE: u64 = MASK_SIE
+    | MASK_MIE
+    | MASK_SPIE
+    | MASK_UBE
+    | MASK_MPIE
+    | MASK_SPP
+    | MASK_MPP
+    | MASK_FS
+    | MASK_MPRV
+    | MASK_SUM
+    | MASK_MXR
+    | MASK_TVM
+    | MASK_TW
+    | MASK_TSR
+    | MASK_SBE
+    | MASK_MBE;
+/// UXL and SXL of a hart whose U-mode and S-mode are 64-bit.
+const MSTATUS_XL64: u64 = 2 << 32 | 2 << 34;
 
 // MIP / SIP field mask
 pub const MASK_SSIP: u64 = 1 << 1;
@@ -88,15 +133,83 @@ const NUM_CSRS: usize = 4096;
 // SATP field
 pub const MASK_PPN:  u64 = (1 << 44) - 1;
 
+// pmpcfg entry fields
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const MASK_PMP_A: u8 = 0b11 << 3;
+pub const PMP_TOR: u8 = 1 << 3;
+pub const PMP_NA4: u8 = 2 << 3;
+pub const PMP_NAPOT: u8 = 3 << 3;
+pub const PMP_L: u8 = 1 << 7;
+const PMP_ENTRIES: usize = 64;
+const MASK_PMPADDR: u64 = (1 << 54) - 1;
+
+/// Whether `addr` is a csr that only exists on RV32, such as the upper halves of the
+/// counters (`cycleh`, `mcycleh`, ...). RV64 reads the whole counter in one access, so
+/// these addresses are illegal here.
+pub fn is_rv32_only(addr: usize) -> bool {
+    matches!(
+        addr,
+        // cycleh, timeh, instreth and hpmcounter3h-hpmcounter31h
+        0xc80..=0xc9f
+        // mcycleh, minstreth and mhpmcounter3h-mhpmcounter31h
+        | 0xb80 | 0xb82..=0xb9f
+        // mstatush, menvcfgh, mseccfgh
+        | 0x310 | 0x31a | 0x757
+    ) || (0x3a0..=0x3af).contains(&addr) && addr % 2 == 1 // pmpcfg1, pmpcfg3, ...
+}
+
+/// The mstatus `value` becomes when written over `old`. Read-only fields keep their
+/// value, and MPP is WARL: the reserved mode 0b10 leaves it unchanged, like on QEMU.
+fn legalize_mstatus(old: u64, value: u64) -> u64 {
+    let mut mstatus = (old & !MASK_MSTATUS_WRITABLE) | (value & MASK_MSTATUS_WRITABLE);
+    if mstatus & MASK_MPP == 0b10 << 11 {
+        mstatus = (mstatus & !MASK_MPP) | (old & MASK_MPP);
+    }
+    if mstatus & MASK_FS == MASK_FS {
+        mstatus | MASK_SD
+    } else {
+        mstatus & !MASK_SD
+    }
+}
+
+/// serde only implements its traits for arrays of up to 32 elements, the csrs go as a
+/// sequence.
+#[cfg(feature = "serde")]
+mod csr_array {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::NUM_CSRS;
+
+    pub fn serialize<S: Serializer>(csrs: &[u64; NUM_CSRS], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(csrs.iter())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u64; NUM_CSRS], D::Error> {
+        let csrs = Vec::<u64>::deserialize(deserializer)?;
+        let len = csrs.len();
+        csrs.try_into().map_err(|_| D::Error::invalid_length(len, &"4096 csrs"))
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Csr {
+    #[cfg_attr(feature = "serde", serde(with = "csr_array"))]
     csrs: [u64; NUM_CSRS],
+    /// Whether any PMP entry is on. Until one is, PMP checks nothing, as on a hart
+    /// without PMP.
+    pmp_active: bool,
 }
 
 impl Csr {
-    pub fn new() -> Csr {
-        Self {
-            csrs: [0; NUM_CSRS],
-        }
+    /// Reset state of the csrs of the hart `hartid`.
+    pub fn new(hartid: u64) -> Csr {
+        let mut csrs = [0; NUM_CSRS];
+        csrs[MHARTID] = hartid;
+        csrs[MSTATUS] = MSTATUS_XL64;
+        Self { csrs, pmp_active: false }
     }
 
     // Register mideleg controls whether an interrupt is delegated to S-mode. 
@@ -143,17 +256,99 @@ impl Csr {
                     (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG])
             }
             SIP => {
-                self.csrs[MIP] =
-                    (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG])
+                // STIP and SEIP are read-only in sip, the timer and the PLIC drive them.
+                let mask = self.csrs[MIDELEG] & MASK_SSIP;
+                self.csrs[MIP] = (self.csrs[MIP] & !mask) | (value & mask)
             }
+            MSTATUS => self.csrs[MSTATUS] = legalize_mstatus(self.csrs[MSTATUS], value),
             SSTATUS => {
                 // Same as above.
-                self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !MASK_SSTATUS) | (value & MASK_SSTATUS)
+                let value = (self.csrs[MSTATUS] & !MASK_SSTATUS) | (value & MASK_SSTATUS);
+                self.csrs[MSTATUS] = legalize_mstatus(self.csrs[MSTATUS], value)
+            }
+            MIDELEG => {
+                // Only the S-level interrupts can be delegated, the M-level ones always
+                // go to M-mode like on QEMU.
+                self.csrs[MIDELEG] = value & (MASK_SSIP | MASK_STIP | MASK_SEIP)
+            }
+            MTVEC | STVEC => {
+                // The BASE field is bits 63:2, so it is always 4-byte aligned. MODE is
+                // WARL and only Direct (0) and Vectored (1) are legal, the reserved
+                // modes 2 and 3 become 0 and 1 like on Spike.
+                self.csrs[addr] = value & !0b10
+            }
+            PMPCFG0..=PMPCFG15 => {
+                // A locked entry keeps its configuration. W without R is reserved and
+                // becomes neither, bits 6:5 are read-only zero.
+                let mut cfg = 0;
+                for i in 0..8 {
+                    let old = (self.csrs[addr] >> (i * 8)) as u8;
+                    let mut new = (value >> (i * 8)) as u8 & !0x60;
+                    if old & PMP_L != 0 {
+                        new = old;
+                    } else if new & (PMP_R | PMP_W) == PMP_W {
+                        new &= !PMP_W;
+                    }
+                    cfg |= (new as u64) << (i * 8);
+                }
+                self.csrs[addr] = cfg;
+                self.pmp_active = (0..PMP_ENTRIES).any(|i| self.pmp_cfg(i) & MASK_PMP_A != 0);
+            }
+            PMPADDR0..=PMPADDR63 => {
+                // The address of a locked entry is locked too, and so is the one below a
+                // locked TOR entry, which is its bottom.
+                let i = addr - PMPADDR0;
+                let locked_tor = |cfg: u8| cfg & (PMP_L | MASK_PMP_A) == PMP_L | PMP_TOR;
+                if self.pmp_cfg(i) & PMP_L == 0 && (i + 1 == PMP_ENTRIES || !locked_tor(self.pmp_cfg(i + 1))) {
+                    self.csrs[addr] = value & MASK_PMPADDR;
+                }
             }
             _ => self.csrs[addr] = value,
         }
     }
 
+    /// The configuration of PMP entry `i`.
+    fn pmp_cfg(&self, i: usize) -> u8 {
+        (self.csrs[PMPCFG0 + i / 8 * 2] >> (i % 8 * 8)) as u8
+    }
+
+    /// Whether PMP lets `mode` access `len` bytes at physical address `addr` with the
+    /// permission `perm`, one of `PMP_R`, `PMP_W` and `PMP_X`.
+    #[inline]
+    pub fn pmp_allows(&self, addr: u64, len: u64, perm: u8, mode: u64) -> bool {
+        !self.pmp_active || self.pmp_match(addr, len, perm, mode)
+    }
+
+    fn pmp_match(&self, addr: u64, len: u64, perm: u8, mode: u64) -> bool {
+        let end = addr.saturating_add(len);
+        for i in 0..PMP_ENTRIES {
+            let cfg = self.pmp_cfg(i);
+            let pmpaddr = self.csrs[PMPADDR0 + i];
+            let (base, top) = match cfg & MASK_PMP_A {
+                PMP_TOR if i == 0 => (0, pmpaddr << 2),
+                PMP_TOR => (self.csrs[PMPADDR0 + i - 1] << 2, pmpaddr << 2),
+                PMP_NA4 => (pmpaddr << 2, (pmpaddr << 2) + 4),
+                PMP_NAPOT => {
+                    // The trailing ones of the address give the size, 8 bytes or more.
+                    let size = 8 << pmpaddr.trailing_ones();
+                    let base = (pmpaddr << 2) & !(size - 1);
+                    (base, base + size)
+                }
+                _ => continue,
+            };
+            // "The lowest-numbered PMP entry that matches any byte of an access determines
+            // whether that access succeeds or fails." It fails unless all bytes match.
+            if addr < top && end > base {
+                let unlocked_machine = mode == Machine && cfg & PMP_L == 0;
+                return addr >= base && end <= top && (unlocked_machine || cfg & perm != 0);
+            }
+        }
+        // "If no PMP entry matches an M-mode access, the access succeeds. If no PMP entry
+        // matches an S-mode or U-mode access, but at least one PMP entry is implemented,
+        // the access fails."
+        mode == Machine
+    }
+
     /// Returns whether this exception cause is delegated from M-mode to S-mode.
     pub fn is_medelegated(&self, cause: u64) -> bool {
         (self.csrs[MEDELEG].wrapping_shr(cause as u32) & 1) == 1
@@ -0,0 +1,424 @@
+//! The decode module contains the table of instructions the cpu can decode.
+//!
+//! Each entry identifies an instruction by the bits that are fixed in its encoding:
+//! `inst & mask == bits`. The first matching entry wins, so an entry that is a
+//! special case of a later one comes first. The table lists exactly what
+//! `Cpu::execute` implements, so keep the two in sync.
+
+/// The ISA extension an instruction belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    Rv64I,
+    Rv64M,
+    Rv64A,
+    Rv64C,
+    Zicsr,
+    Zifencei,
+    /// The instructions of the privileged architecture.
+    Privileged,
+}
+
+/// The encoding format of an instruction, which says where its operands are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    R,
+    I,
+    S,
+    B,
+    U,
+    J,
+    /// Compressed, register.
+    CR,
+    /// Compressed, arithmetic.
+    CA,
+    /// Compressed, immediate.
+    CI,
+    /// Compressed, stack-relative store.
+    CSS,
+    /// Compressed, wide immediate.
+    CIW,
+    /// Compressed, load.
+    CL,
+    /// Compressed, store.
+    CS,
+    /// Compressed, branch.
+    CB,
+    /// Compressed, jump.
+    CJ,
+}
+
+use Extension::*;
+use Format::*;
+
+/// One instruction of the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstEntry {
+    pub name: &'static str,
+    pub extension: Extension,
+    pub format: Format,
+    /// The bits of the encoding that identify the instruction.
+    pub mask: u32,
+    /// The value of those bits.
+    pub bits: u32,
+}
+
+impl InstEntry {
+    pub fn matches(&self, inst: u32) -> bool {
+        inst & self.mask == self.bits
+    }
+}
+
+const fn entry(
+    name: &'static str,
+    extension: Extension,
+    format: Format,
+    mask: u32,
+    bits: u32,
+) -> InstEntry {
+    InstEntry { name, extension, format, mask, bits }
+}
+
+// The masks of the encoding formats.
+const OPCODE: u32 = 0x0000_007f;
+const FUNCT3: u32 = 0x0000_707f;
+const FUNCT7: u32 = 0xfe00_707f;
+const SHAMT6: u32 = 0xfc00_707f;
+const AMO: u32 = 0xf800_707f;
+const EXACT: u32 = 0xffff_ffff;
+
+pub const INSTRUCTIONS: &[InstEntry] = &[
+    // RV32I/RV64I
+    entry("lui", Rv64I, U, OPCODE, 0x0000_0037),
+    entry("auipc", Rv64I, U, OPCODE, 0x0000_0017),
+    entry("jal", Rv64I, J, OPCODE, 0x0000_006f),
+    entry("jalr", Rv64I, I, FUNCT3, 0x0000_0067),
+    entry("beq", Rv64I, B, FUNCT3, 0x0000_0063),
+    entry("bne", Rv64I, B, FUNCT3, 0x0000_1063),
+    entry("blt", Rv64I, B, FUNCT3, 0x0000_4063),
+    entry("bge", Rv64I, B, FUNCT3, 0x0000_5063),
+    entry("bltu", Rv64I, B, FUNCT3, 0x0000_6063),
+    entry("bgeu", Rv64I, B, FUNCT3, 0x0000_7063),
+    entry("lb", Rv64I, I, FUNCT3, 0x0000_0003),
+    entry("lh", Rv64I, I, FUNCT3, 0x0000_1003),
+    entry("lw", Rv64I, I, FUNCT3, 0x0000_2003),
+    entry("ld", Rv64I, I, FUNCT3, 0x0000_3003),
+    entry("lbu", Rv64I, I, FUNCT3, 0x0000_4003),
+    entry("lhu", Rv64I, I, FUNCT3, 0x0000_5003),
+    entry("lwu", Rv64I, I, FUNCT3, 0x0000_6003),
+    entry("sb", Rv64I, S, FUNCT3, 0x0000_0023),
+    entry("sh", Rv64I, S, FUNCT3, 0x0000_1023),
+    entry("sw", Rv64I, S, FUNCT3, 0x0000_2023),
+    entry("sd", Rv64I, S, FUNCT3, 0x0000_3023),
+    entry("addi", Rv64I, I, FUNCT3, 0x0000_0013),
+    entry("slti", Rv64I, I, FUNCT3, 0x0000_2013),
+    entry("sltiu", Rv64I, I, FUNCT3, 0x0000_3013),
+    entry("xori", Rv64I, I, FUNCT3, 0x0000_4013),
+    entry("ori", Rv64I, I, FUNCT3, 0x0000_6013),
+    entry("andi", Rv64I, I, FUNCT3, 0x0000_7013),
+    entry("slli", Rv64I, I, SHAMT6, 0x0000_1013),
+    entry("srli", Rv64I, I, SHAMT6, 0x0000_5013),
+    entry("srai", Rv64I, I, SHAMT6, 0x4000_5013),
+    entry("add", Rv64I, R, FUNCT7, 0x0000_0033),
+    entry("sub", Rv64I, R, FUNCT7, 0x4000_0033),
+    entry("sll", Rv64I, R, FUNCT7, 0x0000_1033),
+    entry("slt", Rv64I, R, FUNCT7, 0x0000_2033),
+    entry("sltu", Rv64I, R, FUNCT7, 0x0000_3033),
+    entry("xor", Rv64I, R, FUNCT7, 0x0000_4033),
+    entry("srl", Rv64I, R, FUNCT7, 0x0000_5033),
+    entry("sra", Rv64I, R, FUNCT7, 0x4000_5033),
+    entry("or", Rv64I, R, FUNCT7, 0x0000_6033),
+    entry("and", Rv64I, R, FUNCT7, 0x0000_7033),
+    entry("addiw", Rv64I, I, FUNCT3, 0x0000_001b),
+    entry("slliw", Rv64I, I, FUNCT7, 0x0000_101b),
+    entry("srliw", Rv64I, I, FUNCT7, 0x0000_501b),
+    entry("sraiw", Rv64I, I, FUNCT7, 0x4000_501b),
+    entry("addw", Rv64I, R, FUNCT7, 0x0000_003b),
+    entry("subw", Rv64I, R, FUNCT7, 0x4000_003b),
+    entry("sllw", Rv64I, R, FUNCT7, 0x0000_103b),
+    entry("srlw", Rv64I, R, FUNCT7, 0x0000_503b),
+    entry("sraw", Rv64I, R, FUNCT7, 0x4000_503b),
+    entry("fence", Rv64I, I, FUNCT3, 0x0000_000f),
+    entry("ecall", Rv64I, I, EXACT, 0x0000_0073),
+    entry("ebreak", Rv64I, I, EXACT, 0x0010_0073),
+    // Zifencei
+    entry("fence.i", Zifencei, I, FUNCT3, 0x0000_100f),
+    // Zicsr
+    entry("csrrw", Zicsr, I, FUNCT3, 0x0000_1073),
+    entry("csrrs", Zicsr, I, FUNCT3, 0x0000_2073),
+    entry("csrrc", Zicsr, I, FUNCT3, 0x0000_3073),
+    entry("csrrwi", Zicsr, I, FUNCT3, 0x0000_5073),
+    entry("csrrsi", Zicsr, I, FUNCT3, 0x0000_6073),
+    entry("csrrci", Zicsr, I, FUNCT3, 0x0000_7073),
+    // RV64M
+    entry("mul", Rv64M, R, FUNCT7, 0x0200_0033),
+    entry("mulh", Rv64M, R, FUNCT7, 0x0200_1033),
+    entry("mulhsu", Rv64M, R, FUNCT7, 0x0200_2033),
+    entry("mulhu", Rv64M, R, FUNCT7, 0x0200_3033),
+    entry("div", Rv64M, R, FUNCT7, 0x0200_4033),
+    entry("divu", Rv64M, R, FUNCT7, 0x0200_5033),
+    entry("rem", Rv64M, R, FUNCT7, 0x0200_6033),
+    entry("remu", Rv64M, R, FUNCT7, 0x0200_7033),
+    entry("mulw", Rv64M, R, FUNCT7, 0x0200_003b),
+    entry("divw", Rv64M, R, FUNCT7, 0x0200_403b),
+    entry("divuw", Rv64M, R, FUNCT7, 0x0200_503b),
+    entry("remw", Rv64M, R, FUNCT7, 0x0200_603b),
+    entry("remuw", Rv64M, R, FUNCT7, 0x0200_703b),
+    // RV64A
+    entry("lr.w", Rv64A, R, 0xf9f0_707f, 0x1000_202f),
+    entry("lr.d", Rv64A, R, 0xf9f0_707f, 0x1000_302f),
+    entry("sc.w", Rv64A, R, AMO, 0x1800_202f),
+    entry("sc.d", Rv64A, R, AMO, 0x1800_302f),
+    entry("amoadd.w", Rv64A, R, AMO, 0x0000_202f),
+    entry("amoadd.d", Rv64A, R, AMO, 0x0000_302f),
+    entry("amoswap.w", Rv64A, R, AMO, 0x0800_202f),
+    entry("amoswap.d", Rv64A, R, AMO, 0x0800_302f),
+    entry("amoxor.w", Rv64A, R, AMO, 0x2000_202f),
+    entry("amoxor.d", Rv64A, R, AMO, 0x2000_302f),
+    entry("amoand.w", Rv64A, R, AMO, 0x6000_202f),
+    entry("amoand.d", Rv64A, R, AMO, 0x6000_302f),
+    entry("amoor.w", Rv64A, R, AMO, 0x4000_202f),
+    entry("amoor.d", Rv64A, R, AMO, 0x4000_302f),
+    entry("amomin.w", Rv64A, R, AMO, 0x8000_202f),
+    entry("amomin.d", Rv64A, R, AMO, 0x8000_302f),
+    entry("amomax.w", Rv64A, R, AMO, 0xa000_202f),
+    entry("amomax.d", Rv64A, R, AMO, 0xa000_302f),
+    entry("amominu.w", Rv64A, R, AMO, 0xc000_202f),
+    entry("amominu.d", Rv64A, R, AMO, 0xc000_302f),
+    entry("amomaxu.w", Rv64A, R, AMO, 0xe000_202f),
+    entry("amomaxu.d", Rv64A, R, AMO, 0xe000_302f),
+    // RV64C, quadrant 0
+    entry("c.addi4spn", Rv64C, CIW, 0xe003, 0x0000),
+    entry("c.lw", Rv64C, CL, 0xe003, 0x4000),
+    entry("c.ld", Rv64C, CL, 0xe003, 0x6000),
+    entry("c.sw", Rv64C, CS, 0xe003, 0xc000),
+    entry("c.sd", Rv64C, CS, 0xe003, 0xe000),
+    // RV64C, quadrant 1
+    entry("c.addi", Rv64C, CI, 0xe003, 0x0001),
+    entry("c.addiw", Rv64C, CI, 0xe003, 0x2001),
+    entry("c.li", Rv64C, CI, 0xe003, 0x4001),
+    entry("c.addi16sp", Rv64C, CI, 0xef83, 0x6101),
+    entry("c.lui", Rv64C, CI, 0xe003, 0x6001),
+    entry("c.srli", Rv64C, CB, 0xec03, 0x8001),
+    entry("c.srai", Rv64C, CB, 0xec03, 0x8401),
+    entry("c.andi", Rv64C, CB, 0xec03, 0x8801),
+    entry("c.sub", Rv64C, CA, 0xfc63, 0x8c01),
+    entry("c.xor", Rv64C, CA, 0xfc63, 0x8c21),
+    entry("c.or", Rv64C, CA, 0xfc63, 0x8c41),
+    entry("c.and", Rv64C, CA, 0xfc63, 0x8c61),
+    entry("c.subw", Rv64C, CA, 0xfc63, 0x9c01),
+    entry("c.addw", Rv64C, CA, 0xfc63, 0x9c21),
+    entry("c.j", Rv64C, CJ, 0xe003, 0xa001),
+    entry("c.beqz", Rv64C, CB, 0xe003, 0xc001),
+    entry("c.bnez", Rv64C, CB, 0xe003, 0xe001),
+    // RV64C, quadrant 2
+    entry("c.slli", Rv64C, CI, 0xe003, 0x0002),
+    entry("c.lwsp", Rv64C, CI, 0xe003, 0x4002),
+    entry("c.ldsp", Rv64C, CI, 0xe003, 0x6002),
+    entry("c.jr", Rv64C, CR, 0xf07f, 0x8002),
+    entry("c.mv", Rv64C, CR, 0xf003, 0x8002),
+    entry("c.ebreak", Rv64C, CR, 0xffff, 0x9002),
+    entry("c.jalr", Rv64C, CR, 0xf07f, 0x9002),
+    entry("c.add", Rv64C, CR, 0xf003, 0x9002),
+    entry("c.swsp", Rv64C, CSS, 0xe003, 0xc002),
+    entry("c.sdsp", Rv64C, CSS, 0xe003, 0xe002),
+    // Privileged
+    entry("sret", Privileged, I, EXACT, 0x1020_0073),
+    entry("mret", Privileged, I, EXACT, 0x3020_0073),
+    entry("wfi", Privileged, I, EXACT, 0x1050_0073),
+    entry("sfence.vma", Privileged, R, 0xfe00_7fff, 0x1200_0073),
+];
+
+/// A decoded instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    /// The entry of `INSTRUCTIONS` it matches, if any.
+    pub entry: Option<&'static InstEntry>,
+    /// Its length in bytes, 2 if it is compressed.
+    pub len: u64,
+}
+
+/// Decode the instruction `inst`. A compressed one is in the low 16 bits.
+pub fn decode(inst: u32) -> Instruction {
+    let len = if inst & 0b11 == 0b11 { 4 } else { 2 };
+    Instruction { entry: lookup(inst).map(|i| &INSTRUCTIONS[i]), len }
+}
+
+/// The index in `INSTRUCTIONS` of the instruction encoded by `inst`, if any.
+pub fn lookup(inst: u32) -> Option<usize> {
+    INSTRUCTIONS.iter().position(|e| e.matches(inst))
+}
+
+/// The fields of a 32-bit instruction, picked out of the encoding once for
+/// `Cpu::execute` and the disassembler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedInst {
+    /// Bits 6:0.
+    pub opcode: u64,
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+    pub funct3: u64,
+    pub funct7: u64,
+    /// The format of the major opcode, `None` for one the cpu doesn't implement.
+    pub format: Option<Format>,
+    /// The immediate of the format, sign-extended, or 0 if it has none. The one of
+    /// U-type is already in bits 31:12.
+    pub imm: u64,
+}
+
+/// Split the 32-bit instruction `inst` into its fields. It is not looked up in
+/// `INSTRUCTIONS`, the format follows from the opcode alone.
+#[inline(always)]
+pub fn decode_fields(inst: u32) -> DecodedInst {
+    let opcode = inst & 0x7f;
+    let inst = inst as u64;
+    let (format, imm) = match opcode {
+        // imm[11:0] = inst[31:20]
+        0x03 | 0x0f | 0x13 | 0x1b | 0x67 | 0x73 => (Some(I), ((inst as i32 as i64) >> 20) as u64),
+        // imm[11:5|4:0] = inst[31:25|11:7], the mask leaves bits 4:0 of the high part 0.
+        0x23 => (Some(S), (((inst & 0xfe00_0000) as i32 as i64 >> 20) as u64) | ((inst >> 7) & 0x1f)),
+        // imm[12|10:5|4:1|11] = inst[31|30:25|11:8|7]
+        0x63 => {
+            let imm = (((inst & 0x8000_0000) as i32 as i64 >> 19) as u64)
+                | ((inst & 0x80) << 4) // imm[11]
+                | ((inst >> 20) & 0x7e0) // imm[10:5]
+                | ((inst >> 7) & 0x1e); // imm[4:1]
+            (Some(B), imm)
+        }
+        // imm[31:12] = inst[31:12]
+        0x17 | 0x37 => (Some(U), (inst & 0xffff_f000) as i32 as i64 as u64),
+        // imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
+        0x6f => {
+            let imm = (((inst & 0x8000_0000) as i32 as i64 >> 11) as u64) // imm[20]
+                | (inst & 0xff000) // imm[19:12]
+                | ((inst >> 9) & 0x800) // imm[11]
+                | ((inst >> 20) & 0x7fe); // imm[10:1]
+            (Some(J), imm)
+        }
+        0x2f | 0x33 | 0x3b => (Some(R), 0),
+        _ => (None, 0),
+    };
+    DecodedInst {
+        opcode: opcode as u64,
+        rd: ((inst >> 7) & 0x1f) as usize,
+        rs1: ((inst >> 15) & 0x1f) as usize,
+        rs2: ((inst >> 20) & 0x1f) as usize,
+        funct3: (inst >> 12) & 0x7,
+        funct7: inst >> 25,
+        format,
+        imm,
+    }
+}
+
+/// An instruction of `INSTRUCTIONS` as data for tools: its mnemonic and the fields
+/// that are fixed in its encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionSpec {
+    pub mnemonic: &'static str,
+    /// The major opcode, bits 6:0. For a compressed instruction, the quadrant in bits 1:0.
+    pub opcode: u8,
+    /// Bits 14:12, or bits 15:13 of a compressed instruction, if they are fixed.
+    pub funct3: Option<u8>,
+    /// Bits 31:25, if they are all fixed.
+    pub funct7: Option<u8>,
+    pub format: Format,
+    pub extension: Extension,
+}
+
+impl InstructionSpec {
+    const fn new(entry: &InstEntry) -> Self {
+        let compressed = entry.bits & 0b11 != 0b11;
+        let (opcode_mask, funct3_shift) = if compressed { (0b11, 13) } else { (0x7f, 12) };
+        let funct3 = if (entry.mask >> funct3_shift) & 0x7 == 0x7 {
+            Some(((entry.bits >> funct3_shift) & 0x7) as u8)
+        } else {
+            None
+        };
+        let funct7 = if !compressed && entry.mask >> 25 == 0x7f {
+            Some((entry.bits >> 25) as u8)
+        } else {
+            None
+        };
+        Self {
+            mnemonic: entry.name,
+            opcode: (entry.bits & opcode_mask) as u8,
+            funct3,
+            funct7,
+            format: entry.format,
+            extension: entry.extension,
+        }
+    }
+}
+
+static INSTRUCTION_TABLE: [InstructionSpec; INSTRUCTIONS.len()] = {
+    let mut table = [InstructionSpec::new(&INSTRUCTIONS[0]); INSTRUCTIONS.len()];
+    let mut i = 1;
+    while i < INSTRUCTIONS.len() {
+        table[i] = InstructionSpec::new(&INSTRUCTIONS[i]);
+        i += 1;
+    }
+    table
+};
+
+/// Every instruction the cpu implements, in the order of `INSTRUCTIONS`.
+pub fn instruction_table() -> &'static [InstructionSpec] {
+    &INSTRUCTION_TABLE
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_fields() {
+        // Encoded by llvm-mc.
+        let neg = |imm: i64| imm as u64;
+        // addi a0, a1, -5
+        let addi = decode_fields(0xffb58513);
+        assert_eq!((addi.opcode, addi.rd, addi.rs1, addi.funct3), (0x13, 10, 11, 0));
+        assert_eq!((addi.format, addi.imm), (Some(I), neg(-5)));
+        // sd ra, -8(sp)
+        let sd = decode_fields(0xfe113c23);
+        assert_eq!((sd.rs1, sd.rs2, sd.funct3, sd.format, sd.imm), (2, 1, 3, Some(S), neg(-8)));
+        // sd ra, -4(sp), sw ra, -2048(sp) and sb ra, 2047(sp)
+        assert_eq!(decode_fields(0xfe113e23).imm, neg(-4));
+        assert_eq!(decode_fields(0x80112023).imm, neg(-2048));
+        assert_eq!(decode_fields(0x7e110fa3).imm, 2047);
+        // bne a0, a1, -2048 and bgeu a0, a1, 4094
+        assert_eq!(decode_fields(0x80b510e3).imm, neg(-2048));
+        assert_eq!(decode_fields(0x7eb57fe3).imm, 4094);
+        // lui a0, 0x80000
+        assert_eq!(decode_fields(0x80000537).imm, 0xffff_ffff_8000_0000);
+        // jal ra, -1048576 and jal zero, 2048
+        assert_eq!(decode_fields(0x800000ef).imm, neg(-1048576));
+        assert_eq!(decode_fields(0x0010006f).imm, 2048);
+        // sub a0, a1, a2
+        let sub = decode_fields(0x40c58533);
+        assert_eq!((sub.rd, sub.rs1, sub.rs2, sub.funct7, sub.format, sub.imm), (10, 11, 12, 0x20, Some(R), 0));
+        // Not an opcode of the cpu.
+        assert_eq!(decode_fields(0x0000_0007).format, None);
+    }
+
+    #[test]
+    fn test_instruction_table() {
+        let table = instruction_table();
+        assert_eq!(table.len(), INSTRUCTIONS.len());
+
+        let addi = table.iter().find(|spec| spec.mnemonic == "addi").unwrap();
+        assert_eq!(addi.opcode, 0x13);
+        assert_eq!(addi.funct3, Some(0));
+        assert_eq!(addi.funct7, None);
+        assert_eq!((addi.format, addi.extension), (Format::I, Extension::Rv64I));
+
+        let mul = table.iter().find(|spec| spec.mnemonic == "mul").unwrap();
+        assert_eq!((mul.opcode, mul.funct3, mul.funct7), (0x33, Some(0), Some(1)));
+
+        for (i, spec) in table.iter().enumerate() {
+            assert!(
+                table[..i].iter().all(|other| other.mnemonic != spec.mnemonic),
+                "{} is in the table twice",
+                spec.mnemonic
+            );
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! The disasm module turns instructions back into assembly, for traces and debugging.
+//!
+//! Instructions are identified with `decode::INSTRUCTIONS`. Like `objdump`, the output
+//! uses the ABI register names and prefers a pseudo-instruction (`li`, `mv`, `ret`, ...)
+//! where one exists. Branch and jump offsets are relative to the instruction.
+
+use crate::cpu::{decompress, RVABI};
+use crate::decode;
+
+/// The register of a 3-bit compressed register field, one of x8-x15.
+fn creg(field: u32) -> &'static str {
+    RVABI[(field & 0x7) as usize + 8]
+}
+
+/// The assembly of `inst`, or a `.word` directive if it is not an instruction the cpu
+/// implements.
+pub fn disassemble(inst: u32) -> String {
+    let name = match decode::lookup(inst) {
+        Some(i) => decode::INSTRUCTIONS[i].name,
+        None => return format!(".word {:#010x}", inst),
+    };
+    let d = decode::decode_fields(inst);
+    let (rd, rs1, rs2) = (RVABI[d.rd], RVABI[d.rs1], RVABI[d.rs2]);
+    let imm = d.imm as i64;
+    let csr = inst >> 20;
+
+    match name {
+        "lui" | "auipc" => format!("{} {}, {:#x}", name, rd, inst >> 12),
+        "jal" if d.rd == 0 => format!("j {}", imm),
+        "jal" => format!("jal {}, {}", rd, imm),
+        "jalr" if d.rd == 0 && d.rs1 == 1 && imm == 0 => String::from("ret"),
+        "jalr" => format!("jalr {}, {}({})", rd, imm, rs1),
+        "beq" | "bne" if d.rs2 == 0 => format!("{}z {}, {}", name, rs1, imm),
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => format!("{} {}, {}, {}", name, rs1, rs2, imm),
+        "lb" | "lh" | "lw" | "ld" | "lbu" | "lhu" | "lwu" => format!("{} {}, {}({})", name, rd, imm, rs1),
+        "sb" | "sh" | "sw" | "sd" => format!("{} {}, {}({})", name, rs2, imm, rs1),
+        "addi" if d.rs1 == 0 => format!("li {}, {}", rd, imm),
+        "addi" if imm == 0 => format!("mv {}, {}", rd, rs1),
+        "addi" | "slti" | "sltiu" | "xori" | "ori" | "andi" | "addiw" => format!("{} {}, {}, {}", name, rd, rs1, imm),
+        "slli" | "srli" | "srai" => format!("{} {}, {}, {}", name, rd, rs1, imm & 0x3f),
+        "slliw" | "srliw" | "sraiw" => format!("{} {}, {}, {}", name, rd, rs1, imm & 0x1f),
+        "fence" | "fence.i" | "ecall" | "ebreak" | "sret" | "mret" | "wfi" | "c.ebreak" => String::from(name),
+        "sfence.vma" => format!("{} {}, {}", name, rs1, rs2),
+        "csrrs" if d.rs1 == 0 => format!("csrr {}, {:#x}", rd, csr),
+        "csrrw" | "csrrs" | "csrrc" => format!("{} {}, {:#x}, {}", name, rd, csr, rs1),
+        "csrrwi" | "csrrsi" | "csrrci" => format!("{} {}, {:#x}, {}", name, rd, csr, d.rs1),
+        "lr.w" | "lr.d" => format!("{} {}, ({})", name, rd, rs1),
+        _ if name.starts_with("sc.") || name.starts_with("amo") => format!("{} {}, {}, ({})", name, rd, rs2, rs1),
+        "c.jr" | "c.jalr" => format!("{} {}", name, rd),
+        "c.mv" | "c.add" => format!("{} {}, {}", name, rd, RVABI[((inst >> 2) & 0x1f) as usize]),
+        "c.sub" | "c.xor" | "c.or" | "c.and" | "c.subw" | "c.addw" => {
+            format!("{} {}, {}", name, creg(inst >> 7), creg(inst >> 2))
+        }
+        // The other compressed instructions are shown as what they expand to, as `objdump` does.
+        _ if name.starts_with("c.") => match decompress(inst as u16) {
+            Some(expanded) => disassemble(expanded),
+            None => format!(".word {:#010x}", inst),
+        },
+        // The R-type instructions.
+        _ => format!("{} {}, {}, {}", name, rd, rs1, rs2),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pseudo_instructions() {
+        assert_eq!(disassemble(0x00500513), "li a0, 5");
+        assert_eq!(disassemble(0x00008067), "ret");
+        assert_eq!(disassemble(0x00058513), "mv a0, a1");
+        assert_eq!(disassemble(0x0080006f), "j 8");
+        assert_eq!(disassemble(0xfe050ee3), "beqz a0, -4");
+        assert_eq!(disassemble(0xf1402573), "csrr a0, 0xf14");
+
+        // Without the special operands they are the instructions themselves.
+        assert_eq!(disassemble(0x00158513), "addi a0, a1, 1");
+        assert_eq!(disassemble(0x000080e7), "jalr ra, 0(ra)");
+        assert_eq!(disassemble(0xfeb50ee3), "beq a0, a1, -4");
+    }
+
+    #[test]
+    fn test_instructions() {
+        // Encoded by llvm-mc.
+        assert_eq!(disassemble(0x00113423), "sd ra, 8(sp)");
+        assert_eq!(disassemble(0xff043503), "ld a0, -16(s0)");
+        assert_eq!(disassemble(0x00452583), "lw a1, 4(a0)");
+        assert_eq!(disassemble(0x02c58533), "mul a0, a1, a2");
+        assert_eq!(disassemble(0x02c5d533), "divu a0, a1, a2");
+        assert_eq!(disassemble(0x00b6252f), "amoadd.w a0, a1, (a2)");
+        assert_eq!(disassemble(0x1005b52f), "lr.d a0, (a1)");
+        assert_eq!(disassemble(0x30059573), "csrrw a0, 0x300, a1");
+        assert_eq!(disassemble(0x30046073), "csrrsi zero, 0x300, 8");
+        assert_eq!(disassemble(0x00b56863), "bltu a0, a1, 16");
+        assert_eq!(disassemble(0x02051513), "slli a0, a0, 32");
+        assert_eq!(disassemble(0x80000537), "lui a0, 0x80000");
+        assert_eq!(disassemble(0x4035d51b), "sraiw a0, a1, 3");
+        assert_eq!(disassemble(0x414c), "lw a1, 4(a0)");
+        assert_eq!(disassemble(0xfd75), "bnez a0, -4");
+        assert_eq!(disassemble(0x852e), "c.mv a0, a1");
+    }
+
+    #[test]
+    fn test_unknown() {
+        assert_eq!(disassemble(0xffffffff), ".word 0xffffffff");
+        assert_eq!(disassemble(0x00000000), ".word 0x00000000");
+        // c.lwsp with rd = x0 is reserved.
+        assert_eq!(disassemble(0x4002), ".word 0x00004002");
+    }
+}
@@ -12,45 +12,107 @@ pub struct Dram {
 impl Dram {
     /// Create a new dram with the given code
     pub fn new(code: Vec<u8>) -> Dram {
-        let mut dram = vec![0; DRAM_SIZE as usize];
+        Self::with_size(code, DRAM_SIZE as usize)
+    }
+
+    /// Create a dram of `size` bytes with the given code at its start. Panics if the
+    /// code is larger than dram.
+    pub fn with_size(code: Vec<u8>, size: usize) -> Dram {
+        assert!(code.len() <= size, "code of {:#x} bytes doesn't fit in dram of {:#x} bytes", code.len(), size);
+        let mut dram = vec![0; size];
         dram[..code.len()].copy_from_slice(&code);
         Self { dram }
     }
 
-    /// Load data of size from addr in memory
-    // addr/size must be valid. Check in bus
+    /// The index range in `dram` of `size` bits at `addr`, if all of them are in dram.
+    fn range(&self, addr: u64, size: u64) -> Option<std::ops::Range<usize>> {
+        let index = usize::try_from(addr.checked_sub(DRAM_BASE)?).ok()?;
+        let end = index.checked_add(size as usize / 8)?;
+        (end <= self.dram.len()).then_some(index..end)
+    }
+
+    /// Load data of size from addr in memory. An access that isn't all in dram
+    /// raises an access fault.
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
         if ![8, 16, 32, 64].contains(&size) {
             return Err(Exception::LoadAccessFault(addr));
         }
+        let Some(range) = self.range(addr, size) else {
+            return Err(Exception::LoadAccessFault(addr));
+        };
 
-        let nbytes = size / 8;
-        let index = (addr - DRAM_BASE) as usize;
         let mut code: u64 = 0;
-        (0..nbytes).for_each(|i| {
-            code |= (self.dram[index + i as usize] as u64) << (8 * i);
+        self.dram[range].iter().enumerate().for_each(|(i, &byte)| {
+            code |= (byte as u64) << (8 * i);
         });
 
         Ok(code)
     }
 
-    /// Store value of size to addr in memory
-    // addr/size must be valid. Check in bus
+    /// Store value of size to addr in memory. An access that isn't all in dram
+    /// raises an access fault.
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         if ![8, 16, 32, 64].contains(&size) {
             return Err(Exception::StoreAMOAccessFault(addr));
         }
+        let Some(range) = self.range(addr, size) else {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        };
 
-        let nbytes = size / 8;
-        let index = (addr - DRAM_BASE) as usize;
-        (0..nbytes).for_each(|i| {
-            self.dram[index + i as usize] = ((value >> (8 * i)) & 0xff) as u8;
+        self.dram[range].iter_mut().enumerate().for_each(|(i, byte)| {
+            *byte = ((value >> (8 * i)) & 0xff) as u8;
         });
         Ok(())
     }
 
+    /// Copy `bytes` to physical address `paddr`, as a loader places a segment. Bytes
+    /// that don't all fit in dram raise an access fault and nothing is written.
+    pub fn load_segment(&mut self, paddr: u64, bytes: &[u8]) -> Result<(), Exception> {
+        let start = paddr
+            .checked_sub(DRAM_BASE)
+            .and_then(|offset| usize::try_from(offset).ok())
+            .filter(|&start| start.checked_add(bytes.len()).is_some_and(|end| end <= self.dram.len()));
+        let Some(start) = start else {
+            return Err(Exception::StoreAMOAccessFault(paddr));
+        };
+        self.dram[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
     /// Return dram size
     pub fn len(&self) -> usize {
         self.dram.len()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::param::DRAM_END;
+
+    #[test]
+    fn test_bounds() {
+        let mut dram = Dram::new(vec![]);
+        dram.store(DRAM_END - 3, 32, 0x1234_5678).unwrap();
+        assert_eq!(dram.load(DRAM_END - 3, 32), Ok(0x1234_5678));
+        // Past the end and below the start.
+        assert_eq!(dram.load(DRAM_END - 4, 64), Err(Exception::LoadAccessFault(DRAM_END - 4)));
+        assert_eq!(dram.store(DRAM_END, 16, 0), Err(Exception::StoreAMOAccessFault(DRAM_END)));
+        assert_eq!(dram.load(DRAM_BASE - 4, 32), Err(Exception::LoadAccessFault(DRAM_BASE - 4)));
+        assert_eq!(dram.load(u64::MAX, 64), Err(Exception::LoadAccessFault(u64::MAX)));
+        assert_eq!(dram.load(DRAM_END - 3, 32), Ok(0x1234_5678));
+    }
+
+    #[test]
+    fn test_load_segment() {
+        let mut dram = Dram::with_size(vec![], 0x1000);
+        assert_eq!(dram.load_segment(DRAM_BASE + 0x200, &[1, 2, 3, 4]), Ok(()));
+        assert_eq!(dram.load_segment(DRAM_BASE + 0xffe, &[5, 6]), Ok(()));
+        assert_eq!(dram.load(DRAM_BASE + 0x200, 32), Ok(0x0403_0201));
+        assert_eq!(dram.load(DRAM_BASE + 0xffe, 16), Ok(0x0605));
+        // Nothing is written unless all of it fits.
+        assert_eq!(dram.load_segment(DRAM_BASE + 0xfff, &[7, 8]), Err(Exception::StoreAMOAccessFault(DRAM_BASE + 0xfff)));
+        assert_eq!(dram.load(DRAM_BASE + 0xfff, 8), Ok(6));
+        assert_eq!(dram.load_segment(DRAM_BASE - 1, &[7]), Err(Exception::StoreAMOAccessFault(DRAM_BASE - 1)));
+    }
+}
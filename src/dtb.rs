@@ -0,0 +1,305 @@
+//! The dtb module generates a flattened device tree (FDT), the blob firmware passes to
+//! the kernel in a1 to describe the machine. The layout follows chapter 5 of the
+//! Devicetree Specification v0.4, and the nodes are those QEMU's `virt` machine has for
+//! the same devices.
+
+use crate::param::*;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const FDT_HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+/// The frequency `mtime` is said to run at. It advances once per instruction, so this
+/// is only the rate the kernel assumes.
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+/// The clock of the UART, which drivers use to compute the divisor.
+const UART_CLOCK_FREQUENCY: u32 = 0x38_4000;
+/// The PLIC sources, 1 to 63.
+const PLIC_NDEV: u32 = 63;
+
+// The local interrupts of a hart, the cause numbers in mip.
+const IRQ_S_EXT: u32 = 9;
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+const IRQ_M_EXT: u32 = 11;
+
+/// The structure and strings blocks of a device tree under construction.
+#[derive(Default)]
+struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl Fdt {
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    /// Pad the structure block to the next 4-byte boundary, where every token starts.
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    /// The offset of `name` in the strings block, which holds each name once.
+    fn string(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        while offset < self.strings.len() {
+            let len = self.strings[offset..].iter().position(|&b| b == 0).unwrap();
+            if &self.strings[offset..offset + len] == name.as_bytes() {
+                return offset as u32;
+            }
+            offset += len + 1;
+        }
+        let offset = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        offset as u32
+    }
+
+    fn prop(&mut self, name: &str, value: &[u8]) {
+        let name = self.string(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(name);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    fn prop_str(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.prop(name, &bytes);
+    }
+
+    fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.prop(name, &bytes);
+    }
+
+    /// A `reg` of one region, with 2 address and 2 size cells.
+    fn prop_reg(&mut self, base: u64, size: u64) {
+        let cells = [(base >> 32) as u32, base as u32, (size >> 32) as u32, size as u32];
+        self.prop_cells("reg", &cells);
+    }
+
+    /// The blob: the header, an empty memory reservation block, then the structure
+    /// and strings blocks.
+    fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+        // The reservation block is 8-byte aligned and ends with an entry of zeros.
+        let off_mem_rsvmap = FDT_HEADER_SIZE.next_multiple_of(8);
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        blob.resize(off_dt_struct, 0);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// The device tree of the machine with `harts` harts from `first_hartid` on and
+/// `dram_size` bytes of dram at `DRAM_BASE`.
+pub fn generate(first_hartid: u64, harts: usize, dram_size: u64) -> Vec<u8> {
+    let mut fdt = Fdt::default();
+    // The interrupt controller of each hart has phandle 1 + its index, the PLIC the next.
+    let intc = |i: usize| 1 + i as u32;
+    let plic = intc(harts);
+
+    fdt.begin_node("");
+    fdt.prop_cells("#address-cells", &[2]);
+    fdt.prop_cells("#size-cells", &[2]);
+    fdt.prop_str("compatible", "riscv-virtio");
+    fdt.prop_str("model", "riscv-virtio,qemu");
+
+    fdt.begin_node("chosen");
+    fdt.prop_str("stdout-path", &format!("/soc/serial@{:x}", UART_BASE));
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", DRAM_BASE));
+    fdt.prop_str("device_type", "memory");
+    fdt.prop_reg(DRAM_BASE, dram_size);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.prop_cells("#address-cells", &[1]);
+    fdt.prop_cells("#size-cells", &[0]);
+    fdt.prop_cells("timebase-frequency", &[TIMEBASE_FREQUENCY]);
+    for i in 0..harts {
+        let hartid = first_hartid + i as u64;
+        fdt.begin_node(&format!("cpu@{:x}", hartid));
+        fdt.prop_str("device_type", "cpu");
+        fdt.prop_cells("reg", &[hartid as u32]);
+        fdt.prop_str("status", "okay");
+        fdt.prop_str("compatible", "riscv");
+        fdt.prop_str("riscv,isa", "rv64imac_zicsr_zifencei");
+        fdt.prop_str("mmu-type", "riscv,sv39");
+        fdt.begin_node("interrupt-controller");
+        fdt.prop_cells("#interrupt-cells", &[1]);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_str("compatible", "riscv,cpu-intc");
+        fdt.prop_cells("phandle", &[intc(i)]);
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.prop_cells("#address-cells", &[2]);
+    fdt.prop_cells("#size-cells", &[2]);
+    fdt.prop_str("compatible", "simple-bus");
+    fdt.prop_empty("ranges");
+
+    fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+    fdt.prop_str("compatible", "riscv,clint0");
+    fdt.prop_reg(CLINT_BASE, CLINT_SIZE);
+    let cells: Vec<u32> = (0..harts).flat_map(|i| [intc(i), IRQ_M_SOFT, intc(i), IRQ_M_TIMER]).collect();
+    fdt.prop_cells("interrupts-extended", &cells);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+    fdt.prop_str("compatible", "riscv,plic0");
+    fdt.prop_cells("#address-cells", &[0]);
+    fdt.prop_cells("#interrupt-cells", &[1]);
+    fdt.prop_empty("interrupt-controller");
+    fdt.prop_reg(PLIC_BASE, PLIC_SIZE);
+    fdt.prop_cells("riscv,ndev", &[PLIC_NDEV]);
+    // Context 2 * i is the M-mode context of hart i, 2 * i + 1 its S-mode one.
+    let cells: Vec<u32> = (0..harts).flat_map(|i| [intc(i), IRQ_M_EXT, intc(i), IRQ_S_EXT]).collect();
+    fdt.prop_cells("interrupts-extended", &cells);
+    fdt.prop_cells("phandle", &[plic]);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("serial@{:x}", UART_BASE));
+    fdt.prop_str("compatible", "ns16550a");
+    fdt.prop_reg(UART_BASE, UART_SIZE);
+    fdt.prop_cells("clock-frequency", &[UART_CLOCK_FREQUENCY]);
+    fdt.prop_cells("interrupts", &[UART_IRQ as u32]);
+    fdt.prop_cells("interrupt-parent", &[plic]);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("virtio_mmio@{:x}", VIRTIO_BASE));
+    fdt.prop_str("compatible", "virtio,mmio");
+    fdt.prop_reg(VIRTIO_BASE, VIRTIO_SIZE);
+    fdt.prop_cells("interrupts", &[VIRTIO_IRQ as u32]);
+    fdt.prop_cells("interrupt-parent", &[plic]);
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.end_node();
+    fdt.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Every property in `blob` as its node path, name and value.
+    fn props(blob: &[u8]) -> Vec<(String, String, Vec<u8>)> {
+        let (structure, strings) = (be32(blob, 8) as usize, be32(blob, 12) as usize);
+        let string = |offset: usize| {
+            let s = &blob[strings + offset..];
+            String::from_utf8(s[..s.iter().position(|&b| b == 0).unwrap()].to_vec()).unwrap()
+        };
+        let mut path: Vec<String> = Vec::new();
+        let mut props = Vec::new();
+        let mut offset = structure;
+        loop {
+            let token = be32(blob, offset);
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let len = blob[offset..].iter().position(|&b| b == 0).unwrap();
+                    path.push(String::from_utf8(blob[offset..offset + len].to_vec()).unwrap());
+                    offset = (offset + len + 1).next_multiple_of(4);
+                }
+                FDT_END_NODE => {
+                    path.pop();
+                }
+                FDT_PROP => {
+                    let (len, name) = (be32(blob, offset) as usize, be32(blob, offset + 4) as usize);
+                    let value = blob[offset + 8..offset + 8 + len].to_vec();
+                    props.push((path.join("/"), string(name), value));
+                    offset = (offset + 8 + len).next_multiple_of(4);
+                }
+                FDT_END => return props,
+                _ => panic!("bad token {:#x} at {:#x}", token, offset - 4),
+            }
+        }
+    }
+
+    fn prop(blob: &[u8], path: &str, name: &str) -> Vec<u8> {
+        props(blob).into_iter().find(|(p, n, _)| p == path && n == name).unwrap().2
+    }
+
+    #[test]
+    fn test_header() {
+        let blob = generate(0, 1, DRAM_SIZE);
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!(be32(&blob, 20), 17);
+        // The reservation block has only its terminator.
+        let rsvmap = be32(&blob, 16) as usize;
+        assert_eq!(rsvmap % 8, 0);
+        assert_eq!(blob[rsvmap..rsvmap + 16], [0; 16]);
+    }
+
+    #[test]
+    fn test_nodes() {
+        let blob = generate(1, 2, 64 * 1024 * 1024);
+        // The memory node has the size of dram, in 2 address and 2 size cells.
+        assert_eq!(prop(&blob, "/memory@80000000", "reg"), [0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x04, 0, 0, 0]);
+        assert_eq!(prop(&blob, "/memory@80000000", "device_type"), b"memory\0");
+
+        assert_eq!(prop(&blob, "/cpus/cpu@1", "reg"), [0, 0, 0, 1]);
+        assert_eq!(prop(&blob, "/cpus/cpu@2", "reg"), [0, 0, 0, 2]);
+        assert_eq!(prop(&blob, "/soc/serial@10000000", "compatible"), b"ns16550a\0");
+        assert_eq!(prop(&blob, "/soc/serial@10000000", "interrupts"), [0, 0, 0, 10]);
+        // The UART's interrupts go to the PLIC, which is after the harts' controllers.
+        assert_eq!(prop(&blob, "/soc/serial@10000000", "interrupt-parent"), [0, 0, 0, 3]);
+        assert_eq!(prop(&blob, "/soc/plic@c000000", "phandle"), [0, 0, 0, 3]);
+        assert_eq!(prop(&blob, "/soc/clint@2000000", "interrupts-extended").len(), 2 * 4 * 4);
+        assert_eq!(prop(&blob, "/chosen", "stdout-path"), b"/soc/serial@10000000\0");
+    }
+}
@@ -0,0 +1,290 @@
+//! The elf module loads statically linked RISC-V ELF64 executables, so a program
+//! doesn't have to be turned into a flat binary with `llvm-objcopy -O binary` first.
+//!
+//! Only what loading needs is read: the file header and the `PT_LOAD` program headers,
+//! and the symbol table for `find_symbol`.
+
+use std::fmt;
+
+/// The magic number at the start of every ELF file.
+pub const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+/// A `PT_LOAD` segment: `data` goes to `paddr`, followed by zeros up to `mem_size` bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramSegment {
+    pub paddr: u64,
+    pub data: Vec<u8>,
+    pub mem_size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file doesn't start with the ELF magic number.
+    NotElf,
+    /// The file isn't a little-endian ELF64 file.
+    Unsupported,
+    /// The file is for another machine than RISC-V.
+    NotRiscv,
+    /// A header or segment goes past the end of the file.
+    Truncated,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Unsupported => write!(f, "not a little-endian ELF64 file"),
+            ElfError::NotRiscv => write!(f, "not a RISC-V executable"),
+            ElfError::Truncated => write!(f, "truncated ELF file"),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+/// Whether `bytes` start like an ELF file.
+pub fn is_elf(bytes: &[u8]) -> bool {
+    bytes.starts_with(ELF_MAGIC)
+}
+
+fn read<const N: usize>(bytes: &[u8], offset: u64) -> Result<[u8; N], ElfError> {
+    let start = usize::try_from(offset).map_err(|_| ElfError::Truncated)?;
+    bytes
+        .get(start..start.checked_add(N).ok_or(ElfError::Truncated)?)
+        .map(|field| field.try_into().unwrap())
+        .ok_or(ElfError::Truncated)
+}
+
+fn u16_at(bytes: &[u8], offset: u64) -> Result<u16, ElfError> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(bytes: &[u8], offset: u64) -> Result<u32, ElfError> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(bytes: &[u8], offset: u64) -> Result<u64, ElfError> {
+    read(bytes, offset).map(u64::from_le_bytes)
+}
+
+/// Parse the ELF64 executable `bytes`, returning its `PT_LOAD` segments and its entry point.
+pub fn load_elf(bytes: &[u8]) -> Result<(Vec<ProgramSegment>, u64), ElfError> {
+    if !is_elf(bytes) {
+        return Err(ElfError::NotElf);
+    }
+    if bytes.len() < EHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    if bytes[4] != ELFCLASS64 || bytes[5] != ELFDATA2LSB {
+        return Err(ElfError::Unsupported);
+    }
+    if u16_at(bytes, 18)? != EM_RISCV {
+        return Err(ElfError::NotRiscv);
+    }
+    let entry = u64_at(bytes, 24)?;
+    let phoff = u64_at(bytes, 32)?;
+    let phentsize = u16_at(bytes, 54)? as u64;
+    let phnum = u16_at(bytes, 56)? as u64;
+    if phnum > 0 && (phentsize as usize) < PHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+
+    let mut segments = Vec::new();
+    for i in 0..phnum {
+        let phdr = phoff.checked_add(i * phentsize).ok_or(ElfError::Truncated)?;
+        if u32_at(bytes, phdr)? != PT_LOAD {
+            continue;
+        }
+        let offset = u64_at(bytes, phdr + 8)?;
+        let paddr = u64_at(bytes, phdr + 24)?;
+        let file_size = u64_at(bytes, phdr + 32)?;
+        let mem_size = u64_at(bytes, phdr + 40)?;
+        let data = usize::try_from(offset)
+            .ok()
+            .zip(usize::try_from(file_size).ok())
+            .and_then(|(start, len)| bytes.get(start..start.checked_add(len)?))
+            .ok_or(ElfError::Truncated)?;
+        segments.push(ProgramSegment { paddr, data: data.to_vec(), mem_size: mem_size.max(file_size) });
+    }
+    Ok((segments, entry))
+}
+
+/// The value of the symbol `name` in the ELF64 file `bytes`, which `load_elf` accepts,
+/// or `None` if it has no such symbol or no symbol table.
+pub fn find_symbol(bytes: &[u8], name: &str) -> Result<Option<u64>, ElfError> {
+    let shoff = u64_at(bytes, 40)?;
+    let shentsize = u16_at(bytes, 58)? as u64;
+    let shnum = u16_at(bytes, 60)? as u64;
+    if shnum > 0 && (shentsize as usize) < SHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    let section = |i: u64| shoff.checked_add(i * shentsize).ok_or(ElfError::Truncated);
+
+    for i in 0..shnum {
+        let shdr = section(i)?;
+        if u32_at(bytes, shdr + 4)? != SHT_SYMTAB {
+            continue;
+        }
+        let offset = u64_at(bytes, shdr + 24)?;
+        let size = u64_at(bytes, shdr + 32)?;
+        // The names are in the string table the symbol table links to.
+        let strings = u64_at(bytes, section(u32_at(bytes, shdr + 40)? as u64)? + 24)?;
+        for sym in (offset..offset.saturating_add(size)).step_by(SYM_SIZE) {
+            let start = strings.checked_add(u32_at(bytes, sym)? as u64).ok_or(ElfError::Truncated)?;
+            let sym_name = usize::try_from(start)
+                .ok()
+                .and_then(|start| bytes.get(start..))
+                .and_then(|rest| rest.split(|&b| b == 0).next())
+                .ok_or(ElfError::Truncated)?;
+            if sym_name == name.as_bytes() {
+                return u64_at(bytes, sym + 8).map(Some);
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        builder::{BuildError, CpuBuilder},
+        cpu::HaltReason,
+        param::DRAM_BASE,
+    };
+
+    /// An ELF file with one `PT_LOAD` segment of `code` at `paddr`, followed by 16 bytes
+    /// of bss, and entry point `entry`.
+    fn elf(code: &[u32], paddr: u64, entry: u64) -> Vec<u8> {
+        let code: Vec<u8> = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        let mut bytes = vec![0; EHDR_SIZE + PHDR_SIZE];
+        bytes[..4].copy_from_slice(ELF_MAGIC);
+        bytes[4] = ELFCLASS64;
+        bytes[5] = ELFDATA2LSB;
+        bytes[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        bytes[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+        bytes[24..32].copy_from_slice(&entry.to_le_bytes());
+        bytes[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        bytes[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        bytes[56..58].copy_from_slice(&1u16.to_le_bytes());
+        let phdr = &mut bytes[EHDR_SIZE..];
+        phdr[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        phdr[8..16].copy_from_slice(&((EHDR_SIZE + PHDR_SIZE) as u64).to_le_bytes());
+        phdr[16..24].copy_from_slice(&paddr.to_le_bytes());
+        phdr[24..32].copy_from_slice(&paddr.to_le_bytes());
+        phdr[32..40].copy_from_slice(&(code.len() as u64).to_le_bytes());
+        phdr[40..48].copy_from_slice(&(code.len() as u64 + 16).to_le_bytes());
+        bytes.extend(code);
+        bytes
+    }
+
+    /// `bytes` from `elf` with a symbol table holding `name` with value `value`.
+    fn with_symbol(mut bytes: Vec<u8>, name: &str, value: u64) -> Vec<u8> {
+        let symtab = bytes.len() as u64;
+        bytes.extend([0; SYM_SIZE]);
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend([0; 4]);
+        bytes.extend(value.to_le_bytes());
+        bytes.extend([0; 8]);
+        let strtab = bytes.len() as u64;
+        bytes.push(0);
+        bytes.extend(name.as_bytes());
+        bytes.push(0);
+
+        let shoff = bytes.len() as u64;
+        // The null section, the symbol table and its string table.
+        let sections = [
+            (0u32, 0, 0, 0u32),
+            (SHT_SYMTAB, symtab, 2 * SYM_SIZE as u64, 2),
+            (3, strtab, name.len() as u64 + 2, 0),
+        ];
+        for (sh_type, offset, size, link) in sections {
+            let mut shdr = [0; SHDR_SIZE];
+            shdr[4..8].copy_from_slice(&sh_type.to_le_bytes());
+            shdr[24..32].copy_from_slice(&offset.to_le_bytes());
+            shdr[32..40].copy_from_slice(&size.to_le_bytes());
+            shdr[40..44].copy_from_slice(&link.to_le_bytes());
+            bytes.extend(shdr);
+        }
+        bytes[40..48].copy_from_slice(&shoff.to_le_bytes());
+        bytes[58..60].copy_from_slice(&(SHDR_SIZE as u16).to_le_bytes());
+        bytes[60..62].copy_from_slice(&3u16.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn test_find_symbol() {
+        let bytes = elf(&[0x00000013], DRAM_BASE, DRAM_BASE);
+        assert_eq!(find_symbol(&bytes, "tohost"), Ok(None));
+
+        let bytes = with_symbol(bytes, "tohost", DRAM_BASE + 0x1000);
+        assert_eq!(find_symbol(&bytes, "tohost"), Ok(Some(DRAM_BASE + 0x1000)));
+        assert_eq!(find_symbol(&bytes, "fromhost"), Ok(None));
+        assert_eq!(find_symbol(&bytes, "tohos"), Ok(None));
+    }
+
+    #[test]
+    fn test_load_elf() {
+        let bytes = elf(&[0x00000013], DRAM_BASE + 0x1000, DRAM_BASE + 0x1000);
+        let (segments, entry) = load_elf(&bytes).unwrap();
+        assert_eq!(entry, DRAM_BASE + 0x1000);
+        assert_eq!(
+            segments,
+            vec![ProgramSegment { paddr: DRAM_BASE + 0x1000, data: vec![0x13, 0, 0, 0], mem_size: 20 }]
+        );
+
+        assert_eq!(load_elf(b"\x7fELF"), Err(ElfError::Truncated));
+        assert_eq!(load_elf(&bytes[..bytes.len() - 1]), Err(ElfError::Truncated));
+        assert_eq!(load_elf(&[0x13, 0, 0, 0]), Err(ElfError::NotElf));
+        let mut arm = bytes.clone();
+        arm[18] = 40;
+        assert_eq!(load_elf(&arm), Err(ElfError::NotRiscv));
+    }
+
+    #[test]
+    fn test_run_elf() {
+        // The entry point is the second instruction.
+        let code = [
+            0x00100513, // li a0, 1
+            0x02a00513, // li a0, 42
+            0x00100073, // ebreak
+        ];
+        let bytes = elf(&code, DRAM_BASE + 0x1000, DRAM_BASE + 0x1004);
+        let mut cpu = CpuBuilder::new().elf(&bytes).unwrap().headless_uart(true).exit_on_ebreak(true).build();
+        assert_eq!(cpu.pc, DRAM_BASE + 0x1004);
+        assert_eq!(cpu.run(), HaltReason::Exit(42));
+
+        // riscv-tests report the result through `tohost`, here in the bss.
+        let code = [
+            0x00700313, // li t1, 7
+            0x00000297, // auipc t0, 0
+            0x0062a823, // sw t1, 16(t0)
+        ];
+        let bytes = with_symbol(elf(&code, DRAM_BASE, DRAM_BASE), "tohost", DRAM_BASE + 0x14);
+        let mut cpu = CpuBuilder::new().elf(&bytes).unwrap().headless_uart(true).build();
+        assert_eq!(cpu.run(), HaltReason::Exit(3));
+
+        let outside = elf(&code, 0x1000, 0x1000);
+        assert_eq!(
+            CpuBuilder::new().elf(&outside).unwrap().try_build().err(),
+            Some(BuildError::OutsideDram { paddr: 0x1000, mem_size: 28 })
+        );
+
+        // A dram size set after the executable is checked too.
+        let bytes = elf(&code, DRAM_BASE + 0x1000, DRAM_BASE + 0x1000);
+        assert_eq!(
+            CpuBuilder::new().elf(&bytes).unwrap().dram_size(0x1000).try_build().err(),
+            Some(BuildError::OutsideDram { paddr: DRAM_BASE + 0x1000, mem_size: 28 })
+        );
+    }
+}
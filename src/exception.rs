@@ -1,6 +1,6 @@
 use std::fmt;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Exception {
     // Riscv Standard Exception
     InstructionAddrMisaligned(u64),
@@ -27,9 +27,9 @@ impl fmt::Display for Exception {
             InstructionAccessFault(addr) => write!(f, "Instruction access fault {:#x}", addr),
             IllegalInstruction(inst) => write!(f, "Illegal instruction {:#x}, opcode = {:#x}", inst, inst & 0x7f),
             Breakpoint(pc) => write!(f, "Breakpoint {:#x}", pc),
-            LoadAccessMisaligned(addr) => write!(f, "Load access {:#x}", addr),
+            LoadAccessMisaligned(addr) => write!(f, "Load address misaligned {:#x}", addr),
             LoadAccessFault(addr) => write!(f, "Load access fault {:#x}", addr),
-            StoreAMOAddrMisaligned(addr) => write!(f, "Store or AMO address misaliged {:#x}", addr),
+            StoreAMOAddrMisaligned(addr) => write!(f, "Store or AMO address misaligned {:#x}", addr),
             StoreAMOAccessFault(addr) => write!(f, "Store or AMO access fault {:#x}", addr),
             EnvironmentCallFromUMode(pc) => write!(f, "Environment call from U-mode {:#x}", pc),
             EnvironmentCallFromSMode(pc) => write!(f, "Environment call from S-mode {:#x}", pc),
@@ -92,7 +92,6 @@ impl Exception {
             InstructionAddrMisaligned(_)
             | InstructionAccessFault(_)
             | LoadAccessFault(_)
-            | StoreAMOAddrMisaligned(_)
             | StoreAMOAccessFault(_) 
             | IllegalInstruction(_) => true,
             _else => false,
@@ -112,4 +111,14 @@ mod test {
         assert_eq!(e.value(), 0);
         assert_eq!(e.code(), 2);
     }
+
+    #[test]
+    fn test_fault_payloads() {
+        let e = Exception::StoreAMOPageFault(0x8000_1000);
+        assert_eq!((e.code(), e.value(), e.is_fatal()), (15, 0x8000_1000, false));
+        let e = Exception::StoreAMOAddrMisaligned(0x8000_0003);
+        assert_eq!((e.code(), e.value()), (6, 0x8000_0003));
+        assert_eq!(e.to_string(), "Store or AMO address misaligned 0x80000003");
+        assert!(Exception::LoadAccessFault(0).is_fatal());
+    }
 }
@@ -0,0 +1,72 @@
+//! The finisher module contains SiFive's test finisher, which the guest writes to power
+//! off or reset the machine, as on QEMU's `virt` machine.
+
+use crate::{
+    exception::Exception::{self, *},
+    param::*,
+};
+
+/// What the guest asked the finisher for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinisherRequest {
+    /// Power off with this exit code, 0 for a pass.
+    Exit(i32),
+    /// Reset the machine.
+    Reset,
+}
+
+/// A test finisher with a single 32-bit register at its base.
+///
+/// Writing `FINISHER_PASS` powers off with exit code 0, `FINISHER_FAIL | code << 16`
+/// with `code`, and `FINISHER_RESET` resets the machine. Other values are ignored and
+/// the register reads as 0.
+pub struct TestFinisher {
+    base: u64,
+    request: Option<FinisherRequest>,
+}
+
+impl TestFinisher {
+    pub fn new(base: u64) -> Self {
+        Self { base, request: None }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    /// The last address of the finisher.
+    pub fn end(&self) -> u64 {
+        self.base + TEST_FINISHER_SIZE - 1
+    }
+
+    /// The request written by the running instruction, which takes effect once it
+    /// completes.
+    #[inline]
+    pub fn take_request(&mut self) -> Option<FinisherRequest> {
+        self.request.take()
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 && size != 64 {
+            return Err(LoadAccessFault(addr));
+        }
+        Ok(0)
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 && size != 64 {
+            return Err(StoreAMOAccessFault(addr));
+        }
+        if addr != self.base {
+            return Ok(());
+        }
+        // The status is in the low 16 bits and the exit code of a failure in the next 16.
+        self.request = match value & 0xffff {
+            FINISHER_PASS => Some(FinisherRequest::Exit(0)),
+            FINISHER_FAIL => Some(FinisherRequest::Exit(((value >> 16) & 0xffff) as i32)),
+            FINISHER_RESET => Some(FinisherRequest::Reset),
+            _ => self.request,
+        };
+        Ok(())
+    }
+}
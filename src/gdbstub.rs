@@ -0,0 +1,362 @@
+//! The gdbstub module lets GDB debug the guest over TCP with the remote serial protocol:
+//!
+//! ```text
+//! (gdb) set architecture riscv:rv64
+//! (gdb) target remote :1234
+//! ```
+//!
+//! It supports the register (`g`, `G`, `p`, `P`) and memory (`m`, `M`) packets,
+//! continuing and stepping (`c`, `s`), software breakpoints (`Z0`, `z0`) and stop
+//! replies (`?`). Breakpoints are the cpu's own, see `Cpu::add_breakpoint`, and Ctrl-C
+//! interrupts a continue.
+
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::cpu::{Cpu, HaltReason, RVABI};
+
+/// Instructions a continue runs between checks for a Ctrl-C from GDB.
+const INTERRUPT_POLL: u64 = 0x10000;
+
+/// The registers GDB knows about: x0-x31 and pc, in that order.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><architecture>riscv:rv64</architecture>\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for (i, name) in RVABI.iter().enumerate() {
+        xml += &format!("<reg name=\"{}\" bitsize=\"64\" type=\"int\" regnum=\"{}\"/>", name, i);
+    }
+    xml += "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"32\"/></feature></target>";
+    xml
+}
+
+fn hex_u64(value: u64) -> String {
+    value.to_le_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex_u64(hex: &str) -> Option<u64> {
+    if hex.len() != 16 {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// `addr,length`, both in hex.
+fn parse_range(range: &str) -> Option<(u64, u64)> {
+    let (addr, len) = range.split_once(',')?;
+    Some((u64::from_str_radix(addr, 16).ok()?, u64::from_str_radix(len, 16).ok()?))
+}
+
+/// The stop reply for a halted cpu, and whether the guest is gone.
+fn stop_reply(reason: HaltReason) -> (String, bool) {
+    match reason {
+        HaltReason::Breakpoint(_) | HaltReason::SelfLoop(_) | HaltReason::Caught { .. } => (String::from("S05"), false),
+        HaltReason::Fault(_) | HaltReason::NullDeref { .. } => (String::from("S0b"), false),
+        HaltReason::Exit(code) => (format!("W{:02x}", code as u8), true),
+        HaltReason::WatchdogReset | HaltReason::Reset => (String::from("X06"), true),
+    }
+}
+
+/// What to do after a packet.
+enum Next {
+    Reply(String),
+    /// Reply, and end the session because the guest is gone.
+    Exit(String, HaltReason),
+    /// End the session and let the guest run on.
+    Detach,
+    Kill,
+}
+
+/// A GDB connection.
+pub struct GdbStub {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        let writer = stream.try_clone()?;
+        Ok(Self { reader: BufReader::new(stream), writer })
+    }
+
+    /// Serve GDB until it detaches, kills the guest or the guest halts for good.
+    /// Returns why the cpu halted, or `None` if GDB killed it.
+    pub fn run(&mut self, cpu: &mut Cpu) -> io::Result<Option<HaltReason>> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(cpu, &packet)? {
+                Next::Reply(reply) => self.write_packet(&reply)?,
+                Next::Exit(reply, reason) => {
+                    self.write_packet(&reply)?;
+                    return Ok(Some(reason));
+                }
+                Next::Detach => {
+                    self.write_packet("OK")?;
+                    return Ok(Some(cpu.run()));
+                }
+                Next::Kill => return Ok(None),
+            }
+        }
+        // GDB went away without detaching.
+        Ok(None)
+    }
+
+    fn handle(&mut self, cpu: &mut Cpu, packet: &str) -> io::Result<Next> {
+        let error = || Next::Reply(String::from("E01"));
+        let reply = |reply: &str| Next::Reply(String::from(reply));
+        let (command, args) = packet.split_at(packet.len().min(1));
+        Ok(match command {
+            "?" => reply("S05"),
+            "g" => {
+                let mut regs: String = cpu.regs.iter().map(|&reg| hex_u64(reg)).collect();
+                regs += &hex_u64(cpu.pc);
+                Next::Reply(regs)
+            }
+            "G" => {
+                let values: Option<Vec<u64>> =
+                    (0..33).map(|i| args.get(i * 16..i * 16 + 16).and_then(parse_hex_u64)).collect();
+                match values {
+                    Some(values) => {
+                        cpu.regs[1..].copy_from_slice(&values[1..32]);
+                        cpu.pc = values[32];
+                        reply("OK")
+                    }
+                    None => error(),
+                }
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(i @ 0..=31) => Next::Reply(hex_u64(cpu.regs[i])),
+                Ok(32) => Next::Reply(hex_u64(cpu.pc)),
+                _ => error(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(i, value)| {
+                    Some((usize::from_str_radix(i, 16).ok()?, parse_hex_u64(value)?))
+                });
+                match parsed {
+                    Some((0, _)) => reply("OK"),
+                    Some((i @ 1..=31, value)) => {
+                        cpu.regs[i] = value;
+                        reply("OK")
+                    }
+                    Some((32, value)) => {
+                        cpu.pc = value;
+                        reply("OK")
+                    }
+                    _ => error(),
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((addr, len)) => {
+                    let bytes: Result<String, _> = (0..len)
+                        .map(|i| cpu.read_mem_virt(addr.wrapping_add(i), 8).map(|b| format!("{:02x}", b)))
+                        .collect();
+                    bytes.map_or_else(|_| reply("E14"), Next::Reply)
+                }
+                None => error(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, data)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() as u64 == len * 2 => {
+                        for i in 0..len {
+                            let byte = u64::from_str_radix(&data[i as usize * 2..i as usize * 2 + 2], 16);
+                            match byte.ok().map(|b| cpu.write_mem_virt(addr.wrapping_add(i), 8, b)) {
+                                Some(Ok(())) => (),
+                                _ => return Ok(reply("E14")),
+                            }
+                        }
+                        reply("OK")
+                    }
+                    _ => error(),
+                }
+            }
+            "c" => self.resume(cpu, false)?,
+            "s" => self.resume(cpu, true)?,
+            "Z" | "z" => match args.split(',').collect::<Vec<_>>()[..] {
+                ["0" | "1", addr, _] => match u64::from_str_radix(addr, 16) {
+                    Ok(addr) if command == "Z" => {
+                        cpu.add_breakpoint(addr);
+                        reply("OK")
+                    }
+                    Ok(addr) => {
+                        cpu.remove_breakpoint(addr);
+                        reply("OK")
+                    }
+                    Err(_) => error(),
+                },
+                // Watchpoints aren't supported.
+                _ => reply(""),
+            },
+            "H" => reply("OK"),
+            "D" => Next::Detach,
+            "k" => Next::Kill,
+            _ if packet.starts_with("qSupported") => reply("PacketSize=4000;qXfer:features:read+"),
+            _ if packet == "qAttached" => reply("1"),
+            _ => match packet.strip_prefix("qXfer:features:read:target.xml:").and_then(parse_range) {
+                Some((offset, len)) => {
+                    let xml = target_xml();
+                    let start = (offset as usize).min(xml.len());
+                    let end = start.saturating_add(len as usize).min(xml.len());
+                    let more = if end < xml.len() { "m" } else { "l" };
+                    Next::Reply(format!("{}{}", more, &xml[start..end]))
+                }
+                // An empty reply tells GDB the packet isn't supported.
+                None => reply(""),
+            },
+        })
+    }
+
+    /// Continue, or run one instruction if `step`, and report why the cpu stopped.
+    fn resume(&mut self, cpu: &mut Cpu, step: bool) -> io::Result<Next> {
+        let start = cpu.pc;
+        let mut count = 0;
+        let reason = loop {
+            match cpu.step() {
+                // Resuming at a breakpoint runs its instruction.
+                Err(HaltReason::Breakpoint(pc)) if count == 0 && pc == start => continue,
+                Err(reason) => break reason,
+                Ok(()) if step => break HaltReason::Breakpoint(cpu.pc),
+                Ok(()) => (),
+            }
+            count += 1;
+            if count % INTERRUPT_POLL == 0 && self.interrupted()? {
+                return Ok(Next::Reply(String::from("S02")));
+            }
+        };
+        let (reply, exited) = stop_reply(reason);
+        Ok(if exited { Next::Exit(reply, reason) } else { Next::Reply(reply) })
+    }
+
+    /// Whether GDB sent a Ctrl-C, without waiting for it.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.reader.get_ref().set_nonblocking(true)?;
+        let interrupt = match self.reader.fill_buf() {
+            Ok(buf) => buf.first() == Some(&0x03),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+            Err(e) => return Err(e),
+        };
+        self.reader.get_ref().set_nonblocking(false)?;
+        if interrupt {
+            self.reader.consume(1);
+        }
+        Ok(interrupt)
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// The next packet `$data#checksum`, acknowledged. `None` when GDB disconnected.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acknowledgements and a Ctrl-C that came too late.
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if expected == Some(data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))) {
+                self.writer.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.writer, "${}#{:02x}", data, checksum)?;
+        self.writer.flush()
+    }
+}
+
+/// Wait for GDB to connect to `port` on localhost and serve it, see `GdbStub::run`.
+pub fn serve(cpu: &mut Cpu, port: u16) -> io::Result<Option<HaltReason>> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let (stream, _) = listener.accept()?;
+    GdbStub::new(stream)?.run(cpu)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{builder::CpuBuilder, param::DRAM_BASE};
+    use std::thread;
+
+    /// Send `data` as a packet and return the reply.
+    fn request(stream: &mut TcpStream, data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(stream, "${}#{:02x}", data, checksum).unwrap();
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        // The acknowledgement, then the reply up to its checksum.
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'+' if reply.is_empty() => continue,
+                b'#' => break,
+                b => reply.push(b),
+            }
+        }
+        stream.read_exact(&mut [0; 2]).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply[1..].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let code: Vec<u8> = [
+            0x00100513u32, // li a0, 1
+            0x00200513,    // li a0, 2
+            0x00300513,    // li a0, 3
+            0x00100073,    // ebreak
+        ]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+            let (stream, _) = listener.accept().unwrap();
+            GdbStub::new(stream).unwrap().run(&mut cpu).unwrap()
+        });
+
+        let mut gdb = TcpStream::connect(addr).unwrap();
+        assert_eq!(request(&mut gdb, "?"), "S05");
+        assert_eq!(request(&mut gdb, "m80000000,4"), "13051000");
+        assert_eq!(request(&mut gdb, &format!("Z0,{:x},4", DRAM_BASE + 4)), "OK");
+        assert_eq!(request(&mut gdb, "c"), "S05");
+        assert_eq!(request(&mut gdb, "p20"), hex_u64(DRAM_BASE + 4));
+        assert_eq!(request(&mut gdb, "pa"), hex_u64(1));
+        assert_eq!(request(&mut gdb, "s"), "S05");
+        assert_eq!(request(&mut gdb, "pa"), hex_u64(2));
+        assert_eq!(&request(&mut gdb, "g")[10 * 16..11 * 16], hex_u64(2));
+        assert_eq!(request(&mut gdb, "Pa=0000000000000000"), "OK");
+        assert_eq!(request(&mut gdb, "c"), "W03");
+        assert_eq!(server.join().unwrap(), Some(HaltReason::Exit(3)));
+    }
+}
@@ -0,0 +1,322 @@
+//! The hart module runs several harts on one `Cpu`, sharing its bus.
+//!
+//! The `Cpu` fields hold the state of the running hart. The others wait in the
+//! `Scheduler`, which swaps them in turn as its `Schedule` says, so an SMP run always
+//! interleaves the harts the same way.
+
+use std::mem::swap;
+
+use crate::{
+    cpu::Cpu,
+    csr::{Csr, MHARTID},
+    param::*,
+    tlb::Tlb,
+};
+
+/// How the harts take turns. Only the steps a hart takes count, not the cycles. A fetch
+/// fault takes a step like an instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Schedule {
+    /// Each hart runs `quantum` instructions, then the next one in hartid order.
+    RoundRobin { quantum: u64 },
+    /// The harts run one instruction each in this order of hart indices, over and over.
+    Pattern(Vec<usize>),
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule::RoundRobin { quantum: 1 }
+    }
+}
+
+/// The architectural state of a hart that isn't running.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HartState {
+    regs: [u64; 32],
+    fregs: [u64; 32],
+    pc: u64,
+    mode: u64,
+    csr: Csr,
+    enable_paging: bool,
+    page_table: u64,
+    /// Only a cache, a restored hart starts with it empty.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    tlb: Tlb,
+    reservation: Option<u64>,
+    waiting: bool,
+}
+
+impl HartState {
+    /// The reset state of the hart `hartid`, with its stack pointer at `sp`.
+    pub fn new(hartid: u64, sp: u64) -> Self {
+        let mut regs = [0; 32];
+        regs[2] = sp;
+        Self {
+            regs,
+            fregs: [0; 32],
+            pc: DRAM_BASE,
+            mode: 0b11,
+            csr: Csr::new(hartid),
+            enable_paging: false,
+            page_table: 0,
+            tlb: Tlb::new(),
+            reservation: None,
+            waiting: false,
+        }
+    }
+}
+
+/// The harts waiting for their turn and whose turn is next.
+#[derive(Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Scheduler {
+    /// The state of every hart by index. The entry of the running hart is stale, its
+    /// state is in the `Cpu`.
+    harts: Vec<HartState>,
+    current: usize,
+    schedule: Schedule,
+    /// Instructions left in the turn of the running hart.
+    left: u64,
+    /// The position in a `Schedule::Pattern`.
+    position: usize,
+}
+
+impl Scheduler {
+    /// A scheduler for `count` harts numbered from `first_hartid`, the first of which
+    /// is running. Their stack pointers start at `sp`.
+    pub fn new(first_hartid: u64, count: usize, sp: u64) -> Self {
+        let harts = (0..count as u64).map(|i| HartState::new(first_hartid + i, sp)).collect();
+        Self { harts, left: 1, ..Self::default() }
+    }
+
+    /// Start every hart with its hartid in a0 and the device tree at `fdt` in a1.
+    pub(crate) fn pass_fdt(&mut self, fdt: u64) {
+        for hart in self.harts.iter_mut() {
+            hart.regs[10] = hart.csr.load(MHARTID);
+            hart.regs[11] = fdt;
+        }
+    }
+
+    /// How many harts there are. A `Cpu` without any is a machine of one hart.
+    pub(crate) fn hart_count(&self) -> usize {
+        self.harts.len().max(1)
+    }
+}
+
+impl Cpu {
+    /// How many harts the machine has.
+    pub fn hart_count(&self) -> usize {
+        self.scheduler.hart_count()
+    }
+
+    /// The index of the running hart, from 0.
+    pub fn current_hart(&self) -> usize {
+        self.scheduler.current
+    }
+
+    /// Set how the harts take turns. The running hart starts a new turn.
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.scheduler.schedule = schedule;
+        self.scheduler.position = 0;
+        self.scheduler.left = match &self.scheduler.schedule {
+            Schedule::RoundRobin { quantum } => *quantum,
+            Schedule::Pattern(_) => 0,
+        };
+        if let Schedule::Pattern(pattern) = &self.scheduler.schedule {
+            if let Some(&first) = pattern.first() {
+                self.switch_hart(first);
+            }
+        }
+    }
+
+    /// Make the hart `index` the running one, as for inspecting its registers.
+    pub fn switch_hart(&mut self, index: usize) {
+        let current = self.scheduler.current;
+        if index == current || index >= self.scheduler.harts.len() {
+            return;
+        }
+        let mut harts = std::mem::take(&mut self.scheduler.harts);
+        self.swap_state(&mut harts[index]);
+        harts.swap(current, index);
+        self.scheduler.harts = harts;
+        self.scheduler.current = index;
+    }
+
+    /// Count a step, an instruction or a fetch fault, against the turn of the running
+    /// hart and hand over to the next one when the turn is over.
+    #[inline]
+    pub(crate) fn schedule_harts(&mut self) {
+        if self.scheduler.harts.len() > 1 {
+            self.next_turn();
+        }
+    }
+
+    /// Drop the reservations of every hart on the doubleword `addr` is in, which a
+    /// store to it invalidates.
+    #[inline]
+    pub(crate) fn invalidate_reservations(&mut self, addr: u64) {
+        let reserved = |reservation: Option<u64>| reservation.is_some_and(|r| r & !0x7 == addr & !0x7);
+        if reserved(self.reservation) {
+            self.reservation = None;
+        }
+        for hart in self.scheduler.harts.iter_mut() {
+            if reserved(hart.reservation) {
+                hart.reservation = None;
+            }
+        }
+    }
+
+    fn next_turn(&mut self) {
+        let next = match &self.scheduler.schedule {
+            Schedule::RoundRobin { quantum } => {
+                self.scheduler.left = self.scheduler.left.saturating_sub(1);
+                if self.scheduler.left > 0 {
+                    return;
+                }
+                self.scheduler.left = (*quantum).max(1);
+                (self.scheduler.current + 1) % self.scheduler.harts.len()
+            }
+            Schedule::Pattern(pattern) if !pattern.is_empty() => {
+                self.scheduler.position = (self.scheduler.position + 1) % pattern.len();
+                pattern[self.scheduler.position]
+            }
+            Schedule::Pattern(_) => return,
+        };
+        self.switch_hart(next);
+    }
+
+    fn swap_state(&mut self, hart: &mut HartState) {
+        swap(&mut self.regs, &mut hart.regs);
+        swap(&mut self.fregs, &mut hart.fregs);
+        swap(&mut self.pc, &mut hart.pc);
+        swap(&mut self.mode, &mut hart.mode);
+        swap(&mut self.csr, &mut hart.csr);
+        swap(&mut self.enable_paging, &mut hart.enable_paging);
+        swap(&mut self.page_table, &mut hart.page_table);
+        swap(&mut self.tlb, &mut hart.tlb);
+        swap(&mut self.reservation, &mut hart.reservation);
+        swap(&mut self.waiting, &mut hart.waiting);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        builder::CpuBuilder,
+        cpu::{HaltReason, Supervisor},
+        csr::{MEDELEG, MEPC, SCAUSE, STVEC},
+    };
+
+    /// Each hart stores hartid + 1 to a shared location and loads it back into a0.
+    fn shared_store() -> Vec<u8> {
+        [
+            0xf14022f3u32, // csrr t0, mhartid
+            0x00000317,    // auipc t1, 0
+            0x0fc30313,    // addi t1, t1, 252
+            0x00128393,    // addi t2, t0, 1
+            0x00733023,    // sd t2, 0(t1)
+            0x00033503,    // ld a0, 0(t1)
+        ]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect()
+    }
+
+    fn run(schedule: Schedule, steps: usize) -> (u64, u64) {
+        let mut cpu = CpuBuilder::new().code(shared_store()).headless_uart(true).harts(2).build();
+        cpu.set_schedule(schedule);
+        for _ in 0..steps {
+            cpu.step().unwrap();
+        }
+        cpu.switch_hart(0);
+        let a0 = cpu.reg("a0");
+        cpu.switch_hart(1);
+        (a0, cpu.reg("a0"))
+    }
+
+    #[test]
+    fn test_round_robin() {
+        // In lockstep, hart 1 stores after hart 0 and both load its value.
+        assert_eq!(run(Schedule::RoundRobin { quantum: 1 }, 12), (2, 2));
+        // Hart 0 runs all of its program first.
+        assert_eq!(run(Schedule::RoundRobin { quantum: 6 }, 12), (1, 2));
+    }
+
+    #[test]
+    fn test_pattern() {
+        // Hart 1 stores first, hart 0 stores last.
+        let pattern = vec![1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 1, 0];
+        assert_eq!(run(Schedule::Pattern(pattern), 12), (1, 1));
+    }
+
+    #[test]
+    fn test_fetch_fault_loop() {
+        // Hart 1 runs in S-mode on an empty page table, so every fetch page faults back
+        // to its stvec. Its faults still end its turns and hart 0 runs its program.
+        let mut cpu = CpuBuilder::new().code(shared_store()).headless_uart(true).harts(2).build();
+        cpu.switch_hart(1);
+        cpu.enable_paging = true;
+        cpu.page_table = DRAM_BASE + 0x10000;
+        cpu.mode = Supervisor;
+        cpu.csr.store(MEDELEG, 1 << 12);
+        cpu.csr.store(STVEC, 0x1000);
+        cpu.pc = 0x1000;
+        cpu.switch_hart(0);
+        for _ in 0..12 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.bus.clint.mtime(), 12);
+        cpu.switch_hart(0);
+        assert_eq!(cpu.reg("a0"), 1);
+        cpu.switch_hart(1);
+        assert_eq!((cpu.csr.load(SCAUSE), cpu.pc), (12, 0x1000));
+    }
+
+    #[test]
+    fn test_ipi() {
+        // Hart 0 raises the software interrupt of hart 1, which is stalled in wfi by then.
+        let code = [
+            0xf14022f3u32, // csrr t0, mhartid
+            0x02029063,    // bnez t0, 32
+            0x01400e13,    // li t3, 20
+            0xfffe0e13,    // addi t3, t3, -1
+            0xfe0e1ee3,    // bnez t3, -4
+            0x02000337,    // lui t1, 0x2000
+            0x00100393,    // li t2, 1
+            0x00732223,    // sw t2, 4(t1)
+            0x0000006f,    // j .
+            0x00000317,    // auipc t1, 0
+            0x02030313,    // addi t1, t1, 32
+            0x30531073,    // csrw mtvec, t1
+            0x00800393,    // li t2, 8
+            0x30439073,    // csrw mie, t2
+            0x30046073,    // csrsi mstatus, 8
+            0x10500073,    // wfi
+            0x0000006f,    // j .
+            0x34202573,    // csrr a0, mcause (trap handler)
+            0x00100073,    // ebreak
+        ];
+        let code = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).harts(2).build();
+
+        // Hart 1 has run its 7 instructions and waits, hart 0 is still in its loop.
+        assert_eq!(cpu.run_steps(30), None);
+        assert_eq!(cpu.current_hart(), 0);
+        assert_eq!(cpu.bus.load(CLINT_MSIP + 4, 32), Ok(0));
+        cpu.switch_hart(1);
+        assert!(cpu.waiting);
+        assert_eq!(cpu.pc, DRAM_BASE + 64);
+        cpu.switch_hart(0);
+
+        // The msip write wakes it and the interrupt is taken after the wfi.
+        assert_eq!(cpu.run(), HaltReason::Exit(3));
+        assert_eq!(cpu.current_hart(), 1);
+        assert!(!cpu.waiting);
+        assert_eq!(cpu.csr.load(MEPC), DRAM_BASE + 64);
+        assert_eq!(cpu.bus.load(CLINT_MSIP + 4, 32), Ok(1));
+        assert_eq!(cpu.bus.load(CLINT_MSIP, 32), Ok(0));
+    }
+}
@@ -0,0 +1,93 @@
+//! The icache module contains the decode cache, which keeps recently fetched
+//! instructions so that a fetch does not go through the bus again.
+//!
+//! Stores of the hart drop the instructions they overwrite, so code it writes runs
+//! right away. Writes by devices, such as the disk filling a buffer, are not seen until
+//! `fence.i`, as on a real hart.
+
+const ENTRIES: usize = 1024;
+const INVALID: u64 = u64::MAX;
+
+/// A direct-mapped cache of instruction words, indexed by physical address.
+pub struct DecodeCache {
+    tags: Vec<u64>,
+    insts: Vec<u32>,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self {
+            tags: vec![INVALID; ENTRIES],
+            insts: vec![0; ENTRIES],
+        }
+    }
+
+    fn index(addr: u64) -> usize {
+        (addr >> 2) as usize % ENTRIES
+    }
+
+    /// The cached instruction at physical address `addr`, if any.
+    #[inline]
+    pub fn get(&self, addr: u64) -> Option<u64> {
+        let i = Self::index(addr);
+        if self.tags[i] == addr {
+            Some(self.insts[i] as u64)
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&mut self, addr: u64, inst: u64) {
+        let i = Self::index(addr);
+        self.tags[i] = addr;
+        self.insts[i] = inst as u32;
+    }
+
+    /// Drop the cached instructions overlapping the `len` bytes at physical address
+    /// `addr`, including one that starts in the halfword before, which may be 32 bits.
+    #[inline]
+    pub fn invalidate(&mut self, addr: u64, len: u64) {
+        let (start, end) = (addr.wrapping_sub(2), addr.wrapping_add(len));
+        let mut word = start & !0x3;
+        while word < end {
+            let i = Self::index(word);
+            if (start..end).contains(&self.tags[i]) {
+                self.tags[i] = INVALID;
+            }
+            word += 4;
+        }
+    }
+
+    /// Drop every cached instruction.
+    pub fn flush(&mut self) {
+        self.tags.fill(INVALID);
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_invalidate() {
+        let mut cache = DecodeCache::new();
+        // A 32-bit instruction at 0x1002 and compressed ones at 0x1006 and 0x100c.
+        cache.insert(0x1002, 0x00150513);
+        cache.insert(0x1006, 0x0505);
+        cache.insert(0x100c, 0x0505);
+        // A byte store to 0x1004 hits the instruction that starts in the halfword before.
+        cache.invalidate(0x1004, 1);
+        assert_eq!(cache.get(0x1002), None);
+        assert_eq!(cache.get(0x1006), Some(0x0505));
+        cache.invalidate(0x1010, 8);
+        assert_eq!(cache.get(0x100c), Some(0x0505));
+        cache.invalidate(0x1008, 8);
+        assert_eq!(cache.get(0x100c), None);
+    }
+}
@@ -0,0 +1,106 @@
+//! The invariant module checks the cpu's state for things no guest can cause, so a
+//! broken instruction shows up at the instruction and not thousands of steps later.
+//!
+//! With the `invariants` feature, `Cpu::step` checks them after every instruction and
+//! panics on a violation.
+
+use crate::{
+    cpu::{Cpu, Machine, Supervisor, User},
+    csr::*,
+};
+
+impl Cpu {
+    /// Checks the invariants of the current state, returning what is wrong if one
+    /// doesn't hold.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        if self.regs[0] != 0 {
+            return Err(format!("x0 is {:#x}", self.regs[0]));
+        }
+        if ![User, Supervisor, Machine].contains(&self.mode) {
+            return Err(format!("mode is {:#b}", self.mode));
+        }
+        // With the C extension no jump or trap can leave the pc odd.
+        if self.pc & 1 != 0 {
+            return Err(format!("pc {:#x} is misaligned", self.pc));
+        }
+        let mpp = (self.csr.load(MSTATUS) & MASK_MPP) >> 11;
+        if mpp == 0b10 {
+            return Err(String::from("mstatus.MPP is the reserved mode 0b10"));
+        }
+        for (name, tvec) in [("mtvec", MTVEC), ("stvec", STVEC)] {
+            if self.csr.load(tvec) & 0b11 > 1 {
+                return Err(format!("{} has the reserved mode {:#b}", name, self.csr.load(tvec) & 0b11));
+            }
+        }
+        Ok(())
+    }
+
+    /// Panics if the instruction `inst` at `pc` left the cpu in a state that breaks an
+    /// invariant. Without translation the pc must also be mapped, unless the instruction
+    /// jumped or trapped there: fetching it will raise the access fault then.
+    #[cfg(feature = "invariants")]
+    pub(crate) fn assert_invariants(&self, pc: u64, inst: u64) {
+        let len = if crate::cpu::is_compressed(inst) { 2 } else { 4 };
+        let result = self.check_invariants().and_then(|()| {
+            if !self.enable_paging && self.pc == pc.wrapping_add(len) && self.bus.region(self.pc).is_none() {
+                Err(format!("pc {:#x} is unmapped", self.pc))
+            } else {
+                Ok(())
+            }
+        });
+        if let Err(violation) = result {
+            panic!(
+                "invariant broken by `{}` ({:#x}) at pc {:#x}: {}",
+                crate::disasm::disassemble(inst as u32),
+                inst,
+                pc,
+                violation
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::builder::CpuBuilder;
+    use crate::cpu::Cpu;
+
+    fn cpu_with(insts: &[u32]) -> Cpu {
+        let code = insts.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        CpuBuilder::new().code(code).headless_uart(true).build()
+    }
+
+    #[test]
+    fn test_invariants_hold() {
+        let mut cpu = cpu_with(&[
+            0x00500513, // li a0, 5
+            0x00a00593, // li a1, 10
+            0x02b50633, // mul a2, a0, a1
+            0x00050013, // addi zero, a0, 0
+        ]);
+        for _ in 0..4 {
+            cpu.step().unwrap();
+            assert_eq!(cpu.check_invariants(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_invariants_broken() {
+        let mut cpu = cpu_with(&[0x00000013]);
+        cpu.regs[0] = 1;
+        assert!(cpu.check_invariants().unwrap_err().contains("x0"));
+
+        let mut cpu = cpu_with(&[0x00000013]);
+        cpu.mode = 0b10;
+        assert!(cpu.check_invariants().unwrap_err().contains("mode"));
+    }
+
+    #[cfg(feature = "invariants")]
+    #[test]
+    #[should_panic(expected = "invariant broken by `li zero, 0`")]
+    fn test_step_panics_on_broken_invariant() {
+        let mut cpu = cpu_with(&[0x00000013]);
+        cpu.mode = 0b10;
+        let _ = cpu.step();
+    }
+}
@@ -0,0 +1,31 @@
+pub mod builder;
+pub mod bus;
+pub mod cpu;
+pub mod decode;
+pub mod disasm;
+pub mod dtb;
+pub mod dram;
+pub mod elf;
+pub mod exception;
+pub mod finisher;
+pub mod gdbstub;
+pub mod hart;
+pub mod icache;
+pub mod invariant;
+pub mod mailbox;
+pub mod monitor;
+pub mod param;
+pub mod csr;
+pub mod uart;
+pub mod clint;
+pub mod plic;
+pub mod semihosting;
+pub mod snapshot;
+pub mod tlb;
+pub mod trace;
+pub mod transcript;
+pub mod interrupt;
+pub mod virtio;
+pub mod virtio_net;
+pub mod virtqueue;
+pub mod watchdog;
@@ -0,0 +1,72 @@
+//! The mailbox module contains a device for tests to talk to the guest. The guest
+//! fills a data buffer and writes a command, and a handler on the host answers it.
+
+use crate::{
+    exception::Exception::{self, *},
+    param::*,
+};
+
+/// Answers a mailbox command. It gets the command and the data buffer, which it may
+/// rewrite, and returns the value of the RESULT register.
+pub type MailboxHandler = Box<dyn FnMut(u64, &mut [u8]) -> u64>;
+
+/// A mailbox shared between the guest and a host handler.
+///
+/// Registers:
+///
+/// CMD: 32 or 64 bits, writing a command calls the handler with it.
+/// RESULT: 32 or 64 bits, read-only, what the handler returned for the last command.
+/// DATA: `MAILBOX_DATA_SIZE` bytes of buffer, accessed with any width.
+pub struct Mailbox {
+    handler: MailboxHandler,
+    result: u64,
+    data: [u8; MAILBOX_DATA_SIZE as usize],
+}
+
+impl Mailbox {
+    pub fn new(handler: MailboxHandler) -> Self {
+        Self { handler, result: 0, data: [0; MAILBOX_DATA_SIZE as usize] }
+    }
+
+    /// The bytes of the data buffer at `addr`, if an access of `size` bits fits there.
+    fn data_range(addr: u64, size: u64) -> Option<std::ops::Range<usize>> {
+        let start = addr.checked_sub(MAILBOX_DATA)?;
+        let end = start + size / 8;
+        (end <= MAILBOX_DATA_SIZE).then_some(start as usize..end as usize)
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if !matches!(size, 8 | 16 | 32 | 64) {
+            return Err(LoadAccessFault(addr));
+        }
+        if let Some(range) = Self::data_range(addr, size) {
+            let value = self.data[range].iter().rev().fold(0, |value, &byte| value << 8 | byte as u64);
+            return Ok(value);
+        }
+        if size != 32 && size != 64 {
+            return Err(LoadAccessFault(addr));
+        }
+        match addr {
+            MAILBOX_RESULT => Ok(self.result),
+            _ => Ok(0),
+        }
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if !matches!(size, 8 | 16 | 32 | 64) {
+            return Err(StoreAMOAccessFault(addr));
+        }
+        if let Some(range) = Self::data_range(addr, size) {
+            let len = range.len();
+            self.data[range].copy_from_slice(&value.to_le_bytes()[..len]);
+            return Ok(());
+        }
+        if size != 32 && size != 64 {
+            return Err(StoreAMOAccessFault(addr));
+        }
+        if addr == MAILBOX_CMD {
+            self.result = (self.handler)(value, &mut self.data);
+        }
+        Ok(())
+    }
+}
@@ -1,85 +1,120 @@
-pub mod bus;
-pub mod cpu;
-pub mod dram;
-pub mod exception;
-pub mod param;
-pub mod csr;
-pub mod uart;
-pub mod clint;
-pub mod plic;
-pub mod interrupt;
-pub mod virtio;
-pub mod virtqueue;
-
-use cpu::Cpu;
+use rusty_riscv_ave::{
+    builder::CpuBuilder,
+    cpu::HaltReason,
+    elf, gdbstub, monitor,
+    param::TEST_FINISHER_BASE,
+};
 use std::{
     env,
     fs::File,
     io::{self, Read},
 };
-use tracing::{error, warn};
-use tracing_subscriber;
+use tracing::{error, info, Level};
+use tracing_subscriber::{filter::Targets, prelude::*};
+
+/// How to run the guest.
+#[derive(Debug)]
+enum Mode {
+    Run,
+    /// Under GDB, listening on this port.
+    Gdb(u16),
+    /// Under the monitor, reading commands from stdin.
+    Monitor,
+}
 
 #[tracing::instrument]
 fn main() -> io::Result<()> {
-    tracing_subscriber::fmt::init();
+    // RUST_LOG takes targets with levels, e.g. `info,rusty_riscv_ave::bus=trace` to
+    // log device accesses.
+    let filter = env::var("RUST_LOG")
+        .ok()
+        .and_then(|s| s.parse::<Targets>().ok())
+        .unwrap_or_else(|| Targets::new().with_default(Level::INFO));
+    tracing_subscriber::registry().with(tracing_subscriber::fmt::layer()).with(filter).init();
 
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 3 {
+    let mode = match &args[..] {
+        [_, _, _] => Some(Mode::Run),
+        [_, _, _, flag, port] if flag == "--gdb" => port.parse::<u16>().ok().map(Mode::Gdb),
+        [_, _, _, flag] if flag == "--monitor" => Some(Mode::Monitor),
+        _ => None,
+    };
+    let Some(mode) = mode else {
         println!(
             "Usage:\n\
-            - cargo run <filename> <disk_image>"
+            - cargo run <filename> <disk_image> [--gdb <port> | --monitor]"
         );
         return Ok(());
-    }
+    };
 
     let mut file = File::open(&args[1])?;
     let mut binary = Vec::new();
     file.read_to_end(&mut binary)?;
 
+    let mut file = File::open(&args[2])?;
     let mut disk_image = Vec::new();
-    if args.len() == 3 {
-        let mut file = File::open(&args[2])?;
-        file.read_to_end(&mut disk_image)?;
-    }
+    file.read_to_end(&mut disk_image)?;
 
-    let mut cpu = Cpu::new(binary, disk_image);
-
-    loop {
-        // fetch
-        let inst = match cpu.fetch() {
-            Ok(inst) => inst,
+    // An ELF executable starts at its entry point, a flat binary at the start of dram.
+    // Either gets a device tree in a1, and may power off through the test finisher.
+    let mut builder = CpuBuilder::new().disk(disk_image).dtb(true).test_finisher(TEST_FINISHER_BASE);
+    if let Mode::Monitor = mode {
+        // The monitor reads stdin, the guest can't have it.
+        builder = builder.uart_output(Box::new(io::stdout()));
+    }
+    let builder = if elf::is_elf(&binary) {
+        match builder.elf(&binary) {
+            Ok(builder) => builder,
             Err(e) => {
-                cpu.handle_exception(e);
-                if e.is_fatal() {
-                    error!("{}", e);
-                    break;
-                }
-                continue;
+                error!("{}: {}", args[1], e);
+                return Ok(());
             }
-        };
-
-        // eprintln!("inst: {:x}, pc: {:x}", inst, cpu.pc);
+        }
+    } else {
+        builder.code(binary)
+    };
+    let mut cpu = match builder.try_build() {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            error!("{}: {}", args[1], e);
+            return Ok(());
+        }
+    };
 
-        // execute
-        match cpu.execute(inst) {
-            Ok(new_pc) => cpu.set_pc(new_pc),
-            Err(e) => {
-                cpu.handle_exception(e);
-                if e.is_fatal() {
-                    error!("{}", e);
-                    break;
+    let reason = match mode {
+        Mode::Gdb(port) => {
+            info!("waiting for gdb on port {}", port);
+            match gdbstub::serve(&mut cpu, port)? {
+                Some(reason) => reason,
+                None => {
+                    info!("killed by gdb");
+                    return Ok(());
                 }
             }
-        };
-
-        match cpu.check_pending_interrupt() {
-            Some(interrupt) => cpu.handle_interrupt(interrupt),
-            None => (),
         }
+        Mode::Monitor => match monitor::run(&mut cpu, io::stdin().lock(), io::stdout())? {
+            Some(reason) => reason,
+            None => {
+                info!("quit from the monitor");
+                return Ok(());
+            }
+        },
+        Mode::Run => cpu.run(),
+    };
+
+    match reason {
+        HaltReason::Fault(e) => error!("{}", e),
+        HaltReason::Exit(code) => info!("exit with code {}", code),
+        HaltReason::WatchdogReset => error!("watchdog reset"),
+        HaltReason::Reset => info!("reset by the guest"),
+        HaltReason::Breakpoint(pc) => info!("breakpoint at {:#x}", pc),
+        HaltReason::NullDeref { addr, pc } => error!("null pointer access {:#x} at pc {:#x}", addr, pc),
+        HaltReason::SelfLoop(pc) => info!("stuck in a loop at {:#x}", pc),
+        HaltReason::Caught { exception, pc } => info!("caught {} at pc {:#x}", exception, pc),
     }
 
+    cpu.bus.uart.flush();
     cpu.dump_registers();
     cpu.dump_csrs();
     cpu.dump_pc();
@@ -0,0 +1,272 @@
+//! The monitor module is a small command line for running the guest by hand, for
+//! `--monitor`. It reads commands from a reader, one per line, before the first
+//! instruction runs:
+//!
+//! ```text
+//! step [n]           run n instructions, 1 by default
+//! continue           run until the cpu halts or reaches a breakpoint
+//! reg <name>         print a register or csr, see `Cpu::try_reg`
+//! mem <addr> <len>   dump len bytes of physical memory
+//! break <addr>       stop before the instruction at addr
+//! delete <addr>      remove the breakpoint at addr
+//! disasm <addr> <n>  disassemble n instructions, see `Cpu::memory_dump`
+//! help               list the commands
+//! quit               stop the guest
+//! ```
+//!
+//! Numbers are decimal, or hex with a `0x` prefix.
+
+use std::io::{self, BufRead, Write};
+
+use crate::cpu::{Cpu, HaltReason};
+
+const HELP: &str = "\
+step [n]           run n instructions, 1 by default
+continue           run until the cpu halts or reaches a breakpoint
+reg <name>         print a register or csr
+mem <addr> <len>   dump len bytes of physical memory
+break <addr>       stop before the instruction at addr
+delete <addr>      remove the breakpoint at addr
+disasm <addr> <n>  disassemble n instructions
+help               list the commands
+quit               stop the guest
+";
+
+/// A line of input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Step(u64),
+    Continue,
+    Reg(String),
+    Mem { addr: u64, len: u64 },
+    Break(u64),
+    Delete(u64),
+    Disasm { addr: u64, count: usize },
+    Help,
+    Quit,
+}
+
+impl Command {
+    /// Parse a line, with a message for the user if it isn't a command. Commands may
+    /// be shortened to their first letter.
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words[..] {
+            ["step" | "s"] => Command::Step(1),
+            ["step" | "s", n] => Command::Step(parse_number(n)?),
+            ["continue" | "c"] => Command::Continue,
+            ["reg" | "r", name] => Command::Reg(name.to_string()),
+            ["mem" | "m", addr, len] => Command::Mem { addr: parse_number(addr)?, len: parse_number(len)? },
+            ["break" | "b", addr] => Command::Break(parse_number(addr)?),
+            ["delete" | "d", addr] => Command::Delete(parse_number(addr)?),
+            ["disasm" | "x", addr, count] => {
+                Command::Disasm { addr: parse_number(addr)?, count: parse_number(count)? as usize }
+            }
+            ["help" | "h"] => Command::Help,
+            ["quit" | "q"] => Command::Quit,
+            [] => return Err(String::from("no command, try help")),
+            [name, ..] => return Err(format!("bad command {}, try help", name)),
+        };
+        Ok(command)
+    }
+}
+
+/// A decimal number, or a hex one starting with `0x`.
+fn parse_number(word: &str) -> Result<u64, String> {
+    let parsed = match word.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    parsed.map_err(|_| format!("bad number {}", word))
+}
+
+/// What `reason` means, in the words of `main`.
+fn describe(reason: HaltReason) -> String {
+    match reason {
+        HaltReason::Fault(e) => format!("{}", e),
+        HaltReason::Exit(code) => format!("exit with code {}", code),
+        HaltReason::WatchdogReset => String::from("watchdog reset"),
+        HaltReason::Reset => String::from("reset by the guest"),
+        HaltReason::Breakpoint(pc) => format!("breakpoint at {:#x}", pc),
+        HaltReason::NullDeref { addr, pc } => format!("null pointer access {:#x} at pc {:#x}", addr, pc),
+        HaltReason::SelfLoop(pc) => format!("stuck in a loop at {:#x}", pc),
+        HaltReason::Caught { exception, pc } => format!("caught {} at pc {:#x}", exception, pc),
+    }
+}
+
+/// Whether the guest can't go on after halting for `reason`.
+fn is_final(reason: HaltReason) -> bool {
+    matches!(reason, HaltReason::Exit(_) | HaltReason::WatchdogReset | HaltReason::Reset)
+}
+
+/// Run `steps` instructions, or until the cpu halts if `None`. A breakpoint at the
+/// pc it starts at doesn't stop it, so that it can go on from a breakpoint.
+fn resume(cpu: &mut Cpu, steps: Option<u64>) -> Option<HaltReason> {
+    let start = cpu.pc;
+    let mut count = 0;
+    while steps.is_none_or(|steps| count < steps) {
+        match cpu.step() {
+            Err(HaltReason::Breakpoint(pc)) if count == 0 && pc == start => continue,
+            Err(reason) => return Some(reason),
+            Ok(()) => count += 1,
+        }
+    }
+    None
+}
+
+/// `len` bytes from `addr` on, 16 to a line.
+fn hex_dump(cpu: &mut Cpu, addr: u64, len: u64) -> String {
+    let mut output = String::new();
+    for line in (0..len).step_by(16) {
+        let start = addr.wrapping_add(line);
+        output += &format!("{:#018x}:", start);
+        for i in 0..(len - line).min(16) {
+            match cpu.read_mem(start.wrapping_add(i), 8) {
+                Ok(byte) => output += &format!(" {:02x}", byte),
+                Err(_) => output += " ??",
+            }
+        }
+        output += "\n";
+    }
+    output
+}
+
+/// Read commands from `input` and answer them on `out` until `quit` or the end of
+/// the input. Returns why the cpu halted if the guest is gone, or `None` if the user
+/// left the monitor.
+pub fn run<R: BufRead, W: Write>(cpu: &mut Cpu, input: R, mut out: W) -> io::Result<Option<HaltReason>> {
+    let mut lines = input.lines();
+    loop {
+        write!(out, "(monitor) ")?;
+        out.flush()?;
+        let Some(line) = lines.next().transpose()? else {
+            return Ok(None);
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let command = match Command::parse(&line) {
+            Ok(command) => command,
+            Err(message) => {
+                writeln!(out, "{}", message)?;
+                continue;
+            }
+        };
+        match command {
+            Command::Step(n) => {
+                let reason = resume(cpu, Some(n));
+                if let Some(reason) = reason {
+                    writeln!(out, "{}", describe(reason))?;
+                    if is_final(reason) {
+                        return Ok(Some(reason));
+                    }
+                }
+                write!(out, "{}", cpu.memory_dump(cpu.pc, 1))?;
+            }
+            Command::Continue => {
+                // Without breakpoints, the guest may never halt.
+                let reason = resume(cpu, None).unwrap();
+                writeln!(out, "{}", describe(reason))?;
+                if is_final(reason) {
+                    return Ok(Some(reason));
+                }
+                write!(out, "{}", cpu.memory_dump(cpu.pc, 1))?;
+            }
+            Command::Reg(name) => match cpu.try_reg(&name) {
+                Some(value) => writeln!(out, "{} = {:#x}", name, value)?,
+                None => writeln!(out, "no register {}", name)?,
+            },
+            Command::Mem { addr, len } => write!(out, "{}", hex_dump(cpu, addr, len))?,
+            Command::Break(addr) => {
+                cpu.add_breakpoint(addr);
+                writeln!(out, "breakpoint at {:#x}", addr)?;
+            }
+            Command::Delete(addr) => cpu.remove_breakpoint(addr),
+            Command::Disasm { addr, count } => write!(out, "{}", cpu.memory_dump(addr, count))?,
+            Command::Help => write!(out, "{}", HELP)?,
+            Command::Quit => return Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{builder::CpuBuilder, param::DRAM_BASE};
+
+    fn rv_code(insts: &[u32]) -> Vec<u8> {
+        insts.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+    }
+
+    /// Run a monitor session on `cpu` with `script` as its input, returning the halt
+    /// reason and what it printed.
+    fn session(cpu: &mut Cpu, script: &str) -> (Option<HaltReason>, String) {
+        let mut out = Vec::new();
+        let reason = run(cpu, script.as_bytes(), &mut out).unwrap();
+        (reason, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse("step"), Ok(Command::Step(1)));
+        assert_eq!(Command::parse("  s 10 "), Ok(Command::Step(10)));
+        assert_eq!(Command::parse("reg a0"), Ok(Command::Reg(String::from("a0"))));
+        assert_eq!(Command::parse("mem 0x80000000 16"), Ok(Command::Mem { addr: DRAM_BASE, len: 16 }));
+        assert_eq!(Command::parse("b 0x80000008"), Ok(Command::Break(DRAM_BASE + 8)));
+        assert_eq!(Command::parse("disasm 0x80000000 3"), Ok(Command::Disasm { addr: DRAM_BASE, count: 3 }));
+        assert_eq!(Command::parse("c"), Ok(Command::Continue));
+        assert_eq!(Command::parse("step 0xzz"), Err(String::from("bad number 0xzz")));
+        assert_eq!(Command::parse("reg"), Err(String::from("bad command reg, try help")));
+        assert_eq!(Command::parse("jump 0"), Err(String::from("bad command jump, try help")));
+    }
+
+    #[test]
+    fn test_session() {
+        let code = rv_code(&[
+            0x00100513, // li a0, 1
+            0x00200593, // li a1, 2
+            0x00b50633, // add a2, a0, a1
+            0x00100073, // ebreak
+        ]);
+        let mut cpu = CpuBuilder::new().code(code).headless_uart(true).exit_on_ebreak(true).build();
+        let script = "\
+            step\n\
+            reg a0\n\
+            reg pc\n\
+            reg nope\n\
+            \n\
+            break 0x80000008\n\
+            continue\n\
+            disasm 0x80000008 2\n\
+            mem 0x80000000 20\n\
+            frobnicate\n\
+            quit\n\
+            step\n";
+        let (reason, text) = session(&mut cpu, script);
+        assert_eq!(reason, None);
+        // The session stopped at quit.
+        assert_eq!(cpu.pc, DRAM_BASE + 8);
+        assert_eq!(cpu.reg("a2"), 0);
+        let expected = "\
+            (monitor) 0x0000000080000004: 00200593  li a1, 2\n\
+            (monitor) a0 = 0x1\n\
+            (monitor) pc = 0x80000004\n\
+            (monitor) no register nope\n\
+            (monitor) (monitor) breakpoint at 0x80000008\n\
+            (monitor) breakpoint at 0x80000008\n\
+            0x0000000080000008: 00b50633  add a2, a0, a1\n\
+            (monitor) 0x0000000080000008: 00b50633  add a2, a0, a1\n\
+            0x000000008000000c: 00100073  ebreak\n\
+            (monitor) 0x0000000080000000: 13 05 10 00 93 05 20 00 33 06 b5 00 73 00 10 00\n\
+            0x0000000080000010: 00 00 00 00\n\
+            (monitor) bad command frobnicate, try help\n\
+            (monitor) ";
+        assert_eq!(text, expected);
+
+        // Continuing from the breakpoint runs into the ebreak, which exits with a0.
+        let (reason, text) = session(&mut cpu, "c\nreg a2\n");
+        assert_eq!(reason, Some(HaltReason::Exit(1)));
+        assert_eq!(cpu.reg("a2"), 3);
+        assert!(text.ends_with("(monitor) exit with code 1\n"), "{}", text);
+    }
+}
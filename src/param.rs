@@ -1,15 +1,25 @@
 // memory layout following QEMU
 // https://github.com/qemu/qemu/blob/master/hw/riscv/virt.c#L46-L63 
 pub const DRAM_BASE: u64 = 0x8000_0000;
+// The default size of dram, `CpuBuilder::dram_size` changes it.
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 pub const DRAM_END: u64 = DRAM_SIZE + DRAM_BASE - 1;
 
+// The boot ROM (MROM of the QEMU virt machine), only mapped when the machine has one.
+// It holds the reset vector, which jumps to dram.
+pub const MROM_BASE: u64 = 0x1000;
+pub const MROM_SIZE: u64 = 0xf000;
+pub const MROM_END: u64 = MROM_BASE + MROM_SIZE - 1;
+
 // The address which the core-local interruptor (CLINT) starts. It contains the timer and
 // generates per-hart software interrupts and timer interrupts.
 pub const CLINT_BASE: u64 = 0x200_0000;
 pub const CLINT_SIZE: u64 = 0x10000;
 pub const CLINT_END: u64 = CLINT_BASE + CLINT_SIZE - 1;
 
+/// The msip register of hart 0. Each hart has a 32-bit one, in hartid order.
+pub const CLINT_MSIP: u64 = CLINT_BASE;
+/// The mtimecmp register of hart 0. Each hart has a 64-bit one, in hartid order.
 pub const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
 pub const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
 
@@ -19,10 +29,49 @@ pub const PLIC_BASE: u64 = 0xc00_0000;
 pub const PLIC_SIZE: u64 = 0x4000000;
 pub const PLIC_END: u64 = PLIC_BASE + PLIC_SIZE - 1;
 
+/// The 32-bit priority of each source, in source order.
+pub const PLIC_PRIORITY: u64 = PLIC_BASE;
+/// The pending bits of all sources.
 pub const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
-pub const PLIC_SENABLE: u64 = PLIC_BASE + 0x2000;
-pub const PLIC_SPRIORITY: u64 = PLIC_BASE + 0x201000;
-pub const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
+/// The enable bits of context 0, each context has `PLIC_ENABLE_STRIDE` bytes of them.
+pub const PLIC_ENABLE: u64 = PLIC_BASE + 0x2000;
+pub const PLIC_ENABLE_STRIDE: u64 = 0x80;
+/// The priority threshold of context 0, followed by its claim/complete register. Each
+/// context has `PLIC_CONTEXT_STRIDE` bytes of them.
+pub const PLIC_THRESHOLD: u64 = PLIC_BASE + 0x200000;
+pub const PLIC_CLAIM: u64 = PLIC_BASE + 0x200004;
+pub const PLIC_CONTEXT_STRIDE: u64 = 0x1000;
+
+// The SiFive test finisher, only mapped when the machine has one. This is where the QEMU
+// virt machine has it, `CpuBuilder::test_finisher` may put it elsewhere.
+pub const TEST_FINISHER_BASE: u64 = 0x10_0000;
+pub const TEST_FINISHER_SIZE: u64 = 0x1000;
+
+// Status values of the test finisher
+pub const FINISHER_FAIL: u64 = 0x3333;
+pub const FINISHER_PASS: u64 = 0x5555;
+pub const FINISHER_RESET: u64 = 0x7777;
+
+// The watchdog timer, only mapped when the machine has one. The address is free in the
+// QEMU virt memory map.
+pub const WATCHDOG_BASE: u64 = 0x10_2000;
+pub const WATCHDOG_SIZE: u64 = 0x1000;
+pub const WATCHDOG_END: u64 = WATCHDOG_BASE + WATCHDOG_SIZE - 1;
+
+pub const WATCHDOG_KICK: u64 = WATCHDOG_BASE;
+pub const WATCHDOG_TIMEOUT: u64 = WATCHDOG_BASE + 0x8;
+pub const WATCHDOG_COUNT: u64 = WATCHDOG_BASE + 0x10;
+
+// The mailbox for tests, only mapped when a handler is set. The address is free in the
+// QEMU virt memory map.
+pub const MAILBOX_BASE: u64 = 0x10_3000;
+pub const MAILBOX_SIZE: u64 = 0x1000;
+pub const MAILBOX_END: u64 = MAILBOX_BASE + MAILBOX_SIZE - 1;
+
+pub const MAILBOX_CMD: u64 = MAILBOX_BASE;
+pub const MAILBOX_RESULT: u64 = MAILBOX_BASE + 0x8;
+pub const MAILBOX_DATA: u64 = MAILBOX_BASE + 0x100;
+pub const MAILBOX_DATA_SIZE: u64 = 0x100;
 
 // UART
 pub const UART_BASE: u64 = 0x1000_0000;
@@ -34,8 +83,19 @@ pub const UART_IRQ: u64 = 10;
 pub const UART_RHR: u64 = 0;
 // Transmit holding register (for output bytes).
 pub const UART_THR: u64 = 0;
+// Interrupt enable register.
+pub const UART_IER: u64 = 1;
+// Interrupt identification register (read) and FIFO control register (write).
+pub const UART_IIR: u64 = 2;
+pub const UART_FCR: u64 = 2;
 // Line control register.
 pub const UART_LCR: u64 = 3;
+// IER BIT 0: interrupt while received data waits in RHR.
+// IER BIT 1: interrupt when THR becomes empty.
+pub const MASK_UART_IER_RX: u8 = 1;
+pub const MASK_UART_IER_TX: u8 = 1 << 1;
+// LCR BIT 7: divisor latch access. The divisor replaces RHR/THR and IER while it is set.
+pub const MASK_UART_LCR_DLAB: u8 = 1 << 7;
 // Line status register.
 // LSR BIT 0:
 //     0 = no data in receive holding register or FIFO.
@@ -43,11 +103,19 @@ pub const UART_LCR: u64 = 3;
 // LSR BIT 5:
 //     0 = transmit holding register is full. 16550 will not accept any data for transmission.
 //     1 = transmitter hold register (or FIFO) is empty. CPU can load the next character.
+// LSR BIT 6:
+//     0 = the transmitter is still sending.
+//     1 = the transmitter is idle, both THR and the shift register are empty.
 pub const UART_LSR: u64 = 5;
 // The receiver (RX) bit MASK.
 pub const MASK_UART_LSR_RX: u8 = 1;
 // The transmitter (TX) bit MASK.
 pub const MASK_UART_LSR_TX: u8 = 1 << 5;
+// The transmitter empty bit MASK.
+pub const MASK_UART_LSR_TEMT: u8 = 1 << 6;
+// FCR BIT 0: enable the FIFOs. FCR BIT 1: clear the receive FIFO.
+pub const MASK_UART_FCR_ENABLE: u8 = 1;
+pub const MASK_UART_FCR_RX_RESET: u8 = 1 << 1;
 
 pub const MASK_INTERRUPT_BIT: u64 = 1 << 63;
 
@@ -89,15 +157,49 @@ pub const VIRTIO_QUEUE_NUM_MAX: u64 = VIRTIO_BASE + 0x034;
 pub const VIRTIO_QUEUE_NUM: u64 = VIRTIO_BASE + 0x038;
 // Physical page number for queue, read and write.
 pub const VIRTIO_QUEUE_PFN: u64 = VIRTIO_BASE + 0x040;
+// Used ring alignment of the current queue, write-only.
+pub const VIRTIO_QUEUE_ALIGN: u64 = VIRTIO_BASE + 0x03c;
 // Notify the queue number, write-only.
 pub const VIRTIO_QUEUE_NOTIFY: u64 = VIRTIO_BASE + 0x050;
+// Why the device interrupted, read-only. Bit 0 means the used ring was updated.
+pub const VIRTIO_INTERRUPT_STATUS: u64 = VIRTIO_BASE + 0x060;
+// Acknowledge the bits of INTERRUPT_STATUS, write-only.
+pub const VIRTIO_INTERRUPT_ACK: u64 = VIRTIO_BASE + 0x064;
 // Device status, read and write. Reading from this register returns the current device status flags.
 // Writing non-zero values to this register sets the status flags, indicating the OS/driver
 // progress. Writing zero (0x0) to this register triggers a device reset.
 pub const VIRTIO_STATUS: u64 = VIRTIO_BASE + 0x070;
+// Device-specific configuration space.
+pub const VIRTIO_CONFIG: u64 = VIRTIO_BASE + 0x100;
+
+// The virtio network device sits in the next virtio slot, as on the QEMU virt machine.
+// Its registers have the same layout as the block device's.
+pub const VIRTIO_NET_BASE: u64 = 0x1000_2000;
+pub const VIRTIO_NET_SIZE: u64 = 0x1000;
+pub const VIRTIO_NET_END: u64 = VIRTIO_NET_BASE + VIRTIO_NET_SIZE - 1;
+pub const VIRTIO_NET_IRQ: u64 = 2;
+
+// The virtqueues of the network device.
+pub const VIRTIO_NET_RX: usize = 0;
+pub const VIRTIO_NET_TX: usize = 1;
+// The device has a MAC address in its configuration space.
+pub const VIRTIO_NET_F_MAC: u32 = 1 << 5;
+// The legacy `struct virtio_net_hdr` in front of every packet.
+pub const VIRTIO_NET_HDR_SIZE: u64 = 10;
+// The driver is set up and ready to drive the device.
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
 
 
 pub const PAGE_SIZE: u64 = 4096;
+// The permission and status bits of an Sv39 page table entry.
+pub const PTE_V: u64 = 1 << 0;
+pub const PTE_R: u64 = 1 << 1;
+pub const PTE_W: u64 = 1 << 2;
+pub const PTE_X: u64 = 1 << 3;
+pub const PTE_U: u64 = 1 << 4;
+pub const PTE_G: u64 = 1 << 5;
+pub const PTE_A: u64 = 1 << 6;
+pub const PTE_D: u64 = 1 << 7;
 pub const SECTOR_SIZE: u64 = 512;
 
 
@@ -105,6 +207,11 @@ pub const SECTOR_SIZE: u64 = 512;
 pub const VIRTIO_BLK_T_IN: u32 = 0;
 pub const VIRTIO_BLK_T_OUT: u32 = 1;
 
+// virtio block request status
+pub const VIRTIO_BLK_S_OK: u64 = 0;
+pub const VIRTIO_BLK_S_IOERR: u64 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u64 = 2;
+
 // virtqueue descriptor flags
 pub const VIRTQ_DESC_F_NEXT: u16 = 1;
 pub const VIRTQ_DESC_F_WRITE: u16 = 2;
@@ -2,49 +2,195 @@
 //! The plic connects all external interrupts in the system to all hart
 //! contexts in the system, via the external interrupt source in each hart.
 //! It's the global interrupt controller in a RISC-V system.
+//!
+//! The memory map is the standard one. Like on QEMU's virt machine, context `2 * hartid`
+//! is the M-mode of a hart and context `2 * hartid + 1` its S-mode.
 
 use crate::param::*;
 use crate::exception::Exception;
 
 use Exception::*;
 
+/// Interrupt sources, including the reserved source 0.
+const PLIC_SOURCES: usize = 64;
 
+/// The enable bits and the threshold of one context.
+#[derive(Clone, Copy, Default)]
+struct Context {
+    enable: u64,
+    threshold: u32,
+}
 
+/// Each source is either level-sensitive or edge-triggered. A level-sensitive source stays
+/// pending as long as its device holds the line up, so it is pending again after a
+/// claim/complete if the device still needs service. An edge-triggered source becomes
+/// pending once per `raise`.
+///
+/// A source that has been claimed is not pending again until the hart writes it back
+/// to the claim/complete register.
+///
+/// A context is interrupted by the pending sources it enables whose priority is above its
+/// threshold. Priority 0 never interrupts.
 pub struct Plic {
     pending: u64,
-    senable: u64,
-    spriority: u64,
-    sclaim: u64,
+    priority: [u32; PLIC_SOURCES],
+    /// Per context, grown on the first write. A context that is missing enables nothing.
+    contexts: Vec<Context>,
+    /// Sources claimed by the hart and not completed yet.
+    claimed: u64,
+    /// Sources whose interrupt line is currently held up.
+    level: u64,
+    /// Sources that are level-sensitive. The others are edge-triggered.
+    level_sensitive: u64,
 }
 
 impl Plic {
     pub fn new() -> Self {
-        Self {pending: 0, senable: 0, spriority: 0, sclaim: 0}
+        Self {
+            pending: 0,
+            priority: [0; PLIC_SOURCES],
+            contexts: Vec::new(),
+            claimed: 0,
+            level: 0,
+            level_sensitive: 1 << UART_IRQ,
+        }
     }
 
-    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if size != 32 {
-            return Err(LoadAccessFault(addr));
+    /// Update the line of a level-sensitive source.
+    pub fn set_level(&mut self, irq: u64, asserted: bool) {
+        let bit = 1 << irq;
+        if asserted {
+            self.level |= bit;
+            if self.claimed & bit == 0 {
+                self.pending |= bit;
+            }
+        } else {
+            self.level &= !bit;
+            self.pending &= !bit;
         }
-        match addr {
-            PLIC_PENDING => Ok(self.pending),
-            PLIC_SENABLE => Ok(self.senable),
-            PLIC_SPRIORITY => Ok(self.spriority),
-            PLIC_SCLAIM => Ok(self.sclaim),
-            _ => Ok(0),
+    }
+
+    /// Signal an edge on an edge-triggered source.
+    pub fn raise(&mut self, irq: u64) {
+        self.pending |= 1 << irq;
+    }
+
+    /// Whether `irq` is waiting to be claimed.
+    pub fn is_pending(&self, irq: u64) -> bool {
+        self.pending & (1 << irq) != 0
+    }
+
+    /// Whether `context` has a source to claim.
+    #[inline]
+    pub fn is_interrupting(&self, context: usize) -> bool {
+        self.pending != 0 && self.best(context) != 0
+    }
+
+    fn context(&self, context: usize) -> Context {
+        self.contexts.get(context).copied().unwrap_or_default()
+    }
+
+    fn context_mut(&mut self, context: usize) -> &mut Context {
+        if self.contexts.len() <= context {
+            self.contexts.resize(context + 1, Context::default());
+        }
+        &mut self.contexts[context]
+    }
+
+    /// The source `context` would claim: the one with the highest priority above the
+    /// threshold among those pending and enabled, the lowest id on a tie. 0 if none.
+    fn best(&self, context: usize) -> u64 {
+        let Context { enable, threshold } = self.context(context);
+        let candidates = self.pending & enable & !1;
+        if candidates == 0 {
+            return 0;
+        }
+        (1..PLIC_SOURCES as u64)
+            .filter(|&irq| candidates & (1 << irq) != 0 && self.priority[irq as usize] > threshold)
+            .min_by_key(|&irq| (std::cmp::Reverse(self.priority[irq as usize]), irq))
+            .unwrap_or(0)
+    }
+
+    /// Return the source `context` claims and clear its pending bit, or 0 if nothing is
+    /// pending for it.
+    fn claim(&mut self, context: usize) -> u64 {
+        let irq = self.best(context);
+        if irq != 0 {
+            self.pending &= !(1 << irq);
+            self.claimed |= 1 << irq;
+        }
+        irq
+    }
+
+    /// The hart is done with `irq`. A level-sensitive source whose line is still
+    /// up becomes pending again.
+    fn complete(&mut self, irq: u64) {
+        if irq >= PLIC_SOURCES as u64 {
+            return;
+        }
+        let bit = 1 << irq;
+        self.claimed &= !bit;
+        if self.level_sensitive & self.level & bit != 0 {
+            self.pending |= bit;
+        }
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 || !addr.is_multiple_of(4) {
+            return Err(LoadAccessFault(addr));
         }
+        let word = |bits: u64, offset: u64| (bits >> (offset * 8)) & 0xffff_ffff;
+        let value = match addr {
+            PLIC_PRIORITY..PLIC_PENDING => {
+                self.priority.get(((addr - PLIC_PRIORITY) / 4) as usize).copied().unwrap_or(0) as u64
+            }
+            PLIC_PENDING..PLIC_ENABLE if addr - PLIC_PENDING < 8 => word(self.pending, addr - PLIC_PENDING),
+            PLIC_ENABLE..PLIC_THRESHOLD if (addr - PLIC_ENABLE) % PLIC_ENABLE_STRIDE < 8 => {
+                let context = ((addr - PLIC_ENABLE) / PLIC_ENABLE_STRIDE) as usize;
+                word(self.context(context).enable, (addr - PLIC_ENABLE) % PLIC_ENABLE_STRIDE)
+            }
+            PLIC_THRESHOLD..=PLIC_END => {
+                let context = ((addr - PLIC_THRESHOLD) / PLIC_CONTEXT_STRIDE) as usize;
+                match (addr - PLIC_THRESHOLD) % PLIC_CONTEXT_STRIDE {
+                    0 => self.context(context).threshold as u64,
+                    4 => self.claim(context),
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        };
+        Ok(value)
     }
 
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if size != 32 {
+        if size != 32 || !addr.is_multiple_of(4) {
             return Err(StoreAMOAccessFault(addr));
         }
+        let value = value & 0xffff_ffff;
         match addr {
-            PLIC_PENDING => Ok(self.pending = value),
-            PLIC_SENABLE => Ok(self.senable = value),
-            PLIC_SPRIORITY => Ok(self.spriority = value),
-            PLIC_SCLAIM => Ok(self.sclaim = value),
-            _ => Ok(()),
+            // Source 0 doesn't exist, its priority stays 0.
+            PLIC_PRIORITY..PLIC_PENDING if addr > PLIC_PRIORITY => {
+                if let Some(priority) = self.priority.get_mut(((addr - PLIC_PRIORITY) / 4) as usize) {
+                    *priority = value as u32;
+                }
+            }
+            PLIC_ENABLE..PLIC_THRESHOLD if (addr - PLIC_ENABLE) % PLIC_ENABLE_STRIDE < 8 => {
+                let context = ((addr - PLIC_ENABLE) / PLIC_ENABLE_STRIDE) as usize;
+                let shift = (addr - PLIC_ENABLE) % PLIC_ENABLE_STRIDE * 8;
+                let enable = &mut self.context_mut(context).enable;
+                *enable = (*enable & !(0xffff_ffff << shift)) | (value << shift);
+            }
+            PLIC_THRESHOLD..=PLIC_END => {
+                let context = ((addr - PLIC_THRESHOLD) / PLIC_CONTEXT_STRIDE) as usize;
+                match (addr - PLIC_THRESHOLD) % PLIC_CONTEXT_STRIDE {
+                    0 => self.context_mut(context).threshold = value as u32,
+                    4 => self.complete(value),
+                    _ => (),
+                }
+            }
+            // The pending bits are read-only, and the rest is reserved.
+            _ => (),
         }
+        Ok(())
     }
 }
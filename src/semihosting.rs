@@ -0,0 +1,78 @@
+//! The semihosting module serves the RISC-V semihosting calls of a guest, with which
+//! newlib and picolibc programs print and exit without a UART driver.
+//!
+//! A call is an `ebreak` between `slli x0, x0, 0x1f` and `srai x0, x0, 7`, all three
+//! uncompressed. The operation is in a0 and its parameter, or a pointer to its
+//! parameter block, in a1. The result is returned in a0, -1 for an operation that
+//! isn't served or whose parameters can't be read. Only console output and exit are
+//! served:
+//!
+//! SYS_WRITEC: write the byte a1 points to to the UART output.
+//! SYS_WRITE0: write the NUL-terminated string a1 points to to the UART output.
+//! SYS_EXIT: a1 points to the reason and the exit code. Halt with the exit code if
+//! the reason is ADP_Stopped_ApplicationExit, or else with 1.
+
+use crate::cpu::{AccessType, Cpu};
+
+pub const SYS_WRITEC: u64 = 0x03;
+pub const SYS_WRITE0: u64 = 0x04;
+pub const SYS_EXIT: u64 = 0x18;
+/// The reason of SYS_EXIT for a program that returned from main or called exit.
+pub const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+/// `slli x0, x0, 0x1f`, the instruction before the `ebreak`.
+const ENTRY: u64 = 0x01f01013;
+/// `srai x0, x0, 7`, the instruction after the `ebreak`.
+const EXIT: u64 = 0x40705013;
+const EBREAK: u64 = 0x00100073;
+
+impl Cpu {
+    /// Whether `inst`, the instruction at the pc, is the `ebreak` of a semihosting call.
+    pub(crate) fn is_semihosting_call(&mut self, inst: u64) -> bool {
+        inst == EBREAK
+            && self.instruction_at(self.pc.wrapping_sub(4)) == Some(ENTRY)
+            && self.instruction_at(self.pc.wrapping_add(4)) == Some(EXIT)
+    }
+
+    /// The 32 bits of code at virtual address `addr`, read a halfword at a time as
+    /// they may be in different pages, if they can be fetched.
+    fn instruction_at(&mut self, addr: u64) -> Option<u64> {
+        let mut halfword = |addr: u64| {
+            let p_addr = self.translate(addr, AccessType::Instruction).ok()?;
+            self.read_mem(p_addr, 16).ok()
+        };
+        Some(halfword(addr)? | halfword(addr.wrapping_add(2))? << 16)
+    }
+
+    /// Serve the semihosting call in a0 and a1. Returns the exit code if the guest
+    /// exits, otherwise the result is in a0.
+    pub(crate) fn semihosting_call(&mut self) -> Option<i32> {
+        let param = self.regs[11];
+        let served = match self.regs[10] {
+            SYS_WRITEC => self.read_mem_virt(param, 8).map(|byte| self.bus.uart.transmit(byte as u8)).is_ok(),
+            SYS_WRITE0 => self.write_string(param).is_some(),
+            SYS_EXIT => {
+                if let (Ok(reason), Ok(code)) = (self.read_mem_virt(param, 64), self.read_mem_virt(param.wrapping_add(8), 64)) {
+                    return Some(if reason == ADP_STOPPED_APPLICATION_EXIT { code as i32 } else { 1 });
+                }
+                false
+            }
+            _ => false,
+        };
+        self.regs[10] = if served { 0 } else { u64::MAX };
+        None
+    }
+
+    /// Transmit the NUL-terminated string at virtual address `addr`. Gives up at the
+    /// first byte that can't be read, with what came before it already written.
+    fn write_string(&mut self, addr: u64) -> Option<()> {
+        let mut addr = addr;
+        loop {
+            match self.read_mem_virt(addr, 8).ok()? as u8 {
+                0 => return Some(()),
+                byte => self.bus.uart.transmit(byte),
+            }
+            addr = addr.wrapping_add(1);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! The snapshot module contains `CpuState`, a copy of the architectural state of a
+//! `Cpu` and its memory that can be restored later to resume from the same point.
+//!
+//! With the `serde` feature `CpuState` implements serde's traits, so a snapshot can be
+//! written to disk and restored by another run.
+
+use std::fmt;
+
+use crate::{clint::Clint, cpu::Cpu, csr::Csr, hart::Scheduler};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The snapshot was taken of a machine with another dram size.
+    DramSize { snapshot: usize, machine: usize },
+    /// The snapshot was taken of a machine with another number of harts.
+    Harts { snapshot: usize, machine: usize },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::DramSize { snapshot, machine } => {
+                write!(f, "snapshot of {:#x} bytes of dram doesn't fit dram of {:#x} bytes", snapshot, machine)
+            }
+            SnapshotError::Harts { snapshot, machine } => {
+                write!(f, "snapshot of {} harts doesn't fit a machine of {} harts", snapshot, machine)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    pub regs: [u64; 32],
+    pub fregs: [u64; 32],
+    pub pc: u64,
+    pub mode: u64,
+    pub csr: Csr,
+    pub enable_paging: bool,
+    pub page_table: u64,
+    pub waiting: bool,
+    pub dram: Vec<u8>,
+    /// The timer, so `time` and pending timer interrupts pick up where they were.
+    pub clint: Clint,
+    /// The image of the virtio block device, if the snapshot includes it. Without it a
+    /// restored machine keeps the disk as it is, which may not match its memory.
+    pub disk: Option<Vec<u8>>,
+    /// The other harts of an SMP machine and whose turn it is.
+    pub(crate) scheduler: Scheduler,
+}
+
+impl Cpu {
+    /// Copy the state of the cpu and dram, and of the disk if `include_disk` is set.
+    pub fn snapshot(&self, include_disk: bool) -> CpuState {
+        CpuState {
+            regs: self.regs,
+            fregs: self.fregs,
+            pc: self.pc,
+            mode: self.mode,
+            csr: self.csr.clone(),
+            enable_paging: self.enable_paging,
+            page_table: self.page_table,
+            waiting: self.waiting,
+            dram: self.bus.dram().to_vec(),
+            clint: self.bus.clint.clone(),
+            disk: include_disk.then(|| self.bus.virtio_blk.disk().to_vec()),
+            scheduler: self.scheduler.clone(),
+        }
+    }
+
+    /// Go back to the state in `state`, of every hart. The snapshot must be of a machine
+    /// with the same dram size and number of harts, otherwise nothing changes.
+    pub fn restore(&mut self, state: &CpuState) -> Result<(), SnapshotError> {
+        if state.dram.len() != self.bus.dram_size() {
+            return Err(SnapshotError::DramSize { snapshot: state.dram.len(), machine: self.bus.dram_size() });
+        }
+        if state.scheduler.hart_count() != self.hart_count() {
+            return Err(SnapshotError::Harts { snapshot: state.scheduler.hart_count(), machine: self.hart_count() });
+        }
+        self.regs = state.regs;
+        self.fregs = state.fregs;
+        self.pc = state.pc;
+        self.mode = state.mode;
+        self.csr = state.csr.clone();
+        self.enable_paging = state.enable_paging;
+        self.page_table = state.page_table;
+        self.waiting = state.waiting;
+        self.bus.dram_mut().copy_from_slice(&state.dram);
+        self.bus.clint = state.clint.clone();
+        if let Some(disk) = &state.disk {
+            self.bus.virtio_blk.set_disk(disk.clone());
+        }
+        self.reservation = None;
+        // Memory changed behind the decode cache and the TLB.
+        self.icache.flush();
+        self.tlb.flush(None, None);
+        self.scheduler = state.scheduler.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{builder::CpuBuilder, param::*};
+
+    #[test]
+    fn test_snapshot_replay() {
+        let mut cpu = counter(1, DRAM_SIZE);
+        let state = |cpu: &mut crate::cpu::Cpu| {
+            (cpu.regs, cpu.pc, cpu.csr.load(crate::csr::MCYCLE), cpu.bus.load(DRAM_END - 7, 64).unwrap())
+        };
+
+        assert_eq!(cpu.run_steps(10), None);
+        let snapshot = cpu.snapshot(false);
+        assert_eq!(cpu.run_steps(20), None);
+        let after = state(&mut cpu);
+        assert_eq!(cpu.reg("a0"), 8);
+
+        // The same instructions from the same state, down to the timer, end up in the same state.
+        cpu.restore(&snapshot).unwrap();
+        assert_eq!(cpu.reg("a0"), 3);
+        assert_eq!(cpu.run_steps(20), None);
+        assert_eq!(state(&mut cpu), after);
+    }
+
+    /// A loop counting in a0, on a machine of `harts` harts with `dram_size`.
+    fn counter(harts: usize, dram_size: u64) -> crate::cpu::Cpu {
+        let code = [
+            0x00150513u32, // addi a0, a0, 1
+            0xfea13c23,    // sd a0, -8(sp)
+            0xc01025f3,    // rdtime a1
+            0xff5ff06f,    // j -12
+        ];
+        let code = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        CpuBuilder::new().code(code).headless_uart(true).harts(harts).dram_size(dram_size).build()
+    }
+
+    #[test]
+    fn test_snapshot_harts() {
+        // Every hart goes back, not only the running one.
+        let mut cpu = counter(2, DRAM_SIZE);
+        let a0 = |cpu: &mut crate::cpu::Cpu| {
+            let running = cpu.current_hart();
+            let a0 = (0..2).map(|i| { cpu.switch_hart(i); cpu.reg("a0") }).collect::<Vec<_>>();
+            cpu.switch_hart(running);
+            a0
+        };
+        assert_eq!(cpu.run_steps(9), None);
+        let snapshot = cpu.snapshot(false);
+        let before = (a0(&mut cpu), cpu.current_hart());
+        assert_eq!(cpu.run_steps(20), None);
+        cpu.restore(&snapshot).unwrap();
+        assert_eq!((a0(&mut cpu), cpu.current_hart()), before);
+        assert_eq!(before, (vec![2, 1], 1));
+    }
+
+    #[test]
+    fn test_snapshot_dram_size() {
+        let snapshot = counter(1, DRAM_SIZE).snapshot(false);
+        let mut cpu = counter(1, 0x10000);
+        let err = cpu.restore(&snapshot).unwrap_err();
+        assert_eq!(err, SnapshotError::DramSize { snapshot: DRAM_SIZE as usize, machine: 0x10000 });
+        assert_eq!(cpu.pc, DRAM_BASE);
+    }
+
+    #[test]
+    fn test_snapshot_hart_count() {
+        let snapshot = counter(2, 0x10000).snapshot(false);
+        let mut cpu = counter(1, 0x10000);
+        let err = cpu.restore(&snapshot).unwrap_err();
+        assert_eq!(err, SnapshotError::Harts { snapshot: 2, machine: 1 });
+        assert_eq!((cpu.pc, cpu.hart_count()), (DRAM_BASE, 1));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_snapshot_serde() {
+        // A debug build moves the 4096 csrs of each hart around by value while it
+        // deserializes them, which takes more than the stack of a test thread.
+        let test = || {
+            let mut cpu = counter(2, 0x10000);
+            assert_eq!(cpu.run_steps(9), None);
+            let json = serde_json::to_string(&cpu.snapshot(false)).unwrap();
+            let after = (cpu.run_steps(20), cpu.regs, cpu.csr.load(crate::csr::MCYCLE));
+
+            let mut restored = counter(2, 0x10000);
+            restored.restore(&serde_json::from_str(&json).unwrap()).unwrap();
+            assert_eq!((restored.run_steps(20), restored.regs, restored.csr.load(crate::csr::MCYCLE)), after);
+        };
+        std::thread::Builder::new().stack_size(8 << 20).spawn(test).unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_disk() {
+        let mut cpu = CpuBuilder::new()
+            .disk(vec![0; 4 * SECTOR_SIZE as usize])
+            .headless_uart(true)
+            .build();
+        let fill = |cpu: &mut crate::cpu::Cpu, sector: u64, value: u64| {
+            for i in 0..SECTOR_SIZE {
+                cpu.bus.virtio_blk.write_disk(sector * SECTOR_SIZE + i, value);
+            }
+        };
+
+        fill(&mut cpu, 0, 0xaa);
+        cpu.regs[10] = 1;
+        let with_disk = cpu.snapshot(true);
+        let without_disk = cpu.snapshot(false);
+
+        fill(&mut cpu, 0, 0xcc);
+        fill(&mut cpu, 1, 0xbb);
+        cpu.regs[10] = 2;
+
+        cpu.restore(&without_disk).unwrap();
+        assert_eq!(cpu.reg("a0"), 1);
+        assert_eq!(cpu.bus.virtio_blk.read_disk(0), 0xcc);
+
+        cpu.restore(&with_disk).unwrap();
+        let disk = cpu.bus.virtio_blk.disk();
+        assert!(disk[..SECTOR_SIZE as usize].iter().all(|&b| b == 0xaa));
+        assert!(disk[SECTOR_SIZE as usize..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,170 @@
+//! The tlb module contains the translation lookaside buffer, which keeps the leaf PTEs
+//! of recent Sv39 translations so that a translation does not walk the page table again.
+//!
+//! Like on a real hart, changes to the page table are not seen until `sfence.vma`
+//! flushes the translations they affect, see `Tlb::flush`, or satp is written.
+
+use crate::param::PTE_G;
+
+const SETS: usize = 64;
+const WAYS: usize = 4;
+
+/// A translation: the leaf PTE found for a virtual page, where it is and at what level
+/// of the page table, 0 for a 4 KiB page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlbEntry {
+    pub pte: u64,
+    pub pte_addr: u64,
+    pub level: i64,
+}
+
+impl TlbEntry {
+    /// Whether the page, which may be a superpage, contains virtual address `vaddr`.
+    fn maps(&self, vpn: u64, vaddr: u64) -> bool {
+        let shift = 12 + 9 * self.level as u64;
+        (vpn << 12) >> shift == (vaddr & 0x7f_ffff_ffff) >> shift
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Slot {
+    asid: u64,
+    /// The virtual page number of the 4 KiB page looked up. A superpage has an entry
+    /// for each of its pages that was used.
+    vpn: u64,
+    entry: TlbEntry,
+}
+
+/// A set-associative cache of translations, indexed by virtual page number and tagged
+/// with the address space. Global pages match any address space.
+#[derive(Clone)]
+pub struct Tlb {
+    /// `WAYS` slots for each set.
+    slots: Vec<Option<Slot>>,
+    /// The way of each set to replace next, in turn.
+    victims: Vec<usize>,
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self { slots: vec![None; SETS * WAYS], victims: vec![0; SETS] }
+    }
+
+    /// The slots of the set of `vpn`.
+    fn set(vpn: u64) -> std::ops::Range<usize> {
+        let set = vpn as usize % SETS;
+        set * WAYS..(set + 1) * WAYS
+    }
+
+    /// The translation of virtual page `vpn` in address space `asid`, if any.
+    #[inline]
+    pub fn get(&self, asid: u64, vpn: u64) -> Option<TlbEntry> {
+        self.slots[Self::set(vpn)].iter().find_map(|slot| match slot {
+            Some(slot) if slot.vpn == vpn && (slot.asid == asid || slot.entry.pte & PTE_G != 0) => Some(slot.entry),
+            _ => None,
+        })
+    }
+
+    /// Cache a translation, in place of the one for the same page if there is one, or
+    /// else in a free way or the next one in turn.
+    pub fn insert(&mut self, asid: u64, vpn: u64, entry: TlbEntry) {
+        let range = Self::set(vpn);
+        let set = range.start / WAYS;
+        let ways = &mut self.slots[range];
+        let way = match ways.iter().position(|slot| slot.is_some_and(|slot| slot.vpn == vpn && slot.asid == asid)) {
+            Some(way) => way,
+            None => ways.iter().position(Option::is_none).unwrap_or_else(|| {
+                let way = self.victims[set];
+                self.victims[set] = (way + 1) % WAYS;
+                way
+            }),
+        };
+        ways[way] = Some(Slot { asid, vpn, entry });
+    }
+
+    /// Drop translations as `sfence.vma` does: those of the page containing `vaddr`, or
+    /// all if `None`, in address space `asid`, or all if `None`. Global pages stay
+    /// unless every address space is flushed.
+    #[cold]
+    pub fn flush(&mut self, vaddr: Option<u64>, asid: Option<u64>) {
+        for slot in self.slots.iter_mut() {
+            let Some(Slot { asid: slot_asid, vpn, entry }) = *slot else {
+                continue;
+            };
+            let page = vaddr.is_none_or(|vaddr| entry.maps(vpn, vaddr));
+            let space = asid.is_none_or(|asid| asid == slot_asid && entry.pte & PTE_G == 0);
+            if page && space {
+                *slot = None;
+            }
+        }
+    }
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_flush() {
+        let entry = |pte, level| TlbEntry { pte, pte_addr: 0, level };
+        let mut tlb = Tlb::new();
+        tlb.insert(1, 0x40000, entry(1, 0));
+        tlb.insert(2, 0x40001, entry(2, 0));
+        tlb.insert(1, 0x40002, entry(3 | PTE_G, 0));
+        // A 2 MiB superpage at 0x4020_0000, used at its second page.
+        tlb.insert(1, 0x40201, entry(4, 1));
+        assert_eq!(tlb.get(1, 0x40000), Some(entry(1, 0)));
+        assert_eq!(tlb.get(2, 0x40000), None);
+        // Global pages are in every address space.
+        assert_eq!(tlb.get(7, 0x40002), Some(entry(3 | PTE_G, 0)));
+
+        // Any address in a superpage flushes it.
+        tlb.flush(Some(0x4030_0abc), None);
+        assert_eq!(tlb.get(1, 0x40201), None);
+        assert!(tlb.get(1, 0x40000).is_some());
+
+        // An address space keeps its global pages.
+        tlb.flush(None, Some(1));
+        assert_eq!(tlb.get(1, 0x40000), None);
+        assert!(tlb.get(2, 0x40001).is_some());
+        assert!(tlb.get(1, 0x40002).is_some());
+
+        tlb.flush(Some(0x4000_2000), Some(1));
+        assert!(tlb.get(1, 0x40002).is_some());
+        tlb.flush(Some(0x4000_2000), None);
+        assert_eq!(tlb.get(1, 0x40002), None);
+
+        tlb.flush(None, None);
+        assert_eq!(tlb.get(2, 0x40001), None);
+    }
+
+    #[test]
+    fn test_replacement() {
+        let entry = |pte| TlbEntry { pte, pte_addr: 0, level: 0 };
+        let mut tlb = Tlb::new();
+        // Pages SETS apart share a set, which has room for WAYS of them.
+        let page = |i: usize| 0x40000 + (i * SETS) as u64;
+        for i in 0..WAYS {
+            tlb.insert(1, page(i), entry(i as u64));
+        }
+        assert!((0..WAYS).all(|i| tlb.get(1, page(i)) == Some(entry(i as u64))));
+
+        // Replacing a translation keeps the others.
+        tlb.insert(1, page(1), entry(7));
+        assert_eq!(tlb.get(1, page(1)), Some(entry(7)));
+        assert!(tlb.get(1, page(0)).is_some());
+
+        // One more takes the place of the first, then of the second.
+        tlb.insert(1, page(WAYS), entry(8));
+        assert_eq!(tlb.get(1, page(0)), None);
+        tlb.insert(1, page(WAYS + 1), entry(9));
+        assert_eq!(tlb.get(1, page(1)), None);
+        assert!(tlb.get(1, page(2)).is_some() && tlb.get(1, page(WAYS)).is_some());
+    }
+}
@@ -0,0 +1,185 @@
+//! The trace module contains the per-instruction tracer. Each executed instruction
+//! becomes a `TraceRecord`, which a `TraceFormatter` writes out in its own format.
+
+use std::io::{self, Write};
+
+use tracing::debug;
+
+use crate::cpu::RVABI;
+
+/// What one instruction did.
+pub struct TraceRecord {
+    pub hartid: u64,
+    /// The privilege mode the instruction ran in.
+    pub mode: u64,
+    pub pc: u64,
+    /// The raw instruction, 16 bits wide if it is compressed.
+    pub inst: u64,
+    /// The mnemonic, if the instruction is in `decode::INSTRUCTIONS`.
+    pub name: Option<&'static str>,
+    /// The register the instruction wrote and its new value.
+    pub write: Option<(usize, u64)>,
+}
+
+impl TraceRecord {
+    fn inst_hex(&self) -> String {
+        if self.inst & 0b11 != 0b11 {
+            format!("{:04x}", self.inst)
+        } else {
+            format!("{:08x}", self.inst)
+        }
+    }
+
+    fn mode_char(&self) -> char {
+        match self.mode {
+            0b00 => 'U',
+            0b01 => 'S',
+            _ => 'M',
+        }
+    }
+}
+
+/// Turns trace records into output.
+pub trait TraceFormatter {
+    fn write(&mut self, out: &mut dyn Write, record: &TraceRecord) -> io::Result<()>;
+}
+
+/// One readable line per instruction: `M 0x0000000080000000 00500513 addi       a0 = 0x5`.
+pub struct Pretty;
+
+impl TraceFormatter for Pretty {
+    fn write(&mut self, out: &mut dyn Write, record: &TraceRecord) -> io::Result<()> {
+        write!(
+            out,
+            "{} {:#018x} {:<8} {:<10}",
+            record.mode_char(),
+            record.pc,
+            record.inst_hex(),
+            record.name.unwrap_or("unknown")
+        )?;
+        match record.write {
+            Some((rd, value)) => writeln!(out, " {} = {:#x}", RVABI[rd], value),
+            None => writeln!(out),
+        }
+    }
+}
+
+/// The commit log of Spike (`--log-commits`), to diff a run against the reference
+/// simulator: `core   0: 3 0x0000000080000000 (0x00500513) x10 0x0000000000000005`.
+pub struct Spike;
+
+impl TraceFormatter for Spike {
+    fn write(&mut self, out: &mut dyn Write, record: &TraceRecord) -> io::Result<()> {
+        write!(
+            out,
+            "core {:>3}: {} {:#018x} (0x{})",
+            record.hartid,
+            record.mode,
+            record.pc,
+            record.inst_hex()
+        )?;
+        match record.write {
+            Some((rd, value)) => writeln!(out, " x{:<2} {:#018x}", rd, value),
+            None => writeln!(out),
+        }
+    }
+}
+
+/// Comma-separated values after a header line: `pc,inst,name,mode,rd,value`.
+/// The last two fields are empty when the instruction wrote no register.
+#[derive(Default)]
+pub struct Csv {
+    header_written: bool,
+}
+
+impl Csv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TraceFormatter for Csv {
+    fn write(&mut self, out: &mut dyn Write, record: &TraceRecord) -> io::Result<()> {
+        if !self.header_written {
+            writeln!(out, "pc,inst,name,mode,rd,value")?;
+            self.header_written = true;
+        }
+        let (rd, value) = match record.write {
+            Some((rd, value)) => (RVABI[rd].to_string(), format!("{:#x}", value)),
+            None => (String::new(), String::new()),
+        };
+        writeln!(
+            out,
+            "{:#x},0x{},{},{},{},{}",
+            record.pc,
+            record.inst_hex(),
+            record.name.unwrap_or(""),
+            record.mode_char(),
+            rd,
+            value
+        )
+    }
+}
+
+/// Passes each line of the trace on to `tracing` as a debug event of target `trace`,
+/// so it shows up with `RUST_LOG=debug` along with the rest of the log.
+#[derive(Default)]
+pub struct DebugLog {
+    line: Vec<u8>,
+}
+
+impl Write for DebugLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte == b'\n' {
+                debug!(target: "trace", "{}", String::from_utf8_lossy(&self.line));
+                self.line.clear();
+            } else {
+                self.line.push(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where the trace goes and in which format. Tracing is off until there is an output.
+pub struct Tracer {
+    formatter: Box<dyn TraceFormatter>,
+    out: Option<Box<dyn Write>>,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self { formatter: Box::new(Pretty), out: None }
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.out.is_some()
+    }
+
+    pub fn set_formatter(&mut self, formatter: Box<dyn TraceFormatter>) {
+        self.formatter = formatter;
+    }
+
+    pub fn set_output(&mut self, out: Option<Box<dyn Write>>) {
+        self.out = out;
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) {
+        if let Some(out) = self.out.as_mut() {
+            // A broken trace output shouldn't stop the guest.
+            let _ = self.formatter.write(out.as_mut(), record);
+        }
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,77 @@
+//! The transcript module contains golden-output testing for console programs: run a
+//! binary with scripted UART input and compare what it prints with what is expected.
+
+use crate::builder::CpuBuilder;
+
+/// The most instructions `run_transcript` runs.
+pub const TRANSCRIPT_STEP_LIMIT: usize = 10_000_000;
+
+/// Bytes of context shown around the first difference.
+const CONTEXT: usize = 16;
+
+/// Run `code` with `input` typed on a headless UART and return what it printed. The
+/// run ends when the cpu halts, once `len` bytes are printed or after
+/// `TRANSCRIPT_STEP_LIMIT` instructions, so programs that never exit can be tested.
+pub fn run_transcript(code: Vec<u8>, input: &[u8], len: usize) -> Vec<u8> {
+    let mut cpu = CpuBuilder::new().code(code).headless_uart(true).build();
+    cpu.bus.uart.push_input(input);
+    for _ in 0..TRANSCRIPT_STEP_LIMIT {
+        if cpu.bus.uart.output().len() >= len || cpu.step().is_err() {
+            break;
+        }
+    }
+    cpu.bus.uart.output().to_vec()
+}
+
+/// Describe how `actual` differs from `expected`: the first differing byte and the
+/// bytes around it. `None` if they are equal.
+pub fn transcript_diff(actual: &[u8], expected: &[u8]) -> Option<String> {
+    if actual == expected {
+        return None;
+    }
+    let at = actual.iter().zip(expected).take_while(|(a, e)| a == e).count();
+    let context = |bytes: &[u8]| {
+        let start = at.saturating_sub(CONTEXT);
+        let end = (at + CONTEXT).min(bytes.len());
+        String::from_utf8_lossy(&bytes[start..end]).escape_debug().to_string()
+    };
+    let byte = |bytes: &[u8]| bytes.get(at).map_or(String::from("end of output"), |b| format!("{:#04x}", b));
+    Some(format!(
+        "output differs at byte {}: expected {}, got {}\n  expected: \"{}\"\n  actual:   \"{}\"",
+        at,
+        byte(expected),
+        byte(actual),
+        context(expected),
+        context(actual)
+    ))
+}
+
+/// Run `code` with `input` like `run_transcript` and panic with a readable diff
+/// unless it prints exactly `expected`.
+pub fn assert_transcript(code: Vec<u8>, input: &[u8], expected: &[u8]) {
+    let actual = run_transcript(code, input, expected.len());
+    if let Some(diff) = transcript_diff(&actual, expected) {
+        panic!("{}", diff);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_echoback_transcript() {
+        let code = include_bytes!("../tests/test_echoback.bin").to_vec();
+        assert_transcript(code, b"abc", b"ABC");
+    }
+
+    #[test]
+    fn test_transcript_diff() {
+        assert_eq!(transcript_diff(b"ABC", b"ABC"), None);
+        let diff = transcript_diff(b"ABx\n", b"ABC\n").unwrap();
+        assert!(diff.starts_with("output differs at byte 2: expected 0x43, got 0x78"), "{}", diff);
+        assert!(diff.contains("\"ABC\\n\""), "{}", diff);
+        let diff = transcript_diff(b"AB", b"ABC").unwrap();
+        assert!(diff.contains("got end of output"), "{}", diff);
+    }
+}
@@ -1,73 +1,290 @@
 use crate::{param::*, exception::Exception};
 use std::{
+    collections::VecDeque,
     io::{self, Read, Write},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Condvar, Mutex,
     },
-    thread::{self}, 
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+type Registers = (Mutex<[u8; UART_SIZE as usize]>, Condvar);
+
+// The values of IIR: no interrupt, THR empty and received data available. The FIFO bits
+// are set while the FIFOs are enabled.
+const IIR_NONE: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_DATA: u8 = 0x04;
+const IIR_FIFO: u8 = 0xc0;
+
+/// Buffered output is flushed once it reaches this many bytes.
+const FLUSH_THRESHOLD: usize = 4096;
+/// Buffered output is flushed once it is this old, see `Uart::flush_if_stale`.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where the bytes written to THR go.
+enum Output {
+    /// Collected in memory, for a headless UART.
+    Captured(Vec<u8>),
+    /// Written to `out` in batches rather than a byte at a time.
+    Stream { out: Box<dyn Write>, buffer: Vec<u8>, last_flush: Instant },
+}
+
+impl Output {
+    fn flush(&mut self) {
+        if let Output::Stream { out, buffer, last_flush } = self {
+            if !buffer.is_empty() {
+                // Like a serial line with nothing attached, output that can't be
+                // written is lost.
+                let _ = out.write_all(buffer).and_then(|_| out.flush());
+                buffer.clear();
+            }
+            *last_flush = Instant::now();
+        }
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
 pub struct Uart {
     /// Pair of an array for UART buffer and a conditional variable.
-    uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
-    /// Bit if an interrupt happens.
-    interrupt: Arc<AtomicBool>,
+    uart: Arc<Registers>,
+    /// Mirror of the RX bit in LSR, so polling the interrupt line doesn't take the lock.
+    /// Only changed while holding the lock.
+    rx_ready: Arc<AtomicBool>,
+    /// Bytes written to THR, captured when running headless and stdout otherwise.
+    output: Output,
+    /// Whether `output` holds bytes that haven't been written yet.
+    buffered: bool,
+    /// Bytes queued by `push_input`, moved into RHR one at a time as the guest reads them.
+    input: VecDeque<u8>,
+    /// The interrupts the guest enabled in IER.
+    ier: u8,
+    /// THR has become empty since the guest last wrote it, read IIR or claimed the
+    /// interrupt. Bytes are sent as soon as they are written, so that is right after
+    /// each write.
+    thr_empty: bool,
+    /// Whether the FIFOs are enabled in FCR.
+    fifo: bool,
+    /// The divisor latch, DLL and DLM. It only matters for the baud rate of real hardware.
+    divisor: [u8; 2],
+    /// Tells the receive thread to exit, see `close`.
+    stop: Arc<AtomicBool>,
+    /// The thread receiving from the input, if the UART has one.
+    reader: Option<JoinHandle<()>>,
 }
 
 impl Uart {
-    /// Create a new UART.
+    /// Create a new UART attached to the terminal.
     pub fn new() -> Self {
+        let mut uart = Self::with_writer(Box::new(io::stdout()));
+        uart.spawn_reader(Box::new(io::stdin()));
+        uart
+    }
+
+    /// Create a UART that is not attached to the terminal and writes what the guest
+    /// transmits to `out`, buffered like the output to stdout.
+    pub fn with_writer(out: Box<dyn Write>) -> Self {
+        let mut uart = Self::headless();
+        uart.output = Output::Stream { out, buffer: Vec::new(), last_flush: Instant::now() };
+        uart
+    }
+
+    /// Create a headless UART that receives the bytes of `input` instead of the
+    /// terminal's. They are received one at a time as the guest reads them.
+    pub fn with_reader(input: Box<dyn Read + Send>) -> Self {
+        let mut uart = Self::headless();
+        uart.spawn_reader(input);
+        uart
+    }
+
+    /// Receive from `input` on a thread of its own until it ends or `close` is called.
+    fn spawn_reader(&mut self, mut input: Box<dyn Read + Send>) {
+        let regs = Arc::clone(&self.uart);
+        let rx_ready = Arc::clone(&self.rx_ready);
+        let stop = Arc::clone(&self.stop);
+        self.reader = Some(thread::spawn(move || {
+            let mut byte = [0];
+            while !stop.load(Ordering::Acquire) {
+                match input.read(&mut byte) {
+                    Ok(0) => break,
+                    Ok(_) => Self::receive(&regs, &rx_ready, &stop, byte[0]),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        println!("{}", e);
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+
+    /// Stop receiving. The receive thread exits at once if it is waiting for the guest
+    /// to read RHR, otherwise when its read returns, which for the terminal is at the
+    /// next key press. Dropping the UART closes it too.
+    pub fn close(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        // Under the lock, so the thread is either waiting and gets woken up or sees the
+        // flag before it waits.
+        let (uart, cvar) = &*self.uart;
+        let _array = uart.lock();
+        cvar.notify_all();
+    }
+
+    /// Put a byte received from the outside into RHR, waiting until the guest has
+    /// read the previous one. The byte is dropped if the UART is closed meanwhile.
+    fn receive(uart: &Registers, rx_ready: &AtomicBool, stop: &AtomicBool, byte: u8) {
+        let (uart, cvar) = uart;
+        let mut array = uart.lock().unwrap();
+        // if data have been received but not yet be transferred.
+        // this thread wait for it to be transferred.
+        while (array[UART_LSR as usize] & MASK_UART_LSR_RX) == 1 {
+            if stop.load(Ordering::Acquire) {
+                return;
+            }
+            array = cvar.wait(array).unwrap();
+        }
+        // data have been transferred, so receive the next one.
+        array[UART_RHR as usize] = byte;
+        // set the RX bit in LSR, which raises the interrupt.
+        array[UART_LSR as usize] |= MASK_UART_LSR_RX;
+        rx_ready.store(true, Ordering::Release);
+    }
+
+    /// Create a UART that is not attached to the terminal. Nothing is read from stdin
+    /// and transmitted bytes are collected in memory, see `output`.
+    pub fn headless() -> Self {
         let mut array = [0; UART_SIZE as usize];
-        array[UART_LSR as usize] |= MASK_UART_LSR_TX;
+        // Bytes are sent as soon as they are written, the transmitter is always idle.
+        array[UART_LSR as usize] |= MASK_UART_LSR_TX | MASK_UART_LSR_TEMT;
 
         let uart = Arc::new((Mutex::new(array), Condvar::new()));
-        let interrupt = Arc::new(AtomicBool::new(false));
-
-        // receive part
-        let read_uart = Arc::clone(&uart);
-        let read_interrupt = Arc::clone(&interrupt);
-        let mut byte = [0];
-        thread::spawn(move || loop {
-            match io::stdin().read(&mut byte) {
-                Ok(_) => {
-                    let (uart, cvar) = &*read_uart;
-                    let mut array = uart.lock().unwrap();
-                    // if data have been received but not yet be transferred.
-                    // this thread wait for it to be transferred.
-                    while (array[UART_LSR as usize] & MASK_UART_LSR_RX) == 1 {
-                        array = cvar.wait(array).unwrap();
-                    }
-                    // data have been transferred, so receive the next one.
-                    array[UART_RHR as usize] = byte[0];
-                    // set the read_interrupt to true.
-                    read_interrupt.store(true, Ordering::Release);
-                    // set the RX bit in LSR.
-                    array[UART_LSR as usize] |= MASK_UART_LSR_RX;
+        let rx_ready = Arc::new(AtomicBool::new(false));
+
+        Self {
+            uart,
+            rx_ready,
+            output: Output::Captured(Vec::new()),
+            buffered: false,
+            input: VecDeque::new(),
+            ier: 0,
+            thr_empty: false,
+            fifo: false,
+            divisor: [0; 2],
+            stop: Arc::new(AtomicBool::new(false)),
+            reader: None,
+        }
+    }
+
+    /// Queue bytes to be received, as if they had been typed on the terminal.
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+        let (uart, _cvar) = &*self.uart;
+        let mut array = uart.lock().unwrap();
+        if (array[UART_LSR as usize] & MASK_UART_LSR_RX) == 0 {
+            if let Some(byte) = self.input.pop_front() {
+                array[UART_RHR as usize] = byte;
+                array[UART_LSR as usize] |= MASK_UART_LSR_RX;
+                self.rx_ready.store(true, Ordering::Release);
+            }
+        }
+    }
 
+    /// Bytes the guest has transmitted so far. Always empty unless headless.
+    pub fn output(&self) -> &[u8] {
+        match &self.output {
+            Output::Captured(output) => output,
+            Output::Stream { .. } => &[],
+        }
+    }
+
+    /// Send `byte` down the line, as a write to THR does.
+    pub fn transmit(&mut self, byte: u8) {
+        match &mut self.output {
+            Output::Captured(output) => output.push(byte),
+            Output::Stream { buffer, .. } => {
+                buffer.push(byte);
+                self.buffered = true;
+                if byte == b'\n' || buffer.len() >= FLUSH_THRESHOLD {
+                    self.flush();
                 }
-                Err(e) => println!("{}", e),
             }
-        });
+        }
+    }
+
+    /// Write out the buffered output. It is also written when the UART is dropped.
+    pub fn flush(&mut self) {
+        self.output.flush();
+        self.buffered = false;
+    }
 
-        Self { uart, interrupt }
+    /// Flush output that has waited for `FLUSH_INTERVAL`, such as a prompt without a
+    /// newline. Cheap enough to call on every instruction.
+    #[inline]
+    pub fn flush_if_stale(&mut self) {
+        if self.buffered {
+            self.flush_stale();
+        }
+    }
+
+    #[cold]
+    fn flush_stale(&mut self) {
+        if let Output::Stream { last_flush, .. } = &self.output {
+            if last_flush.elapsed() >= FLUSH_INTERVAL {
+                self.flush();
+            }
+        }
     }
 
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         if size != 8 {
             return Err(Exception::LoadAccessFault(addr));
         }
+        let index = addr - UART_BASE;
+        // A guest polling the status register may be waiting for its output to show.
+        if index == UART_LSR {
+            self.flush();
+        }
         let (uart, cvar) = &*self.uart;
         let mut array = uart.lock().unwrap();
-        let index = addr - UART_BASE;
+        let dlab = array[UART_LCR as usize] & MASK_UART_LCR_DLAB != 0;
         // a read happends
         match index {
+            UART_RHR | UART_IER if dlab => Ok(self.divisor[index as usize] as u64),
+            UART_IER => Ok(self.ier as u64),
+            UART_IIR => {
+                // The highest priority interrupt. Reading it as the cause clears the THR
+                // empty one.
+                let id = if self.ier & MASK_UART_IER_RX != 0 && array[UART_LSR as usize] & MASK_UART_LSR_RX != 0 {
+                    IIR_RX_DATA
+                } else if self.ier & MASK_UART_IER_TX != 0 && self.thr_empty {
+                    self.thr_empty = false;
+                    IIR_THR_EMPTY
+                } else {
+                    IIR_NONE
+                };
+                Ok((id | if self.fifo { IIR_FIFO } else { 0 }) as u64)
+            }
             UART_RHR => {
-                // TODO: move this down to the end of this branch.
-                cvar.notify_one();
                 // Read the data from RHR and clear the RX bit in LSR.
                 array[UART_LSR as usize] &= !MASK_UART_LSR_RX;
-                Ok(array[UART_RHR as usize] as u64)
+                self.rx_ready.store(false, Ordering::Release);
+                let byte = array[UART_RHR as usize];
+                // Move the next queued byte, if any, into RHR.
+                if let Some(next) = self.input.pop_front() {
+                    array[UART_RHR as usize] = next;
+                    array[UART_LSR as usize] |= MASK_UART_LSR_RX;
+                    self.rx_ready.store(true, Ordering::Release);
+                }
+                // RHR is free again, let the receive thread fill it.
+                cvar.notify_one();
+                Ok(byte as u64)
             }
             _ => Ok(array[index as usize] as u64),
         }
@@ -77,13 +294,41 @@ impl Uart {
         if size != 8 {
             return Err(Exception::StoreAMOAccessFault(addr));
         }
-        let (uart, _cvar) = &*self.uart;
+        let (uart, cvar) = &*self.uart;
         let mut array = uart.lock().unwrap();
         let index = addr - UART_BASE;
+        let dlab = array[UART_LCR as usize] & MASK_UART_LCR_DLAB != 0;
         match index {
+            UART_THR | UART_IER if dlab => {
+                self.divisor[index as usize] = value as u8;
+                Ok(())
+            }
+            UART_IER => {
+                // Enabling the THR empty interrupt raises it, THR is always empty.
+                if self.ier & MASK_UART_IER_TX == 0 && value as u8 & MASK_UART_IER_TX != 0 {
+                    self.thr_empty = true;
+                }
+                self.ier = value as u8 & 0x0f;
+                Ok(())
+            }
+            UART_FCR => {
+                self.fifo = value as u8 & MASK_UART_FCR_ENABLE != 0;
+                // Clearing the receive FIFO drops the byte in RHR and the queued input.
+                // There is nothing to clear on the transmit side.
+                if value as u8 & MASK_UART_FCR_RX_RESET != 0 {
+                    array[UART_LSR as usize] &= !MASK_UART_LSR_RX;
+                    self.rx_ready.store(false, Ordering::Release);
+                    self.input.clear();
+                    cvar.notify_one();
+                }
+                Ok(())
+            }
+            // LSR is read-only.
+            UART_LSR => Ok(()),
             UART_THR => {
-                print!("{}", value as u8 as char);
-                io::stdout().flush().unwrap();
+                drop(array);
+                self.thr_empty = true;
+                self.transmit(value as u8);
                 Ok(())
             }
             _ => {
@@ -93,7 +338,213 @@ impl Uart {
         }
     }
 
+    /// Clear the THR empty interrupt, when the guest claims the UART from the PLIC.
+    pub fn acknowledge_thr_empty(&mut self) {
+        self.thr_empty = false;
+    }
+
+    /// Whether received data is waiting in RHR.
+    pub fn is_rx_ready(&self) -> bool {
+        self.rx_ready.load(Ordering::Acquire)
+    }
+
+    /// Whether the UART is raising its interrupt, the level of its line to the PLIC. It
+    /// is raised while a received byte waits in RHR, so it drops once the byte is read,
+    /// and when THR has become empty, until IIR is read, THR written or the interrupt
+    /// claimed. Each only if enabled in IER.
+    #[inline]
     pub fn is_interrupting(&self) -> bool {
-        self.interrupt.swap(false, Ordering::Acquire)
+        (self.ier & MASK_UART_IER_RX != 0 && self.is_rx_ready()) || (self.ier & MASK_UART_IER_TX != 0 && self.thr_empty)
+    }
+}
+
+impl Drop for Uart {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn test_buffered_output() {
+        #[derive(Clone, Default)]
+        struct Counting(Rc<RefCell<(Vec<u8>, usize, usize)>>);
+        impl Write for Counting {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let mut inner = self.0.borrow_mut();
+                inner.0.extend_from_slice(buf);
+                inner.1 += 1;
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.borrow_mut().2 += 1;
+                Ok(())
+            }
+        }
+
+        let out = Counting::default();
+        let mut uart = Uart::with_writer(Box::new(out.clone()));
+        // 125 lines of 80 bytes.
+        let text: Vec<u8> = (0..10_000).map(|i| if i % 80 == 79 { b'\n' } else { b'a' }).collect();
+        for &byte in &text {
+            uart.store(UART_BASE + UART_THR, 8, byte as u64).unwrap();
+        }
+        drop(uart);
+
+        let (written, writes, flushes) = out.0.take();
+        assert_eq!(written, text);
+        assert!(writes <= 125, "{} writes", writes);
+        assert!(flushes <= 125, "{} flushes", flushes);
+    }
+
+    #[test]
+    fn test_no_interrupt_after_read() {
+        const N: u8 = 200;
+        let mut uart = Uart::headless();
+        uart.store(UART_BASE + UART_IER, 8, MASK_UART_IER_RX as u64).unwrap();
+        let (regs, rx_ready) = (Arc::clone(&uart.uart), Arc::clone(&uart.rx_ready));
+        let stop = Arc::clone(&uart.stop);
+        let sender = thread::spawn(move || {
+            for byte in 0..N {
+                Uart::receive(&regs, &rx_ready, &stop, byte);
+            }
+        });
+
+        // Every interrupt must stand for a byte that hasn't been read yet, so the bytes
+        // come out once each and in order. Every other byte is found by polling LSR, as
+        // a driver without interrupts would, which must not leave an interrupt behind.
+        let mut expected = 0;
+        while expected < N {
+            let ready = if expected % 2 == 0 {
+                uart.load(UART_BASE + UART_LSR, 8).unwrap() as u8 & MASK_UART_LSR_RX != 0
+            } else {
+                uart.is_interrupting()
+            };
+            if ready {
+                assert_eq!(uart.load(UART_BASE + UART_RHR, 8).unwrap(), expected as u64);
+                expected += 1;
+            }
+        }
+        sender.join().unwrap();
+
+        assert!(!uart.is_interrupting());
+        assert!(!uart.is_rx_ready());
+    }
+
+    #[test]
+    fn test_iir_received_byte() {
+        let mut uart = Uart::headless();
+        let iir = |uart: &mut Uart| uart.load(UART_BASE + UART_IIR, 8).unwrap();
+        uart.store(UART_BASE + UART_IER, 8, MASK_UART_IER_RX as u64).unwrap();
+        assert_eq!(iir(&mut uart), 0x01);
+
+        // Each byte is reported until it is read.
+        uart.push_input(b"ab");
+        assert_eq!(iir(&mut uart), 0x04);
+        assert_eq!(iir(&mut uart), 0x04);
+        assert_eq!(uart.load(UART_BASE + UART_RHR, 8), Ok(b'a' as u64));
+        assert_eq!(iir(&mut uart), 0x04);
+        assert_eq!(uart.load(UART_BASE + UART_RHR, 8), Ok(b'b' as u64));
+        assert_eq!(iir(&mut uart), 0x01);
+
+        // Not while the interrupt is disabled, though LSR still shows the byte.
+        uart.store(UART_BASE + UART_IER, 8, 0).unwrap();
+        uart.push_input(b"c");
+        assert_eq!(iir(&mut uart), 0x01);
+        assert_eq!(uart.load(UART_BASE + UART_LSR, 8), Ok(0x61));
+
+        // Clearing the receive FIFO drops it, and LSR can't be written.
+        uart.push_input(b"d");
+        uart.store(UART_BASE + UART_FCR, 8, (MASK_UART_FCR_ENABLE | MASK_UART_FCR_RX_RESET) as u64).unwrap();
+        uart.store(UART_BASE + UART_LSR, 8, 0).unwrap();
+        assert_eq!(uart.load(UART_BASE + UART_LSR, 8), Ok(0x60));
+        assert!(!uart.is_rx_ready());
+    }
+
+    #[test]
+    fn test_interrupt_enable() {
+        let mut uart = Uart::headless();
+        let iir = |uart: &mut Uart| uart.load(UART_BASE + UART_IIR, 8).unwrap();
+        Uart::receive(&uart.uart, &uart.rx_ready, &uart.stop, b'x');
+        assert!(!uart.is_interrupting());
+        assert_eq!(iir(&mut uart), 0x01);
+
+        // Enabling the THR empty interrupt raises it. Received data comes first, and the
+        // THR empty interrupt is left until IIR reports it.
+        uart.store(UART_BASE + UART_IER, 8, (MASK_UART_IER_RX | MASK_UART_IER_TX) as u64).unwrap();
+        uart.store(UART_BASE + UART_FCR, 8, MASK_UART_FCR_ENABLE as u64).unwrap();
+        assert!(uart.is_interrupting());
+        assert_eq!(iir(&mut uart), 0xc4);
+        assert_eq!(uart.load(UART_BASE + UART_RHR, 8), Ok(b'x' as u64));
+        assert!(uart.is_interrupting());
+        assert_eq!(iir(&mut uart), 0xc2);
+        assert!(!uart.is_interrupting());
+        assert_eq!(iir(&mut uart), 0xc1);
+
+        // Each byte written empties THR again.
+        uart.store(UART_BASE + UART_THR, 8, b'y' as u64).unwrap();
+        assert!(uart.is_interrupting());
+        uart.store(UART_BASE + UART_IER, 8, 0).unwrap();
+        assert!(!uart.is_interrupting());
+    }
+
+    #[test]
+    fn test_divisor_latch() {
+        #[derive(Clone, Default)]
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Shared::default();
+        let mut uart = Uart::with_writer(Box::new(out.clone()));
+        // The setup of xv6: 38.4K baud, then 8 bits with no parity.
+        uart.store(UART_BASE + UART_LCR, 8, MASK_UART_LCR_DLAB as u64).unwrap();
+        uart.store(UART_BASE + UART_THR, 8, 0x03).unwrap();
+        uart.store(UART_BASE + UART_IER, 8, 0x00).unwrap();
+        assert_eq!(uart.load(UART_BASE + UART_RHR, 8), Ok(0x03));
+        uart.store(UART_BASE + UART_LCR, 8, 0x03).unwrap();
+        uart.store(UART_BASE + UART_IER, 8, MASK_UART_IER_RX as u64).unwrap();
+        assert_eq!(uart.load(UART_BASE + UART_IER, 8), Ok(MASK_UART_IER_RX as u64));
+        uart.store(UART_BASE + UART_THR, 8, b'a' as u64).unwrap();
+        drop(uart);
+
+        assert_eq!(*out.0.borrow(), b"a");
+    }
+
+    #[test]
+    fn test_close_reader() {
+        // An endless input keeps the thread waiting for the guest to read RHR.
+        let mut uart = Uart::with_reader(Box::new(io::repeat(b'x')));
+        while !uart.is_rx_ready() {
+            thread::yield_now();
+        }
+        assert_eq!(uart.load(UART_BASE + UART_RHR, 8), Ok(b'x' as u64));
+
+        let reader = uart.reader.take().unwrap();
+        drop(uart);
+        reader.join().unwrap();
+
+        // The thread also ends with its input.
+        let mut uart = Uart::with_reader(Box::new(&b"ab"[..]));
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            if uart.is_rx_ready() {
+                received.push(uart.load(UART_BASE + UART_RHR, 8).unwrap() as u8);
+            }
+        }
+        assert_eq!(received, b"ab");
+        uart.reader.take().unwrap().join().unwrap();
+        assert!(!uart.is_rx_ready());
     }
 }
@@ -1,59 +1,79 @@
 use crate::{
     exception::Exception::{self, *},
     param::*,
+    virtqueue::Virtqueue,
 };
 
-/// When we create a virtio block device, we initialize its NOTIFY as maximum number of virtqueue(1 in this case).
-/// When the device is interrupting, NOTIFY contains the index of the virtqueue needed to process.
+/// A virtio block device with a single request queue, see `Cpu::disk_access`.
 ///
 /// The virtio block device provide several APIs:
 ///
-/// interrupting: whether the device is interrupting
+/// take_notify: whether the driver notified the queue since the last call
 /// load: load the value of certain MMIO registers
 /// store: store some value into certain MMIO registers
-/// get_new_id: get the next id of used ring.
-/// desc_addr: get the base address of the virtqueue.
-/// read_disk: read data from disk and store into data buffer.
-/// write_disk: write the data contained in buffer into disk.
+/// queue: the state of the request queue.
+/// notify_used: record that the device used a chain, which it interrupts for.
+/// read_disk: read a byte of the disk.
+/// write_disk: write a byte of the disk.
 pub struct VirtioBlock {
-    id: u64,
     driver_features: u32,
     page_size: u32,
     queue_sel: u32,
-    queue_num: u32,
-    queue_pfn: u32,
-    queue_notify: u32,
+    queue: Virtqueue,
+    notified: bool,
+    interrupt_status: u32,
     status: u32,
     disk: Vec<u8>,
 }
 
-const MAX_BLOCK_QUEUE: u32 = 1;
-
 impl VirtioBlock {
     pub fn new(disk_image: Vec<u8>) -> Self {
-        let mut disk = Vec::new();
-        disk.extend(disk_image.into_iter());
-
         Self {
-            id: 0,
             driver_features: 0,
-            page_size: 0,
+            page_size: PAGE_SIZE as u32,
             queue_sel: 0,
-            queue_num: 0,
-            queue_pfn: 0,
-            queue_notify: MAX_BLOCK_QUEUE,
+            queue: Virtqueue::new(),
+            notified: false,
+            interrupt_status: 0,
             status: 0,
-            // directly use disk_image here?
-            disk,
+            disk: disk_image,
         }
     }
 
-    pub fn is_interrupting(&mut self) -> bool {
-        if self.queue_notify < MAX_BLOCK_QUEUE {
-            self.queue_notify = MAX_BLOCK_QUEUE;
-            return true;
-        }
-        return false;
+    /// Take the pending queue notification.
+    pub fn take_notify(&mut self) -> bool {
+        std::mem::take(&mut self.notified)
+    }
+
+    pub fn page_size(&self) -> u64 {
+        self.page_size as u64
+    }
+
+    pub fn queue(&self) -> Virtqueue {
+        self.queue
+    }
+
+    pub fn queue_mut(&mut self) -> &mut Virtqueue {
+        &mut self.queue
+    }
+
+    /// Record that the device updated the used ring.
+    pub fn notify_used(&mut self) {
+        self.interrupt_status |= 1;
+    }
+
+    fn reset(&mut self) {
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.queue = Virtqueue::new();
+        self.notified = false;
+        self.interrupt_status = 0;
+        self.status = 0;
+    }
+
+    /// The request queue if it is the selected one. There are no others.
+    fn selected(&mut self) -> Option<&mut Virtqueue> {
+        (self.queue_sel == 0).then_some(&mut self.queue)
     }
 
     pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
@@ -61,6 +81,7 @@ impl VirtioBlock {
             return Err(LoadAccessFault(addr));
         }
 
+        let selected = self.queue_sel == 0;
         match addr {
             VIRTIO_MAGIC => Ok(0x74726976),
             VIRTIO_VERSION => Ok(0x1),
@@ -68,8 +89,9 @@ impl VirtioBlock {
             VIRTIO_VENDOR_ID => Ok(0x554d4551),
             VIRTIO_DEVICE_FEATURES => Ok(0), // TODO: what should it return?
             VIRTIO_DRIVER_FEATURES => Ok(self.driver_features as u64),
-            VIRTIO_QUEUE_NUM_MAX => Ok(8),
-            VIRTIO_QUEUE_PFN => Ok(self.queue_pfn as u64),
+            VIRTIO_QUEUE_NUM_MAX => Ok(if selected { DESC_NUM as u64 } else { 0 }),
+            VIRTIO_QUEUE_PFN => Ok(if selected { self.queue.pfn as u64 } else { 0 }),
+            VIRTIO_INTERRUPT_STATUS => Ok(self.interrupt_status as u64),
             VIRTIO_STATUS => Ok(self.status as u64),
             _ => Ok(0),
         }
@@ -83,24 +105,36 @@ impl VirtioBlock {
         let value = value as u32;
 
         match addr {
-            VIRTIO_DEVICE_FEATURES => Ok(self.driver_features = value),
-            VIRTIO_GUEST_PAGE_SIZE => Ok(self.page_size = value),
-            VIRTIO_QUEUE_SEL => Ok(self.queue_sel = value),
-            VIRTIO_QUEUE_NUM => Ok(self.queue_num = value),
-            VIRTIO_QUEUE_PFN => Ok(self.queue_pfn = value),
-            VIRTIO_QUEUE_NOTIFY => Ok(self.queue_notify = value),
-            VIRTIO_STATUS => Ok(self.status = value),
-            _ => Ok(()),
+            VIRTIO_DRIVER_FEATURES => self.driver_features = value,
+            VIRTIO_GUEST_PAGE_SIZE => self.page_size = value,
+            VIRTIO_QUEUE_SEL => self.queue_sel = value,
+            VIRTIO_QUEUE_NUM => {
+                if let Some(q) = self.selected() {
+                    q.num = value.min(DESC_NUM as u32);
+                }
+            }
+            VIRTIO_QUEUE_ALIGN => {
+                if let Some(q) = self.selected() {
+                    q.align = value;
+                }
+            }
+            VIRTIO_QUEUE_PFN => {
+                if let Some(q) = self.selected() {
+                    q.pfn = value;
+                }
+            }
+            VIRTIO_QUEUE_NOTIFY if value == 0 => self.notified = true,
+            VIRTIO_INTERRUPT_ACK => self.interrupt_status &= !value,
+            VIRTIO_STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else {
+                    self.status = value;
+                }
+            }
+            _ => (),
         }
-    }
-
-    pub fn get_new_id(&mut self) -> u64 {
-        self.id = self.id.wrapping_add(1);
-        return self.id;
-    }
-
-    pub fn desc_addr(&self) -> u64 {
-        self.queue_pfn as u64 * self.page_size as u64
+        Ok(())
     }
 
     pub fn read_disk(&self, addr: u64) -> u64 {
@@ -110,4 +144,14 @@ impl VirtioBlock {
     pub fn write_disk(&mut self, addr: u64, value: u64) {
         self.disk[addr as usize] = value as u8;
     }
+
+    /// The whole disk image.
+    pub fn disk(&self) -> &[u8] {
+        &self.disk
+    }
+
+    /// Replace the disk image.
+    pub fn set_disk(&mut self, disk: Vec<u8>) {
+        self.disk = disk;
+    }
 }
@@ -0,0 +1,287 @@
+//! The virtio_net module contains a virtio network device and the backends that
+//! carry its packets on the host side.
+//!
+//! The device speaks the legacy virtio MMIO interface like `VirtioBlock`. It has a
+//! receive queue and a transmit queue. The cpu moves packets between guest dram and
+//! the backend in `Cpu::net_access`.
+
+use std::collections::VecDeque;
+
+use crate::{
+    exception::Exception::{self, *},
+    param::*,
+    virtqueue::Virtqueue,
+};
+
+/// The host side of a network device.
+pub trait NetBackend: Send {
+    /// Send an ethernet frame transmitted by the guest.
+    fn send(&mut self, frame: &[u8]);
+    /// Take the next frame for the guest, if any.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+    /// Whether `recv` has a frame ready.
+    fn has_pending(&mut self) -> bool;
+}
+
+/// A backend that hands every transmitted frame straight back to the guest.
+#[derive(Default)]
+pub struct Loopback {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl NetBackend for Loopback {
+    fn send(&mut self, frame: &[u8]) {
+        self.frames.push_back(frame.to_vec());
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.frames.pop_front()
+    }
+
+    fn has_pending(&mut self) -> bool {
+        !self.frames.is_empty()
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use tap::Tap;
+
+#[cfg(target_os = "linux")]
+mod tap {
+    use std::{
+        fs::{File, OpenOptions},
+        io::{self, Read, Write},
+        os::raw::{c_int, c_ulong},
+        os::unix::io::AsRawFd,
+        sync::mpsc::{self, Receiver},
+        thread,
+    };
+
+    use super::NetBackend;
+
+    const TUNSETIFF: c_ulong = 0x4004_54ca;
+    const IFF_TAP: u16 = 0x0002;
+    const IFF_NO_PI: u16 = 0x1000;
+    const IFNAMSIZ: usize = 16;
+
+    extern "C" {
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+
+    /// A backend bridging the guest to a host TAP interface.
+    ///
+    /// A thread blocks on the interface and queues the frames it reads, so polling
+    /// for received frames never makes a system call.
+    pub struct Tap {
+        file: File,
+        rx: Receiver<Vec<u8>>,
+        peeked: Option<Vec<u8>>,
+    }
+
+    impl Tap {
+        /// Attach to the TAP interface `name`, creating it if the process may.
+        pub fn open(name: &str) -> io::Result<Self> {
+            if name.len() >= IFNAMSIZ {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "interface name too long"));
+            }
+            let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+
+            // struct ifreq: the interface name followed by the flags.
+            let mut ifreq = [0u8; 40];
+            ifreq[..name.len()].copy_from_slice(name.as_bytes());
+            ifreq[IFNAMSIZ..IFNAMSIZ + 2].copy_from_slice(&(IFF_TAP | IFF_NO_PI).to_ne_bytes());
+            if unsafe { ioctl(file.as_raw_fd(), TUNSETIFF, ifreq.as_mut_ptr()) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let mut reader = file.try_clone()?;
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let mut buf = vec![0; 65536];
+                while let Ok(n) = reader.read(&mut buf) {
+                    if n == 0 || tx.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            Ok(Self { file, rx, peeked: None })
+        }
+    }
+
+    impl NetBackend for Tap {
+        fn send(&mut self, frame: &[u8]) {
+            // Like a real link, a frame the host refuses is dropped.
+            let _ = self.file.write(frame);
+        }
+
+        fn recv(&mut self) -> Option<Vec<u8>> {
+            self.peeked.take().or_else(|| self.rx.try_recv().ok())
+        }
+
+        fn has_pending(&mut self) -> bool {
+            if self.peeked.is_none() {
+                self.peeked = self.rx.try_recv().ok();
+            }
+            self.peeked.is_some()
+        }
+    }
+}
+
+/// A virtio network device with a receive queue and a transmit queue.
+///
+/// A write to QUEUE_NOTIFY with the transmit queue marks it for processing. The
+/// receive queue is filled whenever the backend has a frame and the driver has
+/// posted a buffer for it.
+pub struct VirtioNet {
+    driver_features: u32,
+    page_size: u32,
+    queue_sel: u32,
+    queues: [Virtqueue; 2],
+    tx_notified: bool,
+    interrupt_status: u32,
+    status: u32,
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+}
+
+impl VirtioNet {
+    pub fn new(backend: Box<dyn NetBackend>) -> Self {
+        Self {
+            driver_features: 0,
+            page_size: PAGE_SIZE as u32,
+            queue_sel: 0,
+            queues: [Virtqueue::new(); 2],
+            tx_notified: false,
+            interrupt_status: 0,
+            status: 0,
+            // A locally administered unicast address.
+            mac: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56],
+            backend,
+        }
+    }
+
+    /// Whether `Cpu::net_access` has work to do.
+    pub fn needs_service(&mut self) -> bool {
+        self.tx_notified
+            || (self.status & VIRTIO_STATUS_DRIVER_OK != 0
+                && self.queues[VIRTIO_NET_RX].is_ready()
+                && self.backend.has_pending())
+    }
+
+    /// Take the pending transmit notification.
+    pub fn take_tx_notify(&mut self) -> bool {
+        std::mem::take(&mut self.tx_notified)
+    }
+
+    pub fn page_size(&self) -> u64 {
+        self.page_size as u64
+    }
+
+    pub fn queue(&self, index: usize) -> Virtqueue {
+        self.queues[index]
+    }
+
+    pub fn queue_mut(&mut self, index: usize) -> &mut Virtqueue {
+        &mut self.queues[index]
+    }
+
+    pub fn backend(&mut self) -> &mut dyn NetBackend {
+        self.backend.as_mut()
+    }
+
+    /// Record that the device updated a used ring.
+    pub fn notify_used(&mut self) {
+        self.interrupt_status |= 1;
+    }
+
+    fn reset(&mut self) {
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.queues = [Virtqueue::new(); 2];
+        self.tx_notified = false;
+        self.interrupt_status = 0;
+        self.status = 0;
+    }
+
+    fn selected(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        // The registers have the same layout as the block device's.
+        let reg = addr - VIRTIO_NET_BASE + VIRTIO_BASE;
+        if reg >= VIRTIO_CONFIG {
+            // The configuration space is the MAC address, read a byte at a time.
+            let offset = (reg - VIRTIO_CONFIG) as usize;
+            return match (size, self.mac.get(offset)) {
+                (8, Some(&byte)) => Ok(byte as u64),
+                (8, None) => Ok(0),
+                _ => Err(LoadAccessFault(addr)),
+            };
+        }
+        if size != 32 {
+            return Err(LoadAccessFault(addr));
+        }
+
+        let queue = self.queues.get(self.queue_sel as usize);
+        match reg {
+            VIRTIO_MAGIC => Ok(0x74726976),
+            VIRTIO_VERSION => Ok(0x1),
+            VIRTIO_DEVICE_ID => Ok(0x1),
+            VIRTIO_VENDOR_ID => Ok(0x554d4551),
+            VIRTIO_DEVICE_FEATURES => Ok(VIRTIO_NET_F_MAC as u64),
+            VIRTIO_QUEUE_NUM_MAX => Ok(if queue.is_some() { DESC_NUM as u64 } else { 0 }),
+            VIRTIO_QUEUE_PFN => Ok(queue.map_or(0, |q| q.pfn as u64)),
+            VIRTIO_INTERRUPT_STATUS => Ok(self.interrupt_status as u64),
+            VIRTIO_STATUS => Ok(self.status as u64),
+            _ => Ok(0),
+        }
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(StoreAMOAccessFault(addr));
+        }
+
+        let value = value as u32;
+
+        match addr - VIRTIO_NET_BASE + VIRTIO_BASE {
+            VIRTIO_DRIVER_FEATURES => self.driver_features = value,
+            VIRTIO_GUEST_PAGE_SIZE => self.page_size = value,
+            VIRTIO_QUEUE_SEL => self.queue_sel = value,
+            VIRTIO_QUEUE_NUM => {
+                if let Some(q) = self.selected() {
+                    q.num = value.min(DESC_NUM as u32);
+                }
+            }
+            VIRTIO_QUEUE_ALIGN => {
+                if let Some(q) = self.selected() {
+                    q.align = value;
+                }
+            }
+            VIRTIO_QUEUE_PFN => {
+                if let Some(q) = self.selected() {
+                    q.pfn = value;
+                }
+            }
+            VIRTIO_QUEUE_NOTIFY if value as usize == VIRTIO_NET_TX => self.tx_notified = true,
+            VIRTIO_INTERRUPT_ACK => self.interrupt_status &= !value,
+            VIRTIO_STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else {
+                    self.status = value;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
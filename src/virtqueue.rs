@@ -1,6 +1,6 @@
 // VirtQueue
 
-use crate::param::*;
+use crate::{bus::Bus, param::*};
 
 #[repr(C)]
 pub struct VirtqDesc {
@@ -39,3 +39,102 @@ pub struct VirtioBlkRequest {
     pub reserved: u32,
     pub sector: u64,
 }
+
+/// The state of one virtqueue. The device keeps its own position in the
+/// available ring and its own count of used entries.
+#[derive(Clone, Copy)]
+pub struct Virtqueue {
+    pub(crate) num: u32,
+    pub(crate) align: u32,
+    pub(crate) pfn: u32,
+    pub last_avail: u16,
+    pub used_idx: u16,
+}
+
+impl Virtqueue {
+    pub fn new() -> Self {
+        Self { num: 0, align: PAGE_SIZE as u32, pfn: 0, last_avail: 0, used_idx: 0 }
+    }
+
+    pub fn num(&self) -> u64 {
+        self.num as u64
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.num != 0 && self.pfn != 0
+    }
+
+    // 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
+    // ------------------------------------------------------------------
+    // Descriptor Table  | Available Ring | (...padding...) | Used Ring
+    // ------------------------------------------------------------------
+    pub fn desc_addr(&self, page_size: u64) -> u64 {
+        self.pfn as u64 * page_size
+    }
+
+    pub fn avail_addr(&self, page_size: u64) -> u64 {
+        self.desc_addr(page_size) + 16 * self.num()
+    }
+
+    pub fn used_addr(&self, page_size: u64) -> u64 {
+        // flags, idx, ring[num] and used_event, all 16 bits wide.
+        let avail_end = self.avail_addr(page_size) + 2 * (3 + self.num());
+        let align = self.align.max(1) as u64;
+        avail_end.div_ceil(align) * align
+    }
+
+    /// Take the head of the next descriptor chain the driver made available.
+    pub fn next_avail(&mut self, bus: &mut Bus, page_size: u64) -> Option<u64> {
+        if !self.is_ready() {
+            return None;
+        }
+        let avail_addr = self.avail_addr(page_size);
+        let avail_idx = bus.load(avail_addr + 2, 16).ok()? as u16;
+        if avail_idx == self.last_avail {
+            return None;
+        }
+        let slot = self.last_avail as u64 % self.num();
+        let head = bus.load(avail_addr + 4 + 2 * slot, 16).ok()?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        Some(head % self.num())
+    }
+
+    /// The (addr, len, flags) of each descriptor in the chain starting at `head`.
+    pub fn chain(&self, bus: &mut Bus, page_size: u64, head: u64) -> Vec<(u64, u64, u16)> {
+        const DESC_SIZE: u64 = size_of::<VirtqDesc>() as u64;
+        let desc_addr = self.desc_addr(page_size);
+        let mut chain = Vec::new();
+        let mut index = head;
+        // A well-formed chain visits each descriptor at most once.
+        for _ in 0..self.num() {
+            let desc = desc_addr + DESC_SIZE * index;
+            let addr = bus.load(desc, 64).unwrap_or(0);
+            let len = bus.load(desc + 8, 32).unwrap_or(0);
+            let flags = bus.load(desc + 12, 16).unwrap_or(0) as u16;
+            let next = bus.load(desc + 14, 16).unwrap_or(0);
+            chain.push((addr, len, flags));
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next % self.num();
+        }
+        chain
+    }
+
+    /// Return the chain starting at `head` to the driver through the used ring, with
+    /// `len` bytes written to its buffers.
+    pub fn push_used(&mut self, bus: &mut Bus, page_size: u64, head: u64, len: u32) {
+        let used_addr = self.used_addr(page_size);
+        let elem = used_addr + 4 + 8 * (self.used_idx as u64 % self.num());
+        self.used_idx = self.used_idx.wrapping_add(1);
+        let _ = bus.store(elem, 32, head);
+        let _ = bus.store(elem + 4, 32, len as u64);
+        let _ = bus.store(used_addr + 2, 16, self.used_idx as u64);
+    }
+}
+
+impl Default for Virtqueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
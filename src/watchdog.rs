@@ -0,0 +1,61 @@
+//! The watchdog module contains a watchdog timer. Unless the guest kicks it within
+//! its timeout, counted in instructions, it expires and the machine is reset.
+
+use crate::{
+    exception::Exception::{self, *},
+    param::*,
+};
+
+/// A watchdog counting down one per instruction.
+///
+/// Registers, 32 or 64 bits wide:
+///
+/// KICK: write anything to start counting from the timeout again.
+/// TIMEOUT: the budget in instructions. Writing it also kicks the watchdog, 0 stops it.
+/// COUNT: read-only, the instructions left before the watchdog expires.
+pub struct Watchdog {
+    timeout: u64,
+    remaining: u64,
+}
+
+impl Watchdog {
+    pub fn new(timeout: u64) -> Self {
+        Self { timeout, remaining: timeout }
+    }
+
+    /// Count one instruction. Return true when the watchdog expires.
+    #[inline]
+    pub fn tick(&mut self) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        self.remaining -= 1;
+        self.remaining == 0
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 && size != 64 {
+            return Err(LoadAccessFault(addr));
+        }
+        match addr {
+            WATCHDOG_TIMEOUT => Ok(self.timeout),
+            WATCHDOG_COUNT => Ok(self.remaining),
+            _ => Ok(0),
+        }
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 && size != 64 {
+            return Err(StoreAMOAccessFault(addr));
+        }
+        match addr {
+            WATCHDOG_KICK => self.remaining = self.timeout,
+            WATCHDOG_TIMEOUT => {
+                self.timeout = value;
+                self.remaining = value;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}